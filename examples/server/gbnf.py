@@ -0,0 +1,24 @@
+import openai
+
+openai.api_key = "EMPTY"
+openai.base_url = "http://localhost:1234/v1/"
+
+with open("examples/server/json.gbnf", "r") as f:
+    json_gbnf = f.read()
+
+completion = openai.chat.completions.create(
+    model="mistral",
+    messages=[
+        {
+            "role": "user",
+            "content": "Describe the city of Paris as a JSON object with its name, country and population.",
+        }
+    ],
+    max_tokens=256,
+    frequency_penalty=1.0,
+    top_p=0.1,
+    temperature=0,
+    extra_body={"grammar": {"type": "gbnf", "value": json_gbnf}},
+)
+
+print(completion.choices[0].message.content)
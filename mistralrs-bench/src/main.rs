@@ -6,8 +6,9 @@ use mistralrs_core::{
     ModelSelected, Request, RequestMessage, Response, SamplingParams, SchedulerMethod, TokenSource,
     Usage,
 };
+use std::fmt::Display;
 use std::sync::Arc;
-use std::{fmt::Display, sync::mpsc::channel};
+use tokio::sync::mpsc::unbounded_channel;
 use tracing::{info, warn};
 
 enum TestName {
@@ -54,16 +55,24 @@ fn run_bench(
         temperature: Some(0.1),
         top_k: Some(32),
         top_p: Some(0.1),
+        min_p: None,
+        typical_p: None,
+        tfs_z: None,
+        mirostat: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        dry_params: None,
         max_len: Some(n_gen),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
+        raw_logprobs: false,
     };
     let sender = mistralrs.get_sender();
-    let (tx, rx) = channel();
+    let (tx, mut rx) = unbounded_channel();
 
     let req = Request {
         id: mistralrs.next_request_id(),
@@ -74,6 +83,12 @@ fn run_bench(
         is_streaming: false,
         constraint: Constraint::None,
         suffix: None,
+        tools: None,
+        tool_choice: None,
+        priority: 0,
+        tenant: None,
+        deadline: None,
+        max_queue_time: None,
     };
 
     let mut usages = Vec::new();
@@ -83,8 +98,8 @@ fn run_bench(
             sender.send(req.clone()).expect("Expected receiver.");
         }
         for _ in 0..concurrency {
-            match rx.recv() {
-                Ok(r) => match r {
+            match rx.blocking_recv() {
+                Some(r) => match r {
                     Response::InternalError(e) => {
                         unreachable!("Got an internal error: {e:?}");
                     }
@@ -98,12 +113,13 @@ fn run_bench(
                         usages.push(res.usage);
                     }
                     Response::Chunk(_) => unreachable!(),
+                    Response::CompletionChunk(_) => unreachable!(),
                     Response::CompletionModelError(_, _) => unreachable!(),
                     Response::CompletionDone(res) => {
                         usages.push(res.usage);
                     }
                 },
-                Err(e) => unreachable!("Expected a Done response, got: {:?}", e),
+                None => unreachable!("Expected a Done response, the engine dropped the request."),
             }
         }
     }
@@ -311,6 +327,7 @@ fn main() -> anyhow::Result<()> {
                     text: "Rust".to_string(),
                     echo_prompt: false,
                     best_of: 1,
+                    token_healing: false,
                 },
                 args.n_gen - 1,
                 *concurrency,
@@ -468,13 +468,26 @@ impl Recognizer for CfgParser {
     fn special_allowed(&mut self, tok: SpecialToken) -> bool {
         match tok {
             SpecialToken::EndOfSentence => {
-                if let Some(st) = self.try_push(None) {
-                    let tidx = self.grm.eof_token_idx();
-                    let mut pstack = self.pstack_for(&st).clone();
-                    matches!(self.parse_lexeme(tidx, &mut pstack), ParseResult::Accept)
-                } else {
-                    false
+                // Parse the last lexeme without `try_push`, which would reject it if no lexemes are
+                // viable after it, as is the case at the end of grammars without SKIP.
+                let top = self.byte_states.last().unwrap().clone();
+                let mut pstack = self.pstack_for(&top).clone();
+                match self.lexer.advance(top.lexer_state, None) {
+                    Some((_, Some(pat_idx))) => {
+                        if !self.skip_patterns[pat_idx] {
+                            let tidx = self.pat_idx_to_tidx[pat_idx];
+                            if !matches!(
+                                self.parse_lexeme(tidx, &mut pstack),
+                                ParseResult::Continue
+                            ) {
+                                return false;
+                            }
+                        }
+                    }
+                    _ => return false,
                 }
+                let tidx = self.grm.eof_token_idx();
+                matches!(self.parse_lexeme(tidx, &mut pstack), ParseResult::Accept)
             }
             _ => false,
         }
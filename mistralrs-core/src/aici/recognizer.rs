@@ -85,3 +85,21 @@ impl<S: Copy + Debug, R: FunctionalRecognizer<S>> Recognizer for StackRecognizer
         }
     }
 }
+
+/// Accepts any bytes, for when the output is not constrained.
+#[derive(Clone)]
+pub struct AnythingGoes;
+
+impl FunctionalRecognizer<()> for AnythingGoes {
+    fn initial(&self) {}
+
+    fn append(&self, _state: (), _byte: u8) {}
+
+    fn byte_allowed(&self, _state: (), _byte: u8) -> bool {
+        true
+    }
+
+    fn special_allowed(&self, _state: (), _tok: SpecialToken) -> bool {
+        true
+    }
+}
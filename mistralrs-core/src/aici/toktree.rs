@@ -420,6 +420,95 @@ impl TokTrie {
         self.apply_duplicates(logits);
     }
 
+    /// Compute the tokens allowed when the output must start with `prefix`, as with token healing:
+    /// the tokens which are a prefix of `prefix`, and the tokens which extend `prefix` with bytes
+    /// allowed by `r`. The end of sentence is not allowed.
+    pub fn compute_healing_bias(
+        &self,
+        r: &mut impl Recognizer,
+        logits: &mut SimpleVob,
+        prefix: &[u8],
+    ) {
+        logits.set_all(false);
+        for len in 1..=prefix.len() {
+            if let Some(tok) = self.token_id(&prefix[..len]) {
+                logits.allow_token(tok);
+            }
+        }
+        if let Some(n) = self.child_at_bytes(self.root(), prefix) {
+            r.trie_started();
+            // Like `add_bias`, but the walk starts below the root, so the bytes pushed after `prefix`
+            // are counted to not pop more than were pushed when leaving the subtree.
+            let off = self.node_offset(n);
+            let mut p = off + 1;
+            let endp = off + n.subtree_size();
+            let mut pushed = 0;
+            while p < endp {
+                let n = &self.nodes[p];
+                let num_pop = if r.try_push_byte(n.byte()) {
+                    pushed += 1;
+                    if let Some(tok) = n.token_id() {
+                        logits.allow_token(tok);
+                    }
+                    p += 1;
+                    if n.subtree_size() == 1 {
+                        n.num_parents()
+                    } else {
+                        0
+                    }
+                } else {
+                    p += n.subtree_size();
+                    n.num_parents() - 1
+                };
+                let num_pop = num_pop.min(pushed);
+                r.pop_bytes(num_pop);
+                pushed -= num_pop;
+            }
+            r.pop_bytes(pushed);
+            r.trie_finished();
+        }
+        self.apply_duplicates(logits);
+    }
+
+    /// Whether the token is allowed when the output must start with `prefix`, see
+    /// [`TokTrie::compute_healing_bias`].
+    pub fn token_allowed_with_prefix(
+        &self,
+        r: &mut impl Recognizer,
+        t: TokenId,
+        prefix: &[u8],
+    ) -> bool {
+        let bytes = self.token(t);
+        if bytes.len() <= prefix.len() {
+            return !bytes.is_empty() && prefix.starts_with(bytes);
+        }
+        if !bytes.starts_with(prefix) {
+            return false;
+        }
+        let mut num = 0;
+        let mut ok = true;
+        for &byte in &bytes[prefix.len()..] {
+            if r.try_push_byte(byte) {
+                num += 1;
+            } else {
+                ok = false;
+                break;
+            }
+        }
+        r.pop_bytes(num);
+        ok
+    }
+
+    /// Append the bytes of the token which follow `prefix`, the part of the output which must start
+    /// with `prefix` and is not seen by `r`.
+    pub fn append_token_after_prefix(&self, r: &mut impl Recognizer, t: TokenId, prefix: &[u8]) {
+        let bytes = self.token(t);
+        for &byte in bytes.get(prefix.len()..).unwrap_or_default() {
+            r.push_byte(byte)
+        }
+        r.collapse()
+    }
+
     pub fn apply_duplicates(&self, logits: &mut SimpleVob) {
         for (tok, dups) in &self.token_duplicates {
             if logits.is_allowed(*tok) {
@@ -577,3 +666,79 @@ impl TrieHash {
         data[idx].bits2 |= ((data.len() - idx) as u32) << 8;
     }
 }
+
+mod tests {
+    use super::TokTrie;
+    use crate::aici::{
+        bytes::TokRxInfo,
+        recognizer::StackRecognizer,
+        rx::{RecRx, RecRxState},
+    };
+
+    /// The end of sentence has no bytes, like the special tokens of a tokenizer.
+    #[allow(dead_code)]
+    const WORDS: [&str; 7] = ["", "a", "b", "c", "ab", "abc", "ac"];
+
+    #[allow(dead_code)]
+    fn trie() -> TokTrie {
+        let info = TokRxInfo {
+            vocab_size: WORDS.len() as u32,
+            tok_eos: 0,
+        };
+        let words = WORDS
+            .iter()
+            .map(|word| word.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        TokTrie::from(&info, &words)
+    }
+
+    /// A recognizer of the output which follows the healing prefix.
+    #[allow(dead_code)]
+    fn recognizer() -> StackRecognizer<RecRxState, RecRx> {
+        StackRecognizer::from(RecRx::from_rx("bc*").unwrap())
+    }
+
+    #[test]
+    fn test_compute_healing_bias() {
+        let trie = trie();
+        let mut logits = trie.alloc_token_set();
+        trie.compute_healing_bias(&mut recognizer(), &mut logits, b"a");
+        // `a` is the prefix itself, and `ab` and `abc` extend it with bytes allowed by the recognizer.
+        // `ac` extends it with a byte which is not allowed, and the end of sentence is never allowed.
+        let allowed = (0..WORDS.len() as u32)
+            .filter(|tok| logits.is_allowed(*tok))
+            .collect::<Vec<_>>();
+        assert_eq!(allowed, vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn test_token_allowed_with_prefix() {
+        let trie = trie();
+        let mut r = recognizer();
+        let allowed = (0..WORDS.len() as u32)
+            .filter(|tok| trie.token_allowed_with_prefix(&mut r, *tok, b"a"))
+            .collect::<Vec<_>>();
+        assert_eq!(allowed, vec![1, 4, 5]);
+        // Only the bytes after the prefix are seen by the recognizer, which must start with `b`.
+        assert!(trie.token_allowed_with_prefix(&mut r, 4, b"ab"));
+        assert!(!trie.token_allowed_with_prefix(&mut r, 5, b"ab"));
+    }
+
+    #[test]
+    fn test_append_token_after_prefix() {
+        use super::Recognizer;
+
+        let trie = trie();
+        // The prefix itself does not advance the recognizer.
+        let mut r = recognizer();
+        trie.append_token_after_prefix(&mut r, 1, b"a");
+        assert!(r.byte_allowed(b'b'));
+        assert!(!r.byte_allowed(b'c'));
+
+        // Only the bytes after the prefix are pushed.
+        let mut r = recognizer();
+        trie.append_token_after_prefix(&mut r, 5, b"a");
+        assert!(r.byte_allowed(b'c'));
+        assert!(!r.byte_allowed(b'b'));
+    }
+}
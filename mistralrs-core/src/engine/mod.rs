@@ -1,49 +1,65 @@
+mod speculative;
+
+pub use speculative::{PromptLookupConfig, SpeculativeConfig};
+pub(crate) use speculative::{Proposer, Speculative};
+
 use std::{
     cell::RefCell,
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet},
     iter::zip,
     rc::Rc,
-    sync::{mpsc::Receiver, Mutex},
+    sync::{mpsc::Receiver, Arc, Mutex},
     time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
     handle_seq_error_ok, handle_seq_error_stateaware_ok,
-    response::CompletionChoice,
+    response::{CompletionChoice, CompletionChunkChoice},
     CompletionResponse, RequestMessage,
 };
 use candle_core::{quantized::GgmlDType, Result, Tensor};
 use tracing::warn;
 
 use crate::{
-    get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
+    aici::toktree::TokTrie,
+    get_mut_arcmutex,
+    grammar::GrammarCache,
+    handle_pipeline_forward_error, handle_seq_error,
+    paged_attention::{PagedAttentionConfig, PagedKvCache},
     pipeline::Pipeline,
-    prefix_cacher::PrefixCacheManager,
+    prefix_cacher::{
+        DiskPrefixCache, PrefixCacheBudget, PrefixCacheDiskConfig, PrefixCacheManager,
+    },
+    queue::QueueState,
     request::Request,
     response::{
         ChatCompletionResponse, Choice, ChunkChoice, Delta, Logprobs, Response, ResponseLogprob,
-        ResponseMessage, SYSTEM_FINGERPRINT,
+        ResponseMessage, ToolCallResponse, SYSTEM_FINGERPRINT,
     },
     sampler::Sampler,
-    scheduler::{Scheduler, SchedulerMethod},
-    sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState, StopReason},
+    scheduler::{KvCacheBlocks, PriorityBacker, Scheduler, SchedulerMethod},
+    sequence::{Sequence, SequenceGroup, SequenceState, StopReason},
+    tools::{ToolCallingMatcher, ToolChoice},
     Constraint, StopTokens,
 };
 
-const SEED: u64 = 0;
-
 pub struct Engine {
     rx: Receiver<Request>,
     isq_rx: Receiver<GgmlDType>,
+    cancel_rx: Receiver<usize>,
+    queue: Arc<QueueState>,
     pipeline: Box<Mutex<dyn Pipeline>>,
-    scheduler: Scheduler<VecDeque<Sequence>>,
+    scheduler: Scheduler<PriorityBacker>,
     id: usize,
     truncate_sequence: bool,
     no_kv_cache: bool,
     prefix_cacher: PrefixCacheManager,
     is_debug: bool,
     disable_eos_stop: bool,
+    paged_attn: bool,
+    grammar_cache: GrammarCache,
+    speculative: Option<Speculative>,
+    prefill_chunk_size: Option<usize>,
 }
 
 impl Engine {
@@ -51,34 +67,84 @@ impl Engine {
     pub fn new(
         rx: Receiver<Request>,
         isq_rx: Receiver<GgmlDType>,
+        cancel_rx: Receiver<usize>,
+        queue: Arc<QueueState>,
         pipeline: Box<Mutex<dyn Pipeline>>,
         method: SchedulerMethod,
         truncate_sequence: bool,
         no_kv_cache: bool,
         no_prefix_cache: bool,
         prefix_cache_n: usize,
+        prefix_cache_device_bytes: Option<usize>,
+        prefix_cache_host_bytes: usize,
         disable_eos_stop: bool,
+        paged_attn_config: Option<PagedAttentionConfig>,
+        prefix_cache_disk_config: Option<PrefixCacheDiskConfig>,
+        grammar_cache_n: usize,
+        named_grammars: HashMap<String, Constraint>,
+        speculative: Option<Speculative>,
+        prefill_chunk_size: Option<usize>,
+        fair_share: bool,
+        ragged_batches: bool,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).is_xlora();
+        let kv_cache_bytes_per_token = get_mut_arcmutex!(pipeline)
+            .kv_cache_metadata()
+            .bytes_per_token();
+        let max_seq_len = get_mut_arcmutex!(pipeline).get_max_seq_len();
+        let name = get_mut_arcmutex!(pipeline).name();
+        let weights_id = get_mut_arcmutex!(pipeline).weights_id();
+        let kv_cache_dtype = get_mut_arcmutex!(pipeline).kv_cache_metadata().dtype;
+        let prefix_cache_disk = prefix_cache_disk_config.and_then(|config| {
+            DiskPrefixCache::new(&config, &name, &weights_id, kv_cache_dtype)
+                .map_err(|e| warn!("Could not open the prefix cache directory, not using it: {e}"))
+                .ok()
+        });
+        let paged_attn = if let Some(config) = paged_attn_config {
+            let pipeline = get_mut_arcmutex!(pipeline);
+            let cache = PagedKvCache::new(config, pipeline.num_hidden_layers());
+            *pipeline.cache().paged_lock() = Some(cache);
+            true
+        } else {
+            false
+        };
         Self {
             rx,
             isq_rx,
+            cancel_rx,
+            queue,
             pipeline,
-            scheduler: Scheduler::new(method),
+            scheduler: Scheduler::new(
+                method,
+                kv_cache_bytes_per_token,
+                max_seq_len,
+                ragged_batches,
+                fair_share,
+            ),
             id: 0,
             truncate_sequence,
             no_kv_cache,
             prefix_cacher: PrefixCacheManager::new(
                 device,
-                prefix_cache_n,
+                PrefixCacheBudget {
+                    n_on_device: prefix_cache_n,
+                    device_max_bytes: prefix_cache_device_bytes,
+                    host_max_bytes: prefix_cache_host_bytes,
+                },
                 is_xlora,
-                no_prefix_cache,
+                // The prefix cache stores the per-sequence KV caches, which are unused with paged attention.
+                no_prefix_cache || paged_attn,
+                prefix_cache_disk,
             ),
             is_debug: std::env::var("RUST_LOG")
                 .unwrap_or_default()
                 .contains("debug"),
             disable_eos_stop,
+            paged_attn,
+            grammar_cache: GrammarCache::new(grammar_cache_n, named_grammars),
+            speculative,
+            prefill_chunk_size,
         }
     }
 
@@ -86,22 +152,75 @@ impl Engine {
         let mut last_run = Instant::now();
         let mut last_completion_ids: Vec<usize> = vec![];
         'lp: loop {
+            // Collect the cancellations first: a request is always sent before its cancellation,
+            // so it will have been received by the time the cancellation is applied.
+            let mut canceled = self.cancel_rx.try_iter().collect::<HashSet<_>>();
             while let Ok(request) = self.rx.try_recv() {
                 self.add_request(request);
             }
-            let mut scheduled = self.scheduler.schedule();
+            // The requests whose receiver was dropped while their responses were sent are canceled too.
+            canceled.extend(self.scheduler.disconnected_requests());
+            for request_id in canceled {
+                self.cancel_request(request_id);
+            }
+            if let Err(e) = self.expire_requests() {
+                warn!("Finishing timed out requests failed: {e:?}");
+            }
             let mut pipeline = get_mut_arcmutex!(self.pipeline);
+            if let Some(paged_cache) = pipeline.cache().paged_lock().as_ref() {
+                let block_engine = paged_cache.block_engine();
+                self.scheduler.set_kv_cache_blocks(KvCacheBlocks {
+                    block_size: block_engine.block_size(),
+                    num_free: block_engine.num_free_blocks(),
+                    allocated: block_engine.allocations(),
+                });
+            }
+            let mut scheduled = self.scheduler.schedule();
+            if let Some(paged_cache) = pipeline.cache().paged_lock().as_mut() {
+                for id in &scheduled.preempted {
+                    paged_cache.free(*id);
+                }
+            }
             if let Ok(dtype) = self.isq_rx.try_recv() {
-                if let Err(e) = pipeline.re_isq_model(dtype) {
-                    warn!("ISQ requantization failed: {e:?}");
+                match pipeline.re_isq_model(dtype) {
+                    // The prefix caches were computed with the previous weights.
+                    Ok(()) => self.prefix_cacher.clear(),
+                    Err(e) => warn!("ISQ requantization failed: {e:?}"),
                 }
             }
 
-            if scheduled.completion.len() > 0 {
+            let speculate = self.speculative.as_ref().is_some_and(|speculative| {
+                let mut max_seq_len = pipeline.get_max_seq_len();
+                if let Proposer::Draft(ref draft) = speculative.proposer {
+                    max_seq_len = max_seq_len.min(get_mut_arcmutex!(draft).get_max_seq_len());
+                }
+                Self::can_speculate(&mut scheduled.completion, speculative.gamma, max_seq_len)
+            });
+            if scheduled.completion.len() > 0 && speculate {
+                handle_pipeline_forward_error!(
+                    "speculative decoding",
+                    Self::speculative_step(&mut *pipeline, self.speculative.as_ref().unwrap(), &mut scheduled.completion, &mut self.prefix_cacher, self.disable_eos_stop),
+                    &mut scheduled.completion,
+                    pipeline,
+                    'lp,
+                    self.prefix_cacher
+                );
+                // The model cache was rolled back in the sequences, so it is cloned in at the next step.
+                last_completion_ids = vec![];
+            } else if scheduled.completion.len() > 0 {
                 let current_completion_ids: Vec<usize> =
                     scheduled.completion.iter().map(|seq| *seq.id()).collect();
                 // Run the completion seqs
-                if !self.no_kv_cache && last_completion_ids != current_completion_ids {
+                if self.paged_attn {
+                    handle_pipeline_forward_error!(
+                        "paged attention",
+                        Self::prepare_paged_attn(&mut *pipeline, &scheduled.completion, false),
+                        &mut scheduled.completion,
+                        pipeline,
+                        'lp,
+                        self.prefix_cacher
+                    );
+                } else if !self.no_kv_cache && last_completion_ids != current_completion_ids {
                     Self::clone_in_cache(&mut *pipeline, &mut scheduled.completion);
                 }
                 let logits = pipeline.forward(&scheduled.completion, false);
@@ -114,10 +233,11 @@ impl Engine {
                     self.prefix_cacher
                 );
 
-                if !self.no_kv_cache {
-                    Self::clone_out_cache(&mut *pipeline, &mut scheduled.completion);
-                } else {
+                if self.no_kv_cache {
                     Self::set_none_cache(&mut *pipeline);
+                } else if !self.paged_attn {
+                    // With paged attention, the keys and values are already in the paged KV cache.
+                    Self::clone_out_cache(&mut *pipeline, &mut scheduled.completion);
                 }
 
                 handle_pipeline_forward_error!(
@@ -128,12 +248,30 @@ impl Engine {
                     'lp,
                     self.prefix_cacher
                 );
+                if self.paged_attn {
+                    Self::free_paged_blocks(&mut *pipeline, &scheduled.completion);
+                }
                 last_completion_ids = current_completion_ids;
             }
 
             if scheduled.prompt.len() > 0 {
-                // Run the prompt seqs
-                Self::set_none_cache(&mut *pipeline);
+                // Run the prompt seqs. If some of them start from a cached prefix, the KV caches of
+                // the others are all padding.
+                if scheduled.prompt.iter().any(|seq| seq.prefix_len() > 0) {
+                    Self::clone_in_cache(&mut *pipeline, &mut scheduled.prompt);
+                } else {
+                    Self::set_none_cache(&mut *pipeline);
+                }
+                if self.paged_attn {
+                    handle_pipeline_forward_error!(
+                        "paged attention",
+                        Self::prepare_paged_attn(&mut *pipeline, &scheduled.prompt, true),
+                        &mut scheduled.prompt,
+                        pipeline,
+                        'lp,
+                        self.prefix_cacher
+                    );
+                }
                 let logits = pipeline.forward(&scheduled.prompt, true);
                 let logits = handle_pipeline_forward_error!(
                     "prompt",
@@ -144,31 +282,46 @@ impl Engine {
                     self.prefix_cacher
                 );
 
-                if !self.no_kv_cache {
-                    Self::clone_out_cache(&mut *pipeline, &mut scheduled.prompt);
-                } else {
+                if self.no_kv_cache {
                     Self::set_none_cache(&mut *pipeline);
+                } else if !self.paged_attn {
+                    // With paged attention, the keys and values are already in the paged KV cache.
+                    Self::clone_out_cache(&mut *pipeline, &mut scheduled.prompt);
                 }
 
-                handle_pipeline_forward_error!(
-                    "sampling",
-                    Self::sample_seqs(&mut *pipeline, &mut scheduled.prompt, logits, &mut self.prefix_cacher,self.disable_eos_stop,),
-                    &mut scheduled.prompt,
-                    pipeline,
-                    'lp,
-                    self.prefix_cacher
-                );
+                // With chunked prefill, the prompt seqs are only sampled once their last chunk has
+                // run. Ragged batches are disabled with chunked prefill, so the prompt seqs are
+                // bucketed by length and cached prefix: they have the same number of tokens left to
+                // run and finish together.
+                let prompt_done = scheduled
+                    .prompt
+                    .iter_mut()
+                    .map(|seq| seq.finish_prefill_chunk())
+                    .collect::<Vec<_>>();
+                if prompt_done.iter().all(|done| *done) {
+                    handle_pipeline_forward_error!(
+                        "sampling",
+                        Self::sample_seqs(&mut *pipeline, &mut scheduled.prompt, logits, &mut self.prefix_cacher,self.disable_eos_stop,),
+                        &mut scheduled.prompt,
+                        pipeline,
+                        'lp,
+                        self.prefix_cacher
+                    );
+                    if self.paged_attn {
+                        Self::free_paged_blocks(&mut *pipeline, &scheduled.prompt);
+                    }
 
-                for seq in scheduled.prompt.iter_mut() {
-                    seq.set_state(SequenceState::RunningCompletion);
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("Time travel has occurred!")
-                        .as_millis();
-                    #[allow(clippy::cast_precision_loss)]
-                    let prompt_tok_per_sec = seq.len() as f32 / (now - seq.timestamp()) as f32;
-                    seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
-                    seq.prompt_timestamp = Some(now);
+                    for seq in scheduled.prompt.iter_mut() {
+                        seq.set_state(SequenceState::RunningCompletion);
+                        let now = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .expect("Time travel has occurred!")
+                            .as_millis();
+                        #[allow(clippy::cast_precision_loss)]
+                        let prompt_tok_per_sec = seq.len() as f32 / (now - seq.timestamp()) as f32;
+                        seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
+                        seq.prompt_timestamp = Some(now);
+                    }
                 }
                 last_completion_ids = vec![];
             }
@@ -198,13 +351,22 @@ impl Engine {
                         completion_lengths,
                         ms_from_last_run
                     );
+                    if let Some(paged_cache) = pipeline.cache().paged_lock().as_ref() {
+                        let block_engine = paged_cache.block_engine();
+                        tracing::info!(
+                            "Free KV cache blocks: {}/{}",
+                            block_engine.num_free_blocks(),
+                            block_engine.num_blocks()
+                        );
+                    }
                 }
             }
             drop(pipeline);
-            if scheduled.prompt.len() == 0
-                && scheduled.completion.len() == 0
-                && self.scheduler.waiting_len() == 0
-            {
+            let is_idle = scheduled.prompt.len() == 0 && scheduled.completion.len() == 0;
+            // The scheduled sequences borrow the scheduler, so the queue status is updated once they have run.
+            self.queue
+                .set_scheduler_status(self.scheduler.queue_status());
+            if is_idle && self.scheduler.waiting_len() == 0 {
                 // If there is nothing to do, sleep until a request comes in
                 if let Ok(request) = self.rx.recv() {
                     self.add_request(request);
@@ -229,67 +391,124 @@ impl Engine {
             let return_logprobs = seq.return_logprobs();
             let sampled = pipeline.sample(logits_per_seq, seq, return_logprobs);
             let next_token = handle_seq_error_stateaware_ok!(sampled, seq);
-            let next_token_id = next_token.token;
+            Self::commit_token(
+                pipeline,
+                seq,
+                next_token,
+                &eos_tok,
+                prefix_cacher,
+                disable_eos_stop,
+            )?;
+        }
 
-            let eos_tok = if disable_eos_stop {
-                None
-            } else {
-                Some(eos_tok.as_ref())
-            };
-            let is_done = seq.is_done(next_token_id, eos_tok, pipeline.get_max_seq_len());
-            seq.add_token(
-                next_token.clone(),
-                pipeline.tok_trie().decode(&[next_token_id]),
-                &is_done,
-            );
-            // Handle streaming requests
-            if seq.get_mut_group().is_streaming && seq.get_mut_group().is_chat {
-                let token_index = seq.get_toks().len();
-                let rate_limit_allowed = is_done.is_some() || token_index % 3 == 0;
+        Ok(())
+    }
 
-                if rate_limit_allowed {
-                    if let Some(delta) = handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
+    /// Add a sampled token to a sequence, send it to streaming requests and finish the sequence
+    /// if it is done.
+    fn commit_token(
+        pipeline: &mut dyn Pipeline,
+        seq: &mut Sequence,
+        next_token: crate::sampler::Logprobs,
+        eos_tok: &[u32],
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+    ) -> Result<()> {
+        let next_token_id = next_token.token;
+
+        let eos_tok = if disable_eos_stop {
+            None
+        } else {
+            Some(eos_tok)
+        };
+        let is_done = seq.is_done(next_token_id, eos_tok, pipeline.get_max_seq_len());
+        seq.add_token(
+            next_token.clone(),
+            pipeline.tok_trie().decode(&[next_token_id]),
+            &is_done,
+        );
+        // Handle streaming requests
+        if seq.get_mut_group().is_streaming {
+            let token_index = seq.get_toks().len();
+            let rate_limit_allowed = is_done.is_some() || token_index % 3 == 0;
+            // Hold back the output while it may still be a tool call, so that it is sent as a whole.
+            let is_tool_call_prefix = is_done.is_none()
+                && seq
+                    .get_mut_group()
+                    .tool_matcher
+                    .as_ref()
+                    .is_some_and(|matcher| {
+                        matcher
+                            .prefix_could_be_tool(&String::from_utf8_lossy(seq.completion_bytes()))
+                    });
+
+            if rate_limit_allowed && !is_tool_call_prefix {
+                if let Some(delta) = handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
+                    let logprobs = if seq.return_logprobs() {
+                        Some(ResponseLogprob {
+                            token: delta.clone(),
+                            bytes: next_token.bytes.clone().into_bytes(),
+                            logprob: next_token.logprob,
+                            raw_logprob: next_token.raw_logprob,
+                            top_logprobs: next_token.top_logprobs.unwrap().clone(),
+                        })
+                    } else {
+                        None
+                    };
+                    if seq.get_mut_group().is_chat {
+                        let tool_calls = match is_done {
+                            Some(_) => Self::get_tool_calls(
+                                seq,
+                                &String::from_utf8_lossy(seq.completion_bytes()),
+                            ),
+                            None => Vec::new(),
+                        };
+                        let (content, finish_reason) = if tool_calls.is_empty() {
+                            (delta, is_done.map(|x| x.to_string()))
+                        } else {
+                            (String::new(), Some("tool_calls".to_string()))
+                        };
                         seq.add_streaming_chunk_choice_to_group(ChunkChoice {
                             delta: Delta {
-                                content: delta.clone(),
+                                content,
                                 role: "assistant".to_string(),
+                                tool_calls,
                             },
                             index: seq.get_response_index(),
+                            finish_reason,
+                            logprobs,
+                        });
+                    } else {
+                        seq.add_streaming_completion_chunk_choice_to_group(CompletionChunkChoice {
+                            text: delta,
+                            index: seq.get_response_index(),
                             finish_reason: is_done.map(|x| x.to_string()),
-                            logprobs: if seq.return_logprobs() {
-                                Some(ResponseLogprob {
-                                    token: delta,
-                                    bytes: next_token.bytes.clone().into_bytes(),
-                                    logprob: next_token.logprob,
-                                    top_logprobs: next_token.top_logprobs.unwrap().clone(),
-                                })
-                            } else {
-                                None
-                            },
+                            logprobs,
                         });
+                    }
 
-                        if let Some(reason) = is_done {
-                            prefix_cacher.add_sequence(seq);
-                            prefix_cacher.evict_to_cpu()?;
-                            seq.set_state(SequenceState::Done(reason));
-                            pipeline.reset_non_granular_state();
-                        }
+                    if let Some(reason) = is_done {
+                        prefix_cacher.add_sequence(seq);
+                        prefix_cacher.evict_caches()?;
+                        seq.set_state(SequenceState::Done(reason));
+                        pipeline.reset_non_granular_state();
+                    }
 
-                        if seq
-                            .get_mut_group()
-                            .maybe_send_streaming_response(seq, pipeline.name())
-                            .is_err()
-                        {
-                            // If we can't send the response, cancel the sequence
-                            seq.set_state(SequenceState::Done(StopReason::Canceled));
-                            pipeline.reset_non_granular_state();
-                        }
+                    if seq
+                        .get_mut_group()
+                        .maybe_send_streaming_response(seq, pipeline.name())
+                        .is_err()
+                    {
+                        // If we can't send the response, cancel the sequence and the rest of its group
+                        seq.set_state(SequenceState::Done(StopReason::Canceled));
+                        seq.get_mut_group().is_canceled = true;
+                        pipeline.reset_non_granular_state();
                     }
                 }
-            } else if let Some(reason) = is_done {
-                Self::finish_seq(pipeline, seq, reason, prefix_cacher)?;
-                pipeline.reset_non_granular_state();
             }
+        } else if let Some(reason) = is_done {
+            Self::finish_seq(pipeline, seq, reason, prefix_cacher)?;
+            pipeline.reset_non_granular_state();
         }
 
         Ok(())
@@ -314,6 +533,7 @@ impl Engine {
                     ),
                     bytes: logprob.bytes.clone().into_bytes(),
                     logprob: logprob.logprob,
+                    raw_logprob: logprob.raw_logprob,
                     top_logprobs: logprob.top_logprobs.clone().unwrap(),
                 };
                 logprobs.push(resp_logprob);
@@ -328,7 +548,9 @@ impl Engine {
             | StopReason::ModelLength(_)
             | StopReason::Eos
             | StopReason::StopTok(_)
-            | StopReason::Canceled => String::from_utf8_lossy(seq.completion_bytes())
+            | StopReason::Canceled
+            | StopReason::Timeout
+            | StopReason::QueueTimeout => String::from_utf8_lossy(seq.completion_bytes())
                 .trim_start()
                 .to_string(),
             StopReason::StopString {
@@ -341,12 +563,19 @@ impl Engine {
         };
 
         if seq.get_mut_group().is_chat {
+            let tool_calls = Self::get_tool_calls(seq, &text);
+            let (content, finish_reason) = if tool_calls.is_empty() {
+                (text, reason.to_string())
+            } else {
+                (String::new(), "tool_calls".to_string())
+            };
             let choice = Choice {
-                finish_reason: reason.to_string(),
+                finish_reason,
                 index: seq.get_response_index(),
                 message: ResponseMessage {
-                    content: text,
+                    content,
                     role: "assistant".to_string(),
+                    tool_calls,
                 },
                 logprobs: logprobs.map(|l| Logprobs { content: Some(l) }),
             };
@@ -362,9 +591,9 @@ impl Engine {
         }
 
         prefix_cacher.add_sequence(seq);
-        prefix_cacher.evict_to_cpu()?;
+        prefix_cacher.evict_caches()?;
 
-        let group = seq.get_mut_group();
+        let mut group = seq.get_mut_group();
         if group.is_chat {
             group.maybe_send_done_response(
                 ChatCompletionResponse {
@@ -396,33 +625,21 @@ impl Engine {
         Ok(())
     }
 
-    /// Clone the cache FROM the sequences' cache TO the model cache. Only used for completion seqs.
+    /// Clone the cache FROM the sequences' cache TO the model cache. The KV caches which are shorter than
+    /// the longest one are padded at the start, and the models mask out the padding.
     fn clone_in_cache(pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence]) {
+        let lens = seqs
+            .iter_mut()
+            .map(|seq| seq.cache()[0].as_ref().map_or(0, |(k, _)| k.dims()[2]))
+            .collect::<Vec<_>>();
+        let max_len = *lens.iter().max().expect("No sequences.");
         let mut new_cache = Vec::new();
         for layer in 0..pipeline.num_hidden_layers() {
-            let mut k_vec = Vec::new();
-            let mut v_vec = Vec::new();
-            for seq in &mut *seqs {
-                let seq_cache = &*seq.cache();
-                let cache = seq_cache.get(layer).unwrap();
-                let cache = cache
-                    .as_ref()
-                    .expect("Not handling completions in `clone_in_cache`.");
-                k_vec.push(cache.0.clone());
-                v_vec.push(cache.1.clone());
-            }
-            new_cache.push(Some((
-                if k_vec.len() > 1 {
-                    Tensor::cat(&k_vec, 0).unwrap()
-                } else {
-                    k_vec[0].clone()
-                },
-                if v_vec.len() > 1 {
-                    Tensor::cat(&v_vec, 0).unwrap()
-                } else {
-                    v_vec[0].clone()
-                },
-            )));
+            let caches = seqs
+                .iter_mut()
+                .map(|seq| seq.cache()[layer].clone())
+                .collect::<Vec<_>>();
+            new_cache.push(Some(pad_and_cat_caches(&caches, max_len).unwrap()));
         }
         if pipeline.is_xlora() && !pipeline.has_no_kv_cache() {
             let mut new_cache = Vec::new();
@@ -456,9 +673,115 @@ impl Engine {
         if pipeline.is_xlora() {
             *pipeline.cache().get_scalings_cache() = seqs[0].scaling_cache().clone();
         }
+        *pipeline.cache().padding() = if lens.iter().all(|len| *len == max_len) {
+            Vec::new()
+        } else {
+            lens.iter().map(|len| max_len - len).collect()
+        };
         *pipeline.cache().lock() = new_cache;
     }
 
+    /// Reserve the paged KV cache slots for the tokens of the sequences which are about to be run.
+    fn prepare_paged_attn(
+        pipeline: &mut dyn Pipeline,
+        seqs: &[&mut Sequence],
+        is_prompt: bool,
+    ) -> Result<()> {
+        let mut paged_cache = pipeline.cache().paged_lock();
+        paged_cache
+            .as_mut()
+            .expect("Paged attention is enabled.")
+            .prepare(seqs, is_prompt)
+    }
+
+    /// The tool calls in the output of a sequence, if its request has tools.
+    fn get_tool_calls(seq: &Sequence, text: &str) -> Vec<ToolCallResponse> {
+        match &seq.get_mut_group().tool_matcher {
+            Some(matcher) => matcher.get_calls(text, &format!("call-{}", seq.id())),
+            None => Vec::new(),
+        }
+    }
+
+    /// Drop the sequences of a canceled request and return their paged KV cache blocks.
+    /// No response is sent, so the receiver of the request's responses is disconnected.
+    fn cancel_request(&mut self, request_id: usize) {
+        let canceled = self.scheduler.cancel_request(request_id);
+        if canceled.is_empty() {
+            return;
+        }
+        let pipeline = get_mut_arcmutex!(self.pipeline);
+        if let Some(paged_cache) = pipeline.cache().paged_lock().as_mut() {
+            for id in &canceled {
+                paged_cache.free(*id);
+            }
+        }
+        if self.is_debug {
+            tracing::info!(
+                "Canceled request {request_id} ({} sequences).",
+                canceled.len()
+            );
+        }
+    }
+
+    /// Finish the sequences whose request passed its deadline or maximum queue time, with the output
+    /// generated so far and the `timeout` or `queue_timeout` finish reason.
+    fn expire_requests(&mut self) -> Result<()> {
+        let mut expired = self.scheduler.take_expired(Instant::now());
+        if expired.is_empty() {
+            return Ok(());
+        }
+        let mut pipeline = get_mut_arcmutex!(self.pipeline);
+        for (seq, reason) in expired.iter_mut() {
+            if let Some(paged_cache) = pipeline.cache().paged_lock().as_mut() {
+                paged_cache.free(*seq.id());
+            }
+            // The KV cache may only cover part of the prompt, so it must not be added to the prefix cache.
+            seq.cache().iter_mut().for_each(|layer| *layer = None);
+            if !seq.get_mut_group().is_streaming {
+                Self::finish_seq(&mut *pipeline, seq, *reason, &mut self.prefix_cacher)?;
+                continue;
+            }
+            seq.set_state(SequenceState::Done(*reason));
+            if seq.get_mut_group().is_chat {
+                seq.add_streaming_chunk_choice_to_group(ChunkChoice {
+                    delta: Delta {
+                        content: String::new(),
+                        role: "assistant".to_string(),
+                        tool_calls: Vec::new(),
+                    },
+                    index: seq.get_response_index(),
+                    finish_reason: Some(reason.to_string()),
+                    logprobs: None,
+                });
+            } else {
+                seq.add_streaming_completion_chunk_choice_to_group(CompletionChunkChoice {
+                    text: String::new(),
+                    index: seq.get_response_index(),
+                    finish_reason: Some(reason.to_string()),
+                    logprobs: None,
+                });
+            }
+            // The receiver may already be gone, in which case there is nobody to notify.
+            let _ = seq
+                .get_mut_group()
+                .maybe_send_streaming_response(seq, pipeline.name());
+        }
+        if self.is_debug {
+            tracing::info!("{} sequences timed out.", expired.len());
+        }
+        Ok(())
+    }
+
+    /// Return the paged KV cache blocks of the sequences which are no longer running.
+    fn free_paged_blocks(pipeline: &mut dyn Pipeline, seqs: &[&mut Sequence]) {
+        let mut paged_cache = pipeline.cache().paged_lock();
+        if let Some(paged_cache) = paged_cache.as_mut() {
+            for seq in seqs.iter().filter(|seq| !seq.is_running()) {
+                paged_cache.free(*seq.id());
+            }
+        }
+    }
+
     /// Set the model cache to all None. Only used for prompt seqs.
     fn set_none_cache(pipeline: &mut dyn Pipeline) {
         let mut new_cache = Vec::new();
@@ -469,11 +792,37 @@ impl Engine {
         if pipeline.cache().is_xlora() {
             *pipeline.cache().xlora_lock() = new_cache;
         }
+        pipeline.cache().padding().clear();
     }
 
     /// Clone the cache FROM the model cache TO the sequences. Used for prompt, completion seqs.
     fn clone_out_cache(pipeline: &mut dyn Pipeline, seqs: &mut [&mut Sequence]) {
         let num_hidden_layers = pipeline.num_hidden_layers();
+        // Drop the padding at the start of the KV caches, and, for prompts of different lengths, the
+        // keys and values of the padding tokens at the end.
+        let padding = pipeline.cache().padding().clone();
+        let new_toks = seqs
+            .iter()
+            .map(|seq| {
+                if seq.is_prompt() {
+                    seq.get_toks().len()
+                } else {
+                    0
+                }
+            })
+            .collect::<Vec<_>>();
+        let max_new_toks = new_toks.iter().copied().max().unwrap_or(0);
+        let unpad = |seq_i: usize, cache: &Tensor| {
+            let start = padding.get(seq_i).copied().unwrap_or(0);
+            let end = max_new_toks - new_toks[seq_i];
+            if start == 0 && end == 0 {
+                cache.clone()
+            } else {
+                cache
+                    .narrow(2, start, cache.dims()[2] - start - end)
+                    .unwrap()
+            }
+        };
         for layer in 0..num_hidden_layers {
             let cache = pipeline.cache().lock();
             let cache = cache.get(layer).unwrap();
@@ -488,8 +837,8 @@ impl Engine {
             for (seq_i, seq) in seqs.iter_mut().enumerate() {
                 let seq_cache = seq.cache();
                 let seq_cache = &mut seq_cache[layer];
-                let k = k_caches.get(seq_i).unwrap().clone();
-                let v = v_caches.get(seq_i).unwrap().clone();
+                let k = unpad(seq_i, k_caches.get(seq_i).unwrap());
+                let v = unpad(seq_i, v_caches.get(seq_i).unwrap());
                 *seq_cache = Some((k, v));
             }
             if pipeline.is_xlora() && !pipeline.has_no_kv_cache() {
@@ -517,15 +866,22 @@ impl Engine {
         }
     }
 
-    fn build_sequence_recognizer(constraint: &Constraint) -> anyhow::Result<SequenceRecognizer> {
-        let recognizer = match constraint {
-            Constraint::Regex(rx) => {
-                SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx)?).into())
+    /// Remove the last tokens of the prompt for token healing, returning their bytes. Tokens are
+    /// removed while their bytes are the start of a longer token, so that the model can generate
+    /// them again with a better tokenization. The first prompt token is always kept.
+    fn heal_prompt(tok_trie: &TokTrie, prompt: &mut Vec<u32>) -> Vec<u8> {
+        let mut healing_prefix = Vec::new();
+        while prompt.len() > 1 {
+            let last = tok_trie.token(prompt[prompt.len() - 1]);
+            let bytes = [last, &healing_prefix].concat();
+            // Special tokens have no bytes, and are never healed.
+            if last.is_empty() || !tok_trie.has_extensions(&bytes) {
+                break;
             }
-            Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
-            Constraint::None => SequenceRecognizer::None,
-        };
-        Ok(recognizer)
+            prompt.pop();
+            healing_prefix = bytes;
+        }
+        healing_prefix
     }
 
     fn alloc_logits_bias(&self, logits_bias: Option<HashMap<u32, f32>>) -> Result<Option<Tensor>> {
@@ -546,6 +902,7 @@ impl Engine {
     }
 
     fn add_request(&mut self, request: Request) {
+        self.queue.received(&request);
         let is_chat = matches!(request.messages, RequestMessage::Chat(_));
         let echo_prompt = matches!(
             request.messages,
@@ -559,24 +916,59 @@ impl Engine {
             RequestMessage::Completion { best_of, .. } => best_of,
             RequestMessage::Chat(_) | RequestMessage::CompletionTokens(_) => 1,
         };
+        let token_healing = matches!(
+            request.messages,
+            RequestMessage::Completion {
+                token_healing: true,
+                ..
+            }
+        );
         if is_chat
             && !get_mut_arcmutex!(self.pipeline)
                 .get_chat_template()
                 .has_chat_template()
         {
-            request
-                    .response
-                    .send(Response::ValidationError(
-                        "Received messages for a model which does not have a chat template. Either use a different model or pass a single string as the prompt".into(),
-                    )).expect("Expected receiver.");
+            let _ = request
+                .response
+                .send(Response::ValidationError(
+                    "Received messages for a model which does not have a chat template. Either use a different model or pass a single string as the prompt".into(),
+                ));
             return;
         }
+        // Dividing by a penalty of zero or less would make the repeated tokens more likely, or infinite.
+        if let Some(penalty) = request.sampling_params.repetition_penalty {
+            if !(penalty > 0.) {
+                let _ = request.response.send(Response::ValidationError(
+                    format!("`repetition_penalty` must be positive, got {penalty}.").into(),
+                ));
+                return;
+            }
+        }
+
+        let tool_matcher = match request.tools {
+            Some(tools) if is_chat => {
+                ToolCallingMatcher::new(tools, request.tool_choice.unwrap_or(ToolChoice::Auto))
+            }
+            _ => None,
+        };
+        // Constrain the output to a tool call if one is required and there is no other constraint.
+        let constraint = match (&request.constraint, &tool_matcher) {
+            (Constraint::None, Some(matcher)) => matcher
+                .json_schema()
+                .map(Constraint::JsonSchema)
+                .unwrap_or(Constraint::None),
+            (constraint, _) => constraint.clone(),
+        };
 
         let mut force_tokens = None;
         let formatted_prompt = match request.messages {
             RequestMessage::Chat(messages) => {
                 handle_seq_error!(
-                    get_mut_arcmutex!(self.pipeline).apply_chat_template(messages, true),
+                    get_mut_arcmutex!(self.pipeline).apply_chat_template(
+                        messages,
+                        tool_matcher.as_ref().map(|matcher| matcher.tools()),
+                        true
+                    ),
                     request.response
                 )
             }
@@ -591,12 +983,9 @@ impl Engine {
             }
         };
         if formatted_prompt.is_empty() {
-            request
-                .response
-                .send(Response::ValidationError(
-                    "Received an empty prompt.".into(),
-                ))
-                .expect("Expected receiver.");
+            let _ = request.response.send(Response::ValidationError(
+                "Received an empty prompt.".into(),
+            ));
             return;
         }
         let mut prompt = match force_tokens {
@@ -609,11 +998,11 @@ impl Engine {
 
         if prompt.len() > get_mut_arcmutex!(self.pipeline).get_max_seq_len() {
             if !self.truncate_sequence {
-                request
+                let _ = request
                     .response
                     .send(Response::ValidationError(
                         format!("Prompt sequence length is greater than {}, perhaps consider using `truncate_sequence`?", get_mut_arcmutex!(self.pipeline).get_max_seq_len()).into(),
-                    )).expect("Expected receiver.");
+                    ));
                 return;
             } else {
                 let prompt_len = prompt.len();
@@ -632,6 +1021,11 @@ impl Engine {
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt.len());
             }
         }
+        let healing_prefix = if token_healing {
+            Self::heal_prompt(get_mut_arcmutex!(self.pipeline).tok_trie(), &mut prompt)
+        } else {
+            Vec::new()
+        };
         let prefill_cache = handle_seq_error!(
             self.prefix_cacher.search_for_matching_cache(&prompt),
             request.response
@@ -643,6 +1037,9 @@ impl Engine {
             .map(|x| x as i64)
             .unwrap_or(-1);
         let topp = request.sampling_params.top_p.unwrap_or(1.0);
+        let minp = request.sampling_params.min_p.unwrap_or(0.0);
+        let typicalp = request.sampling_params.typical_p.unwrap_or(1.0);
+        let tfsz = request.sampling_params.tfs_z.unwrap_or(1.0);
         let num_hidden_layers = get_mut_arcmutex!(self.pipeline).num_hidden_layers();
 
         let (stop_toks, stop_strings) = match request.sampling_params.stop_toks {
@@ -653,12 +1050,11 @@ impl Engine {
                 for id in i {
                     // We can't use ` ` (space) as a stop token because other tokens like ` moon` start with a space.
                     if tok_trie.has_extensions(tok_trie.token(*id)) {
-                        request
+                        let _ = request
                             .response
                             .send(Response::ValidationError(
                                 format!("Stop token {:?} is also a prefix of other tokens and cannot be used as a stop token.", tok_trie.token_str(*id)).into(),
-                            ))
-                            .expect("Expected receiver.");
+                            ));
                         return;
                     }
                 }
@@ -699,6 +1095,14 @@ impl Engine {
             request.is_streaming,
             is_chat,
             best_of,
+            request.id,
+            tool_matcher,
+            request.priority,
+            request.tenant,
+            request.deadline,
+            request
+                .max_queue_time
+                .map(|max_queue_time| Instant::now() + max_queue_time),
         )));
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -707,49 +1111,52 @@ impl Engine {
         let logits_bias = match self.alloc_logits_bias(request.sampling_params.logits_bias) {
             Ok(logits_bias) => logits_bias,
             Err(err) => {
-                request
-                    .response
-                    .send(Response::ValidationError(
-                        format!("Failed creation of logits bias. {}", err).into(),
-                    ))
-                    .expect("Expected receiver.");
+                let _ = request.response.send(Response::ValidationError(
+                    format!("Failed creation of logits bias. {}", err).into(),
+                ));
                 return;
             }
         };
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
-        let sampler = Sampler::new(
-            SEED,
-            Some(request.sampling_params.temperature.unwrap_or(1.0)),
-            request.sampling_params.top_n_logprobs,
-            tokenizer,
-            request.sampling_params.frequency_penalty,
-            request.sampling_params.presence_penalty,
-            logits_bias,
-            topk,
-            topp,
-        );
-        let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
+        let seed = request.sampling_params.seed.unwrap_or_else(rand::random);
+        let recognizer = match self.grammar_cache.get(&constraint) {
             Ok(recognizer) => recognizer,
             Err(err) => {
-                request
-                    .response
-                    .send(Response::ValidationError(
-                        format!("Invalid grammar. {}", err).into(),
-                    ))
-                    .expect("Expected receiver.");
+                let _ = request.response.send(Response::ValidationError(
+                    format!("Invalid grammar. {}", err).into(),
+                ));
                 return;
             }
         };
         // Add sequences
         for response_index in 0..request.sampling_params.n_choices {
+            // Each choice gets its own random stream, so that they are not identical.
+            let sampler = Sampler::new(
+                seed.wrapping_add(response_index as u64),
+                Some(request.sampling_params.temperature.unwrap_or(1.0)),
+                request.sampling_params.top_n_logprobs,
+                tokenizer.clone(),
+                request.sampling_params.frequency_penalty,
+                request.sampling_params.presence_penalty,
+                request.sampling_params.repetition_penalty,
+                request.sampling_params.dry_params.clone(),
+                logits_bias.clone(),
+                topk,
+                topp,
+                minp,
+                typicalp,
+                tfsz,
+                request.sampling_params.mirostat,
+                request.sampling_params.raw_logprobs,
+            );
             let seq = Sequence::new_waiting(
                 prompt.clone(),
                 self.id,
                 now.as_millis(),
                 num_hidden_layers,
                 request.response.clone(),
-                sampler.clone(),
+                sampler,
                 stop_toks.clone(),
                 stop_strings.clone(),
                 request.sampling_params.max_len,
@@ -765,6 +1172,7 @@ impl Engine {
                 } else {
                     None
                 },
+                healing_prefix.clone(),
             );
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
@@ -775,8 +1183,584 @@ impl Engine {
             } else {
                 seq
             };
+            let seq = if let Some(chunk_size) = self.prefill_chunk_size {
+                seq.with_prefill_chunks(chunk_size)
+            } else {
+                seq
+            };
             self.id += 1;
             self.scheduler.add_seq(seq);
         }
     }
 }
+
+/// Concatenate the KV caches of a layer along the batch dimension, padding each of them with zeros at
+/// the start to `len` tokens. A missing KV cache is all padding.
+fn pad_and_cat_caches(caches: &[Option<(Tensor, Tensor)>], len: usize) -> Result<(Tensor, Tensor)> {
+    let Some((k_like, v_like)) = caches.iter().flatten().next() else {
+        candle_core::bail!("There are no KV caches to batch.");
+    };
+    let mut k_vec = Vec::new();
+    let mut v_vec = Vec::new();
+    for cache in caches {
+        let cached = cache.as_ref().map_or(0, |(k, _)| k.dims()[2]);
+        let pad = |like: &Tensor| {
+            let (_, n_heads, _, head_dim) = like.dims4()?;
+            Tensor::zeros(
+                (1, n_heads, len - cached, head_dim),
+                like.dtype(),
+                like.device(),
+            )
+        };
+        let (k, v) = match cache {
+            Some((k, v)) if cached == len => (k.clone(), v.clone()),
+            Some((k, v)) => (
+                Tensor::cat(&[&pad(k_like)?, k], 2)?,
+                Tensor::cat(&[&pad(v_like)?, v], 2)?,
+            ),
+            None => (pad(k_like)?, pad(v_like)?),
+        };
+        k_vec.push(k);
+        v_vec.push(v);
+    }
+    if k_vec.len() == 1 {
+        return Ok((k_vec.remove(0), v_vec.remove(0)));
+    }
+    Ok((Tensor::cat(&k_vec, 0)?, Tensor::cat(&v_vec, 0)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use candle_core::{quantized::GgmlDType, DType, Device, Tensor};
+    use tokenizers::Tokenizer;
+
+    use crate::{
+        aici::{bytes::TokRxInfo, toktree::TokTrie},
+        models::Cache,
+        pipeline::{ChatTemplate, KvCacheMetadata, ModelInputs, Pipeline},
+        sequence::Sequence,
+        test_utils::{completion_request, word_level_tokenizer, TestSequence},
+        xlora_models::NonGranularState,
+        Mirostat,
+    };
+
+    const VOCAB: [&str; 4] = ["<unk>", "a", "b", "</s>"];
+
+    /// A model with a single layer which predicts the tokens of its `script`: the token at position `i`
+    /// is predicted to be `script[i]`, or `a` after the end of the script. It records the positions which
+    /// each sequence runs, as `(sequence ID, first position, number of tokens)`, and each step takes at
+    /// least `step_time`.
+    pub(super) struct TestPipeline {
+        cache: Cache,
+        kv_cache_metadata: KvCacheMetadata,
+        tokenizer: Arc<Tokenizer>,
+        tok_trie: TokTrie,
+        chat_template: ChatTemplate,
+        non_granular_state: Option<NonGranularState>,
+        runs: Arc<Mutex<Vec<(usize, usize, usize)>>>,
+        step_time: Duration,
+        pub(super) script: Vec<u32>,
+    }
+
+    impl TestPipeline {
+        pub(super) fn new(runs: Arc<Mutex<Vec<(usize, usize, usize)>>>) -> Self {
+            let info = TokRxInfo {
+                vocab_size: VOCAB.len() as u32,
+                tok_eos: 3,
+            };
+            let words = VOCAB
+                .iter()
+                .map(|tok| tok.as_bytes().to_vec())
+                .collect::<Vec<_>>();
+            Self {
+                cache: Cache::new(1, false),
+                kv_cache_metadata: KvCacheMetadata {
+                    num_layers: 1,
+                    num_kv_heads: 1,
+                    head_dim: 1,
+                    dtype: DType::F32,
+                },
+                tokenizer: Arc::new(word_level_tokenizer(&VOCAB)),
+                tok_trie: TokTrie::from(&info, &words),
+                chat_template: serde_json::from_str(
+                    r#"{"eos_token": "</s>", "model_max_length": 4096, "tokenizer_class": "test"}"#,
+                )
+                .unwrap(),
+                non_granular_state: None,
+                runs,
+                step_time: Duration::ZERO,
+                script: Vec::new(),
+            }
+        }
+
+        /// The logits which predict the token at position `pos`.
+        fn logits(&self, pos: usize) -> Vec<f32> {
+            let mut logits = vec![0f32; VOCAB.len()];
+            logits[self.script.get(pos).copied().unwrap_or(1) as usize] = 1.;
+            logits
+        }
+
+        fn append_to_cache(&self, new: Tensor) -> candle_core::Result<()> {
+            let mut cache = self.cache.lock();
+            let k = match cache[0].take() {
+                Some((k, _)) => Tensor::cat(&[&k, &new], 2)?,
+                None => new,
+            };
+            cache[0] = Some((k.clone(), k));
+            Ok(())
+        }
+    }
+
+    impl Pipeline for TestPipeline {
+        fn forward_inputs(&mut self, _inputs: ModelInputs) -> candle_core::Result<Tensor> {
+            unreachable!("The test model runs the sequences directly.")
+        }
+        fn forward(
+            &mut self,
+            input_seqs: &[&mut Sequence],
+            is_prompt: bool,
+        ) -> candle_core::Result<Tensor> {
+            let runs = input_seqs
+                .iter()
+                .map(|seq| {
+                    let toks = seq.get_toks().len();
+                    if is_prompt {
+                        (*seq.id(), seq.prefix_len(), toks)
+                    } else {
+                        (*seq.id(), toks - 1, 1)
+                    }
+                })
+                .collect::<Vec<_>>();
+            let seq_len = runs[0].2;
+            if runs.iter().any(|(_, _, n)| *n != seq_len) {
+                candle_core::bail!(
+                    "The test model only runs sequences with the same number of tokens."
+                );
+            }
+            let logits = runs
+                .iter()
+                .flat_map(|(_, start, n)| self.logits(start + n))
+                .collect::<Vec<_>>();
+            self.runs.lock().unwrap().extend(runs);
+            std::thread::sleep(self.step_time);
+
+            // The cache only needs the right shape, as the test model does not attend to it. With paged
+            // attention, the keys and values are kept in the paged KV cache instead.
+            if self.cache.paged_lock().is_none() {
+                self.append_to_cache(Tensor::zeros(
+                    (input_seqs.len(), 1, seq_len, 1),
+                    DType::F32,
+                    &Device::Cpu,
+                )?)?;
+            }
+            Tensor::from_vec(logits, (input_seqs.len(), 1, VOCAB.len()), &Device::Cpu)
+        }
+        fn forward_tokens(
+            &mut self,
+            toks: &[Vec<u32>],
+            seqlen_offset: usize,
+        ) -> candle_core::Result<Tensor> {
+            // The cache holds the tokens, so that the tests can check which positions it keeps.
+            let seq_len = toks[0].len();
+            let new = toks
+                .iter()
+                .flatten()
+                .map(|tok| *tok as f32)
+                .collect::<Vec<_>>();
+            self.append_to_cache(Tensor::from_vec(
+                new,
+                (toks.len(), 1, seq_len, 1),
+                &Device::Cpu,
+            )?)?;
+            // The positions are the same for each sequence.
+            let logits = (1..=seq_len)
+                .flat_map(|i| self.logits(seqlen_offset + i))
+                .collect::<Vec<_>>()
+                .repeat(toks.len());
+            Tensor::from_vec(logits, (toks.len(), seq_len, VOCAB.len()), &Device::Cpu)
+        }
+        fn device(&self) -> &Device {
+            &Device::Cpu
+        }
+        fn num_hidden_layers(&self) -> usize {
+            1
+        }
+        fn kv_cache_metadata(&self) -> &KvCacheMetadata {
+            &self.kv_cache_metadata
+        }
+        fn cache(&self) -> &Cache {
+            &self.cache
+        }
+        fn tokenizer(&self) -> Arc<Tokenizer> {
+            self.tokenizer.clone()
+        }
+        fn tok_trie(&self) -> &TokTrie {
+            &self.tok_trie
+        }
+        fn eos_tok(&self) -> &[u32] {
+            &[3]
+        }
+        fn name(&self) -> String {
+            "test".to_string()
+        }
+        fn weights_id(&self) -> String {
+            self.name()
+        }
+        fn get_max_seq_len(&self) -> usize {
+            4096
+        }
+        fn is_xlora(&self) -> bool {
+            false
+        }
+        fn has_no_kv_cache(&self) -> bool {
+            false
+        }
+        fn get_chat_template(&self) -> &ChatTemplate {
+            &self.chat_template
+        }
+        fn get_non_granular_state(&self) -> &Option<NonGranularState> {
+            &self.non_granular_state
+        }
+        fn get_repeat_last_n(&self) -> usize {
+            64
+        }
+        fn re_isq_model(&mut self, _dtype: GgmlDType) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Wait until the engine reports `(waiting requests, waiting tokens)`, as it updates the queue status
+    /// at each step.
+    fn wait_for_queue_status(mistralrs: &crate::MistralRs, expected: (usize, usize)) {
+        let start = std::time::Instant::now();
+        loop {
+            let status = mistralrs.queue_status();
+            if (status.waiting_requests, status.waiting_tokens) == expected {
+                return;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "The queue status is {status:?}, expected {expected:?}."
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_chunked_prefill_of_prompts_with_different_lengths() {
+        use std::collections::HashMap;
+
+        use crate::{MistralRsBuilder, Response, SchedulerMethod};
+
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let pipeline = TestPipeline::new(runs.clone());
+        let mistralrs = MistralRsBuilder::new(
+            Box::new(Mutex::new(pipeline)),
+            SchedulerMethod::Fixed(4.try_into().unwrap()),
+        )
+        .with_no_prefix_cache(true)
+        .with_prefill_chunk_size(2)
+        .build();
+
+        let (short, mut short_rx) = completion_request(0, vec![1; 5], 1);
+        let (long, mut long_rx) = completion_request(1, vec![2; 8], 1);
+        let sender = mistralrs.get_sender();
+        sender.send(short).unwrap();
+        sender.send(long).unwrap();
+        for rx in [&mut short_rx, &mut long_rx] {
+            match rx.blocking_recv() {
+                Some(Response::CompletionDone(done)) => {
+                    assert_eq!(done.choices[0].finish_reason, "length");
+                }
+                _ => panic!("The request did not complete."),
+            }
+        }
+
+        // Every prompt token is run exactly once, in order, so the KV cache has no duplicate entries.
+        let mut positions = HashMap::<usize, Vec<usize>>::new();
+        for (id, start, n) in runs.lock().unwrap().iter() {
+            positions.entry(*id).or_default().extend(*start..start + n);
+        }
+        assert_eq!(positions[&0], (0..5).collect::<Vec<_>>());
+        assert_eq!(positions[&1], (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_mirostat_mu_after_constrained_step() {
+        use std::collections::HashMap;
+
+        use crate::{grammar::GrammarCache, Constraint};
+
+        let mut pipeline = TestPipeline::new(Arc::new(Mutex::new(Vec::new())));
+        let recognizer = GrammarCache::new(1, HashMap::new())
+            .get(&Constraint::Regex("b+".to_string()))
+            .unwrap();
+        let (tau, eta) = (2., 0.5);
+        let mut seq = TestSequence::new(pipeline.tokenizer(), vec![1, 1])
+            .with_temperature(1.)
+            .with_mirostat(Mirostat::V2 { tau, eta })
+            .with_recognizer(recognizer)
+            .build();
+
+        // `a` is drawn first, as it is by far the most likely token, but the constraint only allows `b`.
+        let logits = Tensor::new(&[0f32, 20., 0., 0.], &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, VOCAB.len()))
+            .unwrap();
+        let sampled = pipeline.sample(logits, &mut seq, false).unwrap();
+        assert_eq!(sampled.token, 2);
+        // `mu` starts at `2 * tau`, and is only updated by the draw of `b`, whose surprise is 0 once the
+        // constraint is applied.
+        let mu = seq.sampler().mirostat_mu();
+        assert!((mu - (2. * tau + eta * tau)).abs() < 1e-5, "{mu}");
+    }
+
+    #[test]
+    fn test_non_positive_repetition_penalty() {
+        use crate::{MistralRsBuilder, Response, SchedulerMethod};
+
+        let pipeline = TestPipeline::new(Arc::new(Mutex::new(Vec::new())));
+        let mistralrs = MistralRsBuilder::new(
+            Box::new(Mutex::new(pipeline)),
+            SchedulerMethod::Fixed(4.try_into().unwrap()),
+        )
+        .with_no_prefix_cache(true)
+        .build();
+
+        let sender = mistralrs.get_sender();
+        for penalty in [0., -1.] {
+            let (mut request, mut rx) = completion_request(0, vec![1; 2], 1);
+            request.sampling_params.repetition_penalty = Some(penalty);
+            sender.send(request).unwrap();
+            assert!(matches!(
+                rx.blocking_recv(),
+                Some(Response::ValidationError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_heal_prompt() {
+        use super::Engine;
+
+        let words = ["", "a", "b", "c", "ab", "abc", "bc"];
+        let info = TokRxInfo {
+            vocab_size: words.len() as u32,
+            tok_eos: 0,
+        };
+        let words = words
+            .iter()
+            .map(|word| word.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let tok_trie = TokTrie::from(&info, &words);
+
+        // `b` is the start of `bc`, and then `ab` is the start of `abc`, but `cab` is not a token prefix.
+        let mut prompt = vec![3, 3, 1, 2];
+        assert_eq!(Engine::heal_prompt(&tok_trie, &mut prompt), b"ab");
+        assert_eq!(prompt, vec![3, 3]);
+
+        // The first token is always kept.
+        let mut prompt = vec![1];
+        assert!(Engine::heal_prompt(&tok_trie, &mut prompt).is_empty());
+        assert_eq!(prompt, vec![1]);
+
+        // Tokens which are not the start of a longer token, and special tokens, are not removed.
+        for last in [5, 0] {
+            let mut prompt = vec![1, last];
+            assert!(Engine::heal_prompt(&tok_trie, &mut prompt).is_empty());
+            assert_eq!(prompt, vec![1, last]);
+        }
+    }
+
+    #[test]
+    fn test_expired_requests() {
+        use std::time::Instant;
+
+        use crate::{MistralRsBuilder, Response, SchedulerMethod};
+
+        let mut pipeline = TestPipeline::new(Arc::new(Mutex::new(Vec::new())));
+        pipeline.step_time = Duration::from_millis(5);
+        let mistralrs = MistralRsBuilder::new(
+            Box::new(Mutex::new(pipeline)),
+            SchedulerMethod::Fixed(4.try_into().unwrap()),
+        )
+        .with_no_prefix_cache(true)
+        .build();
+        let sender = mistralrs.get_sender();
+
+        // The request starts running, and passes its deadline long before it reaches `max_len`.
+        let (mut running, mut running_rx) = completion_request(0, vec![1; 2], 1000);
+        running.deadline = Some(Instant::now() + Duration::from_millis(50));
+        sender.send(running).unwrap();
+        match running_rx.blocking_recv() {
+            Some(Response::CompletionDone(done)) => {
+                assert_eq!(done.choices[0].finish_reason, "timeout");
+                assert!(!done.choices[0].text.is_empty());
+            }
+            _ => panic!("The request did not time out."),
+        }
+
+        // The request passes its maximum queue time before it can be scheduled.
+        let (mut waiting, mut waiting_rx) = completion_request(1, vec![1; 2], 1000);
+        waiting.max_queue_time = Some(Duration::ZERO);
+        sender.send(waiting).unwrap();
+        match waiting_rx.blocking_recv() {
+            Some(Response::CompletionDone(done)) => {
+                assert_eq!(done.choices[0].finish_reason, "queue_timeout");
+                assert!(done.choices[0].text.is_empty());
+            }
+            _ => panic!("The request did not time out."),
+        }
+    }
+
+    #[test]
+    fn test_queue_status_after_completion() {
+        use crate::{MistralRsBuilder, Response, SchedulerMethod};
+
+        let pipeline = TestPipeline::new(Arc::new(Mutex::new(Vec::new())));
+        let mistralrs = MistralRsBuilder::new(
+            Box::new(Mutex::new(pipeline)),
+            SchedulerMethod::Fixed(4.try_into().unwrap()),
+        )
+        .with_no_prefix_cache(true)
+        .build();
+
+        let (request, mut rx) = completion_request(0, vec![1; 2], 1);
+        mistralrs.get_sender().send(request).unwrap();
+        assert!(matches!(
+            rx.blocking_recv(),
+            Some(Response::CompletionDone(_))
+        ));
+        wait_for_queue_status(&mistralrs, (0, 0));
+    }
+
+    #[test]
+    fn test_queue_status_after_cancel() {
+        use crate::{MistralRsBuilder, SchedulerMethod};
+
+        let mut pipeline = TestPipeline::new(Arc::new(Mutex::new(Vec::new())));
+        pipeline.step_time = Duration::from_millis(5);
+        // Room for 1100 tokens of 8 bytes, so only one of the requests runs.
+        let mistralrs = MistralRsBuilder::new(
+            Box::new(Mutex::new(pipeline)),
+            SchedulerMethod::KvCacheBudget(8800.try_into().unwrap()),
+        )
+        .with_no_prefix_cache(true)
+        .build();
+
+        let (running, _running_rx) = completion_request(0, vec![1; 2], 1000);
+        let (waiting, _waiting_rx) = completion_request(1, vec![1; 4], 1000);
+        let sender = mistralrs.get_sender();
+        sender.send(running).unwrap();
+        sender.send(waiting).unwrap();
+        wait_for_queue_status(&mistralrs, (1, 4));
+
+        mistralrs.cancel_request(1);
+        wait_for_queue_status(&mistralrs, (0, 0));
+        mistralrs.cancel_request(0);
+        wait_for_queue_status(&mistralrs, (0, 0));
+    }
+
+    #[test]
+    fn test_preemption_when_kv_cache_blocks_run_out() {
+        use crate::{MistralRsBuilder, PagedAttentionConfig, Response, SchedulerMethod};
+
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let pipeline = TestPipeline::new(runs.clone());
+        // 4 blocks of 2 tokens, while each request needs 4 blocks by the end.
+        let mistralrs = MistralRsBuilder::new(
+            Box::new(Mutex::new(pipeline)),
+            SchedulerMethod::KvCacheBudget(usize::MAX.try_into().unwrap()),
+        )
+        .with_no_prefix_cache(true)
+        .with_paged_attn(PagedAttentionConfig::new(2, 4))
+        .build();
+
+        let (first, mut first_rx) = completion_request(0, vec![1; 2], 5);
+        let (second, mut second_rx) = completion_request(1, vec![1; 2], 5);
+        let sender = mistralrs.get_sender();
+        sender.send(first).unwrap();
+        sender.send(second).unwrap();
+        for rx in [&mut first_rx, &mut second_rx] {
+            match rx.blocking_recv() {
+                Some(Response::CompletionDone(done)) => {
+                    assert_eq!(done.choices[0].finish_reason, "length");
+                }
+                _ => panic!("The request did not complete."),
+            }
+        }
+
+        // Both sequences ran together until their fifth tokens needed a third block each. The second one
+        // was then preempted, and its 5 tokens were recomputed once the first one finished.
+        let prompt_runs = |id| {
+            runs.lock()
+                .unwrap()
+                .iter()
+                .filter(|(seq_id, start, _)| *seq_id == id && *start == 0)
+                .map(|(_, _, n)| *n)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(prompt_runs(0), vec![2]);
+        assert_eq!(prompt_runs(1), vec![2, 5]);
+    }
+
+    #[test]
+    fn test_dropped_receiver() {
+        use crate::{MistralRsBuilder, Response, SchedulerMethod};
+
+        let pipeline = TestPipeline::new(Arc::new(Mutex::new(Vec::new())));
+        let mistralrs = MistralRsBuilder::new(
+            Box::new(Mutex::new(pipeline)),
+            SchedulerMethod::Fixed(4.try_into().unwrap()),
+        )
+        .with_no_prefix_cache(true)
+        .build();
+
+        // The first request finishes while the second one is running, and its response cannot be sent.
+        let (dropped, dropped_rx) = completion_request(0, vec![1; 2], 2);
+        let (kept, mut kept_rx) = completion_request(1, vec![1; 2], 6);
+        drop(dropped_rx);
+        let sender = mistralrs.get_sender();
+        sender.send(dropped).unwrap();
+        sender.send(kept).unwrap();
+        match kept_rx.blocking_recv() {
+            Some(Response::CompletionDone(done)) => {
+                assert_eq!(done.choices[0].finish_reason, "length");
+            }
+            _ => panic!("The request did not complete."),
+        }
+        wait_for_queue_status(&mistralrs, (0, 0));
+    }
+
+    #[test]
+    fn test_penalty_context_of_chunked_prompt() {
+        use std::collections::HashMap;
+
+        use crate::{MistralRsBuilder, Response, SchedulerMethod};
+
+        let pipeline = TestPipeline::new(Arc::new(Mutex::new(Vec::new())));
+        let mistralrs = MistralRsBuilder::new(
+            Box::new(Mutex::new(pipeline)),
+            SchedulerMethod::Fixed(4.try_into().unwrap()),
+        )
+        .with_no_prefix_cache(true)
+        .with_prefill_chunk_size(2)
+        .build();
+
+        // The last chunk is only `b`, so `a` is only penalized if the whole prompt is the context. Then `b`
+        // is more likely than `a`, and the other tokens are ruled out.
+        let (mut request, mut rx) = completion_request(0, vec![1, 2, 2, 2, 2], 1);
+        request.sampling_params.presence_penalty = Some(2.);
+        request.sampling_params.logits_bias = Some(HashMap::from([(0, -10.), (2, 1.5), (3, -10.)]));
+        mistralrs.get_sender().send(request).unwrap();
+        match rx.blocking_recv() {
+            Some(Response::CompletionDone(done)) => assert_eq!(done.choices[0].text, "b"),
+            _ => panic!("The request did not complete."),
+        }
+    }
+}
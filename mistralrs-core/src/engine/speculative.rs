@@ -0,0 +1,592 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use candle_core::{bail, DType, Device, Result, Tensor};
+
+use crate::{
+    get_mut_arcmutex, models::LayerCaches, pipeline::Pipeline, prefix_cacher::PrefixCacheManager,
+    sequence::Sequence, sequence::SequenceRecognizer,
+};
+
+use super::Engine;
+
+#[derive(Clone, Copy, Debug)]
+/// Configuration for speculative decoding. At each step, the draft model proposes `gamma` tokens
+/// which the model verifies in a single forward pass.
+pub struct SpeculativeConfig {
+    pub gamma: usize,
+}
+
+impl SpeculativeConfig {
+    pub fn new(gamma: usize) -> Self {
+        Self { gamma }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Configuration for prompt lookup decoding, a speculative decoding without a draft model. The
+/// proposed tokens are the ones which followed the last `n` tokens of the sequence in its prompt,
+/// for the largest `n <= max_ngram` which appears in the prompt. At most `gamma` tokens are proposed.
+pub struct PromptLookupConfig {
+    pub gamma: usize,
+    pub max_ngram: usize,
+}
+
+impl PromptLookupConfig {
+    pub fn new(gamma: usize, max_ngram: usize) -> Self {
+        Self { gamma, max_ngram }
+    }
+}
+
+/// Where the proposed tokens of speculative decoding come from.
+pub(crate) enum Proposer {
+    /// A smaller draft model, which samples the proposed tokens.
+    Draft(Box<Mutex<dyn Pipeline>>),
+    /// The tokens which follow the last tokens of the sequence in its prompt.
+    PromptLookup { max_ngram: usize },
+}
+
+pub(crate) struct Speculative {
+    pub(crate) proposer: Proposer,
+    /// The maximum number of proposed tokens at each step.
+    pub(crate) gamma: usize,
+}
+
+impl Engine {
+    /// Whether the next completion step of these sequences can be speculative. Grammars, token
+    /// healing and Mirostat change the distribution of each token depending on the previous ones,
+    /// so those sequences are decoded one token at a time.
+    pub(super) fn can_speculate(
+        seqs: &mut [&mut Sequence],
+        gamma: usize,
+        max_seq_len: usize,
+    ) -> bool {
+        seqs.iter_mut().all(|seq| {
+            matches!(seq.recognizer, SequenceRecognizer::None)
+                && seq.healing_prefix().is_empty()
+                && !seq.sampler().is_stateful()
+                && seq.get_toks().len() + gamma <= max_seq_len
+        })
+    }
+
+    /// Run a completion step with speculative decoding. Up to `gamma` tokens are proposed for each
+    /// sequence, which the model scores in a single forward pass. Each proposed token is accepted with
+    /// probability `min(1, p / q)`, where `p` and `q` are its probabilities under the model and the
+    /// proposer; the proposals of prompt lookup have `q = 1`. At the first rejection, a token is sampled
+    /// from `max(0, p - q)` instead, so the tokens follow the distribution of the model. If all of the
+    /// proposals are accepted, one more token is sampled from the model. The KV caches are then rolled
+    /// back to the committed tokens.
+    ///
+    /// The proposals and the KV caches are batched per sequence length, so the sequences of a ragged
+    /// batch are speculated one length at a time.
+    pub(super) fn speculative_step(
+        pipeline: &mut dyn Pipeline,
+        speculative: &Speculative,
+        seqs: &mut [&mut Sequence],
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+    ) -> Result<()> {
+        let mut buckets: BTreeMap<usize, Vec<&mut Sequence>> = BTreeMap::new();
+        for seq in seqs.iter_mut() {
+            buckets
+                .entry(seq.get_toks().len())
+                .or_default()
+                .push(&mut **seq);
+        }
+        for mut bucket in buckets.into_values() {
+            Self::speculative_bucket_step(
+                pipeline,
+                speculative,
+                &mut bucket,
+                prefix_cacher,
+                disable_eos_stop,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Run a speculative completion step for sequences which all have the same length.
+    fn speculative_bucket_step(
+        pipeline: &mut dyn Pipeline,
+        speculative: &Speculative,
+        seqs: &mut [&mut Sequence],
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+    ) -> Result<()> {
+        // The position of the last token, which is not in the KV caches yet.
+        let seqlen_offset = seqs[0].get_toks().len() - 1;
+        let repeat_last_n = pipeline.get_repeat_last_n();
+
+        let (proposals, draft_probs) = match speculative.proposer {
+            Proposer::Draft(ref draft) => {
+                let mut draft = get_mut_arcmutex!(draft);
+                let (proposals, draft_probs) =
+                    Self::propose_with_draft(&mut *draft, speculative.gamma, seqs, repeat_last_n)?;
+                (proposals, Some(draft_probs))
+            }
+            Proposer::PromptLookup { max_ngram } => {
+                let proposals = seqs
+                    .iter()
+                    .map(|seq| {
+                        prompt_lookup(
+                            seq.get_toks(),
+                            seq.prompt_tokens(),
+                            max_ngram,
+                            speculative.gamma,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                (proposals, None)
+            }
+        };
+
+        // Score the last token and the proposals with the model. Sequences with fewer proposals are
+        // padded at the end, which does not change the logits of the tokens before the padding.
+        let n_proposed = proposals.iter().map(Vec::len).max().unwrap_or(0);
+        Self::clone_in_cache(pipeline, seqs);
+        let toks = seqs
+            .iter()
+            .zip(&proposals)
+            .map(|(seq, proposed)| {
+                let mut toks = [&seq.get_toks()[seqlen_offset..], proposed].concat();
+                toks.resize(n_proposed + 1, 0);
+                toks
+            })
+            .collect::<Vec<_>>();
+        let logits = pipeline
+            .forward_tokens(&toks, seqlen_offset)?
+            .to_dtype(DType::F32)?
+            .to_device(&Device::Cpu)?;
+        Self::clone_out_cache(pipeline, seqs);
+
+        let eos_tok = pipeline.eos_tok().to_vec();
+        for (j, seq) in seqs.iter_mut().enumerate() {
+            let return_logprobs = seq.return_logprobs();
+            let mut accepted = Vec::new();
+            for i in 0..=proposals[j].len() {
+                let ctxt = penalty_ctxt(seq.get_toks(), &proposals[j][..i], repeat_last_n);
+                let logits = logits.get(j)?.get(i)?;
+                let probs = seq.sampler().probs(&logits, Some(&ctxt))?;
+                let (token, is_accepted) = match proposals[j].get(i) {
+                    // All of the proposals were accepted.
+                    None => (seq.sampler().sample_probs(&probs)?, false),
+                    Some(&proposed) => {
+                        let one_hot;
+                        let draft_probs = match draft_probs {
+                            Some(ref draft_probs) => &draft_probs[j][i],
+                            None => {
+                                let mut probs = vec![0.0; probs.len()];
+                                probs[proposed as usize] = 1.0;
+                                one_hot = probs;
+                                &one_hot
+                            }
+                        };
+                        if probs.len() != draft_probs.len() {
+                            bail!(
+                                "The draft model has {} logits, but the model has {}.",
+                                draft_probs.len(),
+                                probs.len()
+                            );
+                        }
+                        if seq
+                            .sampler()
+                            .accept_draft(probs[proposed as usize], draft_probs[proposed as usize])
+                        {
+                            (proposed, true)
+                        } else {
+                            let residual = residual_probs(&probs, draft_probs);
+                            (seq.sampler().sample_probs(&residual)?, false)
+                        }
+                    }
+                };
+                accepted.push(seq.sampler().logprobs(
+                    token,
+                    &logits,
+                    Some(&ctxt),
+                    return_logprobs,
+                )?);
+                if !is_accepted {
+                    break;
+                }
+            }
+
+            for next_token in accepted {
+                if !seq.is_running() {
+                    break;
+                }
+                // Drop the keys and values of the rejected tokens before the sequence can be finished,
+                // which adds its KV cache to the prefix cache.
+                let committed = seq.get_toks().len();
+                truncate_cache(seq.cache(), committed)?;
+                Self::commit_token(
+                    pipeline,
+                    seq,
+                    next_token,
+                    &eos_tok,
+                    prefix_cacher,
+                    disable_eos_stop,
+                )?;
+            }
+            let committed = seq.get_toks().len();
+            truncate_cache(seq.draft_cache(), committed - 1)?;
+        }
+        Ok(())
+    }
+
+    /// Sample `gamma` tokens for each sequence from the draft model, returning them with the
+    /// distributions they were sampled from.
+    #[allow(clippy::type_complexity)]
+    fn propose_with_draft(
+        draft: &mut dyn Pipeline,
+        gamma: usize,
+        seqs: &mut [&mut Sequence],
+        repeat_last_n: usize,
+    ) -> Result<(Vec<Vec<u32>>, Vec<Vec<Vec<f32>>>)> {
+        let seqlen_offset = seqs[0].get_toks().len() - 1;
+
+        // Run the draft model on the tokens it has not seen: the prompt, and the tokens which were
+        // not proposed by it.
+        for seq in seqs.iter_mut() {
+            truncate_cache(seq.draft_cache(), seqlen_offset)?;
+            let cached = cache_len(seq.draft_cache());
+            if cached < seqlen_offset {
+                let toks = seq.get_toks()[cached..seqlen_offset].to_vec();
+                let seq = std::slice::from_mut(seq);
+                set_draft_cache(draft, seq)?;
+                draft.forward_tokens(&[toks], cached)?;
+                take_draft_cache(draft, seq)?;
+            }
+        }
+
+        set_draft_cache(draft, seqs)?;
+        let mut proposals = vec![Vec::new(); seqs.len()];
+        let mut draft_probs = vec![Vec::new(); seqs.len()];
+        let mut last = seqs
+            .iter()
+            .map(|seq| vec![*seq.get_toks().last().unwrap()])
+            .collect::<Vec<_>>();
+        for i in 0..gamma {
+            let logits = draft
+                .forward_tokens(&last, seqlen_offset + i)?
+                .to_dtype(DType::F32)?
+                .to_device(&Device::Cpu)?;
+            for (j, seq) in seqs.iter_mut().enumerate() {
+                let ctxt = penalty_ctxt(seq.get_toks(), &proposals[j], repeat_last_n);
+                let probs = seq.sampler().probs(&logits.get(j)?.get(0)?, Some(&ctxt))?;
+                let token = seq.sampler().sample_probs(&probs)?;
+                proposals[j].push(token);
+                draft_probs[j].push(probs);
+                last[j] = vec![token];
+            }
+        }
+        take_draft_cache(draft, seqs)?;
+        Ok((proposals, draft_probs))
+    }
+}
+
+/// The tokens which followed the last `n` tokens of the sequence in its prompt, for the largest
+/// `n <= max_ngram` which appears in the prompt with tokens after it. The most recent match is used.
+fn prompt_lookup(toks: &[u32], prompt_len: usize, max_ngram: usize, gamma: usize) -> Vec<u32> {
+    if prompt_len < 2 {
+        return Vec::new();
+    }
+    let prompt = &toks[..prompt_len];
+    for n in (1..=max_ngram.min(toks.len())).rev() {
+        let ngram = &toks[toks.len() - n..];
+        if let Some(start) = prompt[..prompt_len - 1]
+            .windows(n)
+            .rposition(|window| window == ngram)
+        {
+            let end = (start + n + gamma).min(prompt_len);
+            return prompt[start + n..end].to_vec();
+        }
+    }
+    Vec::new()
+}
+
+/// The number of tokens in a KV cache.
+fn cache_len(cache: &LayerCaches) -> usize {
+    cache
+        .first()
+        .and_then(|layer| layer.as_ref())
+        .map_or(0, |(k, _)| k.dims()[2])
+}
+
+/// Drop the keys and values after the first `len` tokens of a KV cache.
+fn truncate_cache(cache: &mut LayerCaches, len: usize) -> Result<()> {
+    for (k, v) in cache.iter_mut().flatten() {
+        if k.dims()[2] > len {
+            *k = k.narrow(2, 0, len)?;
+            *v = v.narrow(2, 0, len)?;
+        }
+    }
+    Ok(())
+}
+
+/// Set the draft model cache to the draft caches of the sequences, which all have the same length.
+fn set_draft_cache(draft: &mut dyn Pipeline, seqs: &mut [&mut Sequence]) -> Result<()> {
+    let mut new_cache = Vec::new();
+    for layer in 0..draft.num_hidden_layers() {
+        let mut k_vec = Vec::new();
+        let mut v_vec = Vec::new();
+        for seq in seqs.iter_mut() {
+            if let Some(Some((k, v))) = seq.draft_cache().get(layer) {
+                k_vec.push(k.clone());
+                v_vec.push(v.clone());
+            }
+        }
+        new_cache.push(if k_vec.is_empty() {
+            None
+        } else {
+            Some((Tensor::cat(&k_vec, 0)?, Tensor::cat(&v_vec, 0)?))
+        });
+    }
+    *draft.cache().lock() = new_cache;
+    Ok(())
+}
+
+/// Move the draft model cache back to the draft caches of the sequences.
+fn take_draft_cache(draft: &mut dyn Pipeline, seqs: &mut [&mut Sequence]) -> Result<()> {
+    let cache = draft.cache().lock();
+    for seq in seqs.iter_mut() {
+        seq.draft_cache().clear();
+    }
+    for layer in cache.iter() {
+        let Some((k, v)) = layer else {
+            bail!("The draft model did not fill its KV cache.");
+        };
+        let k_caches = k.chunk(seqs.len(), 0)?;
+        let v_caches = v.chunk(seqs.len(), 0)?;
+        for (seq, (k, v)) in seqs.iter_mut().zip(k_caches.into_iter().zip(v_caches)) {
+            seq.draft_cache().push(Some((k, v)));
+        }
+    }
+    Ok(())
+}
+
+/// The last `repeat_last_n` tokens of a sequence followed by the proposed tokens, which the penalties
+/// are computed from.
+fn penalty_ctxt(toks: &[u32], proposed: &[u32], repeat_last_n: usize) -> Vec<u32> {
+    let toks = [toks, proposed].concat();
+    toks[toks.len().saturating_sub(repeat_last_n)..].to_vec()
+}
+
+/// The distribution which a token is sampled from when a proposed token is rejected, `max(0, p - q)`.
+/// If it is zero everywhere (the distributions only differ by rounding), the model's distribution is used.
+fn residual_probs(probs: &[f32], draft_probs: &[f32]) -> Vec<f32> {
+    let residual = probs
+        .iter()
+        .zip(draft_probs)
+        .map(|(p, q)| (p - q).max(0.0))
+        .collect::<Vec<_>>();
+    if residual.iter().sum::<f32>() > 0.0 {
+        residual
+    } else {
+        probs.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use candle_core::{Device, Tensor};
+
+    use super::{Proposer, Speculative};
+    use crate::{
+        engine::{tests::TestPipeline, Engine},
+        models::LayerCaches,
+        pipeline::Pipeline,
+        prefix_cacher::{PrefixCacheBudget, PrefixCacheManager},
+        sequence::{Sequence, SequenceState},
+        test_utils::{completion_request, TestSequence},
+    };
+
+    fn test_pipeline(script: Vec<u32>) -> TestPipeline {
+        let mut pipeline = TestPipeline::new(Arc::new(Mutex::new(Vec::new())));
+        pipeline.script = script;
+        pipeline
+    }
+
+    /// A running argmax sequence of the test model after a completion step: its KV cache holds all of
+    /// its tokens but the last one.
+    fn running_seq(pipeline: &TestPipeline, toks: &[u32]) -> Sequence {
+        let mut seq = TestSequence::new(pipeline.tokenizer(), toks.to_vec())
+            .with_max_len(64)
+            .build();
+        let cached = toks[..toks.len() - 1]
+            .iter()
+            .map(|tok| *tok as f32)
+            .collect::<Vec<_>>();
+        let k = Tensor::from_vec(cached, (1, 1, toks.len() - 1, 1), &Device::Cpu).unwrap();
+        seq.cache()[0] = Some((k.clone(), k));
+        seq.set_state(SequenceState::RunningCompletion);
+        seq
+    }
+
+    /// The tokens which the test models put in a KV cache.
+    fn cached_toks(cache: &LayerCaches) -> Vec<u32> {
+        cache[0].as_ref().map_or(Vec::new(), |(k, _)| {
+            k.flatten_all()
+                .unwrap()
+                .to_vec1::<f32>()
+                .unwrap()
+                .iter()
+                .map(|tok| *tok as u32)
+                .collect()
+        })
+    }
+
+    fn speculative_step(
+        pipeline: &mut TestPipeline,
+        speculative: &Speculative,
+        seq: &mut Sequence,
+    ) {
+        let budget = PrefixCacheBudget {
+            n_on_device: 0,
+            device_max_bytes: None,
+            host_max_bytes: 0,
+        };
+        let mut prefix_cacher = PrefixCacheManager::new(Device::Cpu, budget, false, true, None);
+        Engine::speculative_step(pipeline, speculative, &mut [seq], &mut prefix_cacher, false)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_speculative_step_rejection() {
+        // The draft model agrees with the model at positions 4 and 5, but not at 6.
+        let script = vec![1, 2, 1, 2, 1, 2, 1, 2];
+        let mut draft_script = script.clone();
+        draft_script[6] = 2;
+        let mut pipeline = test_pipeline(script);
+        let speculative = Speculative {
+            proposer: Proposer::Draft(Box::new(Mutex::new(test_pipeline(draft_script)))),
+            gamma: 4,
+        };
+        let mut seq = running_seq(&pipeline, &[1, 2, 1, 2]);
+
+        speculative_step(&mut pipeline, &speculative, &mut seq);
+        // The proposal at position 6 is replaced by the token of the model, and the later ones are dropped.
+        assert_eq!(seq.get_toks(), &[1, 2, 1, 2, 1, 2, 1]);
+        // Both KV caches hold the committed tokens but the last, without the rejected proposals.
+        assert_eq!(cached_toks(seq.cache()), vec![1, 2, 1, 2, 1, 2]);
+        assert_eq!(cached_toks(seq.draft_cache()), vec![1, 2, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn test_speculative_step_draft_catch_up() {
+        // The draft model only disagrees with the model at position 10.
+        let script = vec![1, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1, 2];
+        let mut draft_script = script.clone();
+        draft_script[10] = 2;
+        let mut pipeline = test_pipeline(script);
+        let speculative = Speculative {
+            proposer: Proposer::Draft(Box::new(Mutex::new(test_pipeline(draft_script)))),
+            gamma: 3,
+        };
+        let mut seq = running_seq(&pipeline, &[1, 2, 1, 2]);
+
+        // The 3 proposals are accepted, and the model adds a token at position 7.
+        speculative_step(&mut pipeline, &speculative, &mut seq);
+        assert_eq!(seq.get_toks(), &[1, 2, 1, 2, 1, 2, 1, 2]);
+        assert_eq!(cached_toks(seq.cache()), vec![1, 2, 1, 2, 1, 2, 1]);
+        // The draft model has not run its last proposal, at position 6.
+        assert_eq!(cached_toks(seq.draft_cache()), vec![1, 2, 1, 2, 1, 2]);
+
+        // The draft model first runs position 6, then proposes positions 8 to 10.
+        speculative_step(&mut pipeline, &speculative, &mut seq);
+        assert_eq!(seq.get_toks(), &[1, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1]);
+        assert_eq!(cached_toks(seq.cache()), vec![1, 2, 1, 2, 1, 2, 1, 2, 1, 2]);
+        assert_eq!(
+            cached_toks(seq.draft_cache()),
+            vec![1, 2, 1, 2, 1, 2, 1, 2, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_speculative_greedy_output() {
+        use crate::{MistralRsBuilder, Response, SchedulerMethod, SpeculativeConfig};
+
+        // The draft model disagrees with the model at some positions, so that proposals are rejected.
+        let script = vec![1, 2, 2, 1, 1, 2, 1, 2, 2, 2, 1, 1, 2, 1];
+        let mut draft_script = script.clone();
+        for pos in [4, 8, 9] {
+            draft_script[pos] = 3 - draft_script[pos];
+        }
+        let complete = |draft: Option<TestPipeline>| {
+            let runs = Arc::new(Mutex::new(Vec::new()));
+            let mut pipeline = TestPipeline::new(runs.clone());
+            pipeline.script = script.clone();
+            let mut builder = MistralRsBuilder::new(
+                Box::new(Mutex::new(pipeline)),
+                SchedulerMethod::Fixed(4.try_into().unwrap()),
+            )
+            .with_no_prefix_cache(true);
+            if let Some(draft) = draft {
+                builder = builder
+                    .with_speculative(Box::new(Mutex::new(draft)), SpeculativeConfig::new(3));
+            }
+            let mistralrs = builder.build();
+
+            let (request, mut rx) = completion_request(0, script[..2].to_vec(), 10);
+            mistralrs.get_sender().send(request).unwrap();
+            let text = match rx.blocking_recv() {
+                Some(Response::CompletionDone(done)) => done.choices[0].text.clone(),
+                _ => panic!("The request did not complete."),
+            };
+            let n_runs = runs.lock().unwrap().len();
+            (text, n_runs)
+        };
+
+        let (text, n_runs) = complete(None);
+        assert!(n_runs > 1);
+        // Only the prompt is run with `forward`, and the completion steps are all speculative.
+        let (speculative_text, n_runs) = complete(Some(test_pipeline(draft_script)));
+        assert_eq!(speculative_text, text);
+        assert_eq!(n_runs, 1);
+    }
+    #[test]
+    fn test_residual_probs() {
+        use super::residual_probs;
+
+        let probs = [0.5, 0.3, 0.2, 0.0];
+        let draft_probs = [0.2, 0.6, 0.1, 0.1];
+        let residual = residual_probs(&probs, &draft_probs);
+        for (r, expected) in residual.iter().zip([0.3, 0.0, 0.1, 0.0]) {
+            assert!((r - expected).abs() < 1e-6);
+        }
+        // Accepting a draft token with probability min(1, p / q) and sampling from the residual
+        // otherwise gives back the model's distribution.
+        let sum = residual.iter().sum::<f32>();
+        let rejected = draft_probs
+            .iter()
+            .zip(probs)
+            .map(|(q, p)| q - q.min(p))
+            .sum::<f32>();
+        for (token, p) in probs.iter().enumerate() {
+            let accepted = draft_probs[token].min(*p);
+            let resampled = rejected * residual[token] / sum;
+            assert!((accepted + resampled - p).abs() < 1e-6);
+        }
+        assert_eq!(residual_probs(&probs, &probs), probs.to_vec());
+    }
+
+    #[test]
+    fn test_prompt_lookup() {
+        use super::prompt_lookup;
+
+        let prompt = [1, 2, 3, 4, 5, 2, 3, 6, 7];
+        // `2 3` last appears before `6 7`.
+        let toks = [&prompt[..], &[9, 2, 3]].concat();
+        assert_eq!(prompt_lookup(&toks, prompt.len(), 3, 4), vec![6, 7]);
+        // `4 2 3` is not in the prompt, but `2 3` is.
+        let toks = [&prompt[..], &[4, 2, 3]].concat();
+        assert_eq!(prompt_lookup(&toks, prompt.len(), 3, 1), vec![6]);
+        // The end of the prompt has no tokens after it.
+        assert_eq!(
+            prompt_lookup(&prompt, prompt.len(), 3, 4),
+            Vec::<u32>::new()
+        );
+        let toks = [&prompt[..], &[8]].concat();
+        assert_eq!(prompt_lookup(&toks, prompt.len(), 3, 4), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+use crate::{
+    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
+    sequence::SequenceRecognizer,
+    Constraint,
+};
+
+use super::{gbnf_to_yacc, json_schema_to_yacc, lark_to_yacc};
+
+/// The kind and text of a constraint, which identify its compiled recognizer.
+type CacheKey = (&'static str, String);
+
+/// A bounded cache of compiled recognizers, keyed by the text of their constraints, and the named
+/// grammars registered when the engine is created.
+///
+/// Compiling the regex DFA or the LR tables of a grammar can take longer than the generation
+/// itself, so each request is given a clone of the cached recognizer instead. There is one cache
+/// per engine, and so per tokenizer. The least recently used recognizers are evicted first;
+/// named grammars are never evicted.
+pub(crate) struct GrammarCache {
+    capacity: usize,
+    recognizers: HashMap<CacheKey, SequenceRecognizer>,
+    /// Keys of `recognizers`, from the least to the most recently used.
+    order: VecDeque<CacheKey>,
+    named: HashMap<String, SequenceRecognizer>,
+}
+
+impl GrammarCache {
+    /// Compile the named grammars. Grammars which fail to compile are skipped with a warning.
+    pub(crate) fn new(capacity: usize, named_grammars: HashMap<String, Constraint>) -> Self {
+        let mut named = HashMap::new();
+        for (name, constraint) in named_grammars {
+            if matches!(constraint, Constraint::Named(_)) {
+                warn!("Named grammar `{name}` cannot refer to another named grammar, skipping it.");
+                continue;
+            }
+            match compile(&constraint) {
+                Ok(recognizer) => {
+                    named.insert(name, recognizer);
+                }
+                Err(err) => warn!("Named grammar `{name}` is invalid, skipping it. {err}"),
+            }
+        }
+        Self {
+            capacity,
+            recognizers: HashMap::new(),
+            order: VecDeque::new(),
+            named,
+        }
+    }
+
+    /// Get a recognizer for the constraint, compiling it if it is not cached.
+    pub(crate) fn get(&mut self, constraint: &Constraint) -> Result<SequenceRecognizer> {
+        if let Constraint::Named(name) = constraint {
+            return match self.named.get(name) {
+                Some(recognizer) => Ok(recognizer.clone()),
+                None => bail!("Unknown named grammar `{name}`."),
+            };
+        }
+        let Some(key) = cache_key(constraint) else {
+            return compile(constraint);
+        };
+        if let Some(recognizer) = self.recognizers.get(&key) {
+            let recognizer = recognizer.clone();
+            if let Some(pos) = self.order.iter().position(|k| *k == key) {
+                let key = self.order.remove(pos).unwrap();
+                self.order.push_back(key);
+            }
+            return Ok(recognizer);
+        }
+
+        let recognizer = compile(constraint)?;
+        if self.capacity > 0 {
+            while self.recognizers.len() >= self.capacity {
+                let Some(lru) = self.order.pop_front() else {
+                    break;
+                };
+                self.recognizers.remove(&lru);
+            }
+            self.recognizers.insert(key.clone(), recognizer.clone());
+            self.order.push_back(key);
+        }
+        Ok(recognizer)
+    }
+}
+
+fn cache_key(constraint: &Constraint) -> Option<CacheKey> {
+    match constraint {
+        Constraint::Regex(rx) => Some(("regex", rx.clone())),
+        Constraint::Yacc(cfg) => Some(("yacc", cfg.clone())),
+        Constraint::Gbnf(gbnf) => Some(("gbnf", gbnf.clone())),
+        Constraint::Lark(lark) => Some(("lark", lark.clone())),
+        Constraint::JsonSchema(schema) => Some(("json_schema", schema.to_string())),
+        Constraint::Named(_) | Constraint::None => None,
+    }
+}
+
+fn compile(constraint: &Constraint) -> Result<SequenceRecognizer> {
+    let recognizer = match constraint {
+        Constraint::Regex(rx) => {
+            SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx)?).into())
+        }
+        Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+        Constraint::Gbnf(gbnf) => {
+            SequenceRecognizer::Cfg(CfgParser::from_yacc(&gbnf_to_yacc(gbnf)?)?.into())
+        }
+        Constraint::Lark(lark) => {
+            SequenceRecognizer::Cfg(CfgParser::from_yacc(&lark_to_yacc(lark)?)?.into())
+        }
+        Constraint::JsonSchema(schema) => {
+            SequenceRecognizer::Cfg(CfgParser::from_yacc(&json_schema_to_yacc(schema)?)?.into())
+        }
+        Constraint::Named(name) => bail!("Unknown named grammar `{name}`."),
+        Constraint::None => SequenceRecognizer::None,
+    };
+    Ok(recognizer)
+}
+
+mod tests {
+    #[test]
+    fn test_grammar_cache() {
+        use std::collections::HashMap;
+
+        use super::GrammarCache;
+        use crate::{sequence::SequenceRecognizer, Constraint};
+
+        let named = HashMap::from([
+            (
+                "digits".to_string(),
+                Constraint::Regex("[0-9]+".to_string()),
+            ),
+            ("invalid".to_string(), Constraint::Regex("(".to_string())),
+        ]);
+        let mut cache = GrammarCache::new(2, named);
+        assert!(matches!(
+            cache.get(&Constraint::Named("digits".to_string())),
+            Ok(SequenceRecognizer::Regex(_))
+        ));
+        assert!(cache
+            .get(&Constraint::Named("invalid".to_string()))
+            .is_err());
+        assert!(cache.get(&Constraint::Regex("(".to_string())).is_err());
+
+        for rx in ["a+", "b+", "a+", "c+"] {
+            cache.get(&Constraint::Regex(rx.to_string())).unwrap();
+        }
+        // `b+` was the least recently used when `c+` was added.
+        assert_eq!(cache.recognizers.len(), 2);
+        assert!(cache.recognizers.contains_key(&("regex", "a+".to_string())));
+        assert!(cache.recognizers.contains_key(&("regex", "c+".to_string())));
+    }
+}
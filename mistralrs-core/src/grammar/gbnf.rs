@@ -0,0 +1,398 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{anyhow, bail, Result};
+
+use super::{yacc_regex, Expr, YaccBuilder};
+
+/// Sorted, disjoint and inclusive ranges of code points.
+type CharSet = Vec<(u32, u32)>;
+
+/// All code points except the surrogates.
+const ANY_CHAR: [(u32, u32); 2] = [(0, 0xd7ff), (0xe000, 0x10ffff)];
+
+/// Compile a GBNF grammar, the grammar format of llama.cpp, to a yacc grammar for
+/// [`CfgParser`](crate::aici::cfg::CfgParser). The start rule is `root`.
+///
+/// GBNF grammars have no lexer, so each character is a token. The characters are split into classes
+/// which no literal or character class of the grammar distinguishes, and each class becomes a token.
+/// The parser is LR(1), so ambiguous grammars may reject some strings which llama.cpp accepts.
+pub(crate) fn gbnf_to_yacc(gbnf: &str) -> Result<String> {
+    let rules = Parser::new(gbnf).parse()?;
+
+    let mut sets = Vec::new();
+    for (_, expr) in &rules {
+        expr.terminals(&mut sets);
+    }
+    let sets = sets.into_iter().cloned().collect::<BTreeSet<_>>();
+    let classes = split_classes(&sets);
+    let tokens = classes
+        .iter()
+        .map(|(class, _)| class_regex(class))
+        .collect::<Vec<_>>();
+
+    let mut builder = YaccBuilder::new();
+    for (name, expr) in rules {
+        let expr = expr.try_map(&mut |set| {
+            let mut alternatives = classes
+                .iter()
+                .zip(&tokens)
+                .filter(|((_, sets), _)| sets.contains(&&set))
+                .map(|(_, token)| Expr::Terminal(token.clone()))
+                .collect::<Vec<_>>();
+            match alternatives.len() {
+                0 => bail!("Rule `{name}` has an empty character class."),
+                1 => Ok(alternatives.remove(0)),
+                _ => Ok(Expr::Alt(alternatives)),
+            }
+        })?;
+        builder.add_rule(&name, expr)?;
+    }
+    builder.finish("root")
+}
+
+/// Split the code points into the classes of characters which are in the same sets, and return each
+/// class with the sets which contain it.
+fn split_classes(sets: &BTreeSet<CharSet>) -> Vec<(CharSet, Vec<&CharSet>)> {
+    let mut bounds = BTreeSet::new();
+    for set in sets {
+        for (start, end) in set {
+            bounds.insert(*start);
+            bounds.insert(end + 1);
+        }
+    }
+    let bounds = bounds.into_iter().collect::<Vec<_>>();
+
+    let mut classes: Vec<(CharSet, Vec<&CharSet>)> = Vec::new();
+    let mut class_indices = HashMap::new();
+    for window in bounds.windows(2) {
+        let (start, end) = (window[0], window[1] - 1);
+        let containing = sets
+            .iter()
+            .filter(|set| contains(set, start))
+            .collect::<Vec<_>>();
+        if containing.is_empty() {
+            continue;
+        }
+        let index = *class_indices.entry(containing.clone()).or_insert_with(|| {
+            classes.push((Vec::new(), containing));
+            classes.len() - 1
+        });
+        let class = &mut classes[index].0;
+        match class.last_mut() {
+            Some((_, last_end)) if *last_end + 1 == start => *last_end = end,
+            _ => class.push((start, end)),
+        }
+    }
+    classes
+}
+
+fn contains(set: &CharSet, c: u32) -> bool {
+    let i = set.partition_point(|(_, end)| *end < c);
+    set.get(i).is_some_and(|(start, _)| *start <= c)
+}
+
+fn class_regex(class: &CharSet) -> String {
+    let ranges = class
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                format!(r"\x{{{start:x}}}")
+            } else {
+                format!(r"\x{{{start:x}}}-\x{{{end:x}}}")
+            }
+        })
+        .collect::<String>();
+    // The lexer matches bytes, Unicode mode matches the UTF-8 encoding of the characters.
+    yacc_regex(&format!("(?u:[{ranges}])"))
+}
+
+/// Sort and merge the ranges, without the surrogates.
+fn normalize(ranges: Vec<(u32, u32)>) -> CharSet {
+    let mut ranges = ranges
+        .into_iter()
+        .flat_map(|(start, end)| {
+            ANY_CHAR
+                .into_iter()
+                .map(move |(any_start, any_end)| (start.max(any_start), end.min(any_end)))
+        })
+        .filter(|(start, end)| start <= end)
+        .collect::<Vec<_>>();
+    ranges.sort();
+    let mut set: CharSet = Vec::new();
+    for (start, end) in ranges {
+        match set.last_mut() {
+            Some((_, last_end)) if start <= *last_end + 1 => *last_end = (*last_end).max(end),
+            _ => set.push((start, end)),
+        }
+    }
+    set
+}
+
+fn complement(set: &CharSet) -> CharSet {
+    let mut ranges = Vec::new();
+    let mut next = 0;
+    for (start, end) in set {
+        if *start > next {
+            ranges.push((next, start - 1));
+        }
+        next = end + 1;
+    }
+    if next <= 0x10ffff {
+        ranges.push((next, 0x10ffff));
+    }
+    normalize(ranges)
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Self {
+        Self { src, pos: 0 }
+    }
+
+    fn error(&self, msg: impl std::fmt::Display) -> anyhow::Error {
+        let line = self.src[..self.pos].matches('\n').count() + 1;
+        anyhow!("Line {line}: {msg}")
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("Unexpected end of grammar."))?;
+        self.pos += c.len_utf8();
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<()> {
+        if !self.rest().starts_with(expected) {
+            return Err(self.error(format!("Expected `{expected}`.")));
+        }
+        self.pos += expected.len();
+        Ok(())
+    }
+
+    /// Skip whitespace and comments.
+    fn skip(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if !trimmed.starts_with('#') {
+                return;
+            }
+            self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+    }
+
+    fn parse(mut self) -> Result<Vec<(String, Expr<CharSet>)>> {
+        let mut rules = Vec::new();
+        loop {
+            self.skip();
+            if self.peek().is_none() {
+                return Ok(rules);
+            }
+            let name = self.parse_name()?;
+            self.skip();
+            self.expect("::=")?;
+            let expr = self.parse_alternatives()?;
+            rules.push((name, expr));
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err(self.error("Expected a rule name."));
+        }
+        let name = self.rest()[..len].to_string();
+        self.pos += len;
+        Ok(name)
+    }
+
+    /// Whether a new rule starts here, which ends the current rule.
+    fn at_rule_start(&mut self) -> bool {
+        let pos = self.pos;
+        let is_start = self.parse_name().is_ok() && {
+            self.skip();
+            self.rest().starts_with("::=")
+        };
+        self.pos = pos;
+        is_start
+    }
+
+    fn parse_alternatives(&mut self) -> Result<Expr<CharSet>> {
+        let mut alternatives = vec![self.parse_sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.parse_sequence()?);
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives.remove(0),
+            _ => Expr::Alt(alternatives),
+        })
+    }
+
+    fn parse_sequence(&mut self) -> Result<Expr<CharSet>> {
+        let mut items = Vec::new();
+        loop {
+            self.skip();
+            let item = match self.peek() {
+                None | Some('|') | Some(')') => break,
+                Some('"') => {
+                    self.pos += 1;
+                    let mut chars = Vec::new();
+                    while self.peek() != Some('"') {
+                        let c = self.parse_char()?;
+                        chars.push(Expr::Terminal(normalize(vec![(c, c)])));
+                    }
+                    self.pos += 1;
+                    Expr::Seq(chars)
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let negated = self.rest().starts_with('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    while self.peek() != Some(']') {
+                        let start = self.parse_char()?;
+                        let end = if self.rest().starts_with('-') && !self.rest().starts_with("-]")
+                        {
+                            self.pos += 1;
+                            self.parse_char()?
+                        } else {
+                            start
+                        };
+                        ranges.push((start, end));
+                    }
+                    self.pos += 1;
+                    let set = normalize(ranges);
+                    Expr::Terminal(if negated { complement(&set) } else { set })
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    Expr::Terminal(ANY_CHAR.to_vec())
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    let expr = self.parse_alternatives()?;
+                    self.skip();
+                    self.expect(")")?;
+                    expr
+                }
+                Some(_) if self.at_rule_start() => break,
+                Some(_) => Expr::Rule(self.parse_name()?),
+            };
+            items.push(self.parse_repetition(item)?);
+        }
+        Ok(match items.len() {
+            1 => items.remove(0),
+            _ => Expr::Seq(items),
+        })
+    }
+
+    fn parse_repetition(&mut self, mut item: Expr<CharSet>) -> Result<Expr<CharSet>> {
+        loop {
+            self.skip();
+            item = match self.peek() {
+                Some('*') => item.repeat(0, None),
+                Some('+') => item.repeat(1, None),
+                Some('?') => item.repeat(0, Some(1)),
+                Some('{') => {
+                    let end = self
+                        .rest()
+                        .find('}')
+                        .ok_or_else(|| self.error("Expected `}`."))?;
+                    let bounds = &self.rest()[1..end];
+                    let parse = |bound: &str| {
+                        bound
+                            .trim()
+                            .parse::<usize>()
+                            .map_err(|_| self.error(format!("Invalid repetition `{{{bounds}}}`.")))
+                    };
+                    let (min, max) = match bounds.split_once(',') {
+                        None => (parse(bounds)?, Some(parse(bounds)?)),
+                        Some((min, max)) if max.trim().is_empty() => (parse(min)?, None),
+                        Some((min, max)) => (parse(min)?, Some(parse(max)?)),
+                    };
+                    self.pos += end;
+                    item.repeat(min, max)
+                }
+                _ => return Ok(item),
+            };
+            self.pos += 1;
+        }
+    }
+
+    fn parse_char(&mut self) -> Result<u32> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c as u32);
+        }
+        let hex = |parser: &mut Self, len: usize| -> Result<u32> {
+            let digits = parser.rest().get(..len).unwrap_or_default();
+            let c = u32::from_str_radix(digits, 16)
+                .map_err(|_| parser.error(format!("Invalid escape `{digits}`.")))?;
+            parser.pos += len;
+            Ok(c)
+        };
+        Ok(match self.next()? {
+            'x' => hex(self, 2)?,
+            'u' => hex(self, 4)?,
+            'U' => hex(self, 8)?,
+            'n' => '\n' as u32,
+            'r' => '\r' as u32,
+            't' => '\t' as u32,
+            c if !c.is_ascii_alphanumeric() => c as u32,
+            c => return Err(self.error(format!("Unknown escape `\\{c}`."))),
+        })
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_gbnf_to_yacc() {
+        use super::gbnf_to_yacc;
+        use crate::aici::{
+            cfg::CfgParser,
+            toktree::{Recognizer, SpecialToken},
+        };
+
+        let gbnf = r#"
+            # A list of key-value pairs.
+            root ::= "{" ws (pair ("," ws pair)*)? "}"
+            pair ::= key ws ":" ws value ws
+            key  ::= [a-z_]+
+            value ::= "\"" [^"\\\n]* "\"" | [0-9]{1,3} | "true" | "false"
+            ws ::= [ \t]*
+        "#;
+        let yacc = gbnf_to_yacc(gbnf).unwrap();
+        let accepts = |output: &str| {
+            let mut parser = CfgParser::from_yacc(&yacc).unwrap();
+            output.bytes().all(|b| parser.try_push_byte(b))
+                && parser.special_allowed(SpecialToken::EndOfSentence)
+        };
+
+        assert!(accepts(r#"{a: "héllo", b_c: 123, true_: true}"#));
+        assert!(accepts("{}"));
+        assert!(!accepts("{a: 1234}"));
+        assert!(!accepts("{A: 1}"));
+        assert!(!accepts(r#"{a: "x}"#));
+        assert!(gbnf_to_yacc("root ::= missing").is_err());
+    }
+}
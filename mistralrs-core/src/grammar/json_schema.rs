@@ -0,0 +1,468 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{bail, Context, Result};
+use regex_automata::{meta::Regex, util::syntax};
+use serde_json::{Map, Value};
+
+use super::yacc_literal;
+
+/// One character of a JSON string: an escape, a printable ASCII character other than `"` and `\`, or a
+/// UTF-8 encoded character.
+const CHAR: &str = r#"(\\["\\/bfnrt]|\\u[0-9a-fA-F]{4}|[\x20\x21\x23-\x5b\x5d-\x7f]|[\xc0-\xdf][\x80-\xbf]|[\xe0-\xef][\x80-\xbf]{2}|[\xf0-\xf7][\x80-\xbf]{3})"#;
+const INTEGER: &str = r#""/-?(0|[1-9][0-9]*)/""#;
+/// Numbers with a fraction or exponent, so that the token does not overlap with `INTEGER`.
+const FRACTION: &str = r#""/-?(0|[1-9][0-9]*)(\.[0-9]+([eE][+-]?[0-9]+)?|[eE][+-]?[0-9]+)/""#;
+const WHITESPACE: &str = r#""/[ \t\n\r]+/""#;
+
+/// Compile a JSON schema to a yacc grammar for [`CfgParser`](crate::aici::cfg::CfgParser), so that the
+/// output is a JSON value which validates against the schema.
+///
+/// Supported are `type`, `properties` and `required` (properties are generated in the schema order),
+/// `items`, `minItems` and `maxItems`, `pattern`, `minLength` and `maxLength`, `enum`, `const`, `anyOf`,
+/// `oneOf`, a single-schema `allOf` and local `$ref`s. Other keywords, such as numeric bounds and formats,
+/// are not enforced. Properties which are not in `properties` are never generated.
+pub(crate) fn json_schema_to_yacc(schema: &Value) -> Result<String> {
+    let mut compiler = Compiler::new(schema);
+    let root = compiler.compile(schema)?;
+    compiler.finish(&root)
+}
+
+/// A string token restricted by `pattern`, `minLength` or `maxLength`.
+struct StringToken {
+    rule: String,
+    token: String,
+    content: String,
+}
+
+struct Compiler<'a> {
+    root: &'a Value,
+    /// The alternatives of each rule, each a space separated list of symbols.
+    rules: Vec<(String, Vec<String>)>,
+    refs: HashMap<String, String>,
+    /// The JSON text of all literal tokens.
+    literals: BTreeSet<String>,
+    string_tokens: Vec<StringToken>,
+    generic: BTreeSet<&'static str>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(root: &'a Value) -> Self {
+        Self {
+            root,
+            rules: Vec::new(),
+            refs: HashMap::new(),
+            literals: BTreeSet::new(),
+            string_tokens: Vec::new(),
+            generic: BTreeSet::new(),
+        }
+    }
+
+    fn literal(&mut self, literal: &str) -> String {
+        self.literals.insert(literal.to_string());
+        yacc_literal(literal)
+    }
+
+    /// Reserve a rule, so that it can be referenced before its alternatives are known.
+    fn reserve_rule(&mut self) -> String {
+        let name = format!("r{}", self.rules.len());
+        self.rules.push((name.clone(), Vec::new()));
+        name
+    }
+
+    fn set_rule(&mut self, name: &str, alternatives: Vec<String>) {
+        let rule = self
+            .rules
+            .iter_mut()
+            .find(|(rule, _)| rule == name)
+            .expect("Rule was reserved.");
+        rule.1 = alternatives;
+    }
+
+    fn add_rule(&mut self, alternatives: Vec<String>) -> String {
+        let name = self.reserve_rule();
+        self.set_rule(&name, alternatives);
+        name
+    }
+
+    /// One of the rules for any JSON value, emitted at the end.
+    fn generic(&mut self, rule: &'static str) -> String {
+        self.generic.insert(rule);
+        rule.to_string()
+    }
+
+    fn compile(&mut self, schema: &'a Value) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.generic("json_value")),
+            Value::Bool(false) => bail!("The `false` schema does not accept any value."),
+            Value::Object(schema) => schema,
+            _ => bail!("Expected a schema object, got `{schema}`."),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.compile_ref(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(self.literal(&value.to_string()));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values.as_array().context("`enum` must be an array.")?;
+            let alternatives = values
+                .iter()
+                .map(|value| self.literal(&value.to_string()))
+                .collect();
+            return Ok(self.add_rule(alternatives));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = schemas
+                    .as_array()
+                    .with_context(|| format!("`{keyword}` must be an array."))?;
+                let alternatives = schemas
+                    .iter()
+                    .map(|schema| self.compile(schema))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(self.add_rule(alternatives));
+            }
+        }
+        if let Some(schemas) = schema.get("allOf") {
+            match schemas.as_array().map(Vec::as_slice) {
+                Some([schema]) => return self.compile(schema),
+                _ => bail!("`allOf` is only supported with a single schema."),
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(tp)) => self.compile_type(tp, schema),
+            Some(Value::Array(tps)) => {
+                let alternatives = tps
+                    .iter()
+                    .map(|tp| match tp {
+                        Value::String(tp) => self.compile_type(tp, schema),
+                        _ => bail!("Invalid type `{tp}`."),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.add_rule(alternatives))
+            }
+            Some(tp) => bail!("Invalid type `{tp}`."),
+            None if schema.contains_key("properties") => self.compile_type("object", schema),
+            None if schema.contains_key("items") => self.compile_type("array", schema),
+            None => Ok(self.generic("json_value")),
+        }
+    }
+
+    fn compile_ref(&mut self, reference: &Value) -> Result<String> {
+        let reference = reference.as_str().context("`$ref` must be a string.")?;
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(rule.clone());
+        }
+        let root = self.root;
+        let schema = reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .with_context(|| {
+                format!("Cannot resolve `$ref` `{reference}`, only local references are supported.")
+            })?;
+        // Reserve the rule first, as the schema may be recursive.
+        let rule = self.reserve_rule();
+        self.refs.insert(reference.to_string(), rule.clone());
+        let symbol = self.compile(schema)?;
+        self.set_rule(&rule, vec![symbol]);
+        Ok(rule)
+    }
+
+    fn compile_type(&mut self, tp: &str, schema: &'a Map<String, Value>) -> Result<String> {
+        match tp {
+            "object" => self.compile_object(schema),
+            "array" => self.compile_array(schema),
+            "string" => self.compile_string(schema),
+            "integer" => Ok(self.generic("json_integer")),
+            "number" => Ok(self.generic("json_number")),
+            "boolean" => {
+                let alternatives = vec![self.literal("true"), self.literal("false")];
+                Ok(self.add_rule(alternatives))
+            }
+            "null" => Ok(self.literal("null")),
+            _ => bail!("Invalid type `{tp}`."),
+        }
+    }
+
+    fn compile_object(&mut self, schema: &'a Map<String, Value>) -> Result<String> {
+        let Some(properties) = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .filter(|properties| !properties.is_empty())
+        else {
+            return Ok(self.generic("json_object"));
+        };
+        let required = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| {
+                required
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut members = Vec::new();
+        for (name, property) in properties {
+            let key = self.literal(&Value::String(name.clone()).to_string());
+            let colon = self.literal(":");
+            let value = self.compile(property)?;
+            members.push((
+                format!("{key} {colon} {value}"),
+                required.contains(&name.as_str()),
+            ));
+        }
+
+        // `rest[i]` generates the members from `i` on, each preceded by a comma. Optional members may be
+        // skipped, but the order is kept so that the next key decides which alternative is taken.
+        let mut rest = vec![String::new(); members.len() + 1];
+        for i in (1..=members.len()).rev() {
+            let alternatives = self.member_alternatives(&members, &rest, i, true);
+            rest[i] = self.add_rule(alternatives);
+        }
+        let first = self.member_alternatives(&members, &rest, 0, false);
+        let first = self.add_rule(first);
+        let open = self.literal("{");
+        let close = self.literal("}");
+        Ok(self.add_rule(vec![format!("{open} {first} {close}")]))
+    }
+
+    fn member_alternatives(
+        &mut self,
+        members: &[(String, bool)],
+        rest: &[String],
+        start: usize,
+        with_comma: bool,
+    ) -> Vec<String> {
+        let comma = if with_comma {
+            format!("{} ", self.literal(","))
+        } else {
+            String::new()
+        };
+        let mut alternatives = Vec::new();
+        for (i, (member, required)) in members.iter().enumerate().skip(start) {
+            alternatives.push(format!("{comma}{member} {}", rest[i + 1]));
+            if *required {
+                return alternatives;
+            }
+        }
+        // All remaining members are optional.
+        alternatives.push(String::new());
+        alternatives
+    }
+
+    fn compile_array(&mut self, schema: &'a Map<String, Value>) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.compile(items)?,
+            None => self.generic("json_value"),
+        };
+        let min = get_usize(schema, "minItems")?.unwrap_or(0);
+        let max = get_usize(schema, "maxItems")?;
+        if max.is_some_and(|max| max < min) {
+            bail!("`maxItems` is less than `minItems`.");
+        }
+        let open = self.literal("[");
+        let close = self.literal("]");
+        let comma = self.literal(",");
+        if max == Some(0) {
+            return Ok(self.add_rule(vec![format!("{open} {close}")]));
+        }
+
+        // `tails[k]` generates the items after the first `k` items.
+        let last = max.unwrap_or(min.max(1));
+        let mut tails = vec![String::new(); last + 1];
+        tails[last] = if max.is_some() {
+            self.add_rule(vec![String::new()])
+        } else {
+            let tail = self.reserve_rule();
+            self.set_rule(&tail, vec![String::new(), format!("{comma} {item} {tail}")]);
+            tail
+        };
+        for k in (1..last).rev() {
+            let next = format!("{comma} {item} {}", tails[k + 1]);
+            tails[k] = if k < min {
+                self.add_rule(vec![next])
+            } else {
+                self.add_rule(vec![String::new(), next])
+            };
+        }
+        let mut alternatives = vec![format!("{open} {item} {} {close}", tails[1])];
+        if min == 0 {
+            alternatives.push(format!("{open} {close}"));
+        }
+        Ok(self.add_rule(alternatives))
+    }
+
+    fn compile_string(&mut self, schema: &'a Map<String, Value>) -> Result<String> {
+        let pattern = schema.get("pattern").and_then(Value::as_str);
+        let min_length = get_usize(schema, "minLength")?;
+        let max_length = get_usize(schema, "maxLength")?;
+        let content = match (pattern, min_length, max_length) {
+            (None, None, None) => return Ok(self.generic("json_string")),
+            // A pattern may match anywhere in the string unless it is anchored. The length is not
+            // enforced together with a pattern.
+            (Some(pattern), _, _) => {
+                let (start, pattern) = match pattern.strip_prefix('^') {
+                    Some(pattern) => (String::new(), pattern),
+                    None => (format!("{CHAR}*"), pattern),
+                };
+                let (pattern, end) = match pattern.strip_suffix('$') {
+                    Some(pattern) => (pattern, String::new()),
+                    None => (pattern, format!("{CHAR}*")),
+                };
+                format!("{start}({pattern}){end}")
+            }
+            (None, min_length, max_length) => format!(
+                "{CHAR}{{{},{}}}",
+                min_length.unwrap_or(0),
+                max_length.map(|x| x.to_string()).unwrap_or_default()
+            ),
+        };
+        let mut content = content.replace('\'', r"\x27").replace('\n', r"\n");
+        // If several regex tokens match, the lexer picks the one with the longest name, so make sure
+        // that this token is picked over the generic string token.
+        while content.len() <= CHAR.len() + 1 {
+            content.insert_str(0, "(?:)");
+        }
+        let rule = self.reserve_rule();
+        self.string_tokens.push(StringToken {
+            rule: rule.clone(),
+            token: format!("'/\"{content}\"/'"),
+            content,
+        });
+        Ok(rule)
+    }
+
+    /// Add the rules which depend on all literals and string tokens, and write the grammar.
+    fn finish(mut self, root: &str) -> Result<String> {
+        // The lexer prefers literal tokens over regex tokens and does not know which tokens the
+        // parser expects, so the rules for strings and numbers also accept matching literals.
+        let string_literals = self
+            .literals
+            .iter()
+            .filter(|literal| literal.starts_with('"'))
+            .cloned()
+            .collect::<Vec<_>>();
+        let string_tokens = std::mem::take(&mut self.string_tokens);
+        for token in &string_tokens {
+            let regex = Regex::builder()
+                .configure(Regex::config().utf8_empty(false))
+                .syntax(syntax::Config::new().unicode(false).utf8(false))
+                .build(&format!("^\"(?:{})\"$", token.content))
+                .with_context(|| format!("Invalid `pattern` `{}`.", token.content))?;
+            let mut alternatives = vec![token.token.clone()];
+            alternatives.extend(
+                string_literals
+                    .iter()
+                    .filter(|literal| regex.is_match(literal.as_bytes()))
+                    .map(|literal| yacc_literal(literal)),
+            );
+            self.set_rule(&token.rule, alternatives);
+        }
+
+        let mut out = format!("%start root\n%%\nSKIP: {WHITESPACE} ;\nroot: {root} ;\n");
+        for (name, alternatives) in &self.rules {
+            out.push_str(&format!("{name}: {} ;\n", alternatives.join(" | ")));
+        }
+
+        let uses_any = ["json_value", "json_object"]
+            .iter()
+            .any(|rule| self.generic.contains(rule));
+        if uses_any {
+            let [open, close, open_arr, close_arr, comma, colon] =
+                ["{", "}", "[", "]", ",", ":"].map(yacc_literal);
+            let [tr, fa, null] = ["true", "false", "null"].map(yacc_literal);
+            out.push_str(&format!(
+                "json_value: json_object | json_array | json_string | json_number | {tr} | {fa} | {null} ;\n\
+                 json_object: {open} {close} | {open} json_members {close} ;\n\
+                 json_members: json_member | json_members {comma} json_member ;\n\
+                 json_member: json_string {colon} json_value ;\n\
+                 json_array: {open_arr} {close_arr} | {open_arr} json_elements {close_arr} ;\n\
+                 json_elements: json_value | json_elements {comma} json_value ;\n"
+            ));
+        }
+        if uses_any || self.generic.contains("json_string") {
+            let mut alternatives = vec![format!("'/\"{CHAR}*\"/'")];
+            alternatives.extend(string_tokens.iter().map(|token| token.token.clone()));
+            alternatives.extend(string_literals.iter().map(|literal| yacc_literal(literal)));
+            out.push_str(&format!("json_string: {} ;\n", alternatives.join(" | ")));
+        }
+        let number_literals = self
+            .literals
+            .iter()
+            .filter_map(|literal| match serde_json::from_str::<Value>(literal) {
+                Ok(Value::Number(number)) => Some((literal, number)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if uses_any || self.generic.contains("json_number") {
+            let mut alternatives = vec![INTEGER.to_string(), FRACTION.to_string()];
+            alternatives.extend(
+                number_literals
+                    .iter()
+                    .map(|(literal, _)| yacc_literal(literal)),
+            );
+            out.push_str(&format!("json_number: {} ;\n", alternatives.join(" | ")));
+        }
+        if self.generic.contains("json_integer") {
+            let mut alternatives = vec![INTEGER.to_string()];
+            alternatives.extend(
+                number_literals
+                    .iter()
+                    .filter(|(_, number)| number.is_i64() || number.is_u64())
+                    .map(|(literal, _)| yacc_literal(literal)),
+            );
+            out.push_str(&format!("json_integer: {} ;\n", alternatives.join(" | ")));
+        }
+        Ok(out)
+    }
+}
+
+fn get_usize(schema: &Map<String, Value>, keyword: &str) -> Result<Option<usize>> {
+    schema
+        .get(keyword)
+        .map(|value| {
+            value
+                .as_u64()
+                .and_then(|value| usize::try_from(value).ok())
+                .with_context(|| format!("`{keyword}` must be a non-negative integer."))
+        })
+        .transpose()
+}
+
+mod tests {
+    #[test]
+    fn test_json_schema_to_yacc() {
+        use super::json_schema_to_yacc;
+        use crate::aici::{
+            cfg::CfgParser,
+            toktree::{Recognizer, SpecialToken},
+        };
+
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "maxLength": 10},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}, "maxItems": 2},
+            },
+            "required": ["name"],
+        });
+        let yacc = json_schema_to_yacc(&schema).unwrap();
+        let accepts = |output: &str| {
+            let mut parser = CfgParser::from_yacc(&yacc).unwrap();
+            output.bytes().all(|b| parser.try_push_byte(b))
+                && parser.special_allowed(SpecialToken::EndOfSentence)
+        };
+
+        assert!(accepts(r#"{"name": "Ann", "age": 30, "tags": ["a", "b"]}"#));
+        assert!(accepts(r#"{"name":"Ann","tags":[]}"#));
+        assert!(!accepts(r#"{"age": 30}"#));
+        assert!(!accepts(r#"{"name": "Ann", "tags": ["c"]}"#));
+        assert!(!accepts(r#"{"name": "Ann", "tags": ["a", "a", "a"]}"#));
+        assert!(!accepts(r#"{"name": "Anne-Marie Smith"}"#));
+    }
+}
@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+
+use super::{yacc_literal, yacc_regex, Expr, YaccBuilder};
+
+/// The terminals of the Lark `common` library which may be imported.
+const COMMON: &str = r#"
+DIGIT: "0".."9"
+HEXDIGIT: "a".."f" | "A".."F" | DIGIT
+INT: DIGIT+
+SIGNED_INT: ["+" | "-"] INT
+DECIMAL: INT "." INT? | "." INT
+_EXP: ("e" | "E") SIGNED_INT
+FLOAT: INT _EXP | DECIMAL _EXP?
+SIGNED_FLOAT: ["+" | "-"] FLOAT
+NUMBER: FLOAT | INT
+SIGNED_NUMBER: ["+" | "-"] NUMBER
+ESCAPED_STRING: /"([^"\\]|\\.)*"/
+LCASE_LETTER: "a".."z"
+UCASE_LETTER: "A".."Z"
+LETTER: UCASE_LETTER | LCASE_LETTER
+WORD: LETTER+
+CNAME: ("_" | LETTER) ("_" | LETTER | DIGIT)*
+WS_INLINE: (" " | /\t/)+
+WS: /[ \t\f\r\n]/+
+CR: /\r/
+LF: /\n/
+NEWLINE: (CR? LF)+
+"#;
+
+/// Compile a Lark grammar to a yacc grammar for [`CfgParser`](crate::aici::cfg::CfgParser). The start
+/// rule is `start`.
+///
+/// Terminals become the tokens of the lexer and `%ignore`d terminals are skipped between tokens.
+/// `%import` is supported for the `common` library. Rule modifiers, aliases and priorities are ignored,
+/// as they only shape the parse tree. Templates and other directives are not supported.
+pub(crate) fn lark_to_yacc(lark: &str) -> Result<String> {
+    let mut grammar = Parser::new(tokenize(lark)?).parse()?;
+    if !grammar.imports.is_empty() {
+        let common = Parser::new(tokenize(COMMON)?).parse()?.terminals;
+        for (name, alias) in std::mem::take(&mut grammar.imports) {
+            let terminal = common
+                .get(&name)
+                .ok_or_else(|| anyhow!("Cannot import `{name}` from `common`."))?;
+            grammar.define_terminal(alias.unwrap_or(name), terminal.clone())?;
+        }
+        // Add the common terminals which the imported terminals use.
+        for (name, terminal) in common {
+            grammar.terminals.entry(name).or_insert(terminal);
+        }
+    }
+
+    let mut builder = YaccBuilder::new();
+    for ignore in &grammar.ignores {
+        let token = grammar.token(ignore)?;
+        builder.add_skip(token);
+    }
+    for (name, expr) in &grammar.rules {
+        let expr = expr
+            .clone()
+            .try_map(&mut |terminal| Ok(Expr::Terminal(grammar.token(&terminal)?)))?;
+        builder.add_rule(name, expr)?;
+    }
+    builder.finish("start")
+}
+
+#[derive(Clone, Debug)]
+enum Terminal {
+    Name(String),
+    /// A string, which may be case insensitive.
+    Literal(String, bool),
+    /// A regex with its flags.
+    Regex(String, String),
+    Range(char, char),
+}
+
+struct Grammar {
+    rules: Vec<(String, Expr<Terminal>)>,
+    terminals: HashMap<String, Expr<Terminal>>,
+    ignores: Vec<Terminal>,
+    /// Imported terminals from `common`, with their alias.
+    imports: Vec<(String, Option<String>)>,
+}
+
+impl Grammar {
+    fn define_terminal(&mut self, name: String, expr: Expr<Terminal>) -> Result<()> {
+        if self.terminals.insert(name.clone(), expr).is_some() {
+            bail!("Terminal `{name}` is defined more than once.");
+        }
+        Ok(())
+    }
+
+    /// The yacc token of a terminal. Plain strings are literal tokens, which the lexer prefers over
+    /// regex tokens.
+    fn token(&self, terminal: &Terminal) -> Result<String> {
+        match terminal {
+            Terminal::Literal(literal, false) => Ok(yacc_literal(literal)),
+            Terminal::Name(name) => match self.terminals.get(name) {
+                Some(Expr::Terminal(Terminal::Literal(literal, false))) => {
+                    Ok(yacc_literal(literal))
+                }
+                Some(_) => Ok(yacc_regex(
+                    &self.regex(&Expr::Terminal(terminal.clone()), &mut vec![])?,
+                )),
+                None => bail!("Undefined terminal `{name}`."),
+            },
+            terminal => Ok(yacc_regex(
+                &self.regex(&Expr::Terminal(terminal.clone()), &mut vec![])?,
+            )),
+        }
+    }
+
+    /// Compile the expression of a terminal to a regex. `stack` holds the terminals being compiled, to
+    /// detect recursion.
+    fn regex(&self, expr: &Expr<Terminal>, stack: &mut Vec<String>) -> Result<String> {
+        Ok(match expr {
+            Expr::Terminal(Terminal::Literal(literal, case_insensitive)) => {
+                let escaped = escape(literal);
+                if *case_insensitive {
+                    format!("(?i:{escaped})")
+                } else {
+                    escaped
+                }
+            }
+            Expr::Terminal(Terminal::Regex(regex, flags)) => {
+                // Python regexes match Unicode characters.
+                let flags = flags
+                    .chars()
+                    .filter(|flag| "imsx".contains(*flag))
+                    .collect::<String>();
+                format!("(?u{flags}:{regex})")
+            }
+            Expr::Terminal(Terminal::Range(start, end)) => {
+                format!(r"(?u:[\x{{{:x}}}-\x{{{:x}}}])", *start as u32, *end as u32)
+            }
+            Expr::Terminal(Terminal::Name(name)) => {
+                if stack.contains(name) {
+                    bail!("Terminal `{name}` is recursive.");
+                }
+                let expr = self
+                    .terminals
+                    .get(name)
+                    .ok_or_else(|| anyhow!("Undefined terminal `{name}`."))?;
+                stack.push(name.clone());
+                let regex = self.regex(expr, stack)?;
+                stack.pop();
+                format!("(?:{regex})")
+            }
+            Expr::Rule(name) => bail!("Terminals cannot use the rule `{name}`."),
+            Expr::Seq(exprs) => exprs
+                .iter()
+                .map(|expr| Ok(format!("(?:{})", self.regex(expr, stack)?)))
+                .collect::<Result<String>>()?,
+            Expr::Alt(exprs) => format!(
+                "(?:{})",
+                exprs
+                    .iter()
+                    .map(|expr| self.regex(expr, stack))
+                    .collect::<Result<Vec<_>>>()?
+                    .join("|")
+            ),
+            Expr::Repeat { expr, min, max } => {
+                let regex = self.regex(expr, stack)?;
+                match max {
+                    Some(max) => format!("(?:{regex}){{{min},{max}}}"),
+                    None => format!("(?:{regex}){{{min},}}"),
+                }
+            }
+        })
+    }
+}
+
+/// Escape a string for a regex.
+fn escape(literal: &str) -> String {
+    literal
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_string()
+            } else if c.is_ascii() {
+                format!(r"\x{:02x}", c as u32)
+            } else {
+                format!(r"(?u:\x{{{:x}}})", c as u32)
+            }
+        })
+        .collect()
+}
+
+fn is_terminal(name: &str) -> bool {
+    name.trim_start_matches('_')
+        .starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Str(String, bool),
+    Regex(String, String),
+    Number(usize),
+    Directive(String),
+    Punct(&'static str),
+    Newline,
+}
+
+const PUNCTUATION: [&str; 15] = [
+    "->", "..", ":", "|", "(", ")", "[", "]", "?", "*", "+", "~", ",", "!", ".",
+];
+
+fn tokenize(src: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut rest = src;
+    while let Some(c) = rest.chars().next() {
+        let error = |msg: &str| anyhow!("Line {line}: {msg}");
+        let (token, len) = if c == '\n' {
+            line += 1;
+            (Some(Token::Newline), 1)
+        } else if c.is_whitespace() {
+            (None, c.len_utf8())
+        } else if rest.starts_with("//") {
+            (None, rest.find('\n').unwrap_or(rest.len()))
+        } else if c == '"' {
+            let (value, len) = parse_string(rest).ok_or_else(|| error("Unterminated string."))?;
+            match rest[len..].starts_with('i') {
+                true => (Some(Token::Str(value, true)), len + 1),
+                false => (Some(Token::Str(value, false)), len),
+            }
+        } else if c == '/' {
+            let mut end = None;
+            let mut escaped = false;
+            for (i, c) in rest.char_indices().skip(1) {
+                match c {
+                    '\n' => break,
+                    '/' if !escaped => {
+                        end = Some(i);
+                        break;
+                    }
+                    _ => escaped = c == '\\' && !escaped,
+                }
+            }
+            let end = end.ok_or_else(|| error("Unterminated regex."))?;
+            let regex = rest[1..end].replace(r"\/", "/");
+            let flags_len = rest[end + 1..]
+                .find(|c: char| !c.is_ascii_lowercase())
+                .unwrap_or(rest.len() - end - 1);
+            let flags = rest[end + 1..end + 1 + flags_len].to_string();
+            (Some(Token::Regex(regex, flags)), end + 1 + flags_len)
+        } else if c == '%' || c == '_' || c.is_ascii_alphabetic() {
+            let start = usize::from(c == '%');
+            let len = rest[start..]
+                .find(|c: char| !(c == '_' || c.is_ascii_alphanumeric()))
+                .unwrap_or(rest.len() - start)
+                + start;
+            let name = rest[start..len].to_string();
+            if c == '%' {
+                (Some(Token::Directive(name)), len)
+            } else {
+                // Skip the priority of a definition, such as `NAME.2`.
+                let priority = match rest[len..].strip_prefix('.') {
+                    Some(priority)
+                        if priority
+                            .trim_start_matches('-')
+                            .starts_with(|c: char| c.is_ascii_digit()) =>
+                    {
+                        let digits = priority.trim_start_matches('-');
+                        1 + priority.len() - digits.len()
+                            + digits
+                                .find(|c: char| !c.is_ascii_digit())
+                                .unwrap_or(digits.len())
+                    }
+                    _ => 0,
+                };
+                (Some(Token::Name(name)), len + priority)
+            }
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let number = rest[..len].parse().map_err(|_| error("Invalid number."))?;
+            (Some(Token::Number(number)), len)
+        } else if c == '{' {
+            bail!("Line {line}: Templates are not supported.");
+        } else {
+            let punct = PUNCTUATION
+                .into_iter()
+                .find(|punct| rest.starts_with(punct))
+                .ok_or_else(|| error(&format!("Unexpected `{c}`.")))?;
+            (Some(Token::Punct(punct)), punct.len())
+        };
+        if let Some(token) = token {
+            tokens.push((token, line));
+        }
+        rest = &rest[len..];
+    }
+    Ok(tokens)
+}
+
+/// Parse a string with Python escapes, returning its value and length.
+fn parse_string(src: &str) -> Option<(String, usize)> {
+    let mut value = String::new();
+    let mut chars = src.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, i + 1)),
+            '\n' => return None,
+            '\\' => {
+                let (_, escape) = chars.next()?;
+                let hex = |chars: &mut std::iter::Skip<std::str::CharIndices>, len| {
+                    let digits = (0..len)
+                        .map(|_| chars.next().map(|(_, c)| c))
+                        .collect::<Option<String>>()?;
+                    char::from_u32(u32::from_str_radix(&digits, 16).ok()?)
+                };
+                value.push(match escape {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'f' => '\x0c',
+                    '0' => '\0',
+                    'x' => hex(&mut chars, 2)?,
+                    'u' => hex(&mut chars, 4)?,
+                    'U' => hex(&mut chars, 8)?,
+                    escape => escape,
+                });
+            }
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<(Token, usize)>) -> Self {
+        Self { tokens, pos: 0 }
+    }
+
+    fn error(&self, msg: impl std::fmt::Display) -> anyhow::Error {
+        match self.tokens.get(self.pos).or(self.tokens.last()) {
+            Some((_, line)) => anyhow!("Line {line}: {msg}"),
+            None => anyhow!("{msg}"),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        let eaten = matches!(self.peek(), Some(Token::Punct(p)) if *p == punct);
+        if eaten {
+            self.pos += 1;
+        }
+        eaten
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<()> {
+        if !self.eat(punct) {
+            return Err(self.error(format!("Expected `{punct}`.")));
+        }
+        Ok(())
+    }
+
+    fn expect_name(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            _ => {
+                self.pos -= 1;
+                Err(self.error("Expected a name."))
+            }
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse(mut self) -> Result<Grammar> {
+        let mut grammar = Grammar {
+            rules: Vec::new(),
+            terminals: HashMap::new(),
+            ignores: Vec::new(),
+            imports: Vec::new(),
+        };
+        loop {
+            self.skip_newlines();
+            match self.peek().cloned() {
+                None => return Ok(grammar),
+                Some(Token::Directive(directive)) => {
+                    self.pos += 1;
+                    self.parse_directive(&directive, &mut grammar)?;
+                }
+                Some(_) => {
+                    // The `?` and `!` modifiers only shape the parse tree.
+                    if !self.eat("?") {
+                        self.eat("!");
+                    }
+                    let name = self.expect_name()?;
+                    self.expect(":")?;
+                    let expr = self.parse_expansions(false)?;
+                    if is_terminal(&name) {
+                        grammar.define_terminal(name, expr)?;
+                    } else {
+                        grammar.rules.push((name, expr));
+                    }
+                }
+            }
+            if !matches!(self.next(), None | Some(Token::Newline)) {
+                self.pos -= 1;
+                return Err(self.error("Expected the end of the line."));
+            }
+        }
+    }
+
+    fn parse_directive(&mut self, directive: &str, grammar: &mut Grammar) -> Result<()> {
+        match directive {
+            "ignore" => match self.next() {
+                Some(Token::Name(name)) => grammar.ignores.push(Terminal::Name(name)),
+                Some(Token::Str(literal, case_insensitive)) => grammar
+                    .ignores
+                    .push(Terminal::Literal(literal, case_insensitive)),
+                Some(Token::Regex(regex, flags)) => {
+                    grammar.ignores.push(Terminal::Regex(regex, flags))
+                }
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("Expected a terminal to ignore."));
+                }
+            },
+            "import" => {
+                let module = self.expect_name()?;
+                if module != "common" {
+                    return Err(self.error(format!(
+                        "Cannot import from `{module}`, only `common` is supported."
+                    )));
+                }
+                if self.eat("(") {
+                    loop {
+                        grammar.imports.push((self.expect_name()?, None));
+                        if !self.eat(",") {
+                            break;
+                        }
+                    }
+                    self.expect(")")?;
+                } else {
+                    self.expect(".")?;
+                    let name = self.expect_name()?;
+                    let alias = match self.eat("->") {
+                        true => Some(self.expect_name()?),
+                        false => None,
+                    };
+                    grammar.imports.push((name, alias));
+                }
+            }
+            directive => {
+                return Err(self.error(format!("The `%{directive}` directive is not supported.")))
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse alternatives. Outside of parentheses and brackets, a new line ends them unless the next line
+    /// continues them with `|`.
+    fn parse_expansions(&mut self, nested: bool) -> Result<Expr<Terminal>> {
+        let mut alternatives = vec![self.parse_alternative(nested)?];
+        loop {
+            let pos = self.pos;
+            self.skip_newlines();
+            if !self.eat("|") {
+                self.pos = pos;
+                break;
+            }
+            alternatives.push(self.parse_alternative(nested)?);
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives.remove(0),
+            _ => Expr::Alt(alternatives),
+        })
+    }
+
+    fn parse_alternative(&mut self, nested: bool) -> Result<Expr<Terminal>> {
+        let mut items = Vec::new();
+        loop {
+            if nested {
+                self.skip_newlines();
+            }
+            let item = match self.next() {
+                Some(Token::Name(name)) if is_terminal(&name) => {
+                    Expr::Terminal(Terminal::Name(name))
+                }
+                Some(Token::Name(name)) => Expr::Rule(name),
+                Some(Token::Str(start, case_insensitive)) => {
+                    if self.eat("..") {
+                        let end = match self.next() {
+                            Some(Token::Str(end, false)) => end,
+                            _ => return Err(self.error("Expected the end of the range.")),
+                        };
+                        let mut start = start.chars();
+                        let mut end = end.chars();
+                        match (start.next(), start.next(), end.next(), end.next()) {
+                            (Some(start), None, Some(end), None) => {
+                                Expr::Terminal(Terminal::Range(start, end))
+                            }
+                            _ => return Err(self.error("Ranges must be between characters.")),
+                        }
+                    } else {
+                        Expr::Terminal(Terminal::Literal(start, case_insensitive))
+                    }
+                }
+                Some(Token::Regex(regex, flags)) => Expr::Terminal(Terminal::Regex(regex, flags)),
+                Some(Token::Punct("(")) => {
+                    let expr = self.parse_expansions(true)?;
+                    self.skip_newlines();
+                    self.expect(")")?;
+                    expr
+                }
+                Some(Token::Punct("[")) => {
+                    let expr = self.parse_expansions(true)?;
+                    self.skip_newlines();
+                    self.expect("]")?;
+                    expr.repeat(0, Some(1))
+                }
+                Some(Token::Punct("->")) => {
+                    // Aliases only shape the parse tree.
+                    self.expect_name()?;
+                    break;
+                }
+                _ => {
+                    self.pos -= 1;
+                    break;
+                }
+            };
+            items.push(self.parse_operators(item)?);
+        }
+        Ok(match items.len() {
+            1 => items.remove(0),
+            _ => Expr::Seq(items),
+        })
+    }
+
+    fn parse_operators(&mut self, mut item: Expr<Terminal>) -> Result<Expr<Terminal>> {
+        loop {
+            item = if self.eat("?") {
+                item.repeat(0, Some(1))
+            } else if self.eat("*") {
+                item.repeat(0, None)
+            } else if self.eat("+") {
+                item.repeat(1, None)
+            } else if self.eat("~") {
+                let min = self.expect_number()?;
+                let max = match self.eat("..") {
+                    true => self.expect_number()?,
+                    false => min,
+                };
+                item.repeat(min, Some(max))
+            } else {
+                return Ok(item);
+            };
+        }
+    }
+
+    fn expect_number(&mut self) -> Result<usize> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(number),
+            _ => {
+                self.pos -= 1;
+                Err(self.error("Expected a number."))
+            }
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_lark_to_yacc() {
+        use super::lark_to_yacc;
+        use crate::aici::{
+            cfg::CfgParser,
+            toktree::{Recognizer, SpecialToken},
+        };
+
+        let lark = r#"
+            // Arithmetic expressions.
+            ?start: sum
+            ?sum: product
+                | sum ("+" | "-") product
+            ?product: atom | product ("*" | "/") atom
+            ?atom: NUMBER -> number
+                 | "-" atom
+                 | NAME
+                 | "(" sum ")"
+            NAME: /[a-z]+/i
+            %import common.NUMBER
+            %import common.WS_INLINE
+            %ignore WS_INLINE
+        "#;
+        let yacc = lark_to_yacc(lark).unwrap();
+        let accepts = |output: &str| {
+            let mut parser = CfgParser::from_yacc(&yacc).unwrap();
+            output.bytes().all(|b| parser.try_push_byte(b))
+                && parser.special_allowed(SpecialToken::EndOfSentence)
+        };
+
+        assert!(accepts("1 + 2*(x - 4.5e3)"));
+        assert!(accepts("-Pi / 2"));
+        assert!(!accepts("1 +"));
+        assert!(!accepts("1 2"));
+        assert!(!accepts("(1"));
+        assert!(lark_to_yacc("start: undefined").is_err());
+    }
+}
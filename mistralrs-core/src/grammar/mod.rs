@@ -0,0 +1,270 @@
+//! Front-ends which compile other grammar formats to the yacc grammars accepted by
+//! [`CfgParser`](crate::aici::cfg::CfgParser).
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+
+mod cache;
+mod gbnf;
+mod json_schema;
+mod lark;
+
+pub(crate) use cache::GrammarCache;
+pub(crate) use gbnf::gbnf_to_yacc;
+pub(crate) use json_schema::json_schema_to_yacc;
+pub(crate) use lark::lark_to_yacc;
+
+/// Write a literal string as a token of a yacc grammar.
+///
+/// Plain literal tokens take precedence over regex tokens in the lexer, so this only falls back to a
+/// regex token if the literal cannot be quoted (it contains both quote characters, a line break or
+/// non-ASCII characters, or would be read as a regex).
+pub(crate) fn yacc_literal(literal: &str) -> String {
+    let is_plain = literal.is_ascii()
+        && !literal.contains(['\n', '\r'])
+        && !(literal.len() > 2 && literal.starts_with('/') && literal.ends_with('/'));
+    if is_plain && !literal.contains('"') {
+        format!("\"{literal}\"")
+    } else if is_plain && !literal.contains('\'') {
+        format!("'{literal}'")
+    } else {
+        let escaped = literal
+            .bytes()
+            .map(|b| {
+                if b.is_ascii_alphanumeric() {
+                    (b as char).to_string()
+                } else {
+                    format!("\\x{b:02x}")
+                }
+            })
+            .collect::<String>();
+        format!("\"/{escaped}/\"")
+    }
+}
+
+/// Write a regex as a token of a yacc grammar.
+pub(crate) fn yacc_regex(regex: &str) -> String {
+    // Yacc tokens cannot contain their quote character, so quotes are written as escapes.
+    let mut escaped = String::new();
+    let mut chars = regex.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('"') => escaped.push_str(r"\x22"),
+                Some(next) => {
+                    escaped.push('\\');
+                    escaped.push(next);
+                }
+                None => escaped.push('\\'),
+            },
+            '"' => escaped.push_str(r"\x22"),
+            '\n' => escaped.push_str(r"\n"),
+            '\r' => escaped.push_str(r"\r"),
+            c => escaped.push(c),
+        }
+    }
+    format!("\"/{escaped}/\"")
+}
+
+/// An expression of a grammar with terminals of type `T`.
+#[derive(Clone, Debug)]
+pub(crate) enum Expr<T> {
+    Terminal(T),
+    Rule(String),
+    Seq(Vec<Expr<T>>),
+    Alt(Vec<Expr<T>>),
+    /// The expression repeated at least `min` and at most `max` times.
+    Repeat {
+        expr: Box<Expr<T>>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+impl<T> Expr<T> {
+    pub(crate) fn repeat(self, min: usize, max: Option<usize>) -> Self {
+        Self::Repeat {
+            expr: Box::new(self),
+            min,
+            max,
+        }
+    }
+
+    pub(crate) fn terminals<'a>(&'a self, out: &mut Vec<&'a T>) {
+        match self {
+            Self::Terminal(terminal) => out.push(terminal),
+            Self::Rule(_) => {}
+            Self::Seq(exprs) | Self::Alt(exprs) => {
+                for expr in exprs {
+                    expr.terminals(out);
+                }
+            }
+            Self::Repeat { expr, .. } => expr.terminals(out),
+        }
+    }
+
+    /// Replace each terminal by an expression.
+    pub(crate) fn try_map<U>(self, f: &mut impl FnMut(T) -> Result<Expr<U>>) -> Result<Expr<U>> {
+        Ok(match self {
+            Self::Terminal(terminal) => f(terminal)?,
+            Self::Rule(name) => Expr::Rule(name),
+            Self::Seq(exprs) => Expr::Seq(
+                exprs
+                    .into_iter()
+                    .map(|expr| expr.try_map(f))
+                    .collect::<Result<_>>()?,
+            ),
+            Self::Alt(exprs) => Expr::Alt(
+                exprs
+                    .into_iter()
+                    .map(|expr| expr.try_map(f))
+                    .collect::<Result<_>>()?,
+            ),
+            Self::Repeat { expr, min, max } => expr.try_map(f)?.repeat(min, max),
+        })
+    }
+}
+
+/// Lowers rules of [`Expr`]s, with yacc tokens as terminals, to a yacc grammar.
+pub(crate) struct YaccBuilder {
+    /// The yacc names of the rules, which may contain characters yacc does not allow.
+    names: HashMap<String, String>,
+    defined: HashSet<String>,
+    rules: Vec<(String, Vec<String>)>,
+    skip: Vec<String>,
+    n_helpers: usize,
+}
+
+impl YaccBuilder {
+    pub(crate) fn new() -> Self {
+        Self {
+            names: HashMap::new(),
+            defined: HashSet::new(),
+            rules: Vec::new(),
+            skip: Vec::new(),
+            n_helpers: 0,
+        }
+    }
+
+    fn rule_name(&mut self, name: &str) -> String {
+        let n_names = self.names.len();
+        self.names
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let name = name
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect::<String>();
+                format!("g{n_names}_{name}")
+            })
+            .clone()
+    }
+
+    pub(crate) fn add_rule(&mut self, name: &str, expr: Expr<String>) -> Result<()> {
+        if !self.defined.insert(name.to_string()) {
+            bail!("Rule `{name}` is defined more than once.");
+        }
+        let yacc_name = self.rule_name(name);
+        let alternatives = self.lower_alternatives(expr);
+        self.rules.push((yacc_name, alternatives));
+        Ok(())
+    }
+
+    /// Add a token which may appear between any tokens, such as whitespace.
+    pub(crate) fn add_skip(&mut self, token: String) {
+        self.skip.push(token);
+    }
+
+    fn helper(&mut self, alternatives: Vec<String>) -> String {
+        let name = self.helper_name();
+        self.rules.push((name.clone(), alternatives));
+        name
+    }
+
+    fn helper_name(&mut self) -> String {
+        self.n_helpers += 1;
+        format!("h{}", self.n_helpers)
+    }
+
+    fn lower_alternatives(&mut self, expr: Expr<String>) -> Vec<String> {
+        match expr {
+            Expr::Alt(exprs) => exprs
+                .into_iter()
+                .flat_map(|expr| self.lower_alternatives(expr))
+                .collect(),
+            expr => vec![self.lower(expr)],
+        }
+    }
+
+    /// Lower an expression to a sequence of symbols.
+    fn lower(&mut self, expr: Expr<String>) -> String {
+        match expr {
+            Expr::Terminal(token) => token,
+            Expr::Rule(name) => self.rule_name(&name),
+            Expr::Seq(exprs) => join_symbols(exprs.into_iter().map(|expr| self.lower(expr))),
+            Expr::Alt(_) => {
+                let alternatives = self.lower_alternatives(expr);
+                match <[String; 1]>::try_from(alternatives) {
+                    Ok([symbols]) => symbols,
+                    Err(alternatives) => self.helper(alternatives),
+                }
+            }
+            Expr::Repeat { expr, min, max } => {
+                let item = self.lower(*expr);
+                let mut symbols = vec![item.clone(); min];
+                match max {
+                    // Left recursion keeps the parse stack small.
+                    None => {
+                        let name = self.helper_name();
+                        let repeated = format!("{name} {item}");
+                        self.rules
+                            .push((name.clone(), vec![String::new(), repeated]));
+                        symbols.push(name);
+                    }
+                    Some(max) => {
+                        let mut tail = String::new();
+                        for _ in min..max {
+                            let repeated = join_symbols([item.clone(), tail]);
+                            tail = self.helper(vec![String::new(), repeated]);
+                        }
+                        symbols.push(tail);
+                    }
+                }
+                join_symbols(symbols)
+            }
+        }
+    }
+
+    pub(crate) fn finish(self, start: &str) -> Result<String> {
+        let mut undefined = self
+            .names
+            .keys()
+            .filter(|name| !self.defined.contains(*name))
+            .collect::<Vec<_>>();
+        undefined.sort();
+        if !undefined.is_empty() {
+            bail!("Undefined rules {undefined:?}.");
+        }
+        let Some(start) = self.names.get(start) else {
+            bail!("The grammar has no `{start}` rule.");
+        };
+
+        let mut out = format!("%start {start}\n%%\n");
+        if !self.skip.is_empty() {
+            out.push_str(&format!("SKIP: {} ;\n", self.skip.join(" | ")));
+        }
+        for (name, alternatives) in &self.rules {
+            out.push_str(&format!("{name}: {} ;\n", alternatives.join(" | ")));
+        }
+        Ok(out)
+    }
+}
+
+fn join_symbols(symbols: impl IntoIterator<Item = String>) -> String {
+    symbols
+        .into_iter()
+        .filter(|symbols| !symbols.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
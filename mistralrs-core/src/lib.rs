@@ -2,6 +2,7 @@
 
 use std::{
     cell::RefCell,
+    collections::HashMap,
     error::Error,
     fs::OpenOptions,
     io::Write,
@@ -15,12 +16,16 @@ use std::{
 
 use candle_core::quantized::GgmlDType;
 use engine::Engine;
+pub use engine::{PromptLookupConfig, SpeculativeConfig};
+use engine::{Proposer, Speculative};
 pub use mistralrs_lora::Ordering;
 pub use pipeline::Pipeline;
+use tracing::warn;
 
 mod aici;
 mod device_map;
 mod engine;
+mod grammar;
 mod model_loader;
 pub use model_loader::{get_tgt_non_granular_index, LoaderBuilder};
 mod model_selected;
@@ -28,29 +33,39 @@ pub use model_selected::ModelSelected;
 
 pub mod layers;
 mod models;
+mod paged_attention;
 mod pipeline;
 mod prefix_cacher;
+mod queue;
 mod request;
 mod response;
 mod sampler;
 mod scheduler;
 mod sequence;
+#[cfg(test)]
+mod test_utils;
+mod tools;
 mod utils;
 mod xlora_models;
 
 pub use device_map::{DeviceMapMetadata, LayerDeviceMapper};
+pub use paged_attention::PagedAttentionConfig;
 pub use pipeline::{
     GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder,
-    GGUFSpecificConfig, GemmaLoader, LlamaLoader, Loader, MistralLoader, MixtralLoader, ModelKind,
-    NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader,
-    Phi3Loader, Qwen2Loader, TokenSource,
+    GGUFSpecificConfig, GemmaLoader, KvCacheMetadata, LlamaLoader, Loader, MistralLoader,
+    MixtralLoader, ModelKind, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
+    NormalSpecificConfig, Phi2Loader, Phi3Loader, Qwen2Loader, TokenSource,
 };
+pub use prefix_cacher::PrefixCacheDiskConfig;
+use queue::QueueState;
+pub use queue::{QueueStatus, RequestSender};
 pub use request::{Constraint, Request, RequestMessage};
 pub use response::Response;
 pub use response::*;
-pub use sampler::{SamplingParams, StopTokens, TopLogprob};
+pub use sampler::{DrySamplingParams, Mirostat, SamplingParams, StopTokens, TopLogprob};
 pub use scheduler::SchedulerMethod;
 use serde::Serialize;
+pub use tools::{Function, Tool, ToolChoice, ToolType};
 
 /// The MistralRs struct handles sending requests to the engine.
 /// It is the core multi-threaded component of mistral.rs, and uses `mspc`
@@ -59,6 +74,10 @@ use serde::Serialize;
 pub struct MistralRs {
     sender: Sender<Request>,
     sender_isq: Sender<GgmlDType>,
+    sender_cancel: Sender<usize>,
+    queue: Arc<QueueState>,
+    max_waiting_requests: Option<usize>,
+    max_waiting_tokens: Option<usize>,
     log: Option<String>,
     id: String,
     creation_time: u64,
@@ -76,7 +95,18 @@ pub struct MistralRsBuilder {
     no_kv_cache: Option<bool>,
     no_prefix_cache: Option<bool>,
     prefix_cache_n: Option<usize>,
+    prefix_cache_device_bytes: Option<usize>,
+    prefix_cache_host_bytes: Option<usize>,
     disable_eos_stop: Option<bool>,
+    paged_attn_config: Option<PagedAttentionConfig>,
+    prefix_cache_disk_config: Option<PrefixCacheDiskConfig>,
+    grammar_cache_n: Option<usize>,
+    named_grammars: HashMap<String, Constraint>,
+    speculative: Option<Speculative>,
+    prefill_chunk_size: Option<usize>,
+    fair_share: Option<bool>,
+    max_waiting_requests: Option<usize>,
+    max_waiting_tokens: Option<usize>,
 }
 
 impl MistralRsBuilder {
@@ -89,7 +119,18 @@ impl MistralRsBuilder {
             no_kv_cache: None,
             no_prefix_cache: None,
             prefix_cache_n: None,
+            prefix_cache_device_bytes: None,
+            prefix_cache_host_bytes: None,
             disable_eos_stop: None,
+            paged_attn_config: None,
+            prefix_cache_disk_config: None,
+            grammar_cache_n: None,
+            named_grammars: HashMap::new(),
+            speculative: None,
+            prefill_chunk_size: None,
+            fair_share: None,
+            max_waiting_requests: None,
+            max_waiting_tokens: None,
         }
     }
 
@@ -117,10 +158,99 @@ impl MistralRsBuilder {
         self.prefix_cache_n = Some(prefix_cache_n);
         self
     }
+    /// Limit the total size of the prefix caches on the device. The least recently used ones are moved to the CPU first.
+    pub fn with_prefix_cache_device_bytes(mut self, prefix_cache_device_bytes: usize) -> Self {
+        self.prefix_cache_device_bytes = Some(prefix_cache_device_bytes);
+        self
+    }
+    /// Limit the total size of the prefix caches on the CPU, 4 GB by default. The least recently used ones are dropped first.
+    pub fn with_prefix_cache_host_bytes(mut self, prefix_cache_host_bytes: usize) -> Self {
+        self.prefix_cache_host_bytes = Some(prefix_cache_host_bytes);
+        self
+    }
     pub fn with_disable_eos_stop(mut self, disable_eos_stop: bool) -> Self {
         self.disable_eos_stop = Some(disable_eos_stop);
         self
     }
+    /// Store the KV cache in fixed-size blocks which are allocated to sequences on demand.
+    /// This disables the prefix cache, and is not supported for X-LoRA models.
+    pub fn with_paged_attn(mut self, paged_attn_config: PagedAttentionConfig) -> Self {
+        self.paged_attn_config = Some(paged_attn_config);
+        self
+    }
+    /// Also write the prefix caches which are evicted from the device to a directory, and reuse them across restarts.
+    pub fn with_prefix_cache_disk(
+        mut self,
+        prefix_cache_disk_config: PrefixCacheDiskConfig,
+    ) -> Self {
+        self.prefix_cache_disk_config = Some(prefix_cache_disk_config);
+        self
+    }
+    /// Number of compiled grammars to cache, 32 by default. The least recently used ones are dropped first.
+    pub fn with_grammar_cache_n(mut self, grammar_cache_n: usize) -> Self {
+        self.grammar_cache_n = Some(grammar_cache_n);
+        self
+    }
+    /// Grammars which are compiled when the engine starts, and used by requests with [`Constraint::Named`].
+    pub fn with_named_grammars(mut self, named_grammars: HashMap<String, Constraint>) -> Self {
+        self.named_grammars = named_grammars;
+        self
+    }
+
+    /// Use speculative decoding, where the draft model proposes tokens which the model verifies in a single
+    /// forward pass. The draft model must use the same tokenizer, and is usually a smaller or quantized model
+    /// of the same family. This is not supported with paged attention, without the KV cache, or for X-LoRA models.
+    pub fn with_speculative(
+        mut self,
+        draft: Box<Mutex<dyn Pipeline>>,
+        speculative_config: SpeculativeConfig,
+    ) -> Self {
+        self.speculative = Some(Speculative {
+            proposer: Proposer::Draft(draft),
+            gamma: speculative_config.gamma,
+        });
+        self
+    }
+    /// Use prompt lookup decoding, a speculative decoding where the proposed tokens are copied from the prompt
+    /// instead of a draft model. This works well when the output repeats parts of the prompt, as with
+    /// summarization or code editing. It replaces [`MistralRsBuilder::with_speculative`], and has the same limitations.
+    pub fn with_prompt_lookup(mut self, prompt_lookup_config: PromptLookupConfig) -> Self {
+        self.speculative = Some(Speculative {
+            proposer: Proposer::PromptLookup {
+                max_ngram: prompt_lookup_config.max_ngram,
+            },
+            gamma: prompt_lookup_config.gamma,
+        });
+        self
+    }
+
+    /// Run prompts in chunks of at most this many tokens, one chunk per engine step, so that decoding
+    /// continues while a long prompt is processed. Prompts are then only batched with prompts of the same
+    /// length. This is not supported with paged attention, without the KV cache, or for X-LoRA models.
+    pub fn with_prefill_chunk_size(mut self, prefill_chunk_size: usize) -> Self {
+        self.prefill_chunk_size = Some(prefill_chunk_size);
+        self
+    }
+
+    /// Admit the waiting requests of the same priority from each tenant in turn, starting with the tenants
+    /// with the fewest running sequences. Requests without a tenant are each their own tenant.
+    pub fn with_fair_share(mut self, fair_share: bool) -> Self {
+        self.fair_share = Some(fair_share);
+        self
+    }
+
+    /// Reject the requests sent with [`MistralRs::try_send_request`] while this many requests are waiting.
+    pub fn with_max_waiting_requests(mut self, max_waiting_requests: usize) -> Self {
+        self.max_waiting_requests = Some(max_waiting_requests);
+        self
+    }
+
+    /// Reject the requests sent with [`MistralRs::try_send_request`] while the waiting requests have this
+    /// many tokens to run.
+    pub fn with_max_waiting_tokens(mut self, max_waiting_tokens: usize) -> Self {
+        self.max_waiting_tokens = Some(max_waiting_tokens);
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -137,21 +267,100 @@ impl MistralRs {
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
+            prefix_cache_device_bytes,
+            prefix_cache_host_bytes,
             disable_eos_stop,
+            paged_attn_config,
+            prefix_cache_disk_config,
+            grammar_cache_n,
+            named_grammars,
+            speculative,
+            prefill_chunk_size,
+            fair_share,
+            max_waiting_requests,
+            max_waiting_tokens,
         } = config;
 
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
         let no_prefix_cache = no_prefix_cache.unwrap_or(false);
         let prefix_cache_n = prefix_cache_n.unwrap_or(16);
+        let prefix_cache_host_bytes = prefix_cache_host_bytes.unwrap_or(4 * 1024 * 1024 * 1024);
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
+        let grammar_cache_n = grammar_cache_n.unwrap_or(32);
+        let fair_share = fair_share.unwrap_or(false);
+        let paged_attn_config = match paged_attn_config {
+            Some(_) if no_kv_cache => {
+                warn!("Paged attention requires the KV cache, disabling it.");
+                None
+            }
+            Some(_) if pipeline.lock().unwrap().is_xlora() => {
+                warn!("Paged attention is not supported for X-LoRA models, disabling it.");
+                None
+            }
+            config => config,
+        };
+        let speculative = match speculative {
+            Some(_) if no_kv_cache || paged_attn_config.is_some() => {
+                warn!("Speculative decoding requires the KV cache without paged attention, disabling it.");
+                None
+            }
+            Some(_) if pipeline.lock().unwrap().is_xlora() => {
+                warn!("Speculative decoding is not supported for X-LoRA models, disabling it.");
+                None
+            }
+            Some(Speculative {
+                proposer: Proposer::Draft(ref draft),
+                ..
+            }) if draft.lock().unwrap().is_xlora() => {
+                warn!("Speculative decoding is not supported for X-LoRA models, disabling it.");
+                None
+            }
+            Some(Speculative {
+                proposer: Proposer::Draft(ref draft),
+                ..
+            }) if draft.lock().unwrap().tokenizer().get_vocab_size(true)
+                != pipeline.lock().unwrap().tokenizer().get_vocab_size(true) =>
+            {
+                warn!("The draft model does not have the same vocabulary as the model, disabling speculative decoding.");
+                None
+            }
+            Some(Speculative { gamma: 0, .. }) => None,
+            speculative => speculative,
+        };
+        let prefill_chunk_size = match prefill_chunk_size {
+            Some(_) if no_kv_cache || paged_attn_config.is_some() => {
+                warn!(
+                    "Chunked prefill requires the KV cache without paged attention, disabling it."
+                );
+                None
+            }
+            Some(_) if pipeline.lock().unwrap().is_xlora() => {
+                warn!("Chunked prefill is not supported for X-LoRA models, disabling it.");
+                None
+            }
+            Some(0) => None,
+            prefill_chunk_size => prefill_chunk_size,
+        };
+        // Paged attention gathers the keys and values of each sequence, which must have the same length.
+        // With chunked prefill, the prompts of a batch must finish their last chunk in the same step, so
+        // they are bucketed by their length and cached prefix instead of being batched with padding.
+        let ragged_batches = pipeline.lock().unwrap().supports_ragged_batches()
+            && paged_attn_config.is_none()
+            && prefill_chunk_size.is_none();
 
         let (tx, rx) = channel();
         let (isq_tx, isq_rx) = channel();
+        let (cancel_tx, cancel_rx) = channel();
+        let queue = Arc::new(QueueState::new(pipeline.lock().unwrap().tokenizer()));
 
         let this = Arc::new(Self {
             sender: tx,
             sender_isq: isq_tx,
+            sender_cancel: cancel_tx,
+            queue: queue.clone(),
+            max_waiting_requests,
+            max_waiting_tokens,
             log,
             id: pipeline.lock().unwrap().name(),
             creation_time: SystemTime::now()
@@ -165,13 +374,25 @@ impl MistralRs {
             let mut engine = Engine::new(
                 rx,
                 isq_rx,
+                cancel_rx,
+                queue,
                 pipeline,
                 method,
                 truncate_sequence,
                 no_kv_cache,
                 no_prefix_cache,
                 prefix_cache_n,
+                prefix_cache_device_bytes,
+                prefix_cache_host_bytes,
                 disable_eos_stop,
+                paged_attn_config,
+                prefix_cache_disk_config,
+                grammar_cache_n,
+                named_grammars,
+                speculative,
+                prefill_chunk_size,
+                fair_share,
+                ragged_batches,
             );
             engine.run();
         });
@@ -179,8 +400,25 @@ impl MistralRs {
         this
     }
 
-    pub fn get_sender(&self) -> Sender<Request> {
-        self.sender.clone()
+    pub fn get_sender(&self) -> RequestSender {
+        RequestSender::new(self.sender.clone(), self.queue.clone())
+    }
+
+    /// The requests which are waiting to be scheduled.
+    pub fn queue_status(&self) -> QueueStatus {
+        self.queue.status()
+    }
+
+    /// Send a request unless the waiting requests are over the limits set with
+    /// [`MistralRsBuilder::with_max_waiting_requests`] or [`MistralRsBuilder::with_max_waiting_tokens`].
+    /// If they are, the request is dropped and the status of the queue is returned.
+    pub fn try_send_request(&self, request: Request) -> Result<(), QueueStatus> {
+        self.queue
+            .try_reserve(&request, self.max_waiting_requests, self.max_waiting_tokens)?;
+        self.get_sender()
+            .send_reserved(request)
+            .expect("Engine is not present.");
+        Ok(())
     }
 
     /// Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML
@@ -189,6 +427,12 @@ impl MistralRs {
         self.sender_isq.send(dtype).expect("Engine is not present.")
     }
 
+    /// Cancel a request by its ID. Its sequences are removed from the engine and their
+    /// KV cache is freed; no further responses will be sent for it.
+    pub fn cancel_request(&self, id: usize) {
+        self.sender_cancel.send(id).expect("Engine is not present.")
+    }
+
     pub fn get_id(&self) -> String {
         self.id.clone()
     }
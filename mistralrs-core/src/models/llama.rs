@@ -10,6 +10,7 @@ use std::{collections::HashMap, sync::Arc};
 use crate::{
     device_map::DeviceMapper,
     layers::RmsNorm,
+    paged_attention::PagedLayerCache,
     pipeline::{extract_logits, NormalModel},
     DeviceMapMetadata,
 };
@@ -43,17 +44,23 @@ impl Cache {
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            mask.to_device(device)
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+    /// The causal mask for `t` new tokens which follow `seqlen_offset` cached tokens. Only the masks of
+    /// whole prompts are cached: the masks of prompt chunks are built each time, as there is one for
+    /// every offset.
+    fn mask(&mut self, t: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
+        if seqlen_offset == 0 {
+            if let Some(mask) = self.masks.get(&t) {
+                return mask.to_device(device);
+            }
+        }
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| (0..t + seqlen_offset).map(move |j| u8::from(j > i + seqlen_offset)))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, t + seqlen_offset), device)?;
+        if seqlen_offset == 0 {
             self.masks.insert(t, mask.clone());
-            Ok(mask)
         }
+        Ok(mask)
     }
 }
 
@@ -71,6 +78,7 @@ struct CausalSelfAttention {
 }
 
 impl CausalSelfAttention {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         x: &Tensor,
@@ -78,7 +86,9 @@ impl CausalSelfAttention {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut super::LayerCaches,
+        paged_cache: Option<PagedLayerCache<'_>>,
         cache: &mut Cache,
+        ragged_mask: Option<&Tensor>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, hidden_size) = x.dims3()?;
 
@@ -116,47 +126,66 @@ impl CausalSelfAttention {
                 .contiguous()?;
         }
 
-        if let Some((cache_k, cache_v)) = &kv_cache[block_idx] {
-            k = candle_nn::ops::kvconcat(cache_k, &k, 2)?.contiguous()?;
-            v = candle_nn::ops::kvconcat(cache_v, &v, 2)?.contiguous()?;
-            let k_seq_len = k.dims()[1];
-            if k_seq_len > MAX_SEQ_LEN {
-                k = k
-                    .narrow(D::Minus1, k_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
-                    .contiguous()?
-            }
-            let v_seq_len = v.dims()[1];
-            if v_seq_len > 2 * MAX_SEQ_LEN {
-                v = v
-                    .narrow(D::Minus1, v_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
-                    .contiguous()?
+        let mut softmax = |att: Tensor| -> Result<Tensor> {
+            let att = att.to_dtype(DType::F32)?;
+            let att = if let Some(mask) = ragged_mask {
+                let mask = mask.to_device(att.device())?.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+            } else if seq_len > 1 {
+                let mask = cache
+                    .mask(seq_len, seqlen_offsets[0], att.device())?
+                    .broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+            } else {
+                att
+            };
+            candle_nn::ops::softmax(&att, D::Minus1)
+        };
+
+        let mut y = if let Some(paged_cache) = paged_cache {
+            let scale = 1f64 / (self.head_dim as f64).sqrt();
+            paged_cache.attention(&q, &k, &v, scale, softmax)?
+        } else {
+            if let Some((cache_k, cache_v)) = &kv_cache[block_idx] {
+                k = candle_nn::ops::kvconcat(cache_k, &k, 2)?.contiguous()?;
+                v = candle_nn::ops::kvconcat(cache_v, &v, 2)?.contiguous()?;
+                let k_seq_len = k.dims()[1];
+                if k_seq_len > MAX_SEQ_LEN {
+                    k = k
+                        .narrow(D::Minus1, k_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
+                        .contiguous()?
+                }
+                let v_seq_len = v.dims()[1];
+                if v_seq_len > 2 * MAX_SEQ_LEN {
+                    v = v
+                        .narrow(D::Minus1, v_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
+                        .contiguous()?
+                }
             }
-        }
-        kv_cache[block_idx] = Some((k.clone(), v.clone()));
+            kv_cache[block_idx] = Some((k.clone(), v.clone()));
 
-        let k = repeat_kv(k, self.num_attention_heads / self.num_key_value_heads)?.contiguous()?;
-        let v = repeat_kv(v, self.num_attention_heads / self.num_key_value_heads)?.contiguous()?;
+            let k =
+                repeat_kv(k, self.num_attention_heads / self.num_key_value_heads)?.contiguous()?;
+            let v =
+                repeat_kv(v, self.num_attention_heads / self.num_key_value_heads)?.contiguous()?;
 
-        let mut y = if self.use_flash_attn {
-            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
-            let q = q.transpose(1, 2)?;
-            let k = k.transpose(1, 2)?;
-            let v = v.transpose(1, 2)?;
-            let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
-            flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?.transpose(1, 2)?
-        } else {
-            let in_dtype = q.dtype();
-            let q = q.to_dtype(DType::F32)?;
-            let k = k.to_dtype(DType::F32)?;
-            let v = v.to_dtype(DType::F32)?;
-            let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-            let mask = cache
-                .mask(seq_len, att.device())?
-                .broadcast_as(att.shape())?;
-            let att = masked_fill(&att, &mask, f32::NEG_INFINITY)?;
-            let att = candle_nn::ops::softmax(&att, D::Minus1)?;
-            // Convert to contiguous as matmul doesn't support strided vs for now.
-            att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
+            if self.use_flash_attn {
+                // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+                let q = q.transpose(1, 2)?;
+                let k = k.transpose(1, 2)?;
+                let v = v.transpose(1, 2)?;
+                let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+                flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?.transpose(1, 2)?
+            } else {
+                let in_dtype = q.dtype();
+                let q = q.to_dtype(DType::F32)?;
+                let k = k.to_dtype(DType::F32)?;
+                let v = v.to_dtype(DType::F32)?;
+                let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+                let att = softmax(att)?;
+                // Convert to contiguous as matmul doesn't support strided vs for now.
+                att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
+            }
         };
         if matches!(self.q_proj, QMatMul::QTensor(_)) {
             y = y.to_dtype(DType::F32)?;
@@ -252,6 +281,7 @@ struct Block {
 }
 
 impl Block {
+    #[allow(clippy::too_many_arguments)]
     fn forward(
         &self,
         x: &Tensor,
@@ -259,7 +289,9 @@ impl Block {
         start_offsets_kernel: Tensor,
         block_idx: usize,
         kv_cache: &mut super::LayerCaches,
+        paged_cache: Option<PagedLayerCache<'_>>,
         cache: &mut Cache,
+        ragged_mask: Option<&Tensor>,
     ) -> Result<Tensor> {
         let residual = x;
         let x = self.rms_1.forward(x)?;
@@ -269,7 +301,9 @@ impl Block {
             start_offsets_kernel,
             block_idx,
             kv_cache,
+            paged_cache,
             cache,
+            ragged_mask,
         )? + residual)?;
         let residual = &x;
         let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
@@ -312,10 +346,13 @@ impl Llama {
         x: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
+        let seq_len = x.dims2()?.1;
+        let ragged_mask = self.kv_cache.ragged_mask(seq_len, None, &self.device)?;
         let mut x = self.wte.forward(x)?;
         let mut cache = self.kv_cache.lock();
+        let mut paged_cache = self.kv_cache.paged_lock();
         for (block_idx, block) in self.blocks.iter().enumerate() {
             x = self.mapper.map(x, block_idx)?;
             x = block.forward(
@@ -324,7 +361,9 @@ impl Llama {
                 start_offsets_kernel.clone(),
                 block_idx,
                 &mut cache,
+                paged_cache.as_mut().map(|c| c.layer(block_idx)),
                 &mut self.cache,
+                ragged_mask.as_ref(),
             )?;
         }
         let x = x.to_device(&self.device)?;
@@ -377,7 +416,7 @@ impl NormalModel for Llama {
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -396,7 +435,7 @@ impl NormalModel for Llama {
         _start_offsets_kernel_full: Tensor,
         _no_kv_cache: bool,
         _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
-        _context_lens: Vec<usize>,
+        _context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
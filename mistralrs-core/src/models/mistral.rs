@@ -1,18 +1,19 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-use candle_core::{quantized::QMatMul, DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor, D};
 use candle_nn::{linear_no_bias, Activation, RotaryEmbedding, VarBuilder};
 use std::sync::Arc;
 
 use crate::{
     device_map::DeviceMapper,
     layers::RmsNorm,
+    paged_attention::PagedLayerCache,
     pipeline::{extract_logits, NormalModel},
     DeviceMapMetadata,
 };
 
-use super::{flash_attn, repeat_kv, Cache};
+use super::{additive_mask, flash_attn, repeat_kv, Cache};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Config {
@@ -120,6 +121,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        paged_cache: Option<PagedLayerCache<'_>>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
 
@@ -157,36 +159,42 @@ impl Attention {
                 .contiguous()?;
         }
 
-        let (k, v) = match &*kv_cache {
-            None => (k, v),
-            Some((prev_k, prev_v)) => {
-                let k = candle_nn::ops::kvconcat(prev_k, &k, 2)?;
-                let v = candle_nn::ops::kvconcat(prev_v, &v, 2)?;
-                (k, v)
-            }
-        };
-        *kv_cache = Some((k.clone(), v.clone()));
-
-        let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
-        let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
-
-        let mut attn_output = if self.use_flash_attn {
-            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
-            let q = q.transpose(1, 2)?;
-            let k = k.transpose(1, 2)?;
-            let v = v.transpose(1, 2)?;
-            let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
-            flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
-        } else {
-            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
-            let attn_weights = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
-
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let softmax = |attn_weights: Tensor| -> Result<Tensor> {
             let attn_weights = match attention_mask {
                 None => attn_weights,
                 Some(mask) => attn_weights.broadcast_add(mask)?,
             };
-            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
-            attn_weights.matmul(&v)?
+            candle_nn::ops::softmax_last_dim(&attn_weights)
+        };
+        let mut attn_output = match paged_cache {
+            Some(paged_cache) => paged_cache.attention(&q, &k, &v, scale, softmax)?,
+            None => {
+                let (k, v) = match &*kv_cache {
+                    None => (k, v),
+                    Some((prev_k, prev_v)) => {
+                        let k = candle_nn::ops::kvconcat(prev_k, &k, 2)?;
+                        let v = candle_nn::ops::kvconcat(prev_v, &v, 2)?;
+                        (k, v)
+                    }
+                };
+                *kv_cache = Some((k.clone(), v.clone()));
+
+                let k = repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+                let v = repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+                if self.use_flash_attn {
+                    // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+                    let q = q.transpose(1, 2)?;
+                    let k = k.transpose(1, 2)?;
+                    let v = v.transpose(1, 2)?;
+                    let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+                    flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+                } else {
+                    let attn_weights = (q.matmul(&k.transpose(2, 3)?)? * scale)?;
+                    softmax(attn_weights)?.matmul(&v)?
+                }
+            }
         };
         if matches!(self.q_proj, QMatMul::QTensor(_)) {
             attn_output = attn_output.to_dtype(DType::F32)?;
@@ -236,6 +244,7 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        paged_cache: Option<PagedLayerCache<'_>>,
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = self.input_layernorm.forward(xs)?;
@@ -245,6 +254,7 @@ impl DecoderLayer {
             seqlen_offsets,
             start_offsets_kernel,
             kv_cache,
+            paged_cache,
         )?;
         let xs = (xs + residual)?;
         let residual = &xs;
@@ -343,44 +353,32 @@ impl Model {
             .to_dtype(self.dtype)
     }
 
-    fn calculate_past_kv_len(&mut self, seq_len: usize) -> Result<usize> {
-        let cache = self.cache.lock();
-        let kv_cache_1 = cache.first().unwrap();
-        if kv_cache_1.is_none() {
-            return Ok(0);
-        }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        if k_cache_1.dims()[0] <= seq_len {
-            Ok(0)
-        } else {
-            let indexed = k_cache_1.i(seq_len)?;
-            let dims = indexed.dims();
-            Ok(dims[dims.len() - 2])
-        }
-    }
-
     pub fn forward(
         &mut self,
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         let (b_size, seq_len) = input_ids.dims2()?;
         if seqlen_offsets.len() > b_size {
             candle_core::bail!("Expected seqlen offsets have length equal to batch size.")
         }
 
-        let past_key_values_length = self.calculate_past_kv_len(seq_len)?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
-            let mask =
-                self.prepare_decoder_attention_mask(b_size, seq_len, past_key_values_length)?;
-            Some(mask)
-        };
+        let attention_mask =
+            match self
+                .cache
+                .ragged_mask(seq_len, self.sliding_window, &self.device)?
+            {
+                Some(mask) => Some(additive_mask(&mask, self.dtype)?),
+                None if seq_len <= 1 => None,
+                None => {
+                    Some(self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offsets[0])?)
+                }
+            };
         let mut xs = self.embed_tokens.forward(input_ids)?;
         let mut cache = self.cache.lock();
+        let mut paged_cache = self.cache.paged_lock();
         for (i, layer) in self.layers.iter_mut().enumerate() {
             xs = self.mapper.map(xs, i)?;
             xs = layer.forward(
@@ -392,6 +390,7 @@ impl Model {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                paged_cache.as_mut().map(|c| c.layer(i)),
             )?;
         }
         let xs = xs.to_device(&self.device)?;
@@ -409,7 +408,7 @@ impl NormalModel for Model {
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -428,7 +427,7 @@ impl NormalModel for Model {
         _start_offsets_kernel_full: Tensor,
         _no_kv_cache: bool,
         _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
-        _context_lens: Vec<usize>,
+        _context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
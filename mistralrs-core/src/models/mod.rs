@@ -1,8 +1,8 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use candle_core::{Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor};
 
-use crate::get_mut_arcmutex;
+use crate::{get_mut_arcmutex, paged_attention::PagedKvCache};
 
 pub(crate) mod gemma;
 pub(crate) mod llama;
@@ -21,6 +21,8 @@ pub struct Cache {
     cache: Arc<Mutex<LayerCaches>>,
     xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    paged_cache: Arc<Mutex<Option<PagedKvCache>>>,
+    padding: Arc<Mutex<Vec<usize>>>,
 }
 
 impl Cache {
@@ -37,6 +39,8 @@ impl Cache {
             } else {
                 None
             },
+            paged_cache: Arc::new(Mutex::new(None)),
+            padding: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
@@ -62,6 +66,61 @@ impl Cache {
     pub(crate) fn is_xlora(&self) -> bool {
         self.xlora_cache.is_some()
     }
+
+    /// The paged KV cache, if it is enabled. If so, the models read and write their keys and
+    /// values through it and the layer caches are unused.
+    pub(crate) fn paged_lock(&self) -> MutexGuard<'_, Option<PagedKvCache>> {
+        get_mut_arcmutex!(self.paged_cache)
+    }
+
+    /// The number of padding positions at the start of the KV cache of each sequence in the batch, when
+    /// sequences with KV caches of different lengths run together. Empty if the KV caches are not padded.
+    pub(crate) fn padding(&self) -> MutexGuard<'_, Vec<usize>> {
+        get_mut_arcmutex!(self.padding)
+    }
+
+    /// The mask for `tgt_len` new tokens of a batch with padded KV caches, of shape
+    /// `(batch, 1, tgt_len, kv_len)` with 1 at the masked positions: the padding, the positions after each
+    /// token, and the positions outside of the sliding window. `None` if the KV caches are not padded.
+    pub(crate) fn ragged_mask(
+        &self,
+        tgt_len: usize,
+        sliding_window: Option<usize>,
+        device: &Device,
+    ) -> Result<Option<Tensor>> {
+        let padding = self.padding().clone();
+        if padding.is_empty() {
+            return Ok(None);
+        }
+        let cache_len = self.lock()[0].as_ref().map_or(0, |(k, _)| k.dims()[2]);
+        let sliding_window = sliding_window.unwrap_or(cache_len + tgt_len);
+        let mask: Vec<u8> = padding
+            .iter()
+            .flat_map(|pad| {
+                (0..tgt_len).flat_map(move |i| {
+                    (0..cache_len + tgt_len).map(move |j| {
+                        u8::from(
+                            j < *pad || j > cache_len + i || j + sliding_window < cache_len + i,
+                        )
+                    })
+                })
+            })
+            .collect();
+        Ok(Some(Tensor::from_slice(
+            &mask,
+            (padding.len(), 1, tgt_len, cache_len + tgt_len),
+            device,
+        )?))
+    }
+}
+
+/// Convert a mask with 1 at the masked positions into one which is added to the attention weights.
+pub(crate) fn additive_mask(mask: &Tensor, dtype: DType) -> Result<Tensor> {
+    let zeros = Tensor::zeros(mask.shape(), dtype, mask.device())?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, mask.device())?
+        .to_dtype(dtype)?
+        .broadcast_as(mask.shape())?;
+    mask.where_cond(&neg_inf, &zeros)
 }
 
 #[cfg(feature = "flash-attn")]
@@ -14,6 +14,7 @@ use serde::Deserialize;
 
 use crate::{
     device_map::DeviceMapper,
+    paged_attention::PagedLayerCache,
     pipeline::{extract_logits, NormalModel},
     DeviceMapMetadata,
 };
@@ -102,11 +103,12 @@ struct Attention {
     use_flash_attn: bool,
 }
 
-fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
+/// The causal mask for `size` new tokens which follow `seqlen_offset` cached tokens.
+fn get_mask(size: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
     let mask: Vec<_> = (0..size)
-        .flat_map(|i| (0..size).map(move |j| u8::from(j > i)))
+        .flat_map(|i| (0..size + seqlen_offset).map(move |j| u8::from(j > i + seqlen_offset)))
         .collect();
-    Tensor::from_slice(&mask, (size, size), device)
+    Tensor::from_slice(&mask, (size, size + seqlen_offset), device)
 }
 
 fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
@@ -166,6 +168,7 @@ impl Attention {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        paged_cache: Option<PagedLayerCache<'_>>,
     ) -> Result<Tensor> {
         let (b_size, seq_len, _n_embd) = xs.dims3()?;
 
@@ -217,42 +220,50 @@ impl Attention {
                 .contiguous()?;
         }
 
-        let (k, v) = match &*kv_cache {
-            None => (k, v),
-            Some((prev_k, prev_v)) => {
-                let k = candle_nn::ops::kvconcat(prev_k, &k, 2)?;
-                let v = candle_nn::ops::kvconcat(prev_v, &v, 2)?;
-                (k, v)
-            }
-        };
-        *kv_cache = Some((k.clone(), v.clone()));
-
-        let k = repeat_kv(k, self.num_heads / self.num_kv_heads)?.contiguous()?;
-        let v = repeat_kv(v, self.num_heads / self.num_kv_heads)?.contiguous()?;
-
-        let mut attn_output = if self.use_flash_attn {
-            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
-            let q = q.transpose(1, 2)?;
-            let k = k.transpose(1, 2)?;
-            let v = v.transpose(1, 2)?;
-            flash_attn(&q, &k, &v, self.softmax_scale as f32, seq_len > 1)?.transpose(1, 2)?
-        } else {
-            let attn_weights = (q
-                .to_dtype(DType::F32)?
-                .contiguous()?
-                .matmul(&k.to_dtype(DType::F32)?.t()?)?
-                * self.softmax_scale)?;
+        let softmax = |attn_weights: Tensor| -> Result<Tensor> {
+            let attn_weights = attn_weights.to_dtype(DType::F32)?;
             let attn_weights = match mask {
                 None => attn_weights,
                 Some(mask) => masked_fill(
                     &attn_weights,
-                    &mask.broadcast_left((b_size, self.num_heads))?,
+                    &mask.broadcast_as(attn_weights.shape())?,
                     f32::NEG_INFINITY,
                 )?,
             };
-            let attn_weights =
-                candle_nn::ops::softmax_last_dim(&attn_weights)?.to_dtype(v.dtype())?;
-            attn_weights.matmul(&v)?
+            candle_nn::ops::softmax_last_dim(&attn_weights)
+        };
+        let mut attn_output = match paged_cache {
+            Some(paged_cache) => paged_cache.attention(&q, &k, &v, self.softmax_scale, softmax)?,
+            None => {
+                let (k, v) = match &*kv_cache {
+                    None => (k, v),
+                    Some((prev_k, prev_v)) => {
+                        let k = candle_nn::ops::kvconcat(prev_k, &k, 2)?;
+                        let v = candle_nn::ops::kvconcat(prev_v, &v, 2)?;
+                        (k, v)
+                    }
+                };
+                *kv_cache = Some((k.clone(), v.clone()));
+
+                let k = repeat_kv(k, self.num_heads / self.num_kv_heads)?.contiguous()?;
+                let v = repeat_kv(v, self.num_heads / self.num_kv_heads)?.contiguous()?;
+
+                if self.use_flash_attn {
+                    // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+                    let q = q.transpose(1, 2)?;
+                    let k = k.transpose(1, 2)?;
+                    let v = v.transpose(1, 2)?;
+                    flash_attn(&q, &k, &v, self.softmax_scale as f32, seq_len > 1)?
+                        .transpose(1, 2)?
+                } else {
+                    let attn_weights = (q
+                        .to_dtype(DType::F32)?
+                        .contiguous()?
+                        .matmul(&k.to_dtype(DType::F32)?.t()?)?
+                        * self.softmax_scale)?;
+                    softmax(attn_weights)?.to_dtype(v.dtype())?.matmul(&v)?
+                }
+            }
         };
         if self.q_proj.is_quant() {
             attn_output = attn_output.to_dtype(DType::F32)?;
@@ -298,12 +309,18 @@ impl DecoderLayer {
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        paged_cache: Option<PagedLayerCache<'_>>,
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = xs.apply(&self.input_layernorm)?;
-        let attn_outputs =
-            self.self_attn
-                .forward(&xs, mask, seqlen_offsets, start_offsets_kernel, kv_cache)?;
+        let attn_outputs = self.self_attn.forward(
+            &xs,
+            mask,
+            seqlen_offsets,
+            start_offsets_kernel,
+            kv_cache,
+            paged_cache,
+        )?;
         let feed_forward_hidden_states = self.mlp.forward(&xs)?;
         attn_outputs + feed_forward_hidden_states + residual
     }
@@ -363,16 +380,16 @@ impl Model {
         xs: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         let (_b_size, seq_len) = xs.dims2()?;
         let mut xs = xs.apply(&self.embed_tokens)?;
-        let mask = if seq_len <= 1 {
-            None
-        } else {
-            Some(get_mask(seq_len, xs.device())?)
+        let mask = match self.cache.ragged_mask(seq_len, None, xs.device())? {
+            None if seq_len > 1 => Some(get_mask(seq_len, seqlen_offsets[0], xs.device())?),
+            mask => mask,
         };
         let mut cache = self.cache.lock();
+        let mut paged_cache = self.cache.paged_lock();
         for (i, layer) in self.layers.iter_mut().enumerate() {
             xs = self.mapper.map(xs, i)?;
             xs = layer.forward(
@@ -383,6 +400,7 @@ impl Model {
                 seqlen_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                paged_cache.as_mut().map(|c| c.layer(i)),
             )?;
         }
         let xs = xs.to_device(&self.device)?;
@@ -400,7 +418,7 @@ impl NormalModel for Model {
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -419,7 +437,7 @@ impl NormalModel for Model {
         _start_offsets_kernel_full: Tensor,
         _no_kv_cache: bool,
         _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
-        _context_lens: Vec<usize>,
+        _context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         unimplemented!()
     }
@@ -9,6 +9,7 @@ use candle_nn::{Embedding, Module, RotaryEmbedding};
 
 use crate::device_map::DeviceMapper;
 use crate::layers::QRmsNorm;
+use crate::paged_attention::PagedLayerCache;
 use crate::pipeline::extract_logits;
 use crate::DeviceMapMetadata;
 
@@ -142,6 +143,7 @@ impl LayerWeights {
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        paged_cache: Option<PagedLayerCache<'_>>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
         let q = self.attention_wq.forward(x)?;
@@ -167,30 +169,42 @@ impl LayerWeights {
                 .transpose(1, 2)?;
         }
 
-        let (k, v) = match &*kv_cache {
-            None => (k, v),
-            Some((k_cache, v_cache)) => {
-                let k = candle_nn::ops::kvconcat(k_cache, &k, 2)?.contiguous()?;
-                let v = candle_nn::ops::kvconcat(v_cache, &v, 2)?.contiguous()?;
-                (k, v)
-            }
+        let softmax = |att: Tensor| -> Result<Tensor> {
+            let att = match mask {
+                None => att,
+                Some(mask) => {
+                    let mask = mask.broadcast_as(att.shape())?;
+                    masked_fill(&att, &mask, &self.neg_inf)?
+                }
+            };
+            candle_nn::ops::softmax_last_dim(&att)
         };
-        *kv_cache = Some((k.clone(), v.clone()));
+        let y = match paged_cache {
+            Some(paged_cache) => {
+                let scale = 1. / (self.head_dim as f64).sqrt();
+                paged_cache.attention(&q, &k, &v, scale, softmax)?
+            }
+            None => {
+                let (k, v) = match &*kv_cache {
+                    None => (k, v),
+                    Some((k_cache, v_cache)) => {
+                        let k = candle_nn::ops::kvconcat(k_cache, &k, 2)?.contiguous()?;
+                        let v = candle_nn::ops::kvconcat(v_cache, &v, 2)?.contiguous()?;
+                        (k, v)
+                    }
+                };
+                *kv_cache = Some((k.clone(), v.clone()));
 
-        let k = repeat_kv(k, self.n_head / self.n_kv_head)?.contiguous()?;
-        let v = repeat_kv(v, self.n_head / self.n_kv_head)?.contiguous()?;
+                let k = repeat_kv(k, self.n_head / self.n_kv_head)?.contiguous()?;
+                let v = repeat_kv(v, self.n_head / self.n_kv_head)?.contiguous()?;
 
-        let att = (q.contiguous()?.matmul(&k.t()?.contiguous()?)? / (self.head_dim as f64).sqrt())?;
-        let att = match mask {
-            None => att,
-            Some(mask) => {
-                let mask = mask.broadcast_as(att.shape())?;
-                masked_fill(&att, &mask, &self.neg_inf)?
+                let att = (q.contiguous()?.matmul(&k.t()?.contiguous()?)?
+                    / (self.head_dim as f64).sqrt())?;
+                let att = softmax(att)?;
+                // Convert to contiguous as matmul doesn't support strided vs for now.
+                att.matmul(&v.contiguous()?)?
             }
         };
-        let att = candle_nn::ops::softmax_last_dim(&att)?;
-        // Convert to contiguous as matmul doesn't support strided vs for now.
-        let y = att.matmul(&v.contiguous()?)?;
         let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
         let y = self.attention_wo.forward(&y)?;
         Ok(y)
@@ -404,17 +418,23 @@ impl ModelWeights {
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+    /// The causal mask for `t` new tokens which follow `seqlen_offset` cached tokens. Only the masks of
+    /// whole prompts are cached: the masks of prompt chunks are built each time, as there is one for
+    /// every offset.
+    fn mask(&mut self, t: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
+        if seqlen_offset == 0 {
+            if let Some(mask) = self.masks.get(&t) {
+                return Ok(mask.clone());
+            }
+        }
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| (0..t + seqlen_offset).map(move |j| u8::from(j > i + seqlen_offset)))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, t + seqlen_offset), device)?;
+        if seqlen_offset == 0 {
             self.masks.insert(t, mask.clone());
-            Ok(mask)
         }
+        Ok(mask)
     }
 
     pub fn forward(
@@ -422,16 +442,16 @@ impl ModelWeights {
         x: &Tensor,
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
-        let mask = if seq_len == 1 {
-            None
-        } else {
-            Some(self.mask(seq_len, x.device())?)
+        let mask = match self.cache.ragged_mask(seq_len, None, x.device())? {
+            None if seq_len > 1 => Some(self.mask(seq_len, start_offsets[0], x.device())?),
+            mask => mask,
         };
         let mut layer_in = self.tok_embeddings.forward(x)?;
         let mut cache = self.cache.lock();
+        let mut paged_cache = self.cache.paged_lock();
         for (i, layer) in self.layers.iter_mut().enumerate() {
             if let Some(ref mapper) = self.mapper {
                 layer_in = mapper.map(layer_in, i)?;
@@ -445,6 +465,7 @@ impl ModelWeights {
                 start_offsets,
                 start_offsets_kernel.clone(),
                 &mut cache[i],
+                paged_cache.as_mut().map(|c| c.layer(i)),
             )?;
             let x = (attn + residual)?;
 
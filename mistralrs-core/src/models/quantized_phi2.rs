@@ -9,6 +9,8 @@ use candle_nn::{Embedding, LayerNorm};
 use mistralrs_lora::layer::QLinear;
 
 use crate::device_map::DeviceMapper;
+use crate::paged_attention::PagedLayerCache;
+use crate::pipeline::extract_logits;
 use crate::DeviceMapMetadata;
 
 use super::repeat_kv;
@@ -71,6 +73,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         seqlen_offsets: &[usize],
         kv_cache: &mut Option<(Tensor, Tensor)>,
+        paged_cache: Option<PagedLayerCache<'_>>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
         let qkv =
@@ -89,30 +92,41 @@ impl LayerWeights {
         let q = self.forward(&q, seqlen_offsets)?.contiguous()?;
         let k = self.forward(&k, seqlen_offsets)?;
 
-        let (k, v) = match &*kv_cache {
-            None => (k, v),
-            Some((prev_k, prev_v)) => {
-                let k = Tensor::cat(&[prev_k, &k], 2)?;
-                let v = Tensor::cat(&[prev_v, &v], 2)?;
-                (k, v)
-            }
+        let softmax = |att: Tensor| -> Result<Tensor> {
+            let att = match mask {
+                None => att,
+                Some(mask) => {
+                    let mask = mask.broadcast_as(att.shape())?;
+                    masked_fill(&att, &mask, &self.neg_inf)?
+                }
+            };
+            candle_nn::ops::softmax_last_dim(&att)
         };
-        *kv_cache = Some((k.clone(), v.clone()));
+        let y = match paged_cache {
+            Some(paged_cache) => {
+                let scale = 1. / (self.head_dim as f64).sqrt();
+                paged_cache.attention(&q, &k, &v, scale, softmax)?
+            }
+            None => {
+                let (k, v) = match &*kv_cache {
+                    None => (k, v),
+                    Some((prev_k, prev_v)) => {
+                        let k = Tensor::cat(&[prev_k, &k], 2)?;
+                        let v = Tensor::cat(&[prev_v, &v], 2)?;
+                        (k, v)
+                    }
+                };
+                *kv_cache = Some((k.clone(), v.clone()));
 
-        let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
-        let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
+                let k = repeat_kv(k, self.n_head / self.n_kv_head)?;
+                let v = repeat_kv(v, self.n_head / self.n_kv_head)?;
 
-        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-        let att = match mask {
-            None => att,
-            Some(mask) => {
-                let mask = mask.broadcast_as(att.shape())?;
-                masked_fill(&att, &mask, &self.neg_inf)?
+                let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+                let att = softmax(att)?;
+                // Convert to contiguous as matmul doesn't support strided vs for now.
+                att.matmul(&v.contiguous()?)?
             }
         };
-        let att = candle_nn::ops::softmax_last_dim(&att)?;
-        // Convert to contiguous as matmul doesn't support strided vs for now.
-        let y = att.matmul(&v.contiguous()?)?;
         let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
         let y = self.attn_output.forward(&y)?;
         Ok(y)
@@ -233,33 +247,39 @@ impl ModelWeights {
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+    /// The causal mask for `t` new tokens which follow `seqlen_offset` cached tokens. Only the masks of
+    /// whole prompts are cached: the masks of prompt chunks are built each time, as there is one for
+    /// every offset.
+    fn mask(&mut self, t: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
+        if seqlen_offset == 0 {
+            if let Some(mask) = self.masks.get(&t) {
+                return Ok(mask.clone());
+            }
+        }
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| (0..t + seqlen_offset).map(move |j| u8::from(j > i + seqlen_offset)))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, t + seqlen_offset), device)?;
+        if seqlen_offset == 0 {
             self.masks.insert(t, mask.clone());
-            Ok(mask)
         }
+        Ok(mask)
     }
 
     pub fn forward(
         &mut self,
         xs: &Tensor,
         seqlen_offsets: &[usize],
-        _context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         let (_b_sz, seq_len) = xs.dims2()?;
-        let mask = if seq_len == 1 {
-            None
-        } else {
-            Some(self.mask(seq_len, xs.device())?)
+        let mask = match self.cache.ragged_mask(seq_len, None, xs.device())? {
+            None if seq_len > 1 => Some(self.mask(seq_len, seqlen_offsets[0], xs.device())?),
+            mask => mask,
         };
         let mut xs = self.tok_embeddings.forward(xs)?;
         let mut cache = self.cache.lock();
+        let mut paged_cache = self.cache.paged_lock();
         for (i, layer) in self.layers.iter_mut().enumerate() {
             xs = self.mapper.map(xs, i)?;
             let residual = &xs;
@@ -271,12 +291,13 @@ impl ModelWeights {
                     .as_ref(),
                 seqlen_offsets,
                 cache.get_mut(i).unwrap(),
+                paged_cache.as_mut().map(|c| c.layer(i)),
             )?;
             let feed_forward_hidden_states = layer.mlp.forward(&xs_norm)?;
             xs = (attn_outputs + feed_forward_hidden_states + residual)?
         }
         let xs = xs.to_device(&self.device)?;
-        let xs = xs.apply(&self.output_norm)?.i((.., seq_len - 1, ..))?;
-        self.output.forward(&xs)
+        let xs = xs.apply(&self.output_norm)?;
+        extract_logits(&self.output.forward(&xs.contiguous()?)?, context_lens)
     }
 }
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+/// The physical blocks owned by one sequence, in logical order.
+#[derive(Debug, Clone, Default)]
+struct BlockTable {
+    blocks: Vec<usize>,
+    num_tokens: usize,
+}
+
+/// The block engine hands out fixed-size KV cache blocks to sequences and keeps
+/// a block table for each of them. A token at position `pos` of a sequence is stored
+/// in the slot `blocks[pos / block_size] * block_size + pos % block_size`.
+#[derive(Debug)]
+pub struct BlockEngine {
+    block_size: usize,
+    num_blocks: usize,
+    free_blocks: Vec<usize>,
+    block_tables: HashMap<usize, BlockTable>,
+}
+
+impl BlockEngine {
+    pub fn new(block_size: usize, num_blocks: usize) -> Self {
+        Self {
+            block_size,
+            num_blocks,
+            // Blocks are popped from the back, so the lowest blocks are handed out first.
+            free_blocks: (0..num_blocks).rev().collect(),
+            block_tables: HashMap::new(),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+
+    /// The number of blocks needed to hold `num_tokens` tokens.
+    pub fn blocks_for(&self, num_tokens: usize) -> usize {
+        num_tokens.div_ceil(self.block_size)
+    }
+
+    /// The number of tokens which are allocated for the sequence, if it has an allocation.
+    pub fn num_tokens(&self, seq_id: usize) -> Option<usize> {
+        self.block_tables.get(&seq_id).map(|table| table.num_tokens)
+    }
+
+    /// The number of tokens which are allocated for each sequence with an allocation, by sequence ID.
+    pub fn allocations(&self) -> HashMap<usize, usize> {
+        self.block_tables
+            .iter()
+            .map(|(seq_id, table)| (*seq_id, table.num_tokens))
+            .collect()
+    }
+
+    /// Allocate the blocks for a sequence of `num_tokens` tokens, replacing any allocation
+    /// it already has. Returns `false` without allocating if there are not enough free blocks.
+    pub fn allocate(&mut self, seq_id: usize, num_tokens: usize) -> bool {
+        self.free(seq_id);
+        let n_blocks = self.blocks_for(num_tokens);
+        if n_blocks > self.free_blocks.len() {
+            return false;
+        }
+        let mut blocks = self
+            .free_blocks
+            .split_off(self.free_blocks.len() - n_blocks);
+        blocks.reverse();
+        self.block_tables
+            .insert(seq_id, BlockTable { blocks, num_tokens });
+        true
+    }
+
+    /// Reserve a slot for one more token of the sequence, returning the slot. A new block
+    /// is only taken when the last block of the sequence is full.
+    pub fn append_slot(&mut self, seq_id: usize) -> Option<usize> {
+        let table = self.block_tables.get_mut(&seq_id)?;
+        if table.num_tokens % self.block_size == 0 {
+            table.blocks.push(self.free_blocks.pop()?);
+        }
+        let pos = table.num_tokens;
+        table.num_tokens += 1;
+        Some(table.blocks[pos / self.block_size] * self.block_size + pos % self.block_size)
+    }
+
+    /// Get the slots for the positions `start..start + len` of the sequence.
+    pub fn slots(&self, seq_id: usize, start: usize, len: usize) -> Option<Vec<usize>> {
+        let table = self.block_tables.get(&seq_id)?;
+        if start + len > table.num_tokens {
+            return None;
+        }
+        Some(
+            (start..start + len)
+                .map(|pos| {
+                    table.blocks[pos / self.block_size] * self.block_size + pos % self.block_size
+                })
+                .collect(),
+        )
+    }
+
+    /// Release all blocks held by the sequence.
+    pub fn free(&mut self, seq_id: usize) {
+        if let Some(table) = self.block_tables.remove(&seq_id) {
+            self.free_blocks.extend(table.blocks);
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_allocate_append_free() {
+        use super::BlockEngine;
+
+        let mut engine = BlockEngine::new(4, 3);
+        assert_eq!(engine.blocks_for(12), 3);
+        assert_eq!(engine.blocks_for(13), 4);
+
+        assert!(engine.allocate(0, 5));
+        assert_eq!(engine.num_free_blocks(), 1);
+        let slots = engine.slots(0, 0, 5).unwrap();
+        assert_eq!(slots[..4], [0, 1, 2, 3]);
+        assert_eq!(slots[4], 4);
+
+        // The second block still has room for 3 more tokens.
+        for pos in 5..8 {
+            assert_eq!(engine.append_slot(0), Some(pos));
+        }
+        assert_eq!(engine.num_free_blocks(), 1);
+        assert_eq!(engine.append_slot(0), Some(8));
+        assert_eq!(engine.num_free_blocks(), 0);
+
+        assert!(!engine.allocate(1, 1));
+        engine.free(0);
+        assert_eq!(engine.num_free_blocks(), 3);
+        assert!(engine.allocate(1, 1));
+        assert_eq!(engine.num_tokens(1), Some(1));
+    }
+}
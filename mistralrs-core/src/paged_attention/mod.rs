@@ -0,0 +1,314 @@
+mod block_engine;
+
+pub use block_engine::BlockEngine;
+
+use candle_core::{bail, IndexOp, Result, Tensor};
+
+use crate::sequence::Sequence;
+
+#[derive(Clone, Copy, Debug)]
+/// Configuration for the block-based (paged) KV cache. Each layer preallocates
+/// `num_blocks * block_size` key and value slots, which are handed out to sequences
+/// one block at a time.
+///
+/// The pools of a layer are allocated in full by its first forward pass rather than when the model is
+/// loaded: with a device map, only the model knows which device each layer is on. If the allocation
+/// fails, that first request gets a model error. The following requests try to allocate the pools again.
+pub struct PagedAttentionConfig {
+    pub block_size: usize,
+    pub num_blocks: usize,
+}
+
+impl PagedAttentionConfig {
+    pub fn new(block_size: usize, num_blocks: usize) -> Self {
+        Self {
+            block_size,
+            num_blocks,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// The layout of the current batch: for each sequence, the slots that the new keys and values
+/// are written to and the runs of consecutive slots which hold its whole context, in order.
+struct PagedAttentionInput {
+    slot_mappings: Vec<Vec<usize>>,
+    context_runs: Vec<Vec<(usize, usize)>>,
+}
+
+#[derive(Debug)]
+/// The paged KV cache holds the key and value pools for every layer and the block engine
+/// which assigns the slots of those pools to sequences.
+pub struct PagedKvCache {
+    block_engine: BlockEngine,
+    pools: Vec<Option<(Tensor, Tensor)>>,
+    input: PagedAttentionInput,
+}
+
+impl PagedKvCache {
+    pub(crate) fn new(config: PagedAttentionConfig, num_layers: usize) -> Self {
+        Self {
+            block_engine: BlockEngine::new(config.block_size, config.num_blocks),
+            pools: vec![None; num_layers],
+            input: PagedAttentionInput::default(),
+        }
+    }
+
+    pub(crate) fn block_engine(&self) -> &BlockEngine {
+        &self.block_engine
+    }
+
+    /// Release the blocks held by a sequence.
+    pub(crate) fn free(&mut self, seq_id: usize) {
+        self.block_engine.free(seq_id);
+    }
+
+    /// Reserve the slots for the tokens which will be computed this step and record the batch layout.
+    /// Prompt sequences are (re)allocated from scratch, while completion sequences grow their existing
+    /// allocation by the number of tokens which are not cached yet.
+    pub(crate) fn prepare(&mut self, seqs: &[&mut Sequence], is_prompt: bool) -> Result<()> {
+        let seqs = seqs
+            .iter()
+            .map(|seq| (*seq.id(), seq.len()))
+            .collect::<Vec<_>>();
+        self.prepare_batch(&seqs, is_prompt)
+    }
+
+    /// [`PagedKvCache::prepare`] for the sequences given by their ID and number of tokens.
+    fn prepare_batch(&mut self, seqs: &[(usize, usize)], is_prompt: bool) -> Result<()> {
+        let mut slot_mappings = Vec::new();
+        let mut context_runs = Vec::new();
+        for &(id, len) in seqs {
+            let cached = if is_prompt {
+                if !self.block_engine.allocate(id, len) {
+                    bail!("Not enough free KV cache blocks for the {len} tokens of sequence {id}.");
+                }
+                0
+            } else {
+                let cached = self.block_engine.num_tokens(id).unwrap_or(0);
+                for _ in cached..len {
+                    if self.block_engine.append_slot(id).is_none() {
+                        bail!("Not enough free KV cache blocks to extend sequence {id}.");
+                    }
+                }
+                cached
+            };
+            slot_mappings.push(
+                self.block_engine
+                    .slots(id, cached, len - cached)
+                    .expect("Slots were just allocated."),
+            );
+            context_runs.push(contiguous_runs(
+                &self
+                    .block_engine
+                    .slots(id, 0, len)
+                    .expect("Slots were just allocated."),
+            ));
+        }
+        self.input = PagedAttentionInput {
+            slot_mappings,
+            context_runs,
+        };
+        Ok(())
+    }
+
+    pub(crate) fn layer(&mut self, layer: usize) -> PagedLayerCache<'_> {
+        PagedLayerCache { cache: self, layer }
+    }
+}
+
+/// One layer of the paged KV cache, used by the attention layers of the models.
+pub struct PagedLayerCache<'a> {
+    cache: &'a mut PagedKvCache,
+    layer: usize,
+}
+
+impl PagedLayerCache<'_> {
+    /// Write the new keys and values into the slots of each sequence, and attend to each sequence's
+    /// context. The keys and values are read from the pools through the block table, one run of
+    /// consecutive slots at a time, so the context is never gathered into a new tensor. The free blocks
+    /// are handed out lowest first, so the blocks of a sequence are mostly consecutive and there are few
+    /// runs: a run costs one matmul for the scores and one for the output.
+    ///
+    /// The queries have a shape of (bs, num_heads, seq_len, head_dim) and the keys and values of
+    /// (bs, num_kv_heads, seq_len, head_dim). `softmax` turns the scaled attention scores, of shape
+    /// (bs, num_heads, seq_len, context_len), into the attention weights, applying the mask. All
+    /// sequences of the batch must have the same context length, which the scheduler ensures by
+    /// bucketing them by length. Returns the attention output with the shape of the queries.
+    pub fn attention(
+        self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        scale: f64,
+        softmax: impl FnOnce(Tensor) -> Result<Tensor>,
+    ) -> Result<Tensor> {
+        let (b_sz, num_heads, q_len, head_dim) = q.dims4()?;
+        let num_kv_heads = k.dim(1)?;
+        // The heads which share a KV head are folded into the rows of the queries, so the keys and
+        // values do not have to be repeated.
+        let folded_shape = (num_kv_heads, num_heads / num_kv_heads * q_len, ());
+        let PagedKvCache {
+            block_engine,
+            pools,
+            input,
+        } = self.cache;
+        if b_sz != input.slot_mappings.len() {
+            bail!(
+                "Paged attention was prepared for {} sequences, got a batch of {b_sz}.",
+                input.slot_mappings.len()
+            );
+        }
+        if pools[self.layer].is_none() {
+            // Allocate at the first forward pass, so that the pools end up on the device of the layer.
+            let shape = (
+                num_kv_heads,
+                block_engine.num_blocks() * block_engine.block_size(),
+                head_dim,
+            );
+            pools[self.layer] = Some((
+                Tensor::zeros(shape, k.dtype(), k.device())?,
+                Tensor::zeros(shape, v.dtype(), v.device())?,
+            ));
+        }
+        let (k_pool, v_pool) = pools[self.layer].as_ref().unwrap();
+
+        for (i, slots) in input.slot_mappings.iter().enumerate() {
+            // (num_kv_heads, seq_len, head_dim)
+            let k_i = k.i(i)?;
+            let v_i = v.i(i)?;
+            let mut written = 0;
+            for (start_slot, n) in contiguous_runs(slots) {
+                k_pool.slice_set(&k_i.narrow(1, written, n)?.contiguous()?, 1, start_slot)?;
+                v_pool.slice_set(&v_i.narrow(1, written, n)?.contiguous()?, 1, start_slot)?;
+                written += n;
+            }
+        }
+
+        let mut scores = Vec::new();
+        for (i, runs) in input.context_runs.iter().enumerate() {
+            let q_i = q.i(i)?.contiguous()?.reshape(folded_shape)?;
+            let scores_i = runs
+                .iter()
+                .map(|&(start_slot, n)| q_i.matmul(&k_pool.narrow(1, start_slot, n)?.t()?))
+                .collect::<Result<Vec<_>>>()?;
+            scores.push(Tensor::cat(&scores_i, 2)?.reshape((1, num_heads, q_len, ()))?);
+        }
+        let weights = softmax((Tensor::cat(&scores, 0)? * scale)?)?.to_dtype(v_pool.dtype())?;
+
+        let mut outputs = Vec::new();
+        for (i, runs) in input.context_runs.iter().enumerate() {
+            let weights_i = weights.i(i)?.contiguous()?.reshape(folded_shape)?;
+            let mut output_i = Tensor::zeros(
+                (num_kv_heads, num_heads / num_kv_heads * q_len, head_dim),
+                v_pool.dtype(),
+                v_pool.device(),
+            )?;
+            let mut pos = 0;
+            for &(start_slot, n) in runs {
+                let weights_run = weights_i.narrow(2, pos, n)?.contiguous()?;
+                output_i = (output_i + weights_run.matmul(&v_pool.narrow(1, start_slot, n)?)?)?;
+                pos += n;
+            }
+            outputs.push(output_i.reshape((1, num_heads, q_len, head_dim))?);
+        }
+        Tensor::cat(&outputs, 0)
+    }
+}
+
+/// Split the slots into runs of consecutive slots, returning the first slot and length of each run.
+fn contiguous_runs(slots: &[usize]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for slot in slots {
+        match runs.last_mut() {
+            Some((start, n)) if *start + *n == *slot => *n += 1,
+            _ => runs.push((*slot, 1)),
+        }
+    }
+    runs
+}
+
+mod tests {
+    #[test]
+    fn test_prepare_slot_mapping() {
+        use super::{PagedAttentionConfig, PagedKvCache};
+
+        let mut cache = PagedKvCache::new(PagedAttentionConfig::new(2, 4), 1);
+        // The prompts take the blocks 0 and 1, and 2 and 3.
+        cache.prepare_batch(&[(0, 3), (1, 3)], true).unwrap();
+        assert_eq!(
+            cache.input.slot_mappings,
+            vec![vec![0, 1, 2], vec![4, 5, 6]]
+        );
+        assert_eq!(cache.input.context_runs, vec![vec![(0, 3)], vec![(4, 3)]]);
+
+        // Only the new token is written, into the free slot of the last block.
+        cache.prepare_batch(&[(0, 4), (1, 4)], false).unwrap();
+        assert_eq!(cache.input.slot_mappings, vec![vec![3], vec![7]]);
+        assert_eq!(cache.input.context_runs, vec![vec![(0, 4)], vec![(4, 4)]]);
+
+        // The next token needs a new block, and there are none left.
+        assert!(cache.prepare_batch(&[(0, 5)], false).is_err());
+
+        // A context which is split over blocks which are not adjacent is read in several runs.
+        cache.free(1);
+        cache.prepare_batch(&[(0, 5)], false).unwrap();
+        assert_eq!(cache.input.slot_mappings, vec![vec![6]]);
+        assert_eq!(cache.input.context_runs, vec![vec![(0, 4), (6, 1)]]);
+    }
+
+    #[test]
+    fn test_attention_matches_dense() {
+        use super::{PagedAttentionConfig, PagedKvCache};
+        use candle_core::{Device, IndexOp, Tensor};
+
+        let dev = Device::Cpu;
+        let softmax = |xs: Tensor| candle_nn::ops::softmax_last_dim(&xs);
+        // 2 query heads share 1 KV head, with a head dimension of 2.
+        let keys = Tensor::new(&[[1f32, 0.], [0., 1.], [1., 1.]], &dev).unwrap();
+        let values = Tensor::new(&[[1f32, 2.], [3., 4.], [5., 6.]], &dev).unwrap();
+        let q = Tensor::new(&[[0.5f32, -1.], [2., 0.25]], &dev).unwrap();
+
+        let mut cache = PagedKvCache::new(PagedAttentionConfig::new(2, 3), 1);
+        // Sequence 1 takes block 0, so that the context of sequence 0 ends up in blocks 1 and 0.
+        let one = Tensor::zeros((1, 1, 1, 2), candle_core::DType::F32, &dev).unwrap();
+        let q_one = Tensor::zeros((1, 2, 1, 2), candle_core::DType::F32, &dev).unwrap();
+        cache.prepare_batch(&[(1, 1)], true).unwrap();
+        cache
+            .layer(0)
+            .attention(&q_one, &one, &one, 1., softmax)
+            .unwrap();
+        cache.prepare_batch(&[(0, 2)], true).unwrap();
+        let prompt_k = keys.i(0..2).unwrap().reshape((1, 1, 2, 2)).unwrap();
+        let prompt_v = values.i(0..2).unwrap().reshape((1, 1, 2, 2)).unwrap();
+        let prompt_q = Tensor::zeros((1, 2, 2, 2), candle_core::DType::F32, &dev).unwrap();
+        cache
+            .layer(0)
+            .attention(&prompt_q, &prompt_k, &prompt_v, 1., softmax)
+            .unwrap();
+        cache.free(1);
+        cache.prepare_batch(&[(0, 3)], false).unwrap();
+        assert_eq!(cache.input.context_runs, vec![vec![(2, 2), (0, 1)]]);
+
+        let scale = 0.5;
+        let out = cache
+            .layer(0)
+            .attention(
+                &q.reshape((1, 2, 1, 2)).unwrap(),
+                &keys.i(2..3).unwrap().reshape((1, 1, 1, 2)).unwrap(),
+                &values.i(2..3).unwrap().reshape((1, 1, 1, 2)).unwrap(),
+                scale,
+                softmax,
+            )
+            .unwrap();
+
+        let weights = softmax((q.matmul(&keys.t().unwrap()).unwrap() * scale).unwrap()).unwrap();
+        let expected = weights.matmul(&values).unwrap();
+        assert_eq!(out.dims(), &[1, 2, 1, 2]);
+        let out = out.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        let expected = expected.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        for (o, e) in out.iter().zip(expected) {
+            assert!((o - e).abs() < 1e-5, "{out:?}");
+        }
+    }
+}
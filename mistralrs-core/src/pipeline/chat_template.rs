@@ -3,11 +3,13 @@ use std::collections::HashMap;
 use anyhow::Result;
 use either::Either;
 use indexmap::IndexMap;
-use minijinja::{context, Environment, ErrorKind};
+use minijinja::{context, value::Value, Environment, ErrorKind};
 use serde::Deserialize;
 use tokenizers::Tokenizer;
 use tracing::info;
 
+use crate::Tool;
+
 const SUPPORTED_ALTERNATE_EOS: [&str; 2] = [
     "<|eot_id|>", // Handle Llama3 chat case
     "<|im_end|>", // Handle ChatML case
@@ -114,8 +116,26 @@ pub fn calculate_eos_tokens(chat_template: &ChatTemplate, tokenizer: &Tokenizer)
     eos_toks
 }
 
+/// Parse the JSON `tool_calls` of a message. Chat templates expect the arguments as a mapping, but
+/// OpenAI clients send them as a JSON string.
+fn parse_tool_calls(tool_calls: &str) -> Result<Value> {
+    let mut tool_calls: serde_json::Value = serde_json::from_str(tool_calls)?;
+    for call in tool_calls.as_array_mut().into_iter().flatten() {
+        if let Some(arguments) = call.pointer_mut("/function/arguments") {
+            if let Some(parsed) = arguments
+                .as_str()
+                .and_then(|x| serde_json::from_str::<serde_json::Value>(x).ok())
+            {
+                *arguments = parsed;
+            }
+        }
+    }
+    Ok(Value::from_serializable(&tool_calls))
+}
+
 pub fn apply_chat_template_to(
     messages: Vec<IndexMap<String, String>>,
+    tools: Option<&[Tool]>,
     add_generation_prompt: bool,
     template: &str,
     bos_tok: Option<String>,
@@ -131,8 +151,25 @@ pub fn apply_chat_template_to(
     env.add_template("chat_template", template.as_str())?;
     env.add_function("raise_exception", raise_exception);
     let tmpl = env.get_template("chat_template").unwrap();
+    let messages = messages
+        .into_iter()
+        .map(|message| {
+            message
+                .into_iter()
+                .map(|(k, v)| {
+                    let v = if k == "tool_calls" {
+                        parse_tool_calls(&v)?
+                    } else {
+                        Value::from(v)
+                    };
+                    Ok((k, v))
+                })
+                .collect::<Result<IndexMap<_, _>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
     Ok(tmpl.render(context! {
         messages => messages,
+        tools => tools,
         add_generation_prompt => add_generation_prompt,
         bos_token => bos_tok,
         eos_token => eos_tok,
@@ -1,6 +1,6 @@
 use super::{
-    calculate_inputs, get_model_paths, get_xlora_paths, Loader, ModelInputs, ModelKind, ModelPaths,
-    Pipeline, TokenSource, XLoraPaths,
+    get_model_paths, get_xlora_paths, weights_id, KvCacheMetadata, Loader, ModelInputs, ModelKind,
+    ModelPaths, Pipeline, TokenSource, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
@@ -11,7 +11,7 @@ use crate::utils::varbuilder_utils::from_mmaped_safetensors;
 use crate::xlora_models::{NonGranularState, XLoraConfig};
 use crate::{deserialize_chat_template, get_paths, DeviceMapMetadata};
 use crate::{
-    models::quantized_llama::ModelWeights as QLlama, sequence::Sequence, utils::tokens::get_token,
+    models::quantized_llama::ModelWeights as QLlama, utils::tokens::get_token,
     xlora_models::XLoraModelWeights as XLoraQLlama,
 };
 use anyhow::Result;
@@ -44,6 +44,7 @@ pub struct MistralModelPaths<P> {
     classifier_path: Option<P>,
     classifier_config: Option<XLoraConfig>,
     xlora_ordering: Option<Ordering>,
+    revision: String,
 }
 
 impl ModelPaths for MistralModelPaths<PathBuf> {
@@ -71,6 +72,9 @@ impl ModelPaths for MistralModelPaths<PathBuf> {
     fn get_ordering(&self) -> &Option<Ordering> {
         &self.xlora_ordering
     }
+    fn get_revision(&self) -> &str {
+        &self.revision
+    }
     fn get_template_filename(&self) -> &PathBuf {
         &self.template_filename
     }
@@ -87,6 +91,8 @@ pub struct GGMLPipeline {
     eos_tok: Vec<u32>,
     non_granular_state: Option<NonGranularState>,
     is_lora: bool,
+    kv_cache_metadata: KvCacheMetadata,
+    weights_id: String,
 }
 
 pub struct GGMLLoader {
@@ -298,6 +304,13 @@ impl Loader for GGMLLoader {
         let mut file = std::fs::File::open(paths.get_weight_filenames().first().unwrap())?;
         let model = ggml_file::Content::read(&mut file, device)
             .map_err(|e| e.with_path(paths.get_weight_filenames().first().unwrap()))?;
+        let kv_cache_metadata = KvCacheMetadata {
+            num_layers: model.hparams.n_layer as usize,
+            num_kv_heads: model.hparams.n_head as usize / self.config.gqa,
+            head_dim: (model.hparams.n_embd / model.hparams.n_head) as usize,
+            // The quantized models run in F32.
+            dtype: DType::F32,
+        };
 
         let mut is_lora = false;
         let model = match self.kind {
@@ -375,6 +388,16 @@ impl Loader for GGMLLoader {
                 }
             }),
             is_lora,
+            kv_cache_metadata,
+            weights_id: weights_id(
+                &[
+                    Some(&self.model_id),
+                    self.quantized_model_id.as_ref(),
+                    self.quantized_filename.as_ref(),
+                    self.xlora_model_id.as_ref(),
+                ],
+                paths,
+            ),
         })))
     }
 
@@ -388,11 +411,7 @@ impl Loader for GGMLLoader {
 }
 
 impl Pipeline for GGMLPipeline {
-    fn forward(
-        &mut self,
-        input_toks: &[&mut Sequence],
-        is_prompt: bool,
-    ) -> Result<Tensor, candle_core::Error> {
+    fn forward_inputs(&mut self, inputs: ModelInputs) -> Result<Tensor, candle_core::Error> {
         let ModelInputs {
             input_ids,
             input_ids_full,
@@ -401,14 +420,7 @@ impl Pipeline for GGMLPipeline {
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full,
             context_lens,
-        } = calculate_inputs(
-            input_toks,
-            is_prompt,
-            self.is_xlora(),
-            self.device(),
-            self.no_kv_cache,
-        )
-        .unwrap();
+        } = inputs;
         match self.model {
             Model::Llama(ref mut model) => model.forward(
                 &input_ids,
@@ -438,6 +450,9 @@ impl Pipeline for GGMLPipeline {
     fn num_hidden_layers(&self) -> usize {
         self.cache().lock().len()
     }
+    fn kv_cache_metadata(&self) -> &KvCacheMetadata {
+        &self.kv_cache_metadata
+    }
     fn cache(&self) -> &Cache {
         match self.model {
             Model::Llama(ref model) => &model.cache,
@@ -456,6 +471,9 @@ impl Pipeline for GGMLPipeline {
     fn name(&self) -> String {
         self.model_id.clone()
     }
+    fn weights_id(&self) -> String {
+        self.weights_id.clone()
+    }
     fn get_max_seq_len(&self) -> usize {
         match &self.model {
             Model::Llama(model) => model.max_seq_len,
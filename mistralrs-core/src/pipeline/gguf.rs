@@ -1,6 +1,6 @@
 use super::{
-    calculate_inputs, get_model_paths, get_xlora_paths, Loader, ModelInputs, ModelKind, ModelPaths,
-    Pipeline, TokenSource, XLoraPaths,
+    get_model_paths, get_xlora_paths, weights_id, KvCacheMetadata, Loader, ModelInputs, ModelKind,
+    ModelPaths, Pipeline, TokenSource, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
@@ -12,7 +12,7 @@ use crate::xlora_models::{NonGranularState, XLoraConfig};
 use crate::{deserialize_chat_template, get_paths, DeviceMapMetadata};
 use crate::{
     models::quantized_llama::ModelWeights as QLlama, models::quantized_phi2::ModelWeights as QPhi,
-    sequence::Sequence, utils::tokens::get_token, xlora_models::XLoraModelWeights as XLoraQLlama,
+    utils::tokens::get_token, xlora_models::XLoraModelWeights as XLoraQLlama,
 };
 use anyhow::{bail, Result};
 use candle_core::quantized::{gguf_file, GgmlDType};
@@ -45,6 +45,7 @@ pub struct MistralModelPaths<P> {
     classifier_path: Option<P>,
     classifier_config: Option<XLoraConfig>,
     xlora_ordering: Option<Ordering>,
+    revision: String,
 }
 
 impl ModelPaths for MistralModelPaths<PathBuf> {
@@ -72,6 +73,9 @@ impl ModelPaths for MistralModelPaths<PathBuf> {
     fn get_ordering(&self) -> &Option<Ordering> {
         &self.xlora_ordering
     }
+    fn get_revision(&self) -> &str {
+        &self.revision
+    }
     fn get_template_filename(&self) -> &PathBuf {
         &self.template_filename
     }
@@ -88,6 +92,8 @@ pub struct GGUFPipeline {
     eos_tok: Vec<u32>,
     non_granular_state: Option<NonGranularState>,
     is_lora: bool,
+    kv_cache_metadata: KvCacheMetadata,
+    weights_id: String,
 }
 
 pub struct GGUFLoader {
@@ -333,6 +339,22 @@ impl Loader for GGUFLoader {
             .unwrap()
             .parse()
             .map_err(anyhow::Error::msg)?;
+        let kv_cache_metadata = {
+            let arch = model.metadata["general.architecture"].to_string().unwrap();
+            let md_get = |key: &str| -> Result<usize> {
+                match model.metadata.get(&format!("{arch}.{key}")) {
+                    None => bail!("cannot find {arch}.{key} in metadata"),
+                    Some(v) => Ok(v.to_u32()? as usize),
+                }
+            };
+            KvCacheMetadata {
+                num_layers: md_get("block_count")?,
+                num_kv_heads: md_get("attention.head_count_kv")?,
+                head_dim: md_get("embedding_length")? / md_get("attention.head_count")?,
+                // The quantized models run in F32.
+                dtype: DType::F32,
+            }
+        };
 
         let mut is_lora = false;
         let model = match self.kind {
@@ -428,6 +450,16 @@ impl Loader for GGUFLoader {
                 }
             }),
             is_lora,
+            kv_cache_metadata,
+            weights_id: weights_id(
+                &[
+                    Some(&self.model_id),
+                    self.quantized_model_id.as_ref(),
+                    self.quantized_filename.as_ref(),
+                    self.xlora_model_id.as_ref(),
+                ],
+                paths,
+            ),
         })))
     }
 
@@ -441,11 +473,7 @@ impl Loader for GGUFLoader {
 }
 
 impl Pipeline for GGUFPipeline {
-    fn forward(
-        &mut self,
-        input_toks: &[&mut Sequence],
-        is_prompt: bool,
-    ) -> Result<Tensor, candle_core::Error> {
+    fn forward_inputs(&mut self, inputs: ModelInputs) -> Result<Tensor, candle_core::Error> {
         let ModelInputs {
             input_ids,
             input_ids_full,
@@ -454,14 +482,7 @@ impl Pipeline for GGUFPipeline {
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full,
             context_lens,
-        } = calculate_inputs(
-            input_toks,
-            is_prompt,
-            self.is_xlora(),
-            self.device(),
-            self.no_kv_cache,
-        )
-        .unwrap();
+        } = inputs;
         match self.model {
             Model::Llama(ref mut model) => model.forward(
                 &input_ids,
@@ -493,6 +514,9 @@ impl Pipeline for GGUFPipeline {
     fn num_hidden_layers(&self) -> usize {
         self.cache().lock().len()
     }
+    fn kv_cache_metadata(&self) -> &KvCacheMetadata {
+        &self.kv_cache_metadata
+    }
     fn cache(&self) -> &Cache {
         match self.model {
             Model::Llama(ref model) => &model.cache,
@@ -512,6 +536,9 @@ impl Pipeline for GGUFPipeline {
     fn name(&self) -> String {
         self.model_id.clone()
     }
+    fn weights_id(&self) -> String {
+        self.weights_id.clone()
+    }
     fn get_max_seq_len(&self) -> usize {
         match &self.model {
             Model::Llama(model) => model.max_seq_len,
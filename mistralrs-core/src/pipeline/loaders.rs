@@ -1,13 +1,14 @@
 use std::{collections::HashMap, str::FromStr};
 
 use anyhow::Result;
+use candle_core::DType;
 use candle_nn::{Activation, VarBuilder};
 use either::Either;
 use mistralrs_lora::{LoraConfig, Ordering};
 use pyo3::pyclass;
 use serde::Deserialize;
 
-use super::{NormalModel, NormalModelLoader};
+use super::{KvCacheMetadata, NormalModel, NormalModelLoader};
 use crate::{
     models,
     xlora_models::{self, XLoraConfig},
@@ -120,6 +121,15 @@ impl NormalModelLoader for MistralLoader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn get_kv_cache_metadata(&self, config: &str, dtype: DType) -> Result<KvCacheMetadata> {
+        let cfg = MistralBasicConfig::deserialize(config, false)?;
+        Ok(KvCacheMetadata {
+            num_layers: cfg.num_hidden_layers,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            dtype,
+        })
+    }
 }
 
 // ======================== Gemma loader
@@ -210,6 +220,15 @@ impl NormalModelLoader for GemmaLoader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn get_kv_cache_metadata(&self, config: &str, dtype: DType) -> Result<KvCacheMetadata> {
+        let cfg = GemmaBasicConfig::deserialize(config, false)?;
+        Ok(KvCacheMetadata {
+            num_layers: cfg.num_hidden_layers,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.head_dim,
+            dtype,
+        })
+    }
 }
 
 // ======================== Llama loader
@@ -290,6 +309,15 @@ impl NormalModelLoader for LlamaLoader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn get_kv_cache_metadata(&self, config: &str, dtype: DType) -> Result<KvCacheMetadata> {
+        let cfg = LlamaBasicConfig::deserialize(config, false)?;
+        Ok(KvCacheMetadata {
+            num_layers: cfg.num_hidden_layers,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            dtype,
+        })
+    }
 }
 
 // ======================== Mixtral loader
@@ -373,6 +401,15 @@ impl NormalModelLoader for MixtralLoader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn get_kv_cache_metadata(&self, config: &str, dtype: DType) -> Result<KvCacheMetadata> {
+        let cfg = MixtralBasicConfig::deserialize(config, false)?;
+        Ok(KvCacheMetadata {
+            num_layers: cfg.num_hidden_layers,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            dtype,
+        })
+    }
 }
 
 // ======================== Phi2 loader
@@ -456,6 +493,15 @@ impl NormalModelLoader for Phi2Loader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn get_kv_cache_metadata(&self, config: &str, dtype: DType) -> Result<KvCacheMetadata> {
+        let cfg = Phi2BasicConfig::deserialize(config, false)?;
+        Ok(KvCacheMetadata {
+            num_layers: cfg.num_hidden_layers,
+            num_kv_heads: cfg.num_key_value_heads(),
+            head_dim: cfg.head_dim(),
+            dtype,
+        })
+    }
 }
 
 // ======================== Phi3 loader
@@ -548,6 +594,15 @@ impl NormalModelLoader for Phi3Loader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn get_kv_cache_metadata(&self, config: &str, dtype: DType) -> Result<KvCacheMetadata> {
+        let cfg = Phi3BasicConfig::deserialize(config, false)?;
+        Ok(KvCacheMetadata {
+            num_layers: cfg.num_hidden_layers,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.head_dim(),
+            dtype,
+        })
+    }
 }
 
 // ======================== Qwen2 loader
@@ -625,4 +680,13 @@ impl NormalModelLoader for Qwen2Loader {
     fn is_gptx(&self) -> bool {
         true
     }
+    fn get_kv_cache_metadata(&self, config: &str, dtype: DType) -> Result<KvCacheMetadata> {
+        let cfg = Qwen2BasicConfig::deserialize(config, false)?;
+        Ok(KvCacheMetadata {
+            num_layers: cfg.num_hidden_layers,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            dtype,
+        })
+    }
 }
@@ -183,6 +183,7 @@ macro_rules! get_paths {
             classifier_config: xlora_config,
             xlora_ordering: xlora_order,
             template_filename,
+            revision,
         }))
     }};
 }
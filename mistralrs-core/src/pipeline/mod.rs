@@ -4,12 +4,16 @@ mod gguf;
 mod loaders;
 mod macros;
 mod normal;
-use crate::aici::toktree::TokTrie;
-use crate::{api_dir_list, api_get_file, DeviceMapMetadata};
+use crate::aici::{
+    recognizer::{AnythingGoes, StackRecognizer},
+    toktree::TokTrie,
+};
+use crate::{api_dir_list, api_get_file, DeviceMapMetadata, Tool};
 use crate::{get_bias_if_not_allowed, sampler::Logprobs, sequence::SequenceRecognizer};
 use candle_core::quantized::{GgmlDType, QMatMul, QTensor};
 use candle_nn::VarBuilder;
-use chat_template::{apply_chat_template_to, ChatTemplate};
+use chat_template::apply_chat_template_to;
+pub(crate) use chat_template::ChatTemplate;
 use core::fmt;
 use either::Either;
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
@@ -25,8 +29,11 @@ pub use loaders::{
 };
 use mistralrs_lora::{LoraConfig, Ordering};
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
+use rustc_hash::FxHasher;
+use std::hash::{Hash, Hasher};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 use std::{collections::HashMap, fs, iter::repeat, path::PathBuf, str::FromStr, sync::Mutex};
 use tokenizers::Tokenizer;
 use tqdm::Iter;
@@ -53,6 +60,8 @@ pub trait ModelPaths {
     fn get_classifier_path(&self) -> &Option<PathBuf>;
     fn get_classifier_config(&self) -> &Option<XLoraConfig>;
     fn get_ordering(&self) -> &Option<Ordering>;
+    /// The revision of the model which the files were downloaded from.
+    fn get_revision(&self) -> &str;
 }
 
 #[derive(Debug, Clone)]
@@ -194,12 +203,50 @@ pub trait Loader {
     fn get_kind(&self) -> ModelKind;
 }
 
+#[derive(Clone, Copy, Debug)]
+/// The shape of the KV cache of a model, used to estimate how much memory a sequence will take up.
+pub struct KvCacheMetadata {
+    pub num_layers: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+    pub dtype: DType,
+}
+
+impl KvCacheMetadata {
+    /// The size of the keys and values of one token across all layers, in bytes.
+    pub fn bytes_per_token(&self) -> usize {
+        2 * self.num_layers * self.num_kv_heads * self.head_dim * self.dtype.size_in_bytes()
+    }
+}
+
 pub trait Pipeline: Send + Sync {
+    fn forward_inputs(&mut self, inputs: ModelInputs) -> Result<Tensor, candle_core::Error>;
     fn forward(
         &mut self,
         input_seqs: &[&mut Sequence],
         is_prompt: bool,
-    ) -> Result<Tensor, candle_core::Error>;
+    ) -> Result<Tensor, candle_core::Error> {
+        let inputs = calculate_inputs(
+            input_seqs,
+            is_prompt,
+            self.is_xlora(),
+            self.device(),
+            self.has_no_kv_cache(),
+        )
+        .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        self.forward_inputs(inputs)
+    }
+    /// Run the same number of tokens for each sequence, all of which follow `seqlen_offset`
+    /// cached tokens, and return the logits of every token. This is how a draft model's proposals
+    /// are verified in a single pass.
+    fn forward_tokens(
+        &mut self,
+        toks: &[Vec<u32>],
+        seqlen_offset: usize,
+    ) -> Result<Tensor, candle_core::Error> {
+        let inputs = get_token_inputs(toks, seqlen_offset, self.device())?;
+        self.forward_inputs(inputs)
+    }
     fn tokenize_prompt(&self, prompt: &str) -> Result<Vec<u32>> {
         let encoding = self
             .tokenizer()
@@ -209,17 +256,28 @@ pub trait Pipeline: Send + Sync {
     }
     fn device(&self) -> &Device;
     fn num_hidden_layers(&self) -> usize;
+    fn kv_cache_metadata(&self) -> &KvCacheMetadata;
     fn cache(&self) -> &Cache;
     fn tokenizer(&self) -> Arc<Tokenizer>;
     fn tok_trie(&self) -> &TokTrie;
     fn eos_tok(&self) -> &[u32];
     fn name(&self) -> String;
+    /// Identifies the weights which the model runs with: the model, its adapters and their quantization,
+    /// with the revision and the files they were loaded from.
+    /// The KV caches computed with different weights are not interchangeable.
+    fn weights_id(&self) -> String;
     fn get_max_seq_len(&self) -> usize;
     fn is_xlora(&self) -> bool;
     fn has_no_kv_cache(&self) -> bool;
+    /// Whether sequences with different lengths can run in the same batch, with their KV caches padded and
+    /// masked. Otherwise, only sequences of the same length are batched together.
+    fn supports_ragged_batches(&self) -> bool {
+        !self.is_xlora()
+    }
     fn apply_chat_template(
         &self,
         messages: Vec<IndexMap<String, String>>,
+        tools: Option<&[Tool]>,
         add_generation_prompt: bool,
     ) -> Result<String> {
         let template = self.get_chat_template().chat_template.as_ref().unwrap();
@@ -245,6 +303,7 @@ pub trait Pipeline: Send + Sync {
         };
         apply_chat_template_to(
             messages,
+            tools,
             add_generation_prompt,
             template,
             bos_tok,
@@ -268,45 +327,85 @@ pub trait Pipeline: Send + Sync {
         return_logprobs: bool,
     ) -> Result<Logprobs> {
         let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+        // The penalties apply to the whole prompt, even if only its last chunk was just run.
         let start_at = seq
-            .get_toks()
+            .all_toks()
             .len()
             .saturating_sub(self.get_repeat_last_n());
-        let ctxt = seq.get_toks()[start_at..].to_vec();
+        let ctxt = seq.all_toks()[start_at..].to_vec();
 
+        // If the constraint rejects the first draw, it is redone with the constraint bias. Only the draw
+        // which is kept may update the state of the sampler.
+        let mirostat_mu = seq.sampler().mirostat_mu();
         let first_lobprobs_response =
             seq.sampler()
-                .sample(logits.clone(), Some(&ctxt), return_logprobs)?;
-
-        let bias_if_not_allowed = match &mut seq.recognizer {
-            SequenceRecognizer::Regex(ref mut rx) => {
-                get_bias_if_not_allowed!(self, rx.as_mut(), first_lobprobs_response.token)
+                .sample(logits.clone(), Some(&ctxt), return_logprobs, None)?;
+
+        // With token healing, the output must first repeat the bytes removed from the prompt.
+        let healing_prefix = seq.healing_prefix().to_vec();
+        let bias_if_not_allowed = if healing_prefix.is_empty() {
+            match &mut seq.recognizer {
+                SequenceRecognizer::Regex(ref mut rx) => {
+                    get_bias_if_not_allowed!(self, rx.as_mut(), first_lobprobs_response.token)
+                }
+                SequenceRecognizer::Cfg(ref mut cfg) => {
+                    get_bias_if_not_allowed!(self, cfg.as_mut(), first_lobprobs_response.token)
+                }
+                SequenceRecognizer::None => None,
             }
-            SequenceRecognizer::Cfg(ref mut cfg) => {
-                get_bias_if_not_allowed!(self, cfg.as_mut(), first_lobprobs_response.token)
+        } else {
+            match &mut seq.recognizer {
+                SequenceRecognizer::Regex(ref mut rx) => get_bias_if_not_allowed!(
+                    self,
+                    rx.as_mut(),
+                    first_lobprobs_response.token,
+                    &healing_prefix
+                ),
+                SequenceRecognizer::Cfg(ref mut cfg) => get_bias_if_not_allowed!(
+                    self,
+                    cfg.as_mut(),
+                    first_lobprobs_response.token,
+                    &healing_prefix
+                ),
+                SequenceRecognizer::None => get_bias_if_not_allowed!(
+                    self,
+                    &mut StackRecognizer::from(AnythingGoes),
+                    first_lobprobs_response.token,
+                    &healing_prefix
+                ),
             }
-            SequenceRecognizer::None => None,
         };
         let second_logprobs_response = match bias_if_not_allowed {
             Some(token_set) => {
                 let mut acc = vec![-f32::INFINITY; self.tok_trie().vocab_size()];
                 token_set.apply_to(&mut acc);
-                let new_logits = (logits + Tensor::from_slice(&acc, acc.len(), self.device())?)?;
-
-                seq.sampler()
-                    .sample(new_logits, Some(&ctxt), return_logprobs)?
+                let constraint_bias = Tensor::from_slice(&acc, acc.len(), &Device::Cpu)?;
+
+                seq.sampler().set_mirostat_mu(mirostat_mu);
+                seq.sampler().sample(
+                    logits,
+                    Some(&ctxt),
+                    return_logprobs,
+                    Some(&constraint_bias),
+                )?
             }
             None => first_lobprobs_response,
         };
 
         match seq.recognizer {
             SequenceRecognizer::Regex(ref mut rx) => {
-                self.tok_trie()
-                    .append_token(rx.as_mut(), second_logprobs_response.token);
+                self.tok_trie().append_token_after_prefix(
+                    rx.as_mut(),
+                    second_logprobs_response.token,
+                    &healing_prefix,
+                );
             }
             SequenceRecognizer::Cfg(ref mut cfg) => {
-                self.tok_trie()
-                    .append_token(cfg.as_mut(), second_logprobs_response.token);
+                self.tok_trie().append_token_after_prefix(
+                    cfg.as_mut(),
+                    second_logprobs_response.token,
+                    &healing_prefix,
+                );
             }
             SequenceRecognizer::None => {}
         }
@@ -315,6 +414,40 @@ pub trait Pipeline: Send + Sync {
     fn re_isq_model(&mut self, dtype: GgmlDType) -> Result<()>;
 }
 
+/// The [`Pipeline::weights_id`] of a model made of the given weights, skipping the missing ones. The
+/// weights can change under the same model ID, so the ID also has the revision and a hash of the names,
+/// sizes and modification times of the weight and adapter files.
+fn weights_id(parts: &[Option<&String>], paths: &dyn ModelPaths) -> String {
+    let mut hasher = FxHasher::default();
+    let adapter_filenames = paths
+        .get_adapter_filenames()
+        .iter()
+        .flatten()
+        .map(|(_, path)| path);
+    for path in paths.get_weight_filenames().iter().chain(adapter_filenames) {
+        path.file_name().hash(&mut hasher);
+        if let Ok(metadata) = fs::metadata(path) {
+            metadata.len().hash(&mut hasher);
+            metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .hash(&mut hasher);
+        }
+    }
+    let models = parts
+        .iter()
+        .flatten()
+        .map(|part| part.as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "{models} @{} {:016x}",
+        paths.get_revision(),
+        hasher.finish()
+    )
+}
+
 pub trait ConfigMarker {}
 
 pub trait NormalModelLoader {
@@ -337,6 +470,7 @@ pub trait NormalModelLoader {
         mapper: DeviceMapMetadata,
     ) -> Result<Box<dyn NormalModel + Send + Sync>>;
     fn is_gptx(&self) -> bool;
+    fn get_kv_cache_metadata(&self, config: &str, dtype: DType) -> Result<KvCacheMetadata>;
 }
 
 pub trait NormalModel {
@@ -345,7 +479,7 @@ pub trait NormalModel {
         input_ids: &Tensor,
         seqlen_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> candle_core::Result<Tensor>;
     #[allow(clippy::too_many_arguments)]
     fn xlora_forward(
@@ -358,7 +492,7 @@ pub trait NormalModel {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> candle_core::Result<Tensor>;
     fn is_xlora(&self) -> bool;
     fn device(&self) -> &Device;
@@ -386,13 +520,14 @@ struct InputMetadata {
     input: Tensor,
     positions: Vec<usize>,
     positions_kernel: Tensor, // [bs, seq len]
-    context_lens: Vec<usize>,
+    /// The start and length of the positions whose logits are returned, for each sequence.
+    context_lens: Vec<(usize, usize)>,
 }
 
 fn get_prompt_input(input_seqs: &[&mut Sequence], device: &Device) -> Result<InputMetadata> {
     let max_len = input_seqs
         .iter()
-        .map(|seq| seq.len())
+        .map(|seq| seq.get_toks().len())
         .max()
         .expect("No sequences");
     let padding_tok = 0;
@@ -402,17 +537,23 @@ fn get_prompt_input(input_seqs: &[&mut Sequence], device: &Device) -> Result<Inp
     let mut context_lens = Vec::new();
     for seq in input_seqs.iter() {
         let mut ctxt = seq.get_toks().to_vec();
-        seqlen_offsets.push(0);
+        // Only the tokens after a cached prefix are run.
+        seqlen_offsets.push(seq.prefix_len());
+        context_lens.push((ctxt.len() - 1, 1));
 
         ctxt.extend(repeat(padding_tok).take(max_len.saturating_sub(ctxt.len())));
-        context_lens.push(seq.len() - 1);
 
         seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
     }
 
     let mut tmp = Vec::new();
-    for pos in (0..seqs_tensors.len())
-        .map(|_| (0..max_len).map(|x| x as i64).collect::<Vec<_>>())
+    for pos in seqlen_offsets
+        .iter()
+        .map(|offset| {
+            (*offset..offset + max_len)
+                .map(|x| x as i64)
+                .collect::<Vec<_>>()
+        })
         .collect::<Vec<_>>()
     {
         tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
@@ -442,7 +583,7 @@ fn get_completion_input(
         let start_pos = seq.get_toks().len().saturating_sub(1);
         let ctxt = seq.get_toks()[start_pos..].to_vec();
         seqlen_offsets.push(start_pos);
-        context_lens.push(0);
+        context_lens.push((0, 1));
 
         seqs_tensors.push(Tensor::new(ctxt, device).unwrap().unsqueeze(0).unwrap());
     }
@@ -462,14 +603,40 @@ fn get_completion_input(
     })
 }
 
-struct ModelInputs {
+/// The inputs of a model's forward pass.
+pub struct ModelInputs {
     input_ids: Tensor,
     input_ids_full: Option<Tensor>,
     seqlen_offsets: Vec<usize>,
     seqlen_offsets_full: Option<Vec<usize>>,
     seqlen_offsets_kernel: Tensor,
     seqlen_offsets_kernel_full: Option<Tensor>,
-    context_lens: Vec<usize>,
+    context_lens: Vec<(usize, usize)>,
+}
+
+fn get_token_inputs(
+    toks: &[Vec<u32>],
+    seqlen_offset: usize,
+    device: &Device,
+) -> candle_core::Result<ModelInputs> {
+    let mut seqs_tensors = Vec::new();
+    let mut positions = Vec::new();
+    for toks in toks {
+        seqs_tensors.push(Tensor::new(toks.as_slice(), device)?.unsqueeze(0)?);
+        let pos = (seqlen_offset..seqlen_offset + toks.len())
+            .map(|x| x as i64)
+            .collect::<Vec<_>>();
+        positions.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
+    }
+    Ok(ModelInputs {
+        input_ids: Tensor::cat(&seqs_tensors, 0)?,
+        input_ids_full: None,
+        seqlen_offsets: vec![seqlen_offset; toks.len()],
+        seqlen_offsets_full: None,
+        seqlen_offsets_kernel: Tensor::cat(&positions, 0)?,
+        seqlen_offsets_kernel_full: None,
+        context_lens: toks.iter().map(|toks| (0, toks.len())).collect(),
+    })
 }
 
 fn calculate_inputs(
@@ -552,10 +719,13 @@ fn calculate_inputs(
     }
 }
 
-pub fn extract_logits(logits: &Tensor, context_lens: Vec<usize>) -> candle_core::Result<Tensor> {
+pub fn extract_logits(
+    logits: &Tensor,
+    context_lens: Vec<(usize, usize)>,
+) -> candle_core::Result<Tensor> {
     let mut toks = Vec::new();
-    for (dim, start) in logits.chunk(logits.dims()[0], 0)?.iter().zip(context_lens) {
-        toks.push(dim.narrow(1, start, 1)?);
+    for (dim, (start, len)) in logits.chunk(logits.dims()[0], 0)?.iter().zip(context_lens) {
+        toks.push(dim.narrow(1, start, len)?);
     }
     Tensor::cat(&toks, 0)
 }
@@ -807,6 +977,7 @@ mod tests {
                 } else {
                     inputs.clone()
                 },
+                None,
                 true,
                 template,
                 Some(bos.to_string()),
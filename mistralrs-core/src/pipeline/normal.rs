@@ -3,23 +3,20 @@ use super::loaders::{
     Phi3Loader, Qwen2Loader,
 };
 use super::{
-    calculate_inputs, get_model_paths, get_xlora_paths, Loader, ModelInputs, ModelKind, ModelPaths,
-    NormalModel, NormalModelLoader, Pipeline, TokenSource, XLoraPaths,
+    get_model_paths, get_xlora_paths, weights_id, KvCacheMetadata, Loader, ModelInputs, ModelKind,
+    ModelPaths, NormalModel, NormalModelLoader, Pipeline, TokenSource, XLoraPaths,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
 use crate::models::Cache;
 use crate::pipeline::chat_template::calculate_eos_tokens;
 use crate::pipeline::ChatTemplate;
+use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
 use crate::xlora_models::{NonGranularState, XLoraConfig};
 use crate::{
     deserialize_chat_template, get_paths, lora_model_loader, normal_model_loader,
     xlora_model_loader, DeviceMapMetadata,
 };
-use crate::{
-    sequence::Sequence,
-    utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors},
-};
 use anyhow::Result;
 use candle_core::quantized::GgmlDType;
 use candle_core::{DType, Device, Tensor};
@@ -45,6 +42,7 @@ pub struct NormalModelPaths<P> {
     classifier_path: Option<P>,
     classifier_config: Option<XLoraConfig>,
     xlora_ordering: Option<Ordering>,
+    revision: String,
 }
 
 impl ModelPaths for NormalModelPaths<PathBuf> {
@@ -72,6 +70,9 @@ impl ModelPaths for NormalModelPaths<PathBuf> {
     fn get_ordering(&self) -> &Option<Ordering> {
         &self.xlora_ordering
     }
+    fn get_revision(&self) -> &str {
+        &self.revision
+    }
     fn get_template_filename(&self) -> &PathBuf {
         &self.template_filename
     }
@@ -88,6 +89,10 @@ pub struct NormalPipeline {
     model_id: String,
     is_lora: bool,
     eos_tok: Vec<u32>,
+    kv_cache_metadata: KvCacheMetadata,
+    weights_id: String,
+    /// The in-situ quantization, which can be changed while the model is running.
+    isq: Option<GgmlDType>,
 }
 
 /// A loader for a "normal" (non-quantized) model.
@@ -257,6 +262,9 @@ impl Loader for NormalLoader {
         };
 
         info!("Model config: {config}");
+        let kv_cache_metadata = self
+            .inner
+            .get_kv_cache_metadata(&config, dtype.unwrap_or(default_dtype))?;
 
         let mut is_lora = false;
         let mut model = match self.kind {
@@ -329,6 +337,12 @@ impl Loader for NormalLoader {
             }),
             model_id: self.model_id.clone(),
             is_lora,
+            kv_cache_metadata,
+            weights_id: weights_id(
+                &[Some(&self.model_id), self.xlora_model_id.as_ref()],
+                paths,
+            ),
+            isq: in_situ_quant,
         })))
     }
 
@@ -342,11 +356,7 @@ impl Loader for NormalLoader {
 }
 
 impl Pipeline for NormalPipeline {
-    fn forward(
-        &mut self,
-        input_toks: &[&mut Sequence],
-        is_prompt: bool,
-    ) -> Result<Tensor, candle_core::Error> {
+    fn forward_inputs(&mut self, inputs: ModelInputs) -> Result<Tensor, candle_core::Error> {
         let ModelInputs {
             input_ids,
             input_ids_full,
@@ -355,14 +365,7 @@ impl Pipeline for NormalPipeline {
             seqlen_offsets_kernel,
             seqlen_offsets_kernel_full,
             context_lens,
-        } = calculate_inputs(
-            input_toks,
-            is_prompt,
-            self.is_xlora(),
-            self.device(),
-            self.no_kv_cache,
-        )
-        .unwrap();
+        } = inputs;
         match self.model.is_xlora() {
             false => self.model.forward(
                 &input_ids,
@@ -389,6 +392,9 @@ impl Pipeline for NormalPipeline {
     fn num_hidden_layers(&self) -> usize {
         self.cache().lock().len()
     }
+    fn kv_cache_metadata(&self) -> &KvCacheMetadata {
+        &self.kv_cache_metadata
+    }
     fn cache(&self) -> &Cache {
         self.model.cache()
     }
@@ -404,6 +410,12 @@ impl Pipeline for NormalPipeline {
     fn name(&self) -> String {
         self.model_id.clone()
     }
+    fn weights_id(&self) -> String {
+        match self.isq {
+            Some(isq) => format!("{} {isq:?}", self.weights_id),
+            None => self.weights_id.clone(),
+        }
+    }
     fn get_max_seq_len(&self) -> usize {
         self.model.max_seq_len()
     }
@@ -413,6 +425,10 @@ impl Pipeline for NormalPipeline {
     fn has_no_kv_cache(&self) -> bool {
         self.no_kv_cache
     }
+    fn supports_ragged_batches(&self) -> bool {
+        // Flash attention does not take the mask of the padded KV caches.
+        !self.is_xlora() && !self.config.use_flash_attn
+    }
     fn get_chat_template(&self) -> &ChatTemplate {
         &self.chat_template
     }
@@ -423,6 +439,8 @@ impl Pipeline for NormalPipeline {
         &self.tok_trie
     }
     fn re_isq_model(&mut self, dtype: GgmlDType) -> Result<()> {
-        self.model.quantize(dtype).map_err(anyhow::Error::msg)
+        self.model.quantize(dtype).map_err(anyhow::Error::msg)?;
+        self.isq = Some(dtype);
+        Ok(())
     }
 }
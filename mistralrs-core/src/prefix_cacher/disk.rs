@@ -0,0 +1,437 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use candle_core::{DType, Device, Error, Result, Tensor};
+use radix_trie::{Trie, TrieCommon};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::Tokens;
+use crate::models::LayerCaches;
+
+const INDEX_FILE: &str = "index.json";
+/// How long changes to the index which only mark caches as used may wait before being written out.
+const INDEX_WRITE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+/// Configuration for the on-disk tier of the prefix cache. Caches which are evicted from the device
+/// are also written to a directory under `path` as safetensors files, so that they can be reused after
+/// a restart. The least recently used files are deleted to keep the total size under `max_bytes`. The
+/// directory of a model is only used with the same weights, quantization and data type.
+pub struct PrefixCacheDiskConfig {
+    pub path: PathBuf,
+    pub max_bytes: usize,
+}
+
+impl PrefixCacheDiskConfig {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: usize) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DiskEntry {
+    tokens: Vec<u32>,
+    file: String,
+    n_layers: usize,
+    n_bytes: usize,
+    last_access: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskIndex {
+    /// The weights which the caches were computed with, see [`crate::Pipeline::weights_id`].
+    weights: String,
+    /// The data type of the caches.
+    dtype: String,
+    entries: Vec<DiskEntry>,
+}
+
+/// The caches stored on disk, with an index of their tokens which is kept in memory and mirrored to
+/// `index.json` in the cache directory.
+pub(crate) struct DiskPrefixCache {
+    dir: PathBuf,
+    max_bytes: usize,
+    total_bytes: usize,
+    clock: u64,
+    entries: Trie<Tokens, DiskEntry>,
+    weights: String,
+    dtype: DType,
+    /// Whether the index has changes which are not written out yet, and when it was last written.
+    index_dirty: bool,
+    index_written: Instant,
+}
+
+impl DiskPrefixCache {
+    /// Open the cache directory of a model, reading the index of any caches stored by a previous run. The
+    /// caches are only valid for the same weights and data type, so a directory with caches of other weights
+    /// or another data type is refused.
+    pub(crate) fn new(
+        config: &PrefixCacheDiskConfig,
+        model_name: &str,
+        weights: &str,
+        dtype: DType,
+    ) -> Result<Self> {
+        let dir = config.path.join(model_name.replace('/', "--"));
+        fs::create_dir_all(&dir)?;
+        let empty = || DiskIndex {
+            weights: weights.to_string(),
+            dtype: dtype.as_str().to_string(),
+            entries: Vec::new(),
+        };
+        let index = match fs::read_to_string(dir.join(INDEX_FILE)) {
+            Ok(index) => serde_json::from_str(&index).unwrap_or_else(|e| {
+                warn!("Could not read the prefix cache index, starting from an empty cache: {e}");
+                empty()
+            }),
+            Err(_) => empty(),
+        };
+        if index.weights != weights || index.dtype != dtype.as_str() {
+            candle_core::bail!(
+                "The prefix caches in {} were computed with {} in {}, not {weights} in {}. Use another directory, or remove it.",
+                dir.display(),
+                index.weights,
+                index.dtype,
+                dtype.as_str()
+            );
+        }
+
+        let mut this = Self {
+            dir,
+            max_bytes: config.max_bytes,
+            total_bytes: 0,
+            clock: 0,
+            entries: Trie::new(),
+            weights: weights.to_string(),
+            dtype,
+            index_dirty: false,
+            index_written: Instant::now(),
+        };
+        for entry in index.entries {
+            if !this.dir.join(&entry.file).exists() {
+                continue;
+            }
+            this.total_bytes += entry.n_bytes;
+            this.clock = this.clock.max(entry.last_access + 1);
+            this.entries.insert(entry.tokens.clone().into(), entry);
+        }
+        // The budget may have been lowered since the last run.
+        this.evict_until_fits(0)?;
+        this.write_index()?;
+        Ok(this)
+    }
+
+    /// The length of the longest prefix which `toks` shares with a stored cache, and the tokens of the most
+    /// recently used cache which shares it.
+    pub(crate) fn longest_common_prefix(&self, toks: &[u32]) -> Option<(usize, Vec<u32>)> {
+        let (len, descendants) = super::longest_common_prefix(&self.entries, toks)?;
+        let stored = descendants
+            .values()
+            .max_by_key(|entry| entry.last_access)?
+            .tokens
+            .clone();
+        Some((len, stored))
+    }
+
+    /// Write a cache to disk. If it is already stored, it is only marked as used.
+    pub(crate) fn store(
+        &mut self,
+        toks: &[u32],
+        cache: &LayerCaches,
+        xlora_cache: Option<&LayerCaches>,
+    ) -> Result<()> {
+        let key = Tokens(toks.to_vec());
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.last_access = self.clock;
+            self.clock += 1;
+            return self.index_touched();
+        }
+        if cache.iter().any(Option::is_none)
+            || xlora_cache.is_some_and(|cache| cache.iter().any(Option::is_none))
+        {
+            return Ok(());
+        }
+
+        let mut tensors = HashMap::new();
+        add_layer_caches(&mut tensors, "", cache);
+        if let Some(xlora_cache) = xlora_cache {
+            add_layer_caches(&mut tensors, "xlora_", xlora_cache);
+        }
+        let n_bytes = tensors
+            .values()
+            .map(|t| t.elem_count() * t.dtype().size_in_bytes())
+            .sum::<usize>();
+        if n_bytes > self.max_bytes {
+            return Ok(());
+        }
+        self.evict_until_fits(n_bytes)?;
+
+        let file = format!("{:016x}.safetensors", token_hash(toks));
+        // Free the file if it is used by other tokens with the same hash.
+        let colliding = self
+            .entries
+            .values()
+            .find(|entry| entry.file == file)
+            .map(|entry| entry.tokens.clone());
+        if let Some(colliding) = colliding {
+            self.remove(&colliding.into())?;
+        }
+        candle_core::safetensors::save(&tensors, self.dir.join(&file))?;
+        self.total_bytes += n_bytes;
+        self.entries.insert(
+            key,
+            DiskEntry {
+                tokens: toks.to_vec(),
+                file,
+                n_layers: cache.len(),
+                n_bytes,
+                last_access: self.clock,
+            },
+        );
+        self.clock += 1;
+        self.write_index()
+    }
+
+    /// Read a stored cache, and the X-LoRA cache if `is_xlora`. Entries which cannot be read are removed.
+    pub(crate) fn load(
+        &mut self,
+        toks: &[u32],
+        device: &Device,
+        is_xlora: bool,
+    ) -> Result<Option<(LayerCaches, Option<LayerCaches>)>> {
+        let key = Tokens(toks.to_vec());
+        let Some(entry) = self.entries.get_mut(&key) else {
+            return Ok(None);
+        };
+        entry.last_access = self.clock;
+        self.clock += 1;
+        let n_layers = entry.n_layers;
+
+        let loaded = candle_core::safetensors::load(self.dir.join(&entry.file), device);
+        let caches = match loaded {
+            Ok(mut tensors) => {
+                let cache = take_layer_caches(&mut tensors, "", n_layers);
+                let xlora_cache = if is_xlora {
+                    take_layer_caches(&mut tensors, "xlora_", n_layers).map(Some)
+                } else {
+                    Some(None)
+                };
+                cache.zip(xlora_cache)
+            }
+            Err(e) => {
+                warn!("Could not read a prefix cache from disk: {e}");
+                None
+            }
+        };
+        if caches.is_none() {
+            self.remove(&key)?;
+            self.write_index()?;
+        } else {
+            self.index_touched()?;
+        }
+        Ok(caches)
+    }
+
+    /// Delete the least recently used caches until `n_bytes` more fit in the budget.
+    fn evict_until_fits(&mut self, n_bytes: usize) -> Result<()> {
+        while self.total_bytes + n_bytes > self.max_bytes {
+            let oldest = self
+                .entries
+                .values()
+                .min_by_key(|entry| entry.last_access)
+                .map(|entry| entry.tokens.clone());
+            match oldest {
+                Some(oldest) => self.remove(&oldest.into())?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, toks: &Tokens) -> Result<()> {
+        if let Some(entry) = self.entries.remove(toks) {
+            self.total_bytes -= entry.n_bytes;
+            remove_file(&self.dir.join(entry.file))?;
+        }
+        Ok(())
+    }
+
+    /// Record that caches were marked as used. This is only written out with the next change to the stored
+    /// caches, or once the last write is older than [`INDEX_WRITE_INTERVAL`], as losing it only affects which
+    /// caches are evicted first.
+    fn index_touched(&mut self) -> Result<()> {
+        self.index_dirty = true;
+        if self.index_written.elapsed() >= INDEX_WRITE_INTERVAL {
+            self.write_index()?;
+        }
+        Ok(())
+    }
+
+    /// Write the index. This is done right away whenever files are added or removed, so that the index always
+    /// lists the stored files.
+    fn write_index(&mut self) -> Result<()> {
+        let index = DiskIndex {
+            weights: self.weights.clone(),
+            dtype: self.dtype.as_str().to_string(),
+            entries: self.entries.values().cloned().collect(),
+        };
+        fs::write(
+            self.dir.join(INDEX_FILE),
+            serde_json::to_vec(&index).map_err(Error::wrap)?,
+        )?;
+        self.index_dirty = false;
+        self.index_written = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for DiskPrefixCache {
+    fn drop(&mut self) {
+        if self.index_dirty {
+            if let Err(e) = self.write_index() {
+                warn!("Could not write the prefix cache index: {e}");
+            }
+        }
+    }
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// A hash of the tokens (64 bit FNV-1a) which is stable across runs, used to name the cache files.
+fn token_hash(toks: &[u32]) -> u64 {
+    toks.iter()
+        .flat_map(|tok| tok.to_le_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        })
+}
+
+fn add_layer_caches(tensors: &mut HashMap<String, Tensor>, prefix: &str, cache: &LayerCaches) {
+    for (i, (k, v)) in cache.iter().flatten().enumerate() {
+        tensors.insert(format!("{prefix}k.{i}"), k.clone());
+        tensors.insert(format!("{prefix}v.{i}"), v.clone());
+    }
+}
+
+fn take_layer_caches(
+    tensors: &mut HashMap<String, Tensor>,
+    prefix: &str,
+    n_layers: usize,
+) -> Option<LayerCaches> {
+    let mut cache = Vec::new();
+    for i in 0..n_layers {
+        let k = tensors.remove(&format!("{prefix}k.{i}"))?;
+        let v = tensors.remove(&format!("{prefix}v.{i}"))?;
+        cache.push(Some((k, v)));
+    }
+    Some(cache)
+}
+
+mod tests {
+    use std::path::PathBuf;
+
+    use candle_core::{DType, Device, Tensor};
+
+    use crate::models::LayerCaches;
+
+    /// An empty directory for the caches of a test.
+    #[allow(dead_code)]
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mistralrs-prefix-cache-{test}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// A cache of one layer for `len` tokens, of `2 * 4 * len` bytes, where the keys and values start at `start`.
+    #[allow(dead_code)]
+    fn cache(start: f32, len: usize) -> LayerCaches {
+        let k = Tensor::arange(start, start + len as f32, &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, len, 1))
+            .unwrap();
+        vec![Some((k.clone(), (k * 2.).unwrap()))]
+    }
+
+    #[allow(dead_code)]
+    fn values(cache: &LayerCaches) -> (Vec<f32>, Vec<f32>) {
+        let (k, v) = cache[0].as_ref().unwrap();
+        (
+            k.flatten_all().unwrap().to_vec1().unwrap(),
+            v.flatten_all().unwrap().to_vec1().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_round_trip() {
+        use super::{DiskPrefixCache, PrefixCacheDiskConfig};
+
+        let dir = temp_dir("round-trip");
+        let config = PrefixCacheDiskConfig::new(&dir, 1 << 20);
+        let stored = cache(1., 3);
+        let mut disk = DiskPrefixCache::new(&config, "org/model", "org/model", DType::F32).unwrap();
+        disk.store(&[1, 2, 3], &stored, None).unwrap();
+        drop(disk);
+
+        let mut disk = DiskPrefixCache::new(&config, "org/model", "org/model", DType::F32).unwrap();
+        assert_eq!(
+            disk.longest_common_prefix(&[1, 2, 4]),
+            Some((2, vec![1, 2, 3]))
+        );
+        let (loaded, xlora) = disk.load(&[1, 2, 3], &Device::Cpu, false).unwrap().unwrap();
+        assert!(xlora.is_none());
+        assert_eq!(values(&loaded), values(&stored));
+        drop(disk);
+
+        // The caches are refused for other weights or another data type.
+        assert!(DiskPrefixCache::new(&config, "org/model", "org/model Q4K", DType::F32).is_err());
+        assert!(DiskPrefixCache::new(&config, "org/model", "org/model", DType::BF16).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_eviction() {
+        use super::{DiskPrefixCache, PrefixCacheDiskConfig};
+
+        let dir = temp_dir("eviction");
+        // Room for two caches of 2 tokens.
+        let config = PrefixCacheDiskConfig::new(&dir, 2 * 16);
+        let mut disk = DiskPrefixCache::new(&config, "model", "model", DType::F32).unwrap();
+        disk.store(&[1, 1], &cache(0., 2), None).unwrap();
+        disk.store(&[2, 2], &cache(0., 2), None).unwrap();
+        // Storing a cache again only marks it as used.
+        disk.store(&[1, 1], &cache(0., 2), None).unwrap();
+        disk.store(&[3, 3], &cache(0., 2), None).unwrap();
+        assert!(disk.longest_common_prefix(&[1, 1]).is_some());
+        assert!(disk.longest_common_prefix(&[2, 2]).is_none());
+        assert!(disk.longest_common_prefix(&[3, 3]).is_some());
+        // A cache larger than the budget is not stored.
+        disk.store(&[4; 5], &cache(0., 5), None).unwrap();
+        assert!(disk.longest_common_prefix(&[4; 5]).is_none());
+        drop(disk);
+
+        // Only the files of the remaining caches are kept, and a lower budget is applied on reopening.
+        assert_eq!(std::fs::read_dir(dir.join("model")).unwrap().count(), 3);
+        let config = PrefixCacheDiskConfig::new(&dir, 16);
+        let disk = DiskPrefixCache::new(&config, "model", "model", DType::F32).unwrap();
+        assert!(disk.longest_common_prefix(&[1, 1]).is_none());
+        assert!(disk.longest_common_prefix(&[3, 3]).is_some());
+        assert_eq!(std::fs::read_dir(dir.join("model")).unwrap().count(), 2);
+        drop(disk);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
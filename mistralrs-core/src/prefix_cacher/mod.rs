@@ -0,0 +1,581 @@
+mod disk;
+
+use candle_core::{Device, Result, Tensor};
+pub(crate) use disk::DiskPrefixCache;
+pub use disk::PrefixCacheDiskConfig;
+use radix_trie::{SubTrie, Trie, TrieCommon, TrieKey};
+use tracing::warn;
+
+use crate::{models::LayerCaches, sequence::Sequence};
+
+#[derive(PartialEq, Eq)]
+struct Tokens(Vec<u32>);
+
+impl TrieKey for Tokens {
+    fn encode_bytes(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|x| bytemuck::bytes_of(x).to_vec())
+            .collect::<Vec<u8>>()
+    }
+}
+
+impl From<Vec<u32>> for Tokens {
+    fn from(value: Vec<u32>) -> Self {
+        Self(value)
+    }
+}
+
+/// The length of the longest common prefix of `toks` and the keys of the trie, and the entries whose keys start
+/// with that prefix. The prefix is found with a binary search, as the prefixes of `toks` which start some key
+/// are exactly the ones up to the longest common prefix.
+fn longest_common_prefix<'a, V>(
+    trie: &'a Trie<Tokens, V>,
+    toks: &[u32],
+) -> Option<(usize, SubTrie<'a, Tokens, V>)> {
+    let descendants = |len: usize| trie.get_raw_descendant(&Tokens(toks[..len].to_vec()));
+    let (mut lo, mut hi) = (0, toks.len());
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        if descendants(mid).is_some() {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    if lo == 0 {
+        return None;
+    }
+    descendants(lo).map(|descendants| (lo, descendants))
+}
+
+struct CacheEntry {
+    cache: LayerCaches,
+    xlora_cache: Option<LayerCaches>,
+    n_bytes: usize,
+    last_access: u64,
+}
+
+impl CacheEntry {
+    fn on_device(&self) -> bool {
+        self.cache
+            .first()
+            .and_then(Option::as_ref)
+            .is_some_and(|(k, _)| !matches!(k.device(), Device::Cpu))
+    }
+}
+
+/// Limits on the memory used by the prefix cache. When they are exceeded, the least recently used caches
+/// are first moved from the device to the CPU, and then dropped from the CPU.
+pub(crate) struct PrefixCacheBudget {
+    /// The maximum number of caches on the device.
+    pub n_on_device: usize,
+    /// The maximum size of the caches on the device, in bytes.
+    pub device_max_bytes: Option<usize>,
+    /// The maximum size of the caches on the CPU, in bytes.
+    pub host_max_bytes: usize,
+}
+
+pub struct PrefixCacheManager {
+    caches: Trie<Tokens, CacheEntry>,
+    is_xlora: bool,
+    device: Device,
+    budget: PrefixCacheBudget,
+    clock: u64,
+    no_prefix_cache: bool,
+    disk: Option<DiskPrefixCache>,
+}
+
+#[derive(Clone)]
+pub struct MatchingCache {
+    pub normal: LayerCaches,
+    pub xlora: Option<LayerCaches>,
+    pub toks: Vec<u32>,
+}
+
+impl PrefixCacheManager {
+    pub(crate) fn new(
+        device: Device,
+        budget: PrefixCacheBudget,
+        is_xlora: bool,
+        no_prefix_cache: bool,
+        disk: Option<DiskPrefixCache>,
+    ) -> Self {
+        PrefixCacheManager {
+            caches: Trie::new(),
+            is_xlora,
+            device,
+            budget,
+            clock: 0,
+            no_prefix_cache,
+            disk,
+        }
+    }
+
+    /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
+    /// some caches will be evicted.
+    pub fn add_sequence(&mut self, seq: &mut Sequence) {
+        if self.no_prefix_cache {
+            return;
+        }
+        let xlora_cache = if seq.is_xlora() {
+            Some(seq.xlora_cache().clone())
+        } else {
+            None
+        };
+        self.insert(seq.get_toks().to_vec(), seq.cache().clone(), xlora_cache);
+    }
+
+    fn insert(&mut self, toks: Vec<u32>, cache: LayerCaches, xlora_cache: Option<LayerCaches>) {
+        if cache.iter().any(Option::is_none) {
+            return;
+        }
+        let n_bytes = Self::cache_bytes(&cache) + xlora_cache.as_ref().map_or(0, Self::cache_bytes);
+        self.caches.insert(
+            toks.into(),
+            CacheEntry {
+                cache,
+                xlora_cache,
+                n_bytes,
+                last_access: self.clock,
+            },
+        );
+        self.clock += 1;
+    }
+
+    fn cache_bytes(cache: &LayerCaches) -> usize {
+        cache
+            .iter()
+            .flatten()
+            .map(|(k, v)| {
+                (k.elem_count() * k.dtype().size_in_bytes())
+                    + (v.elem_count() * v.dtype().size_in_bytes())
+            })
+            .sum()
+    }
+
+    fn cache_to<'a>(
+        cache: impl Iterator<Item = &'a mut Option<(Tensor, Tensor)>>,
+        device: &Device,
+    ) -> Result<()> {
+        for layer in cache {
+            if let Some((ref q, ref k)) = layer {
+                *layer = Some((q.to_device(device)?, k.to_device(device)?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a cache to the disk tier, if there is one. Failing to do so only loses the copy on disk.
+    fn store_on_disk(disk: &mut Option<DiskPrefixCache>, toks: &[u32], entry: &CacheEntry) {
+        if let Some(disk) = disk {
+            if let Err(e) = disk.store(toks, &entry.cache, entry.xlora_cache.as_ref()) {
+                warn!("Could not write a prefix cache to disk: {e}");
+            }
+        }
+    }
+
+    /// Move the cache stored on disk which shares the longest prefix with `toks` into memory, if that prefix is
+    /// longer than the one shared with the caches which are already in memory.
+    fn load_from_disk(&mut self, toks: &[u32]) -> Result<()> {
+        let Some(disk) = self.disk.as_mut() else {
+            return Ok(());
+        };
+        let Some((on_disk, stored)) = disk.longest_common_prefix(toks) else {
+            return Ok(());
+        };
+        let in_memory = longest_common_prefix(&self.caches, toks).map_or(0, |(len, _)| len);
+        if on_disk <= in_memory {
+            return Ok(());
+        }
+        let loaded = match disk.load(&stored, &self.device, self.is_xlora) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                warn!("Could not read a prefix cache from disk: {e}");
+                None
+            }
+        };
+        if let Some((cache, xlora_cache)) = loaded {
+            self.insert(stored, cache, xlora_cache);
+            self.evict_caches()?;
+        }
+        Ok(())
+    }
+
+    /// The tokens of the least recently used cache which is on the device if `on_device`, or on the CPU otherwise.
+    fn least_recently_used(&self, on_device: bool) -> Option<Vec<u32>> {
+        self.caches
+            .iter()
+            .filter(|(_, entry)| entry.on_device() == on_device)
+            .min_by_key(|(_, entry)| entry.last_access)
+            .map(|(toks, _)| toks.0.clone())
+    }
+
+    /// The number and total size of the caches which are on the device if `on_device`, or on the CPU otherwise.
+    fn usage(&self, on_device: bool) -> (usize, usize) {
+        self.caches
+            .values()
+            .filter(|entry| entry.on_device() == on_device)
+            .fold((0, 0), |(n, n_bytes), entry| {
+                (n + 1, n_bytes + entry.n_bytes)
+            })
+    }
+
+    /// Move a cache to the CPU, also writing it to the disk tier if there is one.
+    fn evict_to_cpu(&mut self, toks: Vec<u32>) -> Result<()> {
+        let toks = Tokens(toks);
+        let entry = self
+            .caches
+            .get_mut(&toks)
+            .expect("No cache for the tokens.");
+        Self::cache_to(entry.cache.iter_mut(), &Device::Cpu)?;
+        if let Some(ref mut xlora_cache) = entry.xlora_cache {
+            Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
+        }
+        Self::store_on_disk(&mut self.disk, &toks.0, entry);
+        Ok(())
+    }
+
+    /// Evict the least recently used caches until the budget is met: caches are moved from the device to the CPU
+    /// while there are too many or they are too large, and then dropped while the ones on the CPU are too large.
+    /// Caches which are moved to the CPU or dropped are written to the disk tier, if there is one. Returns the number
+    /// of caches which were moved or dropped.
+    pub fn evict_caches(&mut self) -> Result<usize> {
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        let mut n_evicted = 0;
+        loop {
+            let (n_on_device, device_bytes) = self.usage(true);
+            let over_device_bytes =
+                matches!(self.budget.device_max_bytes, Some(max) if device_bytes > max);
+            if n_on_device <= self.budget.n_on_device && !over_device_bytes {
+                break;
+            }
+            let Some(toks) = self.least_recently_used(true) else {
+                break;
+            };
+            self.evict_to_cpu(toks)?;
+            n_evicted += 1;
+        }
+        while self.usage(false).1 > self.budget.host_max_bytes {
+            let Some(toks) = self.least_recently_used(false) else {
+                break;
+            };
+            let toks = Tokens(toks);
+            if let Some(entry) = self.caches.remove(&toks) {
+                Self::store_on_disk(&mut self.disk, &toks.0, &entry);
+            }
+            n_evicted += 1;
+        }
+        Ok(n_evicted)
+    }
+
+    /// Evict all the caches to CPU, and then drop the least recently used ones until the CPU budget is met.
+    pub fn evict_all_to_cpu(&mut self) -> Result<usize> {
+        if self.no_prefix_cache {
+            return Ok(0);
+        }
+        while let Some(toks) = self.least_recently_used(true) {
+            self.evict_to_cpu(toks)?;
+        }
+        self.evict_caches()?;
+        Ok(self.caches.len())
+    }
+
+    /// Drop all of the caches after the weights of the model changed. The caches on disk are no longer used, as
+    /// they are stored for the previous weights.
+    pub fn clear(&mut self) {
+        self.caches = Trie::new();
+        if self.disk.take().is_some() {
+            warn!("The weights of the model changed, no longer using the prefix caches on disk.");
+        }
+    }
+
+    /// Narrow each layer of the cache to the first `len` tokens.
+    fn narrow_cache(cache: &LayerCaches, len: usize) -> Result<LayerCaches> {
+        let mut narrowed = Vec::new();
+        for layer in cache {
+            narrowed.push(match layer {
+                Some((k, v)) => Some((k.narrow(2, 0, len)?, v.narrow(2, 0, len)?)),
+                None => None,
+            });
+        }
+        Ok(narrowed)
+    }
+
+    /// Search for the cache which shares the longest prefix with some toks. It does not have to be a cache of a
+    /// prefix of the toks: the cache of any tokens which start with the same prefix holds its keys and values. The
+    /// returned cache is narrowed to the length of that prefix, and `toks` holds the remaining tokens which still
+    /// need to be run. At least one token is always left to be run, so that there are logits to sample from.
+    pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
+        if self.no_prefix_cache || toks.len() < 2 {
+            return Ok(None);
+        }
+
+        self.load_from_disk(toks)?;
+        let Some((common_len, descendants)) = longest_common_prefix(&self.caches, toks) else {
+            return Ok(None);
+        };
+        // The cache of a finished sequence does not contain its last token, so it can be shorter than its key.
+        // Use the cache which holds the most of the common prefix.
+        let Some((cached_toks, cached_len)) = descendants
+            .iter()
+            .filter_map(|(key, entry)| {
+                let (k, _) = entry.cache[0].as_ref()?;
+                Some((key.0.clone(), k.dims()[2].min(common_len)))
+            })
+            .max_by_key(|(_, cached_len)| *cached_len)
+        else {
+            return Ok(None);
+        };
+        let prefix_len = cached_len.min(toks.len() - 1);
+        if prefix_len == 0 {
+            return Ok(None);
+        }
+
+        let entry = self
+            .caches
+            .get_mut(&Tokens(cached_toks))
+            .expect("No cache for the prefix.");
+        entry.last_access = self.clock;
+        self.clock += 1;
+        Self::cache_to(entry.cache.iter_mut(), &self.device)?;
+        let normal = Self::narrow_cache(&entry.cache, prefix_len)?;
+        let xlora = if let Some(ref mut xlora_cache) = entry.xlora_cache {
+            Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
+            Some(Self::narrow_cache(xlora_cache, prefix_len)?)
+        } else {
+            None
+        };
+        // The cache may have been moved back to the device.
+        self.evict_caches()?;
+        Ok(Some(MatchingCache {
+            normal,
+            xlora,
+            toks: toks[prefix_len..].to_vec(),
+        }))
+    }
+}
+
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use radix_trie::TrieCommon;
+
+    use super::{PrefixCacheBudget, PrefixCacheManager};
+    use crate::models::LayerCaches;
+
+    /// A cache manager on the CPU, with room for `n` caches.
+    #[allow(dead_code)]
+    fn manager(n: usize) -> PrefixCacheManager {
+        let budget = PrefixCacheBudget {
+            n_on_device: n,
+            device_max_bytes: None,
+            host_max_bytes: usize::MAX,
+        };
+        PrefixCacheManager::new(Device::Cpu, budget, false, false, None)
+    }
+
+    /// A cache manager on the CPU, which holds up to `host_max_bytes` of caches.
+    #[allow(dead_code)]
+    fn host_manager(host_max_bytes: usize) -> PrefixCacheManager {
+        let budget = PrefixCacheBudget {
+            n_on_device: 0,
+            device_max_bytes: None,
+            host_max_bytes,
+        };
+        PrefixCacheManager::new(Device::Cpu, budget, false, false, None)
+    }
+
+    /// The tokens of the caches held by the manager, sorted.
+    #[allow(dead_code)]
+    fn cached_toks(manager: &PrefixCacheManager) -> Vec<Vec<u32>> {
+        let mut toks = manager
+            .caches
+            .keys()
+            .map(|toks| toks.0.clone())
+            .collect::<Vec<_>>();
+        toks.sort();
+        toks
+    }
+
+    /// A cache of one layer for `len` tokens, where the key and value of each token is its position.
+    #[allow(dead_code)]
+    fn cache(len: usize) -> LayerCaches {
+        let positions = Tensor::arange(0, len as u32, &Device::Cpu)
+            .unwrap()
+            .to_dtype(DType::F32)
+            .unwrap()
+            .reshape((1, 1, len, 1))
+            .unwrap();
+        vec![Some((positions.clone(), positions))]
+    }
+
+    /// The positions held by the first layer of a matching cache.
+    #[allow(dead_code)]
+    fn positions(cache: &LayerCaches) -> Vec<f32> {
+        cache[0]
+            .as_ref()
+            .unwrap()
+            .0
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_exact_match() {
+        let mut manager = manager(4);
+        manager.insert(vec![1, 2, 3, 4], cache(4), None);
+        let matching = manager
+            .search_for_matching_cache(&[1, 2, 3, 4])
+            .unwrap()
+            .unwrap();
+        // The last token is run again to get its logits.
+        assert_eq!(matching.toks, vec![4]);
+        assert_eq!(positions(&matching.normal), vec![0., 1., 2.]);
+    }
+
+    #[test]
+    fn test_ancestor_match() {
+        let mut manager = manager(4);
+        manager.insert(vec![1, 2], cache(2), None);
+        manager.insert(vec![1, 2, 3], cache(3), None);
+        let matching = manager
+            .search_for_matching_cache(&[1, 2, 3, 4, 5])
+            .unwrap()
+            .unwrap();
+        assert_eq!(matching.toks, vec![4, 5]);
+        assert_eq!(positions(&matching.normal), vec![0., 1., 2.]);
+    }
+
+    #[test]
+    fn test_partial_match() {
+        let mut manager = manager(4);
+        // The cache of a finished sequence does not hold its last token.
+        manager.insert(vec![1, 2, 3, 9, 9], cache(4), None);
+        manager.insert(vec![1, 2, 8], cache(3), None);
+        let matching = manager
+            .search_for_matching_cache(&[1, 2, 3, 4, 5])
+            .unwrap()
+            .unwrap();
+        assert_eq!(matching.toks, vec![4, 5]);
+        assert_eq!(positions(&matching.normal), vec![0., 1., 2.]);
+
+        // Of the caches sharing the prefix, the one which holds all of it is used.
+        manager.insert(vec![5, 6], cache(1), None);
+        manager.insert(vec![5, 6, 7], cache(3), None);
+        let matching = manager
+            .search_for_matching_cache(&[5, 6, 1])
+            .unwrap()
+            .unwrap();
+        assert_eq!(matching.toks, vec![1]);
+        assert_eq!(positions(&matching.normal), vec![0., 1.]);
+    }
+
+    #[test]
+    fn test_no_match() {
+        let mut manager = manager(4);
+        manager.insert(vec![7, 8, 9], cache(3), None);
+        assert!(manager
+            .search_for_matching_cache(&[1, 2, 3])
+            .unwrap()
+            .is_none());
+        // A single shared token is not reused, as it is the only token left to run.
+        assert!(manager.search_for_matching_cache(&[7]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        // Room for three caches of 2 tokens, which take 16 bytes each.
+        let mut manager = host_manager(48);
+        manager.insert(vec![1, 2], cache(2), None);
+        manager.insert(vec![3, 4], cache(2), None);
+        manager.insert(vec![5, 6], cache(2), None);
+        // Using a cache makes it the most recently used.
+        assert!(manager
+            .search_for_matching_cache(&[1, 2, 9])
+            .unwrap()
+            .is_some());
+        manager.insert(vec![7, 8], cache(2), None);
+        assert_eq!(manager.evict_caches().unwrap(), 1);
+        assert_eq!(
+            cached_toks(&manager),
+            vec![vec![1, 2], vec![5, 6], vec![7, 8]]
+        );
+    }
+
+    #[test]
+    fn test_evict_to_host_budget() {
+        let mut manager = host_manager(40);
+        manager.insert(vec![1, 2, 3], cache(3), None);
+        manager.insert(vec![4, 5], cache(2), None);
+        // 40 bytes fit in the budget.
+        assert_eq!(manager.evict_caches().unwrap(), 0);
+        manager.insert(vec![6], cache(1), None);
+        // Dropping the least recently used cache is enough to get back to 24 bytes.
+        assert_eq!(manager.evict_caches().unwrap(), 1);
+        assert_eq!(cached_toks(&manager), vec![vec![4, 5], vec![6]]);
+
+        // Every cache is dropped if none fit.
+        let mut manager = host_manager(8);
+        manager.insert(vec![1, 2], cache(2), None);
+        manager.insert(vec![3, 4], cache(2), None);
+        assert_eq!(manager.evict_caches().unwrap(), 2);
+        assert!(cached_toks(&manager).is_empty());
+    }
+
+    #[cfg(feature = "cuda")]
+    #[test]
+    fn test_evict_to_device_budget() {
+        let device = Device::new_cuda(0).unwrap();
+        let on_device = |manager: &PrefixCacheManager| {
+            let mut toks = manager
+                .caches
+                .iter()
+                .filter(|(_, entry)| entry.on_device())
+                .map(|(toks, _)| toks.0.clone())
+                .collect::<Vec<_>>();
+            toks.sort();
+            toks
+        };
+        let device_cache = |len| {
+            cache(len)
+                .into_iter()
+                .map(|layer| {
+                    layer.map(|(k, v)| {
+                        (k.to_device(&device).unwrap(), v.to_device(&device).unwrap())
+                    })
+                })
+                .collect()
+        };
+
+        // At most two caches on the device: the least recently used one is moved to the CPU, not dropped.
+        let budget = PrefixCacheBudget {
+            n_on_device: 2,
+            device_max_bytes: None,
+            host_max_bytes: usize::MAX,
+        };
+        let mut manager = PrefixCacheManager::new(device.clone(), budget, false, false, None);
+        manager.insert(vec![1, 2], device_cache(2), None);
+        manager.insert(vec![3, 4], device_cache(2), None);
+        manager.insert(vec![5, 6], device_cache(2), None);
+        assert_eq!(manager.evict_caches().unwrap(), 1);
+        assert_eq!(on_device(&manager), vec![vec![3, 4], vec![5, 6]]);
+        assert_eq!(cached_toks(&manager).len(), 3);
+
+        // At most 24 bytes on the device.
+        let budget = PrefixCacheBudget {
+            n_on_device: usize::MAX,
+            device_max_bytes: Some(24),
+            host_max_bytes: usize::MAX,
+        };
+        let mut manager = PrefixCacheManager::new(device.clone(), budget, false, false, None);
+        manager.insert(vec![1, 2], device_cache(2), None);
+        manager.insert(vec![3], device_cache(1), None);
+        manager.insert(vec![4], device_cache(1), None);
+        assert_eq!(manager.evict_caches().unwrap(), 1);
+        assert_eq!(on_device(&manager), vec![vec![3], vec![4]]);
+    }
+}
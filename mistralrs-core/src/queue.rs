@@ -0,0 +1,234 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{SendError, Sender},
+    Arc, Mutex,
+};
+
+use serde::Serialize;
+use tokenizers::Tokenizer;
+
+use crate::{Request, RequestMessage};
+
+/// The requests which are waiting to be scheduled by the engine.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct QueueStatus {
+    /// Requests which were sent to the engine and have waiting sequences, or were not received yet.
+    pub waiting_requests: usize,
+    /// Tokens of the waiting sequences which must be run before they generate. The prompts of the
+    /// requests which the engine has not received yet are not tokenized with the chat template, so
+    /// only the tokens of their messages are counted.
+    pub waiting_tokens: usize,
+}
+
+/// The waiting requests, shared between the engine and the senders.
+pub(crate) struct QueueState {
+    tokenizer: Arc<Tokenizer>,
+    /// Waiting requests, including the ones which were sent but not received by the engine yet.
+    waiting_requests: AtomicUsize,
+    /// Tokens of the waiting requests, including the ones which were sent but not received yet.
+    waiting_tokens: AtomicUsize,
+    /// The part of the counts which is accounted to the engine: the waiting sequences of the scheduler,
+    /// and the requests received since the engine last updated it.
+    scheduler: Mutex<QueueStatus>,
+}
+
+impl QueueState {
+    pub(crate) fn new(tokenizer: Arc<Tokenizer>) -> Self {
+        Self {
+            tokenizer,
+            waiting_requests: AtomicUsize::new(0),
+            waiting_tokens: AtomicUsize::new(0),
+            scheduler: Mutex::new(QueueStatus::default()),
+        }
+    }
+
+    pub(crate) fn status(&self) -> QueueStatus {
+        QueueStatus {
+            waiting_requests: self.waiting_requests.load(Ordering::SeqCst),
+            waiting_tokens: self.waiting_tokens.load(Ordering::SeqCst),
+        }
+    }
+
+    /// The tokens of the messages of a request which was not received by the engine yet.
+    fn prompt_tokens(&self, request: &Request) -> usize {
+        let n_toks = match &request.messages {
+            RequestMessage::CompletionTokens(toks) => toks.len(),
+            RequestMessage::Completion { text, .. } => self.n_toks(text),
+            RequestMessage::Chat(messages) => messages
+                .iter()
+                .filter_map(|message| message.get("content"))
+                .map(|content| self.n_toks(content))
+                .sum(),
+        };
+        n_toks * request.sampling_params.n_choices
+    }
+
+    fn n_toks(&self, text: &str) -> usize {
+        self.tokenizer
+            .encode(text, false)
+            .map_or(0, |encoding| encoding.len())
+    }
+
+    /// Count a request as waiting, unless this would take the waiting requests or tokens over their
+    /// limit. Otherwise, the request is not counted and the status of the queue is returned.
+    pub(crate) fn try_reserve(
+        &self,
+        request: &Request,
+        max_requests: Option<usize>,
+        max_tokens: Option<usize>,
+    ) -> Result<(), QueueStatus> {
+        let n_toks = self.prompt_tokens(request);
+        let fits = |n: usize, max: Option<usize>| !max.is_some_and(|max| n >= max);
+        if self
+            .waiting_requests
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                fits(n, max_requests).then_some(n + 1)
+            })
+            .is_err()
+        {
+            return Err(self.status());
+        }
+        if self
+            .waiting_tokens
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                fits(n, max_tokens).then_some(n + n_toks)
+            })
+            .is_err()
+        {
+            release(&self.waiting_requests, 1);
+            return Err(self.status());
+        }
+        Ok(())
+    }
+
+    /// Count a request as waiting, without any limit.
+    fn reserve(&self, request: &Request) {
+        self.waiting_requests.fetch_add(1, Ordering::SeqCst);
+        self.waiting_tokens
+            .fetch_add(self.prompt_tokens(request), Ordering::SeqCst);
+    }
+
+    /// Stop counting a request which could not be sent.
+    fn unreserve(&self, request: &Request) {
+        release(&self.waiting_requests, 1);
+        release(&self.waiting_tokens, self.prompt_tokens(request));
+    }
+
+    /// The engine received a request. Its reservation is released at the next update of the scheduler's
+    /// status, which counts its waiting sequences instead, so that the request is counted in between.
+    pub(crate) fn received(&self, request: &Request) {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        scheduler.waiting_requests += 1;
+        scheduler.waiting_tokens += self.prompt_tokens(request);
+    }
+
+    pub(crate) fn set_scheduler_status(&self, status: QueueStatus) {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        self.waiting_requests
+            .fetch_add(status.waiting_requests, Ordering::SeqCst);
+        self.waiting_tokens
+            .fetch_add(status.waiting_tokens, Ordering::SeqCst);
+        release(&self.waiting_requests, scheduler.waiting_requests);
+        release(&self.waiting_tokens, scheduler.waiting_tokens);
+        *scheduler = status;
+    }
+}
+
+/// Subtract from a count, which must include what is released.
+fn release(count: &AtomicUsize, n: usize) {
+    let released = count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+        count.checked_sub(n)
+    });
+    assert!(released.is_ok(), "Released more than the waiting count.");
+}
+
+/// Sends requests to the engine. The requests are counted as waiting until the engine receives them,
+/// so that a burst of requests is reflected in the [`QueueStatus`] before the engine gets to them.
+#[derive(Clone)]
+pub struct RequestSender {
+    sender: Sender<Request>,
+    queue: Arc<QueueState>,
+}
+
+impl RequestSender {
+    pub(crate) fn new(sender: Sender<Request>, queue: Arc<QueueState>) -> Self {
+        Self { sender, queue }
+    }
+
+    pub fn send(&self, request: Request) -> Result<(), SendError<Request>> {
+        self.queue.reserve(&request);
+        self.send_reserved(request)
+    }
+
+    /// Send a request which was already counted as waiting.
+    pub(crate) fn send_reserved(&self, request: Request) -> Result<(), SendError<Request>> {
+        self.sender.send(request).map_err(|e| {
+            self.queue.unreserve(&e.0);
+            e
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{QueueState, QueueStatus};
+    use crate::test_utils::{completion_request, word_level_tokenizer};
+
+    fn counts(queue: &QueueState) -> (usize, usize) {
+        let status = queue.status();
+        (status.waiting_requests, status.waiting_tokens)
+    }
+
+    /// Reserve the same request of 4 tokens from many threads at once, and return how many succeeded.
+    fn reserve_concurrently(
+        queue: &QueueState,
+        max_requests: Option<usize>,
+        max_tokens: Option<usize>,
+    ) -> usize {
+        std::thread::scope(|s| {
+            let handles = (0..16)
+                .map(|id| {
+                    s.spawn(move || {
+                        let (request, _rx) = completion_request(id, vec![1; 4], 1);
+                        queue
+                            .try_reserve(&request, max_requests, max_tokens)
+                            .is_ok()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .filter(|handle| handle.join().unwrap())
+                .count()
+        })
+    }
+
+    #[test]
+    fn test_concurrent_reservations() {
+        let tokenizer = Arc::new(word_level_tokenizer(&["<unk>", "a"]));
+
+        let queue = QueueState::new(tokenizer.clone());
+        assert_eq!(reserve_concurrently(&queue, Some(3), None), 3);
+        assert_eq!(counts(&queue), (3, 12));
+
+        // The requests are admitted while there are less than 10 waiting tokens.
+        let queue = QueueState::new(tokenizer);
+        assert_eq!(reserve_concurrently(&queue, None, Some(10)), 3);
+        assert_eq!(counts(&queue), (3, 12));
+    }
+
+    #[test]
+    fn test_received_request_is_counted_until_scheduled() {
+        let queue = QueueState::new(Arc::new(word_level_tokenizer(&["<unk>", "a"])));
+        let (request, _rx) = completion_request(0, vec![1; 4], 1);
+        queue.try_reserve(&request, None, None).unwrap();
+        queue.received(&request);
+        assert_eq!(counts(&queue), (1, 4));
+
+        // The scheduler runs the request.
+        queue.set_scheduler_status(QueueStatus::default());
+        assert_eq!(counts(&queue), (0, 0));
+    }
+}
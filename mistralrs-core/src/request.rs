@@ -1,40 +1,78 @@
 use indexmap::IndexMap;
 
-use crate::{response::Response, sampler::SamplingParams};
-use std::{fmt::Debug, sync::mpsc::Sender};
+use crate::{
+    response::Response,
+    sampler::SamplingParams,
+    tools::{Tool, ToolChoice},
+};
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::UnboundedSender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, a grammar (Yacc, GBNF or Lark) or a JSON schema.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    /// A GBNF grammar as used by llama.cpp, starting at `root`.
+    Gbnf(String),
+    /// A Lark grammar, starting at `start`.
+    Lark(String),
+    /// Generate JSON which validates against the schema.
+    JsonSchema(serde_json::Value),
+    /// A grammar registered by name with [`MistralRsBuilder::with_named_grammars`](crate::MistralRsBuilder::with_named_grammars).
+    Named(String),
     None,
 }
 
 #[derive(Clone, Debug)]
 /// Message or messages for a [`Request`].
+///
+/// Chat messages are passed to the chat template as is. The `tool_calls` of an assistant message
+/// are given as a JSON string, and are passed to the chat template as a list.
 pub enum RequestMessage {
     Chat(Vec<IndexMap<String, String>>),
     Completion {
         text: String,
         echo_prompt: bool,
         best_of: usize,
+        /// Remove the last prompt tokens, and constrain the output to start with their text. This lets
+        /// the model pick a better tokenization when the prompt ends in the middle of a word.
+        token_healing: bool,
     },
     CompletionTokens(Vec<u32>),
 }
 
 #[derive(Clone)]
 /// A request to the Engine, encapsulating the various parameters as well as
-/// the `mspc` response `Sender` used to return the [`Response`].
+/// the unbounded Tokio response `Sender` used to return the [`Response`]. Its receiver
+/// can be awaited, or used from synchronous code with `blocking_recv`.
 pub struct Request {
     pub messages: RequestMessage,
     pub sampling_params: SamplingParams,
-    pub response: Sender<Response>,
+    pub response: UnboundedSender<Response>,
     pub return_logprobs: bool,
     pub is_streaming: bool,
     pub id: usize,
     pub constraint: Constraint,
     pub suffix: Option<String>,
+    /// Tools the model may call, for chat requests. Ignored if the tool choice is [`ToolChoice::None`].
+    pub tools: Option<Vec<Tool>>,
+    /// Defaults to [`ToolChoice::Auto`].
+    pub tool_choice: Option<ToolChoice>,
+    /// Waiting requests with a higher priority are scheduled first. The default priority is 0.
+    pub priority: i32,
+    /// The tenant which sent the request, such as the OpenAI `user`. With fair share scheduling, waiting
+    /// requests of the same priority are scheduled from each tenant in turn.
+    pub tenant: Option<String>,
+    /// Once this passes, the request is finished with the `timeout` finish reason, returning the
+    /// output generated so far.
+    pub deadline: Option<Instant>,
+    /// The longest time the request may wait to be scheduled. If it is still waiting after this,
+    /// it is finished with the `queue_timeout` finish reason.
+    pub max_queue_time: Option<Duration>,
 }
 
 impl Debug for Request {
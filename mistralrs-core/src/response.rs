@@ -3,7 +3,7 @@ use std::error::Error;
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::sampler::TopLogprob;
+use crate::{sampler::TopLogprob, tools::ToolType};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -18,12 +18,38 @@ macro_rules! generate_repr {
     };
 }
 
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Debug, Clone, Serialize)]
+pub struct CalledFunction {
+    pub name: String,
+    /// The arguments as a JSON string.
+    pub arguments: String,
+}
+
+generate_repr!(CalledFunction);
+
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolCallResponse {
+    pub id: String,
+    pub index: usize,
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: CalledFunction,
+}
+
+generate_repr!(ToolCallResponse);
+
 #[pyclass]
 #[pyo3(get_all)]
 #[derive(Debug, Clone, Serialize)]
 pub struct ResponseMessage {
     pub content: String,
     pub role: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCallResponse>,
 }
 
 generate_repr!(ResponseMessage);
@@ -34,6 +60,8 @@ generate_repr!(ResponseMessage);
 pub struct Delta {
     pub content: String,
     pub role: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCallResponse>,
 }
 
 generate_repr!(Delta);
@@ -44,6 +72,8 @@ generate_repr!(Delta);
 pub struct ResponseLogprob {
     pub token: String,
     pub logprob: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_logprob: Option<f32>,
     pub bytes: Vec<u8>,
     pub top_logprobs: Vec<TopLogprob>,
 }
@@ -159,6 +189,33 @@ pub struct CompletionResponse {
 
 generate_repr!(CompletionResponse);
 
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletionChunkChoice {
+    pub text: String,
+    pub index: usize,
+    pub logprobs: Option<ResponseLogprob>,
+    pub finish_reason: Option<String>,
+}
+
+generate_repr!(CompletionChunkChoice);
+
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Debug, Clone, Serialize)]
+/// An OpenAI compatible streamed completion chunk.
+pub struct CompletionChunkResponse {
+    pub id: String,
+    pub choices: Vec<CompletionChunkChoice>,
+    pub created: u128,
+    pub model: String,
+    pub system_fingerprint: String,
+    pub object: String,
+}
+
+generate_repr!(CompletionChunkResponse);
+
 /// The response enum contains 3 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no suffix or prefix)
@@ -173,4 +230,5 @@ pub enum Response {
     // Completion
     CompletionModelError(String, CompletionResponse),
     CompletionDone(CompletionResponse),
+    CompletionChunk(CompletionChunkResponse),
 }
@@ -1,12 +1,15 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use std::{collections::HashMap, iter::zip, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use candle_core::{bail, Device, Error, Result, Tensor, D};
 use pyo3::pyclass;
 use rand::{
     distributions::{Distribution, WeightedIndex},
-    SeedableRng,
+    Rng, SeedableRng,
 };
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
@@ -18,19 +21,139 @@ pub enum StopTokens {
     Ids(Vec<u32>),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Mirostat sampling (https://arxiv.org/abs/2007.14966), which adapts the truncation of the distribution
+/// at each step so that the surprise of the generated text stays close to the target `tau`. The threshold
+/// `mu` is updated with the learning rate `eta`.
+pub enum Mirostat {
+    /// Mirostat 1.0, which estimates the Zipf exponent of the distribution to choose a top-k value.
+    V1 { tau: f32, eta: f32 },
+    /// Mirostat 2.0, which discards the tokens with a surprise above `mu`.
+    V2 { tau: f32, eta: f32 },
+}
+
+impl Mirostat {
+    pub const DEFAULT_TAU: f32 = 5.0;
+    pub const DEFAULT_ETA: f32 = 0.1;
+
+    /// Select Mirostat by its version number, as used by llama.cpp. Version 0 (or any other number) disables it.
+    pub fn from_version(version: usize, tau: Option<f32>, eta: Option<f32>) -> Option<Self> {
+        let tau = tau.unwrap_or(Self::DEFAULT_TAU);
+        let eta = eta.unwrap_or(Self::DEFAULT_ETA);
+        match version {
+            1 => Some(Self::V1 { tau, eta }),
+            2 => Some(Self::V2 { tau, eta }),
+            _ => None,
+        }
+    }
+
+    fn tau(&self) -> f32 {
+        match self {
+            Self::V1 { tau, .. } | Self::V2 { tau, .. } => *tau,
+        }
+    }
+
+    fn eta(&self) -> f32 {
+        match self {
+            Self::V1 { eta, .. } | Self::V2 { eta, .. } => *eta,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// DRY ("don't repeat yourself") sampling penalizes the tokens which would extend a sequence that already
+/// occurs in the context. A token which would make a repeat of `n >= allowed_length` tokens has its logit
+/// reduced by `multiplier * base^(n - allowed_length)`. Repeats do not extend across the sequence breakers.
+pub struct DrySamplingParams {
+    pub multiplier: f32,
+    pub base: f32,
+    pub allowed_length: usize,
+    pub sequence_breakers: Vec<String>,
+}
+
+impl DrySamplingParams {
+    pub const DEFAULT_BASE: f32 = 1.75;
+    pub const DEFAULT_ALLOWED_LENGTH: usize = 2;
+
+    /// Use the default values for the parameters which are not specified.
+    pub fn new_with_defaults(
+        multiplier: f32,
+        base: Option<f32>,
+        allowed_length: Option<usize>,
+        sequence_breakers: Option<Vec<String>>,
+    ) -> Self {
+        Self {
+            multiplier,
+            base: base.unwrap_or(Self::DEFAULT_BASE),
+            allowed_length: allowed_length.unwrap_or(Self::DEFAULT_ALLOWED_LENGTH),
+            sequence_breakers: sequence_breakers.unwrap_or_else(|| {
+                ["\n", ":", "\"", "*"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// DRY parameters with the sequence breakers as token ids.
+#[derive(Clone, Debug)]
+struct DryPenalty {
+    multiplier: f32,
+    base: f32,
+    allowed_length: usize,
+    sequence_breakers: HashSet<u32>,
+}
+
+impl DryPenalty {
+    fn new(params: DrySamplingParams, tokenizer: &Tokenizer) -> Self {
+        // A sequence breaker is matched by the last token of its encoding.
+        let sequence_breakers = params
+            .sequence_breakers
+            .iter()
+            .filter_map(|breaker| tokenizer.encode(breaker.as_str(), false).ok())
+            .filter_map(|encoding| encoding.get_ids().last().copied())
+            .collect();
+        Self {
+            multiplier: params.multiplier,
+            base: params.base,
+            allowed_length: params.allowed_length,
+            sequence_breakers,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 /// Sampling params are used to control sampling.
 pub struct SamplingParams {
     pub temperature: Option<f64>,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
+    /// Discard tokens with a probability below `min_p` times that of the most likely token.
+    pub min_p: Option<f64>,
+    /// Locally typical sampling: keep the tokens closest to the expected information content,
+    /// up to a cumulative probability of `typical_p`.
+    pub typical_p: Option<f64>,
+    /// Tail-free sampling: discard the tail of the distribution, found using the second derivative
+    /// of the sorted probabilities.
+    pub tfs_z: Option<f64>,
+    /// Use Mirostat instead of the other truncation methods. It has no effect without a temperature.
+    pub mirostat: Option<Mirostat>,
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    /// CTRL-style repetition penalty (https://arxiv.org/abs/1909.05858): the logits of the tokens in the penalty
+    /// context are divided by this if they are positive, and multiplied by it otherwise. It must be positive.
+    pub repetition_penalty: Option<f32>,
+    pub dry_params: Option<DrySamplingParams>,
     pub stop_toks: Option<StopTokens>,
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
+    /// Seed for the random number generator. A random seed is used if this is `None`.
+    pub seed: Option<u64>,
+    /// Also report the logprobs of the raw model logits, before the penalties and biases are applied.
+    pub raw_logprobs: bool,
 }
 
 /// Sampler for sampling.
@@ -42,9 +165,18 @@ pub struct Sampler {
     tokenizer: Arc<Tokenizer>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    repetition_penalty: Option<f32>,
+    dry_penalty: Option<DryPenalty>,
     logits_bias: Option<Tensor>,
     topk: i64,
     topp: f64,
+    minp: f64,
+    typicalp: f64,
+    tfsz: f64,
+    mirostat: Option<Mirostat>,
+    /// The running surprise threshold of Mirostat, kept across the steps of a sequence.
+    mirostat_mu: f32,
+    raw_logprobs: bool,
 }
 
 #[pyclass]
@@ -54,6 +186,8 @@ pub struct Sampler {
 pub struct TopLogprob {
     pub token: u32,
     pub logprob: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_logprob: Option<f32>,
     pub bytes: String,
 }
 
@@ -61,6 +195,7 @@ pub struct TopLogprob {
 pub struct Logprobs {
     pub token: u32,
     pub logprob: f32,
+    pub raw_logprob: Option<f32>,
     pub bytes: String,
     pub top_logprobs: Option<Vec<TopLogprob>>,
 }
@@ -74,15 +209,23 @@ impl Sampler {
         tokenizer: Arc<Tokenizer>,
         frequency_penalty: Option<f32>,
         presence_penalty: Option<f32>,
+        repetition_penalty: Option<f32>,
+        dry_params: Option<DrySamplingParams>,
         logits_bias: Option<Tensor>,
         topk: i64,
         topp: f64,
+        minp: f64,
+        typicalp: f64,
+        tfsz: f64,
+        mirostat: Option<Mirostat>,
+        raw_logprobs: bool,
     ) -> Self {
         let temperature = if temperature.map_or(true, |v| v < 1e-7) {
             None
         } else {
             temperature
         };
+        let dry_penalty = dry_params.map(|params| DryPenalty::new(params, &tokenizer));
         Self {
             rng: rand::rngs::StdRng::seed_from_u64(seed),
             temperature,
@@ -90,68 +233,76 @@ impl Sampler {
             tokenizer,
             frequency_penalty,
             presence_penalty,
+            repetition_penalty,
+            dry_penalty,
             logits_bias,
             topk,
             topp,
+            minp,
+            typicalp,
+            tfsz,
+            mirostat,
+            mirostat_mu: mirostat.map_or(0.0, |mirostat| 2.0 * mirostat.tau()),
+            raw_logprobs,
         }
     }
 
+    /// The `top_n_logprobs` most likely tokens, by descending log-probability.
     fn get_top_logprobs(
         &self,
-        probs: &[f32],
-        argsort_indices: &[usize],
+        logprobs: &[f32],
+        raw_logprobs: Option<&[f32]>,
     ) -> Result<Vec<TopLogprob>> {
-        let mut argsort_indices_sorted = argsort_indices.to_vec();
-        // Sort by descending prob
-        argsort_indices_sorted
-            .sort_by(|a, b| probs[*b].partial_cmp(&probs[*a]).expect("No ordering."));
-        // These are where the top n are
-        let top_n_toks_range = 0..self.top_n_logprobs;
-        // The top n's values
-        let top_n_logprobs = argsort_indices_sorted[top_n_toks_range.clone()]
-            .iter()
-            .map(|x| probs[*x].log(10.0))
-            .collect::<Vec<_>>();
-        // Find where they actually are in the logits
-        let mut top_n_toks = Vec::new();
-        for val in top_n_toks_range {
-            top_n_toks.push(argsort_indices[val]);
-        }
-
-        let mut bytes = Vec::new();
-        for tok in &top_n_toks {
-            bytes.push(
-                self.tokenizer
-                    .decode(&[*tok as u32], true)
+        let mut argsort_indices = (0..logprobs.len()).collect::<Vec<_>>();
+        argsort_indices.sort_unstable_by(|&i, &j| {
+            logprobs[j].partial_cmp(&logprobs[i]).expect("No ordering.")
+        });
+        argsort_indices.truncate(self.top_n_logprobs);
+
+        let mut top_logprobs = Vec::new();
+        for token in argsort_indices {
+            top_logprobs.push(TopLogprob {
+                token: token as u32,
+                logprob: logprobs[token],
+                raw_logprob: raw_logprobs.map(|raw_logprobs| raw_logprobs[token]),
+                bytes: self
+                    .tokenizer
+                    .decode(&[token as u32], true)
                     .map_err(|x| Error::Msg(x.to_string()))?,
-            );
+            });
         }
-        Ok(zip(bytes, zip(top_n_toks, top_n_logprobs))
-            .map(|(bytes, (token, logprob))| TopLogprob {
-                token: token as u32,
-                logprob,
-                bytes,
-            })
-            .collect::<Vec<_>>())
+        Ok(top_logprobs)
     }
 
-    fn sample_argmax(&mut self, logits: Tensor, return_logprobs: bool) -> Result<Logprobs> {
-        let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
-
-        let probs: Vec<f32> = logits.to_vec1()?;
-
-        let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-        let logprob = probs[next_token as usize].log(10.0);
+    /// Build the logprobs of the sampled token. They are the natural log-softmax of the logits after the
+    /// penalties and biases, and before the temperature and truncation. If enabled, the log-softmax of
+    /// the raw model logits is also reported.
+    fn get_logprobs(
+        &self,
+        next_token: u32,
+        logits: &Tensor,
+        raw_logits: Vec<f32>,
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
+        let logprobs: Vec<f32> = candle_nn::ops::log_softmax(logits, D::Minus1)?.to_vec1()?;
+        let raw_logprobs: Option<Vec<f32>> = if self.raw_logprobs {
+            let vocab_size = raw_logits.len();
+            let raw_logits = Tensor::from_vec(raw_logits, vocab_size, &Device::Cpu)?;
+            Some(candle_nn::ops::log_softmax(&raw_logits, D::Minus1)?.to_vec1()?)
+        } else {
+            None
+        };
 
         let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+            Some(self.get_top_logprobs(&logprobs, raw_logprobs.as_deref())?)
         } else {
             None
         };
 
         Ok(Logprobs {
             token: next_token,
-            logprob,
+            logprob: logprobs[next_token as usize],
+            raw_logprob: raw_logprobs.map(|raw_logprobs| raw_logprobs[next_token as usize]),
             top_logprobs,
             bytes: self
                 .tokenizer
@@ -160,46 +311,106 @@ impl Sampler {
         })
     }
 
-    fn sample_multinomial(
-        &mut self,
-        probs: &mut Vec<f32>,
-        argsort_indices: Vec<usize>,
-        return_logprobs: bool,
-    ) -> Result<Logprobs> {
-        let distr = WeightedIndex::new(&*probs).map_err(Error::wrap)?;
+    fn sample_argmax(&self, logits: &Tensor) -> Result<u32> {
+        logits.argmax(D::Minus1)?.to_scalar::<u32>()
+    }
+
+    fn sample_multinomial(&mut self, probs: &[f32]) -> Result<u32> {
+        let distr = WeightedIndex::new(probs).map_err(Error::wrap)?;
         let next_token = distr.sample(&mut self.rng); // "Find the first item which has a weight *higher* than the chosen weight."
-        let logprob = probs[next_token].log(10.0);
+        Ok(next_token as u32)
+    }
 
-        let top_logprobs = if return_logprobs {
-            Some(self.get_top_logprobs(probs, &argsort_indices)?)
+    /// Tail-free sampling (https://www.trentonbricken.com/Tail-Free-Sampling/). The tail starts where the
+    /// cumulative sum of the normalized absolute second derivative of the sorted probabilities exceeds `z`.
+    fn apply_tfs(probs: &mut [f32], argsort_indices: &[usize], z: f32) {
+        let kept = argsort_indices
+            .iter()
+            .copied()
+            .filter(|&i| probs[i] > 0.0)
+            .collect::<Vec<_>>();
+        if kept.len() < 3 {
+            return;
+        }
+        let sorted = kept.iter().map(|&i| probs[i]).collect::<Vec<_>>();
+        let first_derivatives = sorted.windows(2).map(|w| w[0] - w[1]).collect::<Vec<_>>();
+        let mut second_derivatives = first_derivatives
+            .windows(2)
+            .map(|w| (w[0] - w[1]).abs())
+            .collect::<Vec<_>>();
+        let sum = second_derivatives.iter().sum::<f32>();
+        if sum > 1e-6 {
+            second_derivatives.iter_mut().for_each(|d| *d /= sum);
         } else {
-            None
-        };
+            second_derivatives
+                .iter_mut()
+                .for_each(|d| *d = 1.0 / kept.len() as f32);
+        }
 
-        Ok(Logprobs {
-            token: next_token as u32,
-            logprob,
-            top_logprobs,
-            bytes: self
-                .tokenizer
-                .decode(&[next_token.try_into().unwrap()], true)
-                .map_err(|x| Error::Msg(x.to_string()))?,
-        })
+        let mut cumsum = 0.;
+        let mut n_kept = kept.len();
+        for (index, d) in second_derivatives.iter().enumerate() {
+            cumsum += d;
+            if cumsum > z && index >= 1 {
+                n_kept = index;
+                break;
+            }
+        }
+        for index in &kept[n_kept..] {
+            probs[*index] = 0.0;
+        }
     }
 
-    fn sample_topkp(
-        &mut self,
-        probs: &mut Vec<f32>,
-        top_k: i64,
-        top_p: f32,
-        return_logprobs: bool,
-    ) -> Result<Logprobs> {
+    /// Locally typical sampling (https://arxiv.org/abs/2202.00666). Keep the tokens whose information content
+    /// is closest to the entropy of the distribution, until their cumulative probability reaches `typical_p`.
+    fn apply_typical(probs: &mut [f32], argsort_indices: &[usize], typical_p: f32) {
+        let mut kept = argsort_indices
+            .iter()
+            .copied()
+            .filter(|&i| probs[i] > 0.0)
+            .collect::<Vec<_>>();
+        let sum = kept.iter().map(|&i| probs[i]).sum::<f32>();
+        let entropy = -kept
+            .iter()
+            .map(|&i| probs[i] / sum)
+            .map(|p| p * p.ln())
+            .sum::<f32>();
+        let surprisal_distance = |i: usize| (-(probs[i] / sum).ln() - entropy).abs();
+        kept.sort_by(|&i, &j| {
+            surprisal_distance(i)
+                .partial_cmp(&surprisal_distance(j))
+                .expect("No ordering.")
+        });
+
+        let mut cumsum = 0.;
+        for index in kept {
+            if cumsum >= typical_p {
+                probs[index] = 0.0;
+            } else {
+                cumsum += probs[index] / sum;
+            }
+        }
+    }
+
+    fn sample_truncated(&mut self, probs: &mut [f32]) -> Result<u32> {
+        self.truncate(probs);
+        self.sample_multinomial(probs)
+    }
+
+    /// Clamp the probabilities removed by `top-k`, tail-free, typical, `top-p` and `min-p` sampling to zero.
+    fn truncate(&self, probs: &mut [f32]) {
         let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
 
         // Sort by descending probability.
         argsort_indices
             .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
+        let top_k = self.topk;
+        let top_p = self.topp as f32;
+        let min_p = self.minp as f32;
+        let typical_p = self.typicalp as f32;
+        let tfs_z = self.tfsz as f32;
+
         if top_k > 0 {
             // Clamp smaller probabilities to zero.
             for (index, val) in argsort_indices.iter().enumerate() {
@@ -209,30 +420,117 @@ impl Sampler {
             }
         }
 
-        if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs);
+        if tfs_z > 0.0 && tfs_z < 1.0 {
+            Self::apply_tfs(probs, &argsort_indices, tfs_z);
         }
-        // TOP P
 
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
+        if typical_p > 0.0 && typical_p < 1.0 {
+            Self::apply_typical(probs, &argsort_indices, typical_p);
+        }
 
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index] = 0.0;
-            } else {
-                cumsum += probs[*index];
+        if top_p > 0.0 && top_p < 1.0 {
+            // TOP P
+
+            // top-p sampling (or "nucleus sampling") samples from the smallest set of
+            // tokens that exceed probability top_p. This way we never sample tokens that
+            // have very low probabilities and are less likely to go "off the rails".
+
+            // Clamp smaller probabilities to zero.
+            let mut cumsum = 0.;
+            for index in &argsort_indices {
+                if cumsum >= top_p {
+                    probs[*index] = 0.0;
+                } else {
+                    cumsum += probs[*index];
+                }
             }
         }
 
-        // Sample with clamped probabilities.
-        self.sample_multinomial(probs, argsort_indices, return_logprobs)
+        if min_p > 0.0 && min_p < 1.0 {
+            // MIN P
+
+            // min-p sampling discards the tokens which are much less likely than the most likely one. Unlike
+            // top-p, the cutoff scales with the confidence of the model, which works better at high temperatures.
+            let max_prob = probs.iter().copied().fold(0.0f32, f32::max);
+            let threshold = min_p * max_prob;
+            for index in &argsort_indices {
+                if probs[*index] < threshold {
+                    probs[*index] = 0.0;
+                }
+            }
+        }
+    }
+
+    fn sample_mirostat(&mut self, probs: &mut [f32], mirostat: Mirostat) -> Result<u32> {
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+
+        // Sort by descending probability.
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+
+        match mirostat {
+            Mirostat::V1 { .. } => {
+                // Estimate the Zipf exponent from the most likely tokens, and use it to find the top-k value
+                // which gives the target surprise.
+                const M: usize = 100;
+                let (mut sum_ti_bi, mut sum_ti_sq) = (0.0f32, 0.0f32);
+                for i in 0..(M - 1).min(argsort_indices.len() - 1) {
+                    let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+                    let b_i = (probs[argsort_indices[i]] / probs[argsort_indices[i + 1]]).ln();
+                    sum_ti_bi += t_i * b_i;
+                    sum_ti_sq += t_i * t_i;
+                }
+                let s_hat = sum_ti_bi / sum_ti_sq;
+                let epsilon_hat = s_hat - 1.0;
+                let n_vocab = probs.len() as f32;
+                let k = ((epsilon_hat * 2f32.powf(self.mirostat_mu))
+                    / (1.0 - n_vocab.powf(-epsilon_hat)))
+                .powf(1.0 / s_hat);
+                // A NaN k (from a degenerate distribution) also keeps only the most likely token.
+                let k = if k.is_finite() {
+                    k.max(1.0) as usize
+                } else {
+                    1
+                };
+                for index in argsort_indices.iter().skip(k) {
+                    probs[*index] = 0.0;
+                }
+            }
+            Mirostat::V2 { .. } => {
+                // Discard the tokens which are more surprising than `mu`, always keeping the most likely one.
+                for index in argsort_indices.iter().skip(1) {
+                    if -probs[*index].log2() > self.mirostat_mu {
+                        probs[*index] = 0.0;
+                    }
+                }
+            }
+        }
+
+        let next_token = self.sample_multinomial(probs)?;
+
+        // Update `mu` with the surprise of the sampled token in the truncated distribution.
+        let sum = probs.iter().sum::<f32>();
+        let observed_surprise = -(probs[next_token as usize] / sum).log2();
+        self.mirostat_mu -= mirostat.eta() * (observed_surprise - mirostat.tau());
+        Ok(next_token)
     }
 
     fn apply_penalties(&self, mut logits: Vec<f32>, context: Option<&[u32]>) -> Result<Tensor> {
+        if self.repetition_penalty.is_some() && context.is_none() {
+            bail!("Must specify penalty context.");
+        }
+        if let (Some(penalty), Some(context)) = (self.repetition_penalty, context) {
+            let context = context.iter().copied().collect::<HashSet<_>>();
+            for token_id in context {
+                let logit = &mut logits[token_id as usize];
+                if *logit >= 0.0 {
+                    *logit /= penalty;
+                } else {
+                    *logit *= penalty;
+                }
+            }
+        }
+
         if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
             if context.is_none() {
                 bail!("Must specify penalty context.");
@@ -255,44 +553,188 @@ impl Sampler {
                     - if count > 0.0 { 1. } else { 0. } * presence_penalty;
             }
         }
+
+        if let Some(ref dry_penalty) = self.dry_penalty {
+            if context.is_none() {
+                bail!("Must specify penalty context.");
+            }
+            Self::apply_dry_penalty(dry_penalty, &mut logits, context.unwrap());
+        }
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
+    fn apply_dry_penalty(dry_penalty: &DryPenalty, logits: &mut [f32], context: &[u32]) {
+        let Some(&last_token) = context.last() else {
+            return;
+        };
+        if dry_penalty.sequence_breakers.contains(&last_token) {
+            return;
+        }
+
+        // For each earlier occurrence of the last token, find how far the match with the end of the context
+        // extends backwards. The token which followed that occurrence would extend the repeat.
+        let mut match_lengths = HashMap::new();
+        for (index, _) in context[..context.len() - 1]
+            .iter()
+            .enumerate()
+            .filter(|(_, tok)| **tok == last_token)
+        {
+            let next_token = context[index + 1];
+            if dry_penalty.sequence_breakers.contains(&next_token) {
+                continue;
+            }
+            let mut match_length = 1;
+            while match_length <= index {
+                let previous = context[context.len() - 1 - match_length];
+                if context[index - match_length] != previous
+                    || dry_penalty.sequence_breakers.contains(&previous)
+                {
+                    break;
+                }
+                match_length += 1;
+            }
+            let entry = match_lengths.entry(next_token).or_insert(0);
+            *entry = match_length.max(*entry);
+        }
+
+        for (token, match_length) in match_lengths {
+            if match_length >= dry_penalty.allowed_length {
+                let exponent = (match_length - dry_penalty.allowed_length) as f32;
+                logits[token as usize] -= dry_penalty.multiplier * dry_penalty.base.powf(exponent);
+            }
+        }
+    }
+
     /// Sample the provided tokens.
     ///
     /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
-    /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
-    /// If `frequency_penalty.is_some()` or `presence_penalty.is_some()`, then `penalty_ctxt` must be provided.
+    /// The distribution is truncated with `top-k`, tail-free, typical, `top-p` and `min-p` sampling, in that
+    /// order. Each of these is disabled if its value is `<= 0.0` or `>= 1.0` (or `<= 0` for `top-k`).
+    /// If Mirostat is used, it replaces all of these.
+    /// If any of the penalties (frequency, presence, repetition or DRY) is used, then `penalty_ctxt` must be provided.
+    /// `constraint_bias` is added to the logits like the logits bias, and is used to mask out the tokens which
+    /// are not allowed by a grammar. It must be on the CPU.
     pub fn sample(
         &mut self,
         logits: Tensor,
         penalty_ctxt: Option<&[u32]>,
         return_logprobs: bool,
+        constraint_bias: Option<&Tensor>,
     ) -> Result<Logprobs> {
-        let logits = self.apply_penalties(logits.to_vec1()?, penalty_ctxt)?;
+        let raw_logits: Vec<f32> = logits.to_vec1()?;
+        let logits = self.apply_biases(raw_logits.clone(), penalty_ctxt, constraint_bias)?;
+        let next_token = match self.temperature {
+            None => self.sample_argmax(&logits)?,
+            Some(temperature) => {
+                let scaled_logits = (&logits / temperature)?;
+                let probs = candle_nn::ops::softmax_last_dim(&scaled_logits)?;
+                let mut probs: Vec<f32> = probs.to_vec1()?;
+                match self.mirostat {
+                    Some(mirostat) => self.sample_mirostat(&mut probs, mirostat)?,
+                    None => self.sample_truncated(&mut probs)?,
+                }
+            }
+        };
+        self.get_logprobs(next_token, &logits, raw_logits, return_logprobs)
+    }
+
+    /// Apply the penalties, the logits bias and the constraint bias to the raw logits.
+    fn apply_biases(
+        &self,
+        raw_logits: Vec<f32>,
+        penalty_ctxt: Option<&[u32]>,
+        constraint_bias: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let logits = self.apply_penalties(raw_logits, penalty_ctxt)?;
         let logits = match self.logits_bias {
-            Some(ref bias) => (logits + bias)?,
+            Some(ref bias) => (logits + bias.to_device(&Device::Cpu)?)?,
             None => logits,
         };
-        let next_token = match self.temperature {
-            None => self.sample_argmax(logits, return_logprobs)?,
+        match constraint_bias {
+            Some(bias) => logits + bias,
+            None => Ok(logits),
+        }
+    }
+
+    /// Whether the sampler keeps state across steps, so that the distribution of a step depends on the
+    /// tokens sampled before it.
+    pub fn is_stateful(&self) -> bool {
+        self.mirostat.is_some()
+    }
+
+    /// The running surprise threshold of Mirostat, which [`Sampler::sample`] updates.
+    pub fn mirostat_mu(&self) -> f32 {
+        self.mirostat_mu
+    }
+
+    /// Restore the surprise threshold of Mirostat, undoing the update of a draw which is discarded.
+    pub fn set_mirostat_mu(&mut self, mirostat_mu: f32) {
+        self.mirostat_mu = mirostat_mu;
+    }
+
+    /// The normalized distribution which [`Sampler::sample`] draws the next token from, without a constraint
+    /// bias. With argmax sampling, all of the probability is on the most likely token. Mirostat is not
+    /// supported, as its truncation changes at each step.
+    pub fn probs(&self, logits: &Tensor, penalty_ctxt: Option<&[u32]>) -> Result<Vec<f32>> {
+        if self.mirostat.is_some() {
+            bail!("The distribution of Mirostat sampling depends on the previous steps.");
+        }
+        let logits = self.apply_biases(logits.to_vec1()?, penalty_ctxt, None)?;
+        let mut probs = match self.temperature {
+            None => {
+                let mut probs = vec![0.0; logits.dim(0)?];
+                probs[self.sample_argmax(&logits)? as usize] = 1.0;
+                probs
+            }
             Some(temperature) => {
-                let logits = (&logits / temperature)?;
-                let probs = candle_nn::ops::softmax_last_dim(&logits)?;
-                let mut probs: Vec<f32> = probs.to_vec1()?;
-                self.sample_topkp(&mut probs, self.topk, self.topp as f32, return_logprobs)?
+                let scaled_logits = (&logits / temperature)?;
+                let mut probs: Vec<f32> =
+                    candle_nn::ops::softmax_last_dim(&scaled_logits)?.to_vec1()?;
+                self.truncate(&mut probs);
+                probs
             }
         };
-        Ok(next_token)
+        let sum = probs.iter().sum::<f32>();
+        for prob in &mut probs {
+            *prob /= sum;
+        }
+        Ok(probs)
+    }
+
+    /// Sample a token from a distribution, which does not need to be normalized.
+    pub fn sample_probs(&mut self, probs: &[f32]) -> Result<u32> {
+        self.sample_multinomial(probs)
+    }
+
+    /// The rejection sampling test of speculative decoding: a token proposed by the draft model with
+    /// probability `draft_prob` is accepted with probability `min(1, target_prob / draft_prob)`.
+    pub fn accept_draft(&mut self, target_prob: f32, draft_prob: f32) -> bool {
+        self.rng.gen::<f32>() * draft_prob < target_prob
+    }
+
+    /// The logprobs of a token which was chosen from these logits outside of [`Sampler::sample`], such as
+    /// a token accepted by speculative decoding.
+    pub fn logprobs(
+        &self,
+        token: u32,
+        logits: &Tensor,
+        penalty_ctxt: Option<&[u32]>,
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
+        let raw_logits: Vec<f32> = logits.to_vec1()?;
+        let logits = self.apply_biases(raw_logits.clone(), penalty_ctxt, None)?;
+        self.get_logprobs(token, &logits, raw_logits, return_logprobs)
     }
 }
 
+#[cfg(test)]
 mod tests {
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
     use tokenizers::Tokenizer;
 
-    #[allow(dead_code)]
+    use crate::test_utils::{word_level_tokenizer, TestSequence};
+
     fn get_tokenizer() -> Tokenizer {
         let api = ApiBuilder::new()
             .with_progress(true)
@@ -309,6 +751,207 @@ mod tests {
         Tokenizer::from_file(tokenizer_filename).unwrap()
     }
 
+    /// The vocabulary of the test samplers, which does not need to be downloaded.
+    const VOCAB: [&str; 5] = ["<unk>", "a", "b", "c", ":"];
+
+    /// A sequence of `VOCAB` without a prompt, to build the samplers of the tests.
+    fn test_sequence() -> TestSequence {
+        TestSequence::new(word_level_tokenizer(&VOCAB).into(), vec![])
+    }
+
+    /// An argmax sampler of `VOCAB` without penalties or truncation.
+    fn argmax_sampler() -> super::Sampler {
+        test_sequence().sampler()
+    }
+
+    /// The penalized logits of `[a, b, c, :]`, which are all 0 before the penalty.
+    fn dry_penalized(
+        allowed_length: usize,
+        sequence_breakers: &[&str],
+        context: &[u32],
+    ) -> Vec<f32> {
+        use super::DrySamplingParams;
+
+        let params = DrySamplingParams::new_with_defaults(
+            1.0,
+            Some(2.0),
+            Some(allowed_length),
+            Some(sequence_breakers.iter().map(|s| s.to_string()).collect()),
+        );
+        let sampler = test_sequence().with_dry_params(params).sampler();
+        let logits = sampler
+            .apply_penalties(vec![0.0; 5], Some(context))
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        logits[1..].to_vec()
+    }
+
+    /// The tokens which are left after the truncation of `sampler`.
+    fn kept(sampler: &super::Sampler, probs: &[f32]) -> Vec<usize> {
+        let mut probs = probs.to_vec();
+        sampler.truncate(&mut probs);
+        (0..probs.len()).filter(|i| probs[*i] > 0.0).collect()
+    }
+
+    /// Sorted by descending probability, the tokens are 1, 3, 4, 2 and 0.
+    const PROBS: [f32; 5] = [0.05, 0.4, 0.1, 0.25, 0.2];
+
+    #[test]
+    fn test_top_k() {
+        let mut sampler = argmax_sampler();
+        sampler.topk = 2;
+        assert_eq!(kept(&sampler, &PROBS), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_top_p() {
+        let mut sampler = argmax_sampler();
+        // The most likely tokens are kept until their cumulative probability reaches 0.7.
+        sampler.topp = 0.7;
+        assert_eq!(kept(&sampler, &PROBS), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn test_min_p() {
+        let mut sampler = argmax_sampler();
+        // The threshold is 0.6 * 0.4.
+        sampler.minp = 0.6;
+        assert_eq!(kept(&sampler, &PROBS), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_typical_p() {
+        let mut sampler = argmax_sampler();
+        // The tokens closest to the entropy come first, so the most likely token is discarded.
+        sampler.typicalp = 0.3;
+        assert_eq!(kept(&sampler, &PROBS), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_tail_free() {
+        // The second derivative is largest between the third and fourth tokens, where the tail starts.
+        let probs = [0.35, 0.3, 0.25, 0.04, 0.03, 0.02, 0.01];
+        let mut sampler = argmax_sampler();
+        sampler.tfsz = 0.5;
+        assert_eq!(kept(&sampler, &probs), vec![0, 1]);
+        sampler.tfsz = 0.3;
+        assert_eq!(kept(&sampler, &probs), vec![0]);
+    }
+
+    #[test]
+    fn test_truncation_order() {
+        // Typical sampling after top-k only considers the 3 tokens left by top-k. The other way around, it
+        // would keep tokens 3 and 4.
+        let mut sampler = argmax_sampler();
+        sampler.topk = 3;
+        sampler.typicalp = 0.3;
+        assert_eq!(kept(&sampler, &PROBS), vec![1, 3]);
+
+        // Top-p after typical sampling only considers the 3 tokens left by typical sampling. The other way
+        // around, it would only keep token 1.
+        let mut sampler = argmax_sampler();
+        sampler.typicalp = 0.5;
+        sampler.topp = 0.5;
+        assert_eq!(kept(&sampler, &PROBS), vec![1, 3]);
+
+        // Min-p after typical sampling is relative to the most likely token which is left. The other way
+        // around, it would only keep token 1.
+        let mut sampler = argmax_sampler();
+        sampler.typicalp = 0.3;
+        sampler.minp = 0.6;
+        assert_eq!(kept(&sampler, &PROBS), vec![3, 4]);
+    }
+
+    /// Sample `PROBS` with Mirostat, returning the sampled token and the updated `mu`.
+    fn mirostat_step(mirostat: super::Mirostat) -> (u32, f32) {
+        let mut sampler = argmax_sampler();
+        sampler.mirostat = Some(mirostat);
+        sampler.mirostat_mu = 2.0 * mirostat.tau();
+        let mut probs = PROBS.to_vec();
+        let token = sampler.sample_mirostat(&mut probs, mirostat).unwrap();
+        (token, sampler.mirostat_mu())
+    }
+
+    #[test]
+    fn test_mirostat_v1() {
+        use super::Mirostat;
+
+        // With `mu = 2`, the estimated Zipf exponent of `PROBS` gives `k = 2`, so only tokens 1 and 3 are kept.
+        let (tau, eta) = (1.0, 0.1);
+        let (token, mu) = mirostat_step(Mirostat::V1 { tau, eta });
+        let kept = PROBS[1] + PROBS[3];
+        let surprise = match token {
+            1 | 3 => -(PROBS[token as usize] / kept).log2(),
+            _ => panic!("Token {token} should have been discarded."),
+        };
+        assert!(
+            (mu - (2.0 * tau - eta * (surprise - tau))).abs() < 1e-5,
+            "{mu}"
+        );
+    }
+
+    #[test]
+    fn test_mirostat_v2() {
+        use super::Mirostat;
+
+        // With `mu = 3`, tokens 2 and 0 are more surprising than `mu`, and are discarded.
+        let (tau, eta) = (1.5, 0.1);
+        let (token, mu) = mirostat_step(Mirostat::V2 { tau, eta });
+        let kept = PROBS[1] + PROBS[3] + PROBS[4];
+        let surprise = match token {
+            1 | 3 | 4 => -(PROBS[token as usize] / kept).log2(),
+            _ => panic!("Token {token} should have been discarded."),
+        };
+        assert!(
+            (mu - (2.0 * tau - eta * (surprise - tau))).abs() < 1e-5,
+            "{mu}"
+        );
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        let sampler = test_sequence().with_repetition_penalty(2.0).sampler();
+        // `a` and `b` are in the context: the positive logit is divided, the negative one multiplied.
+        let logits = sampler
+            .apply_penalties(vec![1.0, 4.0, -2.0, 3.0, 0.0], Some(&[1, 2, 1]))
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        assert_eq!(logits, vec![1.0, 2.0, -4.0, 3.0, 0.0]);
+        assert!(sampler.apply_penalties(vec![0.0; 5], None).is_err());
+    }
+
+    #[test]
+    fn test_dry_penalty() {
+        // `a b c a b`: `c` would repeat `a b c`, a match of 2 tokens which is penalized by `base^0`.
+        assert_eq!(
+            dry_penalized(2, &[], &[1, 2, 3, 1, 2]),
+            vec![0.0, 0.0, -1.0, 0.0]
+        );
+        // The same match is allowed with a longer `allowed_length`.
+        assert_eq!(dry_penalized(3, &[], &[1, 2, 3, 1, 2]), vec![0.0; 4]);
+        // Each token beyond `allowed_length` multiplies the penalty by `base`.
+        assert_eq!(
+            dry_penalized(1, &[], &[1, 2, 3, 1, 2]),
+            vec![0.0, 0.0, -2.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_dry_sequence_breakers() {
+        // `: a b c : a b`: the match of `c` extends over the first `:` unless it is a sequence breaker.
+        let context = [4, 1, 2, 3, 4, 1, 2];
+        assert_eq!(dry_penalized(2, &[], &context), vec![0.0, 0.0, -2.0, 0.0]);
+        assert_eq!(
+            dry_penalized(2, &[":"], &context),
+            vec![0.0, 0.0, -1.0, 0.0]
+        );
+        // Nothing is penalized after a sequence breaker, and a breaker is never penalized as a repeat.
+        assert_eq!(dry_penalized(1, &[":"], &[1, 4, 1, 4]), vec![0.0; 4]);
+        assert_eq!(dry_penalized(1, &[":"], &[1, 4, 2, 1]), vec![0.0; 4]);
+    }
+
     #[test]
     fn test_argmax() {
         use super::Sampler;
@@ -322,14 +965,46 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
             32,
             0.1,
+            0.0,
+            1.0,
+            1.0,
+            None,
+            false,
         );
 
         let logits = Tensor::arange(0f32, 1024f32, &Device::Cpu).unwrap();
-        let res = sampler.sample(logits, None, false).unwrap();
+        let res = sampler.sample(logits, None, false, None).unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        // The log-softmax of the largest of 0..1024 is ln(1 - e^-1), up to a negligible term.
+        assert!((res.logprob - (1. - (-1f32).exp()).ln()).abs() < 1e-4)
+    }
+
+    #[test]
+    fn test_seeded_sampling() {
+        use candle_core::{DType, Device, Tensor};
+
+        let draws = |seed| {
+            let mut sampler = test_sequence()
+                .with_seed(seed)
+                .with_temperature(1.0)
+                .sampler();
+            // A uniform distribution, so that every draw depends on the random stream.
+            let logits = Tensor::zeros(1024, DType::F32, &Device::Cpu).unwrap();
+            (0..16)
+                .map(|_| {
+                    sampler
+                        .sample(logits.clone(), None, false, None)
+                        .unwrap()
+                        .token
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(43));
     }
 }
@@ -1,9 +1,16 @@
-use std::collections::{
-    vec_deque::{Iter, IterMut},
-    HashMap, VecDeque,
+use std::{
+    cmp::Reverse,
+    collections::{
+        vec_deque::{Iter, IterMut},
+        HashMap, HashSet, VecDeque,
+    },
+    time::Instant,
 };
 
-use crate::sequence::{Sequence, SequenceState};
+use crate::{
+    sequence::{Sequence, SequenceState, StopReason},
+    QueueStatus,
+};
 use range_checked::UsizeBounded;
 
 pub trait FcfsBacker: Default {
@@ -13,7 +20,9 @@ pub trait FcfsBacker: Default {
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
     fn iter(&self) -> impl Iterator<Item = &Sequence>;
     fn mut_iter(&mut self) -> impl Iterator<Item = &mut Sequence>;
-    fn sort_ascending_ids(&mut self);
+    /// Sort the sequences in the order in which they are admitted to run. With fair share, the
+    /// `running` sequences count towards the share of their tenants.
+    fn sort_for_admission(&mut self, running: &[Sequence], fair_share: bool);
 }
 
 impl FcfsBacker for VecDeque<Sequence> {
@@ -35,15 +44,129 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn into_iter(self) -> impl Iterator<Item = Sequence> {
         <Self as IntoIterator>::into_iter(self)
     }
-    fn sort_ascending_ids(&mut self) {
+    fn sort_for_admission(&mut self, _running: &[Sequence], _fair_share: bool) {
         let slice = self.make_contiguous();
         slice.sort_by_key(|seq| *seq.id());
     }
 }
 
+/// A waiting list which admits the sequences with a higher priority first, and the sequences with
+/// the same priority in the order they were added. With fair share, the sequences of each priority
+/// are admitted from each tenant in turn, starting with the tenants with the fewest running sequences,
+/// so that a tenant with many sequences does not hold back the others. Requests without a tenant are
+/// each their own tenant.
+#[derive(Default)]
+pub struct PriorityBacker {
+    seqs: Vec<Sequence>,
+}
+
+/// The sort key of the priority of a sequence, which is lower for higher priorities.
+fn priority(seq: &Sequence) -> Reverse<i32> {
+    Reverse(seq.get_mut_group().priority)
+}
+
+/// The tenant of a sequence, or its request if it has none.
+fn tenant(seq: &Sequence) -> (Option<String>, usize) {
+    let group = seq.get_mut_group();
+    match &group.tenant {
+        Some(tenant) => (Some(tenant.clone()), 0),
+        None => (None, group.request_id),
+    }
+}
+
+impl FcfsBacker for PriorityBacker {
+    fn new() -> Self {
+        Self::default()
+    }
+    fn add(&mut self, item: Sequence) {
+        self.seqs.push(item)
+    }
+    fn next(&mut self) -> Option<Sequence> {
+        let (i, _) = self
+            .seqs
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, seq)| (priority(seq), *seq.id()))?;
+        Some(self.seqs.remove(i))
+    }
+    fn iter(&self) -> impl Iterator<Item = &Sequence> {
+        self.seqs.iter()
+    }
+    fn mut_iter(&mut self) -> impl Iterator<Item = &mut Sequence> {
+        self.seqs.iter_mut()
+    }
+    fn into_iter(self) -> impl Iterator<Item = Sequence> {
+        self.seqs.into_iter()
+    }
+    fn sort_for_admission(&mut self, running: &[Sequence], fair_share: bool) {
+        self.seqs.sort_by_key(|seq| (priority(seq), *seq.id()));
+        if !fair_share {
+            return;
+        }
+        let mut shares: HashMap<(Option<String>, usize), usize> = HashMap::new();
+        for seq in running {
+            *shares.entry(tenant(seq)).or_default() += 1;
+        }
+        // The turn of each sequence is the number of sequences of its tenant which run before it.
+        let mut turns = HashMap::new();
+        for seq in &self.seqs {
+            let share = shares.entry(tenant(seq)).or_default();
+            turns.insert(*seq.id(), *share);
+            *share += 1;
+        }
+        self.seqs
+            .sort_by_key(|seq| (priority(seq), turns[seq.id()], *seq.id()));
+    }
+}
+
+/// The paged KV cache blocks at the start of a step. The scheduler only runs the sequences for which
+/// there are enough free blocks, and takes the blocks they need from `num_free`.
+#[derive(Clone, Debug, Default)]
+pub struct KvCacheBlocks {
+    pub block_size: usize,
+    pub num_free: usize,
+    /// The number of tokens which are allocated for each sequence holding blocks, by sequence ID.
+    pub allocated: HashMap<usize, usize>,
+}
+
+impl KvCacheBlocks {
+    fn blocks_for(&self, num_tokens: usize) -> usize {
+        num_tokens.div_ceil(self.block_size)
+    }
+
+    /// The blocks which a sequence needs to run all of its tokens, beyond the ones it already holds.
+    fn needed(&self, seq: &Sequence) -> usize {
+        let held = self
+            .allocated
+            .get(seq.id())
+            .map_or(0, |num_tokens| self.blocks_for(*num_tokens));
+        self.blocks_for(seq.len()).saturating_sub(held)
+    }
+
+    /// Take the blocks which a sequence needs to run, unless there are not enough free blocks.
+    fn reserve(&mut self, seq: &Sequence) -> bool {
+        let needed = self.needed(seq);
+        if needed > self.num_free {
+            return false;
+        }
+        self.num_free -= needed;
+        self.allocated.insert(*seq.id(), seq.len());
+        true
+    }
+
+    /// Return the blocks of a preempted sequence.
+    fn release(&mut self, seq_id: usize) {
+        if let Some(num_tokens) = self.allocated.remove(&seq_id) {
+            self.num_free += self.blocks_for(num_tokens);
+        }
+    }
+}
+
 pub struct SchedulerOutput<'a> {
     pub completion: Box<[&'a mut Sequence]>,
     pub prompt: Box<[&'a mut Sequence]>,
+    /// The IDs of the sequences which were moved back to the waiting list to free KV cache space.
+    pub preempted: Vec<usize>,
 }
 
 /// The scheduler method controld how sequences are scheduled during each
@@ -52,23 +175,57 @@ pub struct SchedulerOutput<'a> {
 /// is used to allow waiting sequences to run.
 pub enum SchedulerMethod {
     Fixed(UsizeBounded<1, { usize::MAX }, false>),
+    /// Admit waiting sequences while the estimated KV cache size of the running sequences, in bytes,
+    /// stays within the budget. This is used even if there are no running sequences.
+    ///
+    /// A sequence is estimated to need its prompt length plus its `max_len` tokens, or just its prompt
+    /// length if it has no `max_len`. If the running sequences outgrow the budget, the ones with the
+    /// lowest priority are preempted, most recently added first: their KV cache is dropped and they
+    /// are moved back to the waiting list to be recomputed later.
+    KvCacheBudget(UsizeBounded<1, { usize::MAX }, false>),
 }
 
 pub struct Scheduler<Backer: FcfsBacker> {
     waiting: Backer,
     running: Vec<Sequence>,
     method: SchedulerMethod,
+    kv_cache_bytes_per_token: usize,
+    max_seq_len: usize,
+    /// Whether sequences with different lengths can run in the same batch.
+    ragged_batches: bool,
+    /// Whether the waiting sequences are admitted from each tenant in turn.
+    fair_share: bool,
+    /// The free paged KV cache blocks for the next step, with paged attention.
+    kv_cache_blocks: Option<KvCacheBlocks>,
 }
 
 impl<Backer: FcfsBacker> Scheduler<Backer> {
-    pub fn new(method: SchedulerMethod) -> Self {
+    pub fn new(
+        method: SchedulerMethod,
+        kv_cache_bytes_per_token: usize,
+        max_seq_len: usize,
+        ragged_batches: bool,
+        fair_share: bool,
+    ) -> Self {
         Self {
             running: Vec::new(),
             waiting: Backer::new(),
             method,
+            kv_cache_bytes_per_token,
+            max_seq_len,
+            ragged_batches,
+            fair_share,
+            kv_cache_blocks: None,
         }
     }
 
+    /// Set the paged KV cache blocks which are free for the next step. Waiting sequences are only
+    /// admitted if there are enough blocks for their tokens, and if the running sequences need more
+    /// blocks than are free, the ones with the lowest priority are preempted, most recently added first.
+    pub fn set_kv_cache_blocks(&mut self, kv_cache_blocks: KvCacheBlocks) {
+        self.kv_cache_blocks = Some(kv_cache_blocks);
+    }
+
     pub fn add_seq(&mut self, seq: Sequence) {
         if seq.is_running() {
             // prefill case
@@ -82,6 +239,80 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
         self.waiting.iter().count()
     }
 
+    /// The requests with waiting sequences, and the tokens which those sequences must run.
+    pub fn queue_status(&self) -> QueueStatus {
+        let waiting_requests = self
+            .waiting
+            .iter()
+            .map(|seq| seq.get_mut_group().request_id)
+            .collect::<HashSet<_>>()
+            .len();
+        QueueStatus {
+            waiting_requests,
+            waiting_tokens: self.waiting.iter().map(|seq| seq.len()).sum(),
+        }
+    }
+
+    /// The requests whose receiver was dropped while their responses were being sent.
+    pub fn disconnected_requests(&self) -> HashSet<usize> {
+        self.running
+            .iter()
+            .chain(self.waiting.iter())
+            .map(|seq| seq.get_mut_group())
+            .filter(|group| group.is_canceled)
+            .map(|group| group.request_id)
+            .collect()
+    }
+
+    /// Remove all running and waiting sequences of a request, marking them as canceled.
+    /// Returns the IDs of the removed sequences.
+    pub fn cancel_request(&mut self, request_id: usize) -> Vec<usize> {
+        let mut canceled = Vec::new();
+        let mut keep = |seq: Sequence| {
+            if seq.get_mut_group().request_id == request_id {
+                seq.set_state(SequenceState::Done(StopReason::Canceled));
+                canceled.push(*seq.id());
+                None
+            } else {
+                Some(seq)
+            }
+        };
+        let running = std::mem::take(&mut self.running);
+        self.running = running.into_iter().filter_map(&mut keep).collect();
+        let waiting = std::mem::take(&mut self.waiting);
+        for seq in waiting.into_iter().filter_map(&mut keep) {
+            self.waiting.add(seq);
+        }
+        canceled
+    }
+
+    /// Remove the running and waiting sequences whose request passed its deadline, or which waited
+    /// longer than their request's maximum queue time. They are returned to be finished, with the reason.
+    pub fn take_expired(&mut self, now: Instant) -> Vec<(Sequence, StopReason)> {
+        let mut expired = Vec::new();
+        let mut keep = |seq: Sequence| {
+            let expiry = if seq.is_running() || seq.is_waiting() {
+                seq.get_mut_group().expiry(now, seq.is_waiting())
+            } else {
+                None
+            };
+            match expiry {
+                Some(reason) => {
+                    expired.push((seq, reason));
+                    None
+                }
+                None => Some(seq),
+            }
+        };
+        let running = std::mem::take(&mut self.running);
+        self.running = running.into_iter().filter_map(&mut keep).collect();
+        let waiting = std::mem::take(&mut self.waiting);
+        for seq in waiting.into_iter().filter_map(&mut keep) {
+            self.waiting.add(seq);
+        }
+        expired
+    }
+
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
     /// The others are moved to the waiting list (retaining high priority due to start time),
     /// without a state modification.
@@ -100,10 +331,18 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
         running: Vec<Sequence>,
         waiting: &mut Backer,
     ) -> Vec<Sequence> {
+        if self.ragged_batches {
+            // The KV caches are padded and masked, so all of the sequences run together.
+            return running;
+        }
         // Now, get the sequences with the smallest sequence lengths, and allow them to catch up.
-        let mut seq_buckets: HashMap<usize, Vec<Sequence>> = HashMap::new();
+        // Sequences which reuse a cached prefix only run the rest of their prompt, so they are
+        // bucketed apart from the sequences of the same length which run all of their tokens.
+        // Prompts and completions run as separate batches, so the shortest bucket of each runs:
+        // this lets completions keep decoding while a long prompt is prefilled in chunks.
+        let mut seq_buckets: HashMap<(bool, usize, usize), Vec<Sequence>> = HashMap::new();
         for seq in running {
-            let len = seq.len();
+            let len = (seq.is_completion(), seq.len(), seq.prefix_len());
             match seq_buckets.get_mut(&len) {
                 Some(bucket) => bucket.push(seq),
                 None => {
@@ -111,28 +350,50 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
                 }
             }
         }
-        let running = if seq_buckets.len() <= 1 {
-            // Full steam ahead or have everything
-            seq_buckets
-                .into_iter()
-                .flat_map(|(_, x)| x)
-                .collect::<Vec<_>>()
-        } else {
-            // Set the min seqs to be the running ones, and the rest to be waiting (but their states are not changed!)
-            // Allow the min seqs to catch up.
-            let min = *seq_buckets.keys().min().expect("No sequence buckets.");
-            let min_seqs = seq_buckets.remove(&min).unwrap();
-            for (_, seqs) in seq_buckets {
+        let min_prompt = seq_buckets
+            .keys()
+            .filter(|(completion, _, _)| !completion)
+            .min()
+            .copied();
+        let min_completion = seq_buckets
+            .keys()
+            .filter(|(completion, _, _)| *completion)
+            .min()
+            .copied();
+        // Set the min seqs to be the running ones, and the rest to be waiting (but their states are not changed!)
+        // Allow the min seqs to catch up.
+        let mut running = Vec::new();
+        for (len, seqs) in seq_buckets {
+            if Some(len) == min_prompt || Some(len) == min_completion {
+                running.extend(seqs);
+            } else {
                 for seq in seqs {
                     waiting.add(seq);
                 }
             }
-            // Know min_seqs.len < running.len() <= max
-            min_seqs
-        };
+        }
         running
     }
 
+    /// Split the running sequences into the completion and prompt batches of this step.
+    fn output(&mut self, preempted: Vec<usize>) -> SchedulerOutput {
+        let mut completion = Vec::new();
+        let mut prompt = Vec::new();
+        for seq in &mut self.running {
+            if seq.is_completion() {
+                completion.push(seq);
+            } else {
+                prompt.push(seq);
+            }
+        }
+
+        SchedulerOutput {
+            completion: completion.into(),
+            prompt: prompt.into(),
+            preempted,
+        }
+    }
+
     /// Schedule all sequences based on their state and the available space.
     pub fn schedule(&mut self) -> SchedulerOutput {
         // Filter out all done sequences
@@ -143,44 +404,66 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
             .filter(|seq| seq.is_running())
             .collect::<Vec<_>>();
 
+        let mut preempted = Vec::new();
+        if matches!(self.method, SchedulerMethod::KvCacheBudget(_))
+            || self.kv_cache_blocks.is_some()
+        {
+            // Sequences which were bucketed out last step still hold their KV cache, so account for them.
+            let mut new_waiting = Backer::new();
+            for seq in waiting.into_iter() {
+                if seq.is_running() {
+                    running.push(seq);
+                } else {
+                    new_waiting.add(seq);
+                }
+            }
+            waiting = new_waiting;
+            preempted = self.preempt_seqs(&mut running, &mut waiting);
+        }
+
         match (waiting.iter().count(), running.len()) {
             (0, 0) => {
                 self.running = running;
                 return SchedulerOutput {
                     prompt: vec![].into(),
                     completion: vec![].into(),
+                    preempted,
                 };
             }
-            (_, 0) => {
+            (_, 0)
+                if matches!(self.method, SchedulerMethod::Fixed(_))
+                    && self.kv_cache_blocks.is_none() =>
+            {
                 for seq in waiting.into_iter() {
-                    seq.set_state(SequenceState::RunningPrompt);
+                    // Sequences which were bucketed out last step keep their state.
+                    if seq.is_waiting() {
+                        seq.set_state(SequenceState::RunningPrompt);
+                    }
                     self.running.push(seq);
                 }
                 self.waiting = Backer::new();
                 let running = std::mem::take(&mut self.running);
                 self.running = self.bucket_and_waitlist_seqs(running);
-                return SchedulerOutput {
-                    prompt: self.running.iter_mut().collect::<Vec<_>>().into(),
-                    completion: vec![].into(),
-                };
+                return self.output(preempted);
             }
             (0, _) => {
+                // The running sequences may include prompts which are prefilled in chunks.
                 self.running = self.bucket_and_waitlist_seqs(running);
-                return SchedulerOutput {
-                    prompt: vec![].into(),
-                    completion: self.running.iter_mut().collect::<Vec<_>>().into(),
-                };
+                return self.output(preempted);
             }
             _ => {}
         }
 
         // Sort the waiting seqs
-        waiting.sort_ascending_ids();
+        waiting.sort_for_admission(&running, self.fair_share);
 
         // If the waiting sequence will fit, add it. Otherwise remove it
         let mut new_waiting = Backer::new();
         for seq in waiting.into_iter() {
             if self.sequence_fits(&running, &seq) {
+                if let Some(kv_cache_blocks) = &mut self.kv_cache_blocks {
+                    kv_cache_blocks.reserve(&seq);
+                }
                 if seq.is_waiting() {
                     seq.set_state(SequenceState::RunningPrompt);
                 }
@@ -194,25 +477,315 @@ impl<Backer: FcfsBacker> Scheduler<Backer> {
 
         self.waiting = new_waiting;
 
-        let mut completion = Vec::new();
-        let mut prompt = Vec::new();
-        for seq in &mut self.running {
-            if seq.is_completion() {
-                completion.push(seq);
-            } else {
-                prompt.push(seq);
+        self.output(preempted)
+    }
+
+    fn sequence_fits(&self, running: &[Sequence], seq: &Sequence) -> bool {
+        // Always run at least one sequence, even if it needs more blocks than are free.
+        if let Some(kv_cache_blocks) = &self.kv_cache_blocks {
+            if !running.is_empty() && kv_cache_blocks.needed(seq) > kv_cache_blocks.num_free {
+                return false;
             }
         }
+        match &self.method {
+            SchedulerMethod::Fixed(n) => (running.len() + 1) <= **n,
+            SchedulerMethod::KvCacheBudget(budget) => {
+                // Always run at least one sequence, even if it is larger than the budget.
+                running.is_empty()
+                    || self.kv_cache_tokens(running) + self.estimated_len(seq)
+                        <= **budget / self.kv_cache_bytes_per_token
+            }
+        }
+    }
 
-        SchedulerOutput {
-            completion: completion.into(),
-            prompt: prompt.into(),
+    /// The number of tokens a sequence is expected to reach, or its current length if it is already longer.
+    fn estimated_len(&self, seq: &Sequence) -> usize {
+        let estimated = (seq.prompt_tokens() + seq.max_len().unwrap_or(0)).min(self.max_seq_len);
+        estimated.max(seq.len())
+    }
+
+    fn kv_cache_tokens(&self, seqs: &[Sequence]) -> usize {
+        seqs.iter().map(|seq| self.estimated_len(seq)).sum()
+    }
+
+    /// Whether the running sequences have enough free paged KV cache blocks to run their tokens.
+    fn blocks_fit(&self, running: &[Sequence]) -> bool {
+        match &self.kv_cache_blocks {
+            Some(kv_cache_blocks) => {
+                running
+                    .iter()
+                    .map(|seq| kv_cache_blocks.needed(seq))
+                    .sum::<usize>()
+                    <= kv_cache_blocks.num_free
+            }
+            None => true,
         }
     }
 
-    fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
-        match &self.method {
-            SchedulerMethod::Fixed(n) => (running.len() + 1) <= **n,
+    /// Preempt the running sequences with the lowest priority, the most recently added first, until the
+    /// rest fit in the KV cache budget and in the free paged KV cache blocks. The blocks of the remaining
+    /// sequences are then reserved.
+    /// Returns the IDs of the preempted sequences.
+    fn preempt_seqs(&mut self, running: &mut Vec<Sequence>, waiting: &mut Backer) -> Vec<usize> {
+        let max_tokens = match &self.method {
+            SchedulerMethod::KvCacheBudget(budget) => **budget / self.kv_cache_bytes_per_token,
+            SchedulerMethod::Fixed(_) => usize::MAX,
+        };
+        running.sort_by_key(|seq| (priority(seq), *seq.id()));
+        let mut preempted = Vec::new();
+        while running.len() > 1
+            && (self.kv_cache_tokens(running) > max_tokens || !self.blocks_fit(running))
+        {
+            let mut seq = running.pop().expect("There are running sequences.");
+            if let Some(kv_cache_blocks) = &mut self.kv_cache_blocks {
+                kv_cache_blocks.release(*seq.id());
+            }
+            preempted.push(*seq.id());
+            seq.preempt();
+            waiting.add(seq);
+        }
+        if let Some(kv_cache_blocks) = &mut self.kv_cache_blocks {
+            for seq in running.iter() {
+                kv_cache_blocks.reserve(seq);
+            }
         }
+        preempted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::{PriorityBacker, Scheduler, SchedulerMethod};
+    use crate::test_utils::{word_level_tokenizer, TestSequence};
+
+    /// A waiting sequence with a prompt of `prompt_len` tokens.
+    fn test_seq(id: usize, prompt_len: usize) -> TestSequence {
+        TestSequence::new(
+            word_level_tokenizer(&["<unk>", "a"]).into(),
+            vec![1; prompt_len],
+        )
+        .with_id(id)
+    }
+
+    /// A scheduler with a KV cache budget of `budget` tokens.
+    fn budget_scheduler(budget: usize) -> Scheduler<PriorityBacker> {
+        Scheduler::new(
+            SchedulerMethod::KvCacheBudget(budget.try_into().unwrap()),
+            1,
+            4096,
+            false,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_admission_under_kv_cache_budget() {
+        let mut scheduler = budget_scheduler(16);
+        for id in 0..2 {
+            scheduler.add_seq(test_seq(id, 4).with_max_len(4).build());
+        }
+        // Each sequence is estimated to need 8 tokens, so both fit.
+        let output = scheduler.schedule();
+        let mut ids = output
+            .prompt
+            .iter()
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1]);
+        assert!(output.preempted.is_empty());
+        assert_eq!(scheduler.waiting_len(), 0);
+    }
+
+    #[test]
+    fn test_refusal_over_kv_cache_budget() {
+        let mut scheduler = budget_scheduler(12);
+        for id in 0..2 {
+            scheduler.add_seq(test_seq(id, 4).with_max_len(4).build());
+        }
+        // The second sequence would bring the estimate to 16 tokens, so it keeps waiting.
+        let output = scheduler.schedule();
+        let ids = output
+            .prompt
+            .iter()
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![0]);
+        assert_eq!(scheduler.waiting_len(), 1);
+
+        // A sequence which is larger than the whole budget still runs on its own.
+        let mut scheduler = budget_scheduler(4);
+        scheduler.add_seq(test_seq(0, 4).with_max_len(4).build());
+        assert_eq!(scheduler.schedule().prompt.len(), 1);
+    }
+
+    #[test]
+    fn test_preemption_order() {
+        use candle_core::{DType, Device, Tensor};
+
+        use super::FcfsBacker;
+        use crate::sequence::SequenceState;
+
+        let mut scheduler = budget_scheduler(8);
+        // Four running sequences of 4 tokens, alternating between priorities 0 and 1.
+        for id in 0..4 {
+            let mut seq = test_seq(id, 4).with_priority(id as i32 % 2).build();
+            let kv = Tensor::zeros((1, 1, 4, 1), DType::F32, &Device::Cpu).unwrap();
+            seq.cache()[0] = Some((kv.clone(), kv));
+            seq.set_state(SequenceState::RunningPrompt);
+            scheduler.add_seq(seq);
+        }
+
+        // The sequences with the lowest priority are preempted, the most recently added first.
+        let output = scheduler.schedule();
+        assert_eq!(output.preempted, vec![2, 0]);
+        let mut ids = output
+            .prompt
+            .iter()
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 3]);
+
+        // The preempted sequences are waiting again, without their KV cache, to be recomputed.
+        let mut waiting = scheduler
+            .waiting
+            .mut_iter()
+            .map(|seq| {
+                assert!(seq.is_waiting());
+                assert!(seq.cache().iter().all(Option::is_none));
+                *seq.id()
+            })
+            .collect::<Vec<_>>();
+        waiting.sort();
+        assert_eq!(waiting, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_preemption_when_kv_cache_blocks_run_out() {
+        use std::collections::HashMap;
+
+        use super::KvCacheBlocks;
+        use crate::sequence::SequenceState;
+
+        let mut scheduler = budget_scheduler(usize::MAX);
+        // Two running sequences of 5 tokens, whose first 4 tokens are in 2 blocks of 2 tokens each.
+        for id in 0..2 {
+            let seq = test_seq(id, 5).build();
+            seq.set_state(SequenceState::RunningCompletion);
+            scheduler.add_seq(seq);
+        }
+        scheduler.add_seq(test_seq(2, 2).build());
+        scheduler.set_kv_cache_blocks(KvCacheBlocks {
+            block_size: 2,
+            num_free: 1,
+            allocated: HashMap::from([(0, 4), (1, 4)]),
+        });
+
+        // Both running sequences need a third block, so the most recently added one is preempted. Its
+        // blocks leave room for the waiting prompt, but not for its own 5 tokens.
+        let output = scheduler.schedule();
+        assert_eq!(output.preempted, vec![1]);
+        let completion = output
+            .completion
+            .iter()
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        assert_eq!(completion, vec![0]);
+        let prompt = output
+            .prompt
+            .iter()
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        assert_eq!(prompt, vec![2]);
+        assert_eq!(scheduler.waiting_len(), 1);
+    }
+
+    #[test]
+    fn test_priority_order() {
+        use super::FcfsBacker;
+
+        let mut waiting = PriorityBacker::new();
+        for (id, priority) in [(0, 0), (1, 2), (2, 1), (3, 2)] {
+            waiting.add(test_seq(id, 4).with_priority(priority).build());
+        }
+        // Higher priorities first, and the order in which they were added within a priority.
+        waiting.sort_for_admission(&[], false);
+        let order = waiting.iter().map(|seq| *seq.id()).collect::<Vec<_>>();
+        assert_eq!(order, vec![1, 3, 2, 0]);
+        let next = std::iter::from_fn(|| waiting.next())
+            .map(|seq| *seq.id())
+            .collect::<Vec<_>>();
+        assert_eq!(next, vec![1, 3, 2, 0]);
+    }
+
+    #[test]
+    fn test_fair_share_round_robin() {
+        use super::FcfsBacker;
+
+        let mut waiting = PriorityBacker::new();
+        for (id, tenant) in [(0, "a"), (1, "a"), (2, "a"), (3, "b"), (4, "b"), (5, "c")] {
+            waiting.add(test_seq(id, 4).with_tenant(tenant).build());
+        }
+        // `a` already has a running sequence, so `b` and `c` get their first turn before `a` gets its second.
+        let running = [test_seq(6, 4).with_tenant("a").build()];
+        waiting.sort_for_admission(&running, true);
+        let order = waiting.iter().map(|seq| *seq.id()).collect::<Vec<_>>();
+        assert_eq!(order, vec![3, 5, 0, 4, 1, 2]);
+
+        // Fair share is within a priority: a higher priority is still admitted first, and counts towards the
+        // share of its tenant at lower priorities.
+        let mut waiting = PriorityBacker::new();
+        for (id, priority, tenant) in [(0, 0, "a"), (1, 0, "b"), (2, 1, "a"), (3, 1, "a")] {
+            waiting.add(
+                test_seq(id, 4)
+                    .with_priority(priority)
+                    .with_tenant(tenant)
+                    .build(),
+            );
+        }
+        waiting.sort_for_admission(&[], true);
+        let order = waiting.iter().map(|seq| *seq.id()).collect::<Vec<_>>();
+        assert_eq!(order, vec![2, 3, 1, 0]);
+    }
+
+    #[test]
+    fn test_take_expired() {
+        use std::time::Duration;
+
+        use crate::sequence::SequenceState;
+
+        let now = Instant::now();
+        let past = Some(now - Duration::from_secs(1));
+        let future = Some(now + Duration::from_secs(1));
+        let mut scheduler = budget_scheduler(usize::MAX);
+        // A waiting sequence past its maximum queue time, and one which can still wait.
+        scheduler.add_seq(test_seq(0, 4).with_queue_deadline(past).build());
+        scheduler.add_seq(test_seq(1, 4).with_queue_deadline(future).build());
+        // A running sequence past its deadline, and one which is past its maximum queue time, which only
+        // applies while it waits.
+        for (id, deadline, queue_deadline) in [(2, past, None), (3, future, past)] {
+            let seq = test_seq(id, 4)
+                .with_deadline(deadline)
+                .with_queue_deadline(queue_deadline)
+                .build();
+            seq.set_state(SequenceState::RunningPrompt);
+            scheduler.add_seq(seq);
+        }
+
+        let mut expired = scheduler
+            .take_expired(now)
+            .iter()
+            .map(|(seq, reason)| (*seq.id(), reason.to_string()))
+            .collect::<Vec<_>>();
+        expired.sort();
+        assert_eq!(
+            expired,
+            vec![(0, "queue_timeout".to_string()), (2, "timeout".to_string())]
+        );
+        assert_eq!(scheduler.waiting_len(), 1);
+        assert_eq!(scheduler.running.len(), 1);
     }
 }
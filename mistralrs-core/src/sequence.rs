@@ -1,13 +1,14 @@
 use std::{
     cell::{Cell, RefCell, RefMut},
     rc::Rc,
-    sync::mpsc::{SendError, Sender},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::mpsc::{error::SendError, UnboundedSender};
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
-    response::CompletionChoice,
+    response::{CompletionChoice, CompletionChunkChoice, CompletionChunkResponse},
+    tools::ToolCallingMatcher,
     CompletionResponse,
 };
 use crate::{
@@ -31,6 +32,10 @@ pub enum StopReason {
         completion_bytes_pos: usize,
     },
     Canceled,
+    /// The request passed its deadline.
+    Timeout,
+    /// The sequence waited longer than its request's maximum queue time.
+    QueueTimeout,
 }
 
 impl ToString for StopReason {
@@ -40,6 +45,8 @@ impl ToString for StopReason {
             StopReason::Length(_) | StopReason::ModelLength(_) => "length".to_string(),
             StopReason::StopTok(_) | StopReason::StopString { .. } => "stop".to_string(),
             StopReason::Canceled => "canceled".to_string(),
+            StopReason::Timeout => "timeout".to_string(),
+            StopReason::QueueTimeout => "queue_timeout".to_string(),
         }
     }
 }
@@ -70,10 +77,11 @@ pub struct Sequence {
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
     return_logprobs: bool,
-    responder: Sender<Response>,
+    responder: UnboundedSender<Response>,
     response_index: usize,
     creation_time: u64,
     prefill_prompt_toks: Option<Vec<u32>>,
+    prefill_chunk_size: Option<usize>,
     suffix: Option<String>,
     prefix: Option<String>,
 
@@ -81,6 +89,8 @@ pub struct Sequence {
     scaling_cache: Option<Tensor>,
     cache: LayerCaches,
     xlora_cache: Option<LayerCaches>,
+    /// The KV cache of the draft model of speculative decoding, empty until the draft model first runs.
+    draft_cache: LayerCaches,
 
     // Mutables
     tokens: Vec<u32>,
@@ -89,6 +99,7 @@ pub struct Sequence {
     completion_bytes: Vec<u8>,
     stream_idx: usize,
     pub recognizer: SequenceRecognizer,
+    healing_prefix: Vec<u8>,
 
     // GPU things
     pub prompt_tok_per_sec: f32,
@@ -103,7 +114,7 @@ impl Sequence {
         id: usize,
         timestamp: u128,
         layers: usize,
-        responder: Sender<Response>,
+        responder: UnboundedSender<Response>,
         sampler: Sampler,
         stop_tokens: Vec<u32>,
         stop_strings: Vec<String>,
@@ -116,6 +127,7 @@ impl Sequence {
         recognizer: SequenceRecognizer,
         suffix: Option<String>,
         prefix: Option<String>,
+        healing_prefix: Vec<u8>,
     ) -> Self {
         let prompt_len = tokens.len();
         Self {
@@ -131,6 +143,7 @@ impl Sequence {
             } else {
                 None
             },
+            draft_cache: Vec::new(),
             responder,
             sampler,
             stop_tokens,
@@ -144,7 +157,9 @@ impl Sequence {
             response_index,
             creation_time,
             recognizer,
+            healing_prefix,
             prefill_prompt_toks: None,
+            prefill_chunk_size: None,
             suffix,
             prefix,
             cumulative_logprob: 0.,
@@ -166,8 +181,34 @@ impl Sequence {
         self
     }
 
+    /// Run the prompt in chunks of at most `chunk_size` tokens, one chunk per engine step, so that
+    /// a long prompt does not stall the decoding of the other sequences.
+    pub fn with_prefill_chunks(mut self, chunk_size: usize) -> Self {
+        if self.prefill_prompt_toks.is_none() {
+            self.prefill_prompt_toks = Some(self.tokens.clone());
+        }
+        self.prefill_chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Mark the prompt chunk which was just run as being in the KV cache. Returns whether the
+    /// whole prompt has now been run, in which case the next token should be sampled.
+    pub fn finish_prefill_chunk(&mut self) -> bool {
+        match (&mut self.prefill_prompt_toks, self.prefill_chunk_size) {
+            (Some(toks), Some(chunk_size)) if toks.len() > chunk_size => {
+                toks.drain(..chunk_size);
+                false
+            }
+            _ => true,
+        }
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
+        if self.prefill_prompt_toks.is_some() {
+            // The KV cache only holds the cached prefix of the prompt.
+            return self.tokens.len();
+        }
         // Use xlora cache first because of non granular
         if self.xlora_cache.as_ref().is_some_and(|c| c[0].is_some()) {
             self.xlora_cache.as_ref().unwrap()[0]
@@ -179,13 +220,18 @@ impl Sequence {
         } else if let Some((_, x)) = &self.cache[0] {
             x.dims()[2] + 1
         } else {
-            if let Some(toks) = &self.prefill_prompt_toks {
-                return toks.len();
-            }
             self.tokens.len()
         }
     }
 
+    /// The number of prompt tokens which are already in the KV cache, from the prefix cache or
+    /// from earlier prompt chunks, and are not run again.
+    pub fn prefix_len(&self) -> usize {
+        self.prefill_prompt_toks
+            .as_ref()
+            .map_or(0, |toks| self.tokens.len() - toks.len())
+    }
+
     pub fn id(&self) -> &usize {
         &self.id
     }
@@ -211,15 +257,30 @@ impl Sequence {
 
     pub fn get_toks(&self) -> &[u32] {
         if let Some(toks) = &self.prefill_prompt_toks {
-            return toks;
+            return match self.prefill_chunk_size {
+                Some(chunk_size) => &toks[..chunk_size.min(toks.len())],
+                None => toks,
+            };
         }
         &self.tokens
     }
 
+    /// All of the tokens of the sequence. Unlike [`Sequence::get_toks`], this includes the prompt tokens
+    /// which are already in the KV cache while the prompt is prefilled.
+    pub fn all_toks(&self) -> &[u32] {
+        &self.tokens
+    }
+
     pub fn completion_bytes(&self) -> &[u8] {
         &self.completion_bytes
     }
 
+    /// The bytes of the prompt tokens removed by token healing which have not been generated yet.
+    /// The output must start with them.
+    pub fn healing_prefix(&self) -> &[u8] {
+        &self.healing_prefix
+    }
+
     pub fn cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
         &mut self.cache
     }
@@ -228,6 +289,23 @@ impl Sequence {
         self.xlora_cache.as_mut().expect("No X-LoRA cache.")
     }
 
+    pub fn draft_cache(&mut self) -> &mut LayerCaches {
+        &mut self.draft_cache
+    }
+
+    /// Drop the KV cache and move the sequence back to the waiting state. When it is scheduled again,
+    /// all of its tokens, including the ones which were already generated, are run as the prompt.
+    pub fn preempt(&mut self) {
+        self.cache.iter_mut().for_each(|layer| *layer = None);
+        if let Some(xlora_cache) = self.xlora_cache.as_mut() {
+            xlora_cache.iter_mut().for_each(|layer| *layer = None);
+        }
+        self.draft_cache.clear();
+        self.scaling_cache = None;
+        self.prefill_prompt_toks = self.prefill_chunk_size.map(|_| self.tokens.clone());
+        self.set_state(SequenceState::Waiting);
+    }
+
     pub fn scaling_cache(&mut self) -> &mut Option<Tensor> {
         &mut self.scaling_cache
     }
@@ -250,11 +328,15 @@ impl Sequence {
             is_done,
             Some(StopReason::Eos) | Some(StopReason::StopTok(_))
         );
+        // The bytes which were removed from the prompt by token healing are already part of the prompt.
+        let healed = self.healing_prefix.len().min(completion_bytes.len());
+        self.healing_prefix.drain(..healed);
         if !stopped_by_token {
             // Completion bytes is used to check for stop strings, and as the response buffer.
             // We don't need to add stop tokens to the completion bytes to check for stop strings.
             // And by not adding it here, we can avoid having to delete these tokens from the output.
-            self.completion_bytes.extend_from_slice(&completion_bytes);
+            self.completion_bytes
+                .extend_from_slice(&completion_bytes[healed..]);
         }
         self.cumulative_logprob += tok.logprob;
         self.tokens.push(tok.token);
@@ -262,7 +344,7 @@ impl Sequence {
         self.prefill_prompt_toks = None;
     }
 
-    pub fn responder(&self) -> Sender<Response> {
+    pub fn responder(&self) -> UnboundedSender<Response> {
         self.responder.clone()
     }
 
@@ -326,6 +408,10 @@ impl Sequence {
         self.prompt_len
     }
 
+    pub fn max_len(&self) -> Option<usize> {
+        self.max_len
+    }
+
     pub fn stop_strings(&self) -> &[String] {
         &self.stop_strings
     }
@@ -405,6 +491,20 @@ impl Sequence {
     pub fn add_streaming_chunk_choice_to_group(&self, chunk: ChunkChoice) {
         get_mut_group!(self).streaming_chunks.push(chunk);
     }
+
+    /// Add the echoed prompt to the first streamed chunk of a completion, and the suffix to the last one.
+    pub fn add_streaming_completion_chunk_choice_to_group(
+        &mut self,
+        mut chunk: CompletionChunkChoice,
+    ) {
+        let prefix = self.prefix.take().unwrap_or_default();
+        let suffix = match chunk.finish_reason {
+            Some(_) => self.suffix.as_deref().unwrap_or(""),
+            None => "",
+        };
+        chunk.text = format!("{prefix}{}{suffix}", chunk.text);
+        get_mut_group!(self).completion_streaming_chunks.push(chunk);
+    }
 }
 
 pub struct SequenceGroup {
@@ -418,12 +518,34 @@ pub struct SequenceGroup {
     choices: Vec<Choice>,
     completion_choices: Vec<(f32, CompletionChoice)>,
     pub streaming_chunks: Vec<ChunkChoice>,
+    pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    pub request_id: usize,
+    pub tool_matcher: Option<ToolCallingMatcher>,
+    pub priority: i32,
+    pub tenant: Option<String>,
+    pub deadline: Option<Instant>,
+    /// When the sequences which are still waiting to be scheduled time out.
+    pub queue_deadline: Option<Instant>,
+    /// Whether the receiver of the responses was dropped, so the sequences of the group are canceled.
+    pub is_canceled: bool,
 }
 
 impl SequenceGroup {
-    pub fn new(n_choices: usize, is_streaming: bool, is_chat: bool, best_of: usize) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        n_choices: usize,
+        is_streaming: bool,
+        is_chat: bool,
+        best_of: usize,
+        request_id: usize,
+        tool_matcher: Option<ToolCallingMatcher>,
+        priority: i32,
+        tenant: Option<String>,
+        deadline: Option<Instant>,
+        queue_deadline: Option<Instant>,
+    ) -> Self {
         Self {
             choices: Vec::new(),
             completion_choices: Vec::new(),
@@ -434,9 +556,28 @@ impl SequenceGroup {
             total_time: 0,
             total_completion_time: 0,
             streaming_chunks: Vec::new(),
+            completion_streaming_chunks: Vec::new(),
             is_streaming,
             is_chat,
             best_of,
+            request_id,
+            tool_matcher,
+            priority,
+            tenant,
+            deadline,
+            queue_deadline,
+            is_canceled: false,
+        }
+    }
+
+    /// Why a sequence of this group has run out of time at `now`, if it has.
+    pub fn expiry(&self, now: Instant, is_waiting: bool) -> Option<StopReason> {
+        if self.deadline.is_some_and(|deadline| now >= deadline) {
+            Some(StopReason::Timeout)
+        } else if is_waiting && self.queue_deadline.is_some_and(|deadline| now >= deadline) {
+            Some(StopReason::QueueTimeout)
+        } else {
+            None
         }
     }
 
@@ -475,15 +616,14 @@ impl SequenceGroup {
         }
     }
 
+    /// If the receiver was dropped, the group is canceled instead.
     pub fn maybe_send_done_response(
-        &self,
+        &mut self,
         response: ChatCompletionResponse,
-        sender: Sender<Response>,
+        sender: UnboundedSender<Response>,
     ) {
-        if self.choices.len() == self.n_choices {
-            sender
-                .send(Response::Done(response))
-                .expect("Expected receiver.");
+        if self.choices.len() == self.n_choices && sender.send(Response::Done(response)).is_err() {
+            self.is_canceled = true;
         }
     }
 
@@ -506,19 +646,37 @@ impl SequenceGroup {
                     system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
                     object: "chat.completion.chunk".to_string(),
                 }))?;
+        } else if self.completion_streaming_chunks.len() == self.n_choices && self.is_streaming {
+            let mut swap_streaming_chunks = vec![];
+
+            std::mem::swap(
+                &mut swap_streaming_chunks,
+                &mut self.completion_streaming_chunks,
+            );
+
+            seq.responder()
+                .send(Response::CompletionChunk(CompletionChunkResponse {
+                    id: seq.id.to_string(),
+                    choices: swap_streaming_chunks,
+                    created: seq.timestamp,
+                    model: model.clone(),
+                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    object: "text_completion".to_string(),
+                }))?;
         }
         Ok(())
     }
 
+    /// If the receiver was dropped, the group is canceled instead.
     pub fn maybe_send_completion_done_response(
-        &self,
+        &mut self,
         response: CompletionResponse,
-        sender: Sender<Response>,
+        sender: UnboundedSender<Response>,
     ) {
-        if self.completion_choices.len() == self.n_choices {
-            sender
-                .send(Response::CompletionDone(response))
-                .expect("Expected receiver.");
+        if self.completion_choices.len() == self.n_choices
+            && sender.send(Response::CompletionDone(response)).is_err()
+        {
+            self.is_canceled = true;
         }
     }
 }
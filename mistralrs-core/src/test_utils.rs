@@ -0,0 +1,230 @@
+//! Tokenizers, samplers and sequences for the unit tests, which do not need a model to be downloaded.
+
+use std::{cell::RefCell, rc::Rc, sync::Arc, time::Instant};
+
+use tokenizers::Tokenizer;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    sampler::{DrySamplingParams, Sampler},
+    sequence::{Sequence, SequenceGroup, SequenceRecognizer},
+    Constraint, Mirostat, Request, RequestMessage, Response, SamplingParams,
+};
+
+/// A word-level tokenizer which splits on whitespace. The ID of each token is its index in `vocab`, which
+/// must contain `<unk>`.
+pub(crate) fn word_level_tokenizer(vocab: &[&str]) -> Tokenizer {
+    let vocab = vocab
+        .iter()
+        .enumerate()
+        .map(|(i, tok)| format!("{}: {i}", serde_json::to_string(tok).unwrap()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        r#"{{"version": "1.0", "truncation": null, "padding": null, "added_tokens": [],
+        "normalizer": null, "pre_tokenizer": {{"type": "Whitespace"}}, "post_processor": null,
+        "decoder": null, "model": {{"type": "WordLevel", "vocab": {{{vocab}}}, "unk_token": "<unk>"}}}}"#
+    )
+    .parse()
+    .unwrap()
+}
+
+/// A request to complete `toks` with up to `max_len` tokens, sampled by argmax.
+pub(crate) fn completion_request(
+    id: usize,
+    toks: Vec<u32>,
+    max_len: usize,
+) -> (Request, UnboundedReceiver<Response>) {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let request = Request {
+        messages: RequestMessage::CompletionTokens(toks),
+        sampling_params: SamplingParams {
+            temperature: Some(0.),
+            max_len: Some(max_len),
+            n_choices: 1,
+            ..Default::default()
+        },
+        response: tx,
+        return_logprobs: false,
+        is_streaming: false,
+        id,
+        constraint: Constraint::None,
+        suffix: None,
+        tools: None,
+        tool_choice: None,
+        priority: 0,
+        tenant: None,
+        deadline: None,
+        max_queue_time: None,
+    };
+    (request, rx)
+}
+
+/// Builds a waiting sequence of a model with a single layer, which is the only sequence of its request.
+/// By default, it is sampled by argmax without penalties or truncation, and has no `max_len`.
+pub(crate) struct TestSequence {
+    tokenizer: Arc<Tokenizer>,
+    toks: Vec<u32>,
+    id: usize,
+    max_len: Option<usize>,
+    seed: u64,
+    temperature: Option<f64>,
+    repetition_penalty: Option<f32>,
+    dry_params: Option<DrySamplingParams>,
+    mirostat: Option<Mirostat>,
+    recognizer: SequenceRecognizer,
+    priority: i32,
+    tenant: Option<String>,
+    deadline: Option<Instant>,
+    queue_deadline: Option<Instant>,
+    responder: Option<UnboundedSender<Response>>,
+}
+
+impl TestSequence {
+    pub(crate) fn new(tokenizer: Arc<Tokenizer>, toks: Vec<u32>) -> Self {
+        Self {
+            tokenizer,
+            toks,
+            id: 0,
+            max_len: None,
+            seed: 0,
+            temperature: None,
+            repetition_penalty: None,
+            dry_params: None,
+            mirostat: None,
+            recognizer: SequenceRecognizer::None,
+            priority: 0,
+            tenant: None,
+            deadline: None,
+            queue_deadline: None,
+            responder: None,
+        }
+    }
+
+    /// The ID of both the sequence and its request.
+    pub(crate) fn with_id(mut self, id: usize) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub(crate) fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    pub(crate) fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub(crate) fn with_temperature(mut self, temperature: f64) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub(crate) fn with_repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.repetition_penalty = Some(repetition_penalty);
+        self
+    }
+
+    pub(crate) fn with_dry_params(mut self, dry_params: DrySamplingParams) -> Self {
+        self.dry_params = Some(dry_params);
+        self
+    }
+
+    pub(crate) fn with_mirostat(mut self, mirostat: Mirostat) -> Self {
+        self.mirostat = Some(mirostat);
+        self
+    }
+
+    pub(crate) fn with_recognizer(mut self, recognizer: SequenceRecognizer) -> Self {
+        self.recognizer = recognizer;
+        self
+    }
+
+    pub(crate) fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub(crate) fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    pub(crate) fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub(crate) fn with_queue_deadline(mut self, queue_deadline: Option<Instant>) -> Self {
+        self.queue_deadline = queue_deadline;
+        self
+    }
+
+    /// Send the responses of the sequence to `responder`. Otherwise, they are dropped.
+    pub(crate) fn with_responder(mut self, responder: UnboundedSender<Response>) -> Self {
+        self.responder = Some(responder);
+        self
+    }
+
+    pub(crate) fn sampler(&self) -> Sampler {
+        Sampler::new(
+            self.seed,
+            self.temperature,
+            0,
+            self.tokenizer.clone(),
+            None,
+            None,
+            self.repetition_penalty,
+            self.dry_params.clone(),
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            1.0,
+            self.mirostat,
+            false,
+        )
+    }
+
+    pub(crate) fn build(self) -> Sequence {
+        let sampler = self.sampler();
+        let group = SequenceGroup::new(
+            1,
+            false,
+            false,
+            1,
+            self.id,
+            None,
+            self.priority,
+            self.tenant,
+            self.deadline,
+            self.queue_deadline,
+        );
+        let responder = self
+            .responder
+            .unwrap_or_else(|| tokio::sync::mpsc::unbounded_channel().0);
+        Sequence::new_waiting(
+            self.toks,
+            self.id,
+            0,
+            1,
+            responder,
+            sampler,
+            vec![],
+            vec![],
+            self.max_len,
+            false,
+            false,
+            Rc::new(RefCell::new(group)),
+            0,
+            0,
+            self.recognizer,
+            None,
+            None,
+            Vec::new(),
+        )
+    }
+}
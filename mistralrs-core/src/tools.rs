@@ -0,0 +1,214 @@
+use pyo3::pyclass;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::response::{CalledFunction, ToolCallResponse};
+
+/// Prefixes and wrappers which models use to mark their tool calls.
+const TOOL_CALL_PREFIXES: [&str; 3] = ["[TOOL_CALLS]", "<|python_tag|>", "<tool_call>"];
+const TOOL_CALL_END: &str = "</tool_call>";
+
+#[pyclass]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ToolType {
+    #[serde(rename = "function")]
+    Function,
+}
+
+/// A function which the model may call. `parameters` is the JSON schema of its arguments.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+    pub parameters: Option<Value>,
+}
+
+/// A tool definition, passed to the chat template as `tools`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: Function,
+}
+
+/// Controls if and which tools the model may call.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ToolChoice {
+    /// The tools are not given to the model.
+    None,
+    /// The model decides whether to call a tool.
+    Auto,
+    /// The model must call one of the tools. Unless the request has another constraint, the output is
+    /// constrained to a tool call.
+    Required,
+    /// The model must call the named function, constrained like [`ToolChoice::Required`].
+    Function(String),
+}
+
+#[derive(Deserialize)]
+struct CalledFunctionParameters {
+    name: String,
+    #[serde(alias = "parameters")]
+    arguments: Value,
+}
+
+/// Matches the output of a model against the tools of a request to extract the tool calls.
+///
+/// A tool call is a JSON object with a `name` and `arguments` (or `parameters`), or a list of them,
+/// optionally marked with one of the prefixes models use for tool calls (`[TOOL_CALLS]`,
+/// `<|python_tag|>` or `<tool_call>...</tool_call>`).
+pub struct ToolCallingMatcher {
+    tools: Vec<Tool>,
+    tool_choice: ToolChoice,
+}
+
+impl ToolCallingMatcher {
+    /// Returns `None` if no tools may be called.
+    pub fn new(tools: Vec<Tool>, tool_choice: ToolChoice) -> Option<Self> {
+        if tools.is_empty() || tool_choice == ToolChoice::None {
+            return None;
+        }
+        Some(Self { tools, tool_choice })
+    }
+
+    /// The tools to pass to the chat template.
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+
+    /// The JSON schema of a tool call if the model must call a tool, used to constrain the output.
+    pub fn json_schema(&self) -> Option<Value> {
+        let calls = self
+            .tools
+            .iter()
+            .filter(|tool| match &self.tool_choice {
+                ToolChoice::Required => true,
+                ToolChoice::Function(name) => &tool.function.name == name,
+                ToolChoice::None | ToolChoice::Auto => false,
+            })
+            .map(|tool| {
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "name": {"const": tool.function.name},
+                        "arguments": tool.function.parameters.clone().unwrap_or(json!({"type": "object"})),
+                    },
+                    "required": ["name", "arguments"],
+                })
+            })
+            .collect::<Vec<_>>();
+        if calls.is_empty() {
+            None
+        } else {
+            Some(json!({"anyOf": calls}))
+        }
+    }
+
+    /// Whether the output so far may still become a tool call. While it may, streamed output is held back
+    /// so that the tool call is sent as a whole.
+    pub fn prefix_could_be_tool(&self, text: &str) -> bool {
+        let text = text.trim_start();
+        text.is_empty()
+            || text.starts_with('{')
+            || text.starts_with('[')
+            || TOOL_CALL_PREFIXES
+                .iter()
+                .any(|prefix| prefix.starts_with(text) || text.starts_with(prefix))
+    }
+
+    /// Extract the tool calls from the output. If the output is not entirely a tool call of the
+    /// allowed tools, no calls are returned and the output should be treated as text.
+    pub fn get_calls(&self, text: &str, call_id_prefix: &str) -> Vec<ToolCallResponse> {
+        let mut text = text.trim();
+        let mut calls = Vec::new();
+        if text.starts_with("<tool_call>") {
+            for call in text.split("<tool_call>").filter(|x| !x.trim().is_empty()) {
+                let Some(call) = call.trim().strip_suffix(TOOL_CALL_END) else {
+                    return vec![];
+                };
+                match Self::parse_calls(call) {
+                    Some(parsed) => calls.extend(parsed),
+                    None => return vec![],
+                }
+            }
+        } else {
+            for prefix in TOOL_CALL_PREFIXES {
+                text = text.strip_prefix(prefix).unwrap_or(text);
+            }
+            match Self::parse_calls(text) {
+                Some(parsed) => calls.extend(parsed),
+                None => return vec![],
+            }
+        }
+
+        let allowed = |name: &str| match &self.tool_choice {
+            ToolChoice::Function(function) => function == name,
+            _ => self.tools.iter().any(|tool| tool.function.name == name),
+        };
+        if calls.is_empty() || !calls.iter().all(|call| allowed(&call.name)) {
+            return vec![];
+        }
+        calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| ToolCallResponse {
+                id: format!("{call_id_prefix}-{index}"),
+                index,
+                tp: ToolType::Function,
+                function: CalledFunction {
+                    name: call.name,
+                    arguments: match call.arguments {
+                        Value::String(arguments) => arguments,
+                        arguments => arguments.to_string(),
+                    },
+                },
+            })
+            .collect()
+    }
+
+    fn parse_calls(text: &str) -> Option<Vec<CalledFunctionParameters>> {
+        let text = text.trim();
+        if let Ok(call) = serde_json::from_str::<CalledFunctionParameters>(text) {
+            return Some(vec![call]);
+        }
+        serde_json::from_str::<Vec<CalledFunctionParameters>>(text).ok()
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_get_calls() {
+        use super::{Function, Tool, ToolCallingMatcher, ToolChoice, ToolType};
+
+        let tool = Tool {
+            tp: ToolType::Function,
+            function: Function {
+                name: "get_weather".to_string(),
+                description: None,
+                parameters: None,
+            },
+        };
+        let matcher = ToolCallingMatcher::new(vec![tool], ToolChoice::Auto).unwrap();
+        let outputs = [
+            r#"{"name": "get_weather", "arguments": {"city": "Paris"}}"#,
+            r#"[TOOL_CALLS] [{"name": "get_weather", "arguments": {"city": "Paris"}}]"#,
+            r#"<|python_tag|>{"name": "get_weather", "parameters": {"city": "Paris"}}"#,
+            "<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>",
+        ];
+        for output in outputs {
+            assert!(matcher.prefix_could_be_tool(output), "{output}");
+            let calls = matcher.get_calls(output, "call");
+            assert_eq!(calls.len(), 1, "{output}");
+            assert_eq!(calls[0].function.name, "get_weather");
+            assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        }
+
+        assert!(!matcher.prefix_could_be_tool("The weather is"));
+        assert!(matcher
+            .get_calls("The weather is sunny.", "call")
+            .is_empty());
+        assert!(matcher
+            .get_calls(r#"{"name": "get_time", "arguments": {}}"#, "call")
+            .is_empty());
+    }
+}
@@ -19,9 +19,8 @@ macro_rules! handle_seq_error {
             Ok(v) => v,
             Err(e) => {
                 use $crate::response::Response;
-                $response
-                    .send(Response::InternalError(e.into()))
-                    .expect("Expected receiver.");
+                // The receiver may already be gone, in which case there is nobody to notify.
+                let _ = $response.send(Response::InternalError(e.into()));
                 return;
             }
         }
@@ -35,9 +34,8 @@ macro_rules! handle_seq_error_ok {
             Ok(v) => v,
             Err(e) => {
                 use $crate::response::Response;
-                $response
-                    .send(Response::InternalError(e.into()))
-                    .expect("Expected receiver.");
+                // The receiver may already be gone, in which case there is nobody to notify.
+                let _ = $response.send(Response::InternalError(e.into()));
                 return Ok(());
             }
         }
@@ -52,9 +50,13 @@ macro_rules! handle_seq_error_stateaware {
             Err(e) => {
                 use $crate::response::Response;
                 use $crate::sequence::SequenceState;
-                $seq.responder()
+                if $seq
+                    .responder()
                     .send(Response::InternalError(e.into()))
-                    .expect("Expected receiver.");
+                    .is_err()
+                {
+                    $seq.get_mut_group().is_canceled = true;
+                }
                 $seq.set_state(SequenceState::Error);
                 return;
             }
@@ -70,9 +72,13 @@ macro_rules! handle_seq_error_stateaware_ok {
             Err(e) => {
                 use $crate::response::Response;
                 use $crate::sequence::SequenceState;
-                $seq.responder()
+                if $seq
+                    .responder()
                     .send(Response::InternalError(e.into()))
-                    .expect("Expected receiver.");
+                    .is_err()
+                {
+                    $seq.get_mut_group().is_canceled = true;
+                }
                 $seq.set_state(SequenceState::Error);
                 return Ok(());
             }
@@ -94,9 +100,10 @@ macro_rules! handle_pipeline_forward_error {
                 error!("{} - Model failed with error: {:?}", $stage, &e);
                 for seq in $seq_slice.iter_mut() {
                     // Step 1: Add all choices to groups
+                    // A prompt which is being prefilled in chunks only has part of its tokens in `get_toks`.
                     let res = match $pipeline
                         .tokenizer()
-                        .decode(&seq.get_toks()[seq.prompt_tokens()..], false)
+                        .decode(seq.get_toks().get(seq.prompt_tokens()..).unwrap_or_default(), false)
                     {
                         Ok(v) => v,
                         Err(_) => "".to_string(),
@@ -109,6 +116,7 @@ macro_rules! handle_pipeline_forward_error {
                             message: ResponseMessage {
                                 content: res,
                                 role: "assistant".to_string(),
+                                tool_calls: Vec::new(),
                             },
                             logprobs: None,
                         };
@@ -125,7 +133,7 @@ macro_rules! handle_pipeline_forward_error {
                 }
                 for seq in $seq_slice.iter_mut() {
                     // Step 2: Respond with all groups
-                    let group = seq.get_mut_group();
+                    let mut group = seq.get_mut_group();
 
                     if group.is_chat {
                         let partial_completion_response = ChatCompletionResponse {
@@ -138,12 +146,16 @@ macro_rules! handle_pipeline_forward_error {
                             usage: group.get_usage(),
                         };
 
-                        seq.responder()
+                        if seq
+                            .responder()
                             .send(Response::ModelError(
                                 e.to_string(),
                                 partial_completion_response
                             ))
-                            .unwrap();
+                            .is_err()
+                        {
+                            group.is_canceled = true;
+                        }
                     } else {
                         let partial_completion_response = CompletionResponse {
                             id: seq.id().to_string(),
@@ -155,12 +167,16 @@ macro_rules! handle_pipeline_forward_error {
                             usage: group.get_usage(),
                         };
 
-                        seq.responder()
+                        if seq
+                            .responder()
                             .send(Response::CompletionModelError(
                                 e.to_string(),
                                 partial_completion_response
                             ))
-                            .unwrap();
+                            .is_err()
+                        {
+                            group.is_canceled = true;
+                        }
                     }
                 }
                 for seq in $seq_slice.iter_mut() {
@@ -169,6 +185,11 @@ macro_rules! handle_pipeline_forward_error {
                 }
 
                 Engine::set_none_cache(&mut *$pipeline);
+                if let Some(paged_cache) = $pipeline.cache().paged_lock().as_mut() {
+                    for seq in $seq_slice.iter() {
+                        paged_cache.free(*seq.id());
+                    }
+                }
                 $prefix_cacher.evict_all_to_cpu().unwrap();
 
                 continue $label;
@@ -199,4 +220,18 @@ macro_rules! get_bias_if_not_allowed {
             Some(token_set)
         }
     };
+    ($pipeline:expr, $rx:expr, $next_token_id:expr, $healing_prefix:expr) => {
+        if $pipeline
+            .tok_trie()
+            .token_allowed_with_prefix($rx, $next_token_id, $healing_prefix)
+        {
+            None
+        } else {
+            let mut token_set = $pipeline.tok_trie().alloc_token_set();
+            $pipeline
+                .tok_trie()
+                .compute_healing_bias($rx, &mut token_set, $healing_prefix);
+            Some(token_set)
+        }
+    };
 }
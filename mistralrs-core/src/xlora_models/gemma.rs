@@ -2,7 +2,7 @@
 
 use std::sync::Arc;
 
-use candle_core::{quantized::QMatMul, DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_core::{quantized::QMatMul, DType, Device, Module, Result, Tensor, D};
 use candle_nn::{RotaryEmbedding, VarBuilder};
 use mistralrs_lora::{layer::QLinear, linear_b as linear, LinearLayerLike, LoraConfig, Ordering};
 use tqdm::Iter;
@@ -522,24 +522,6 @@ impl XLoraModel {
             .to_dtype(self.dtype)
     }
 
-    fn calculate_past_kv_len(
-        &self,
-        seq_len: usize,
-        kv_cache_1: &Option<(Tensor, Tensor)>,
-    ) -> Result<usize> {
-        if kv_cache_1.is_none() {
-            return Ok(0);
-        }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        if k_cache_1.dims()[0] <= seq_len {
-            Ok(0)
-        } else {
-            let indexed = k_cache_1.i(seq_len)?;
-            let dims = indexed.dims();
-            Ok(dims[dims.len() - 2])
-        }
-    }
-
     #[allow(clippy::too_many_arguments)]
     fn inner_forward(
         &mut self,
@@ -569,13 +551,10 @@ impl XLoraModel {
         } else {
             self.cache.lock()
         };
-        let past_key_values_length =
-            self.calculate_past_kv_len(seq_len, cache.first().as_ref().unwrap())?;
         let attention_mask = if seq_len <= 1 {
             None
         } else {
-            let mask =
-                self.prepare_decoder_attention_mask(b_size, seq_len, past_key_values_length)?;
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offsets[0])?;
             Some(mask)
         };
         let xs = self.embed_tokens.forward(input_ids)?;
@@ -614,7 +593,7 @@ impl XLoraModel {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -688,7 +667,7 @@ impl NormalModel for XLoraModel {
         _input_ids: &Tensor,
         _seqlen_offsets: &[usize],
         _start_offsets_kernel: Tensor,
-        _context_lens: Vec<usize>,
+        _context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         unreachable!()
     }
@@ -702,7 +681,7 @@ impl NormalModel for XLoraModel {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<crate::xlora_models::NonGranularState>,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
@@ -35,17 +35,23 @@ impl Cache {
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            mask.to_device(device)
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+    /// The causal mask for `t` new tokens which follow `seqlen_offset` cached tokens. Only the masks of
+    /// whole prompts are cached: the masks of prompt chunks are built each time, as there is one for
+    /// every offset.
+    fn mask(&mut self, t: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
+        if seqlen_offset == 0 {
+            if let Some(mask) = self.masks.get(&t) {
+                return mask.to_device(device);
+            }
+        }
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| (0..t + seqlen_offset).map(move |j| u8::from(j > i + seqlen_offset)))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, t + seqlen_offset), device)?;
+        if seqlen_offset == 0 {
             self.masks.insert(t, mask.clone());
-            Ok(mask)
         }
+        Ok(mask)
     }
 }
 
@@ -161,10 +167,14 @@ impl CausalSelfAttention {
             let k = k.to_dtype(DType::F32)?;
             let v = v.to_dtype(DType::F32)?;
             let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-            let mask = cache
-                .mask(seq_len, att.device())?
-                .broadcast_as(att.shape())?;
-            let att = masked_fill(&att, &mask, f32::NEG_INFINITY)?;
+            let att = if seq_len > 1 {
+                let mask = cache
+                    .mask(seq_len, seqlen_offsets[0], att.device())?
+                    .broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+            } else {
+                att
+            };
             let att = candle_nn::ops::softmax(&att, D::Minus1)?;
             // Convert to contiguous as matmul doesn't support strided vs for now.
             att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
@@ -435,7 +445,7 @@ impl XLoraLlama {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -584,7 +594,7 @@ impl NormalModel for XLoraLlama {
         _input_ids: &Tensor,
         _seqlen_offsets: &[usize],
         _start_offsets_kernel: Tensor,
-        _context_lens: Vec<usize>,
+        _context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         unreachable!()
     }
@@ -598,7 +608,7 @@ impl NormalModel for XLoraLlama {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<crate::xlora_models::NonGranularState>,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
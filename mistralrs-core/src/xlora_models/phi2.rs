@@ -114,11 +114,12 @@ struct Attention {
     use_flash_attn: bool,
 }
 
-fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
+/// The causal mask for `size` new tokens which follow `seqlen_offset` cached tokens.
+fn get_mask(size: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
     let mask: Vec<_> = (0..size)
-        .flat_map(|i| (0..size).map(move |j| u8::from(j > i)))
+        .flat_map(|i| (0..size + seqlen_offset).map(move |j| u8::from(j > i + seqlen_offset)))
         .collect();
-    Tensor::from_slice(&mask, (size, size), device)
+    Tensor::from_slice(&mask, (size, size + seqlen_offset), device)
 }
 
 fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
@@ -499,7 +500,7 @@ impl Model {
         let mask = if seq_len <= 1 {
             None
         } else {
-            Some(get_mask(seq_len, xs.device())?)
+            Some(get_mask(seq_len, seqlen_offsets[0], xs.device())?)
         };
         let mut cache = if is_full_pass {
             if no_kv_cache {
@@ -547,7 +548,7 @@ impl Model {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
@@ -621,7 +622,7 @@ impl NormalModel for Model {
         _input_ids: &Tensor,
         _seqlen_offsets: &[usize],
         _start_offsets_kernel: Tensor,
-        _context_lens: Vec<usize>,
+        _context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         unreachable!()
     }
@@ -635,7 +636,7 @@ impl NormalModel for Model {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<crate::xlora_models::NonGranularState>,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         self.forward(
             input_ids,
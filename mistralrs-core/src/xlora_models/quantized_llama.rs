@@ -679,17 +679,23 @@ impl ModelWeights {
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+    /// The causal mask for `t` new tokens which follow `seqlen_offset` cached tokens. Only the masks of
+    /// whole prompts are cached: the masks of prompt chunks are built each time, as there is one for
+    /// every offset.
+    fn mask(&mut self, t: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
+        if seqlen_offset == 0 {
+            if let Some(mask) = self.masks.get(&t) {
+                return Ok(mask.clone());
+            }
+        }
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| (0..t + seqlen_offset).map(move |j| u8::from(j > i + seqlen_offset)))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, t + seqlen_offset), device)?;
+        if seqlen_offset == 0 {
             self.masks.insert(t, mask.clone());
-            Ok(mask)
         }
+        Ok(mask)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -707,7 +713,7 @@ impl ModelWeights {
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(self.mask(seq_len, start_offsets[0], x.device())?)
         };
         let mut layer_in = self.tok_embeddings.forward(x)?;
         let mut cache = if is_full_pass {
@@ -775,7 +781,7 @@ impl ModelWeights {
         start_offsets_kernel_full: Tensor,
         no_kv_cache: bool,
         non_granular_state: &Option<NonGranularState>,
-        context_lens: Vec<usize>,
+        context_lens: Vec<(usize, usize)>,
     ) -> Result<Tensor> {
         if self.xlora_classifier.is_some() {
             let scalings = self.get_scalings(
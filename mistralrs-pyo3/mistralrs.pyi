@@ -22,9 +22,24 @@ class ChatCompletionRequest:
     temperature: float | None = None
     top_p: float | None = None
     stream: bool = False
+    raw_logprobs: bool = False
     top_k: int | None = None
+    min_p: float | None = None
+    typical_p: float | None = None
+    tfs_z: float | None = None
+    mirostat: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    repetition_penalty: float | None = None
+    dry_multiplier: float | None = None
+    dry_base: float | None = None
+    dry_allowed_length: int | None = None
+    dry_sequence_breakers: list[str] | None = None
     grammar: str | None = None
     grammar_type: str | None = None
+    seed: int | None = None
+    tool_schemas: list[str] | None = None
+    tool_choice: str | None = None
 
 @dataclass
 class CompletionRequest:
@@ -47,8 +62,22 @@ class CompletionRequest:
     top_p: float | None = None
     top_k: int | None = None
     suffix: str | None = None
+    stream: bool = False
+    min_p: float | None = None
+    typical_p: float | None = None
+    tfs_z: float | None = None
+    mirostat: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    repetition_penalty: float | None = None
+    dry_multiplier: float | None = None
+    dry_base: float | None = None
+    dry_allowed_length: int | None = None
+    dry_sequence_breakers: list[str] | None = None
     grammar: str | None = None
     grammar_type: str | None = None
+    seed: int | None = None
+    token_healing: bool = False
 
 @dataclass
 class Architecture(Enum):
@@ -156,6 +185,7 @@ class Runner:
         chat_template: str | None = None,
         num_device_layers: int | None = None,
         in_situ_quant: str | None = None,
+        grammars: dict[str, tuple[str, str]] | None = None,
     ) -> None:
         """
         Load a model.
@@ -171,6 +201,8 @@ class Runner:
             It is used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
         - `num_device_layers` sets the number of layers to load and run on the device.
         - `in_situ_quant` sets the optional in-situ quantization for models that are not quantized (not GGUF or GGML).
+        - `grammars` registers grammars by name, as a `(grammar_type, grammar)` pair. They are compiled when the model is loaded,
+            and requests use them with the `named` grammar type and the name as the grammar.
         """
         ...
 
@@ -182,9 +214,12 @@ class Runner:
         over chunk objects.
         """
 
-    def send_completion_request(self, request: CompletionRequest) -> CompletionResponse:
+    def send_completion_request(
+        self, request: CompletionRequest
+    ) -> CompletionResponse | Iterator[CompletionChunkResponse]:
         """
-        Send a chat completion request to the mistral.rs engine, returning the response object.
+        Send a completion request to the mistral.rs engine, returning the response object or a generator
+        over chunk objects.
         """
 
     def send_re_isq(self, dtype: str) -> CompletionResponse:
@@ -201,6 +236,7 @@ class Role(Enum):
     User = 1
     Assistant = 2
     System = 3
+    Tool = 4
 
 @dataclass
 class Message:
@@ -223,21 +259,40 @@ class Usage:
     total_prompt_time_sec: float
     total_completion_time_sec: float
 
+@dataclass
+class ToolType(Enum):
+    Function = 1
+
+@dataclass
+class CalledFunction:
+    name: str
+    arguments: str
+
+@dataclass
+class ToolCallResponse:
+    id: str
+    index: int
+    tp: ToolType
+    function: CalledFunction
+
 @dataclass
 class ResponseMessage:
     content: str
     role: str
+    tool_calls: list[ToolCallResponse]
 
 @dataclass
 class TopLogprob:
     token: int
     logprob: float
+    raw_logprob: float | None
     bytes: str
 
 @dataclass
 class ResponseLogprob:
     token: str
     logprob: float
+    raw_logprob: float | None
     bytes: list[int]
     top_logprobs: list[TopLogprob]
 
@@ -266,6 +321,7 @@ class ChatCompletionResponse:
 class Delta:
     content: str
     role: str
+    tool_calls: list[ToolCallResponse]
 
 @dataclass
 class ChunkChoice:
@@ -299,3 +355,19 @@ class CompletionResponse:
     system_fingerprint: str
     object: str
     usage: Usage
+
+@dataclass
+class CompletionChunkChoice:
+    text: str
+    index: int
+    logprobs: ResponseLogprob | None
+    finish_reason: str | None
+
+@dataclass
+class CompletionChunkResponse:
+    id: str
+    choices: list[CompletionChunkChoice]
+    created: int
+    model: str
+    system_fingerprint: str
+    object: str
@@ -9,16 +9,18 @@ use std::{
     collections::HashMap,
     fmt::Debug,
     str::FromStr,
-    sync::{mpsc::channel, Arc, Mutex},
+    sync::{Arc, Mutex},
 };
-use stream::ChatCompletionStreamer;
+use stream::{ChatCompletionStreamer, CompletionStreamer};
+use tokio::sync::mpsc::unbounded_channel;
 
 use candle_core::Device;
 use mistralrs_core::{
-    ChatCompletionResponse, CompletionResponse, Constraint, DeviceMapMetadata, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, MistralRs, MistralRsBuilder,
-    NormalLoaderBuilder, NormalSpecificConfig, Request as _Request, RequestMessage, Response,
-    SamplingParams, SchedulerMethod, StopTokens, TokenSource,
+    ChatCompletionResponse, CompletionResponse, Constraint, DeviceMapMetadata, DrySamplingParams,
+    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, Mirostat,
+    MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalSpecificConfig, Request as _Request,
+    RequestMessage, Response, SamplingParams, SchedulerMethod, StopTokens, TokenSource, Tool,
+    ToolChoice,
 };
 use pyo3::{
     exceptions::{PyTypeError, PyValueError},
@@ -75,6 +77,30 @@ fn parse_isq(s: &str) -> std::result::Result<GgmlDType, String> {
     }
 }
 
+fn parse_constraint(grammar: Option<&str>, grammar_type: Option<&str>) -> PyResult<Constraint> {
+    let Some(grammar_type) = grammar_type else {
+        return Ok(Constraint::None);
+    };
+    let Some(grammar) = grammar.map(ToString::to_string) else {
+        return Err(PyValueError::new_err(
+            "Grammar type is specified but not grammar text",
+        ));
+    };
+    match grammar_type {
+        "regex" => Ok(Constraint::Regex(grammar)),
+        "yacc" => Ok(Constraint::Yacc(grammar)),
+        "gbnf" => Ok(Constraint::Gbnf(grammar)),
+        "lark" => Ok(Constraint::Lark(grammar)),
+        "json_schema" => serde_json::from_str(&grammar)
+            .map(Constraint::JsonSchema)
+            .map_err(|e| PyValueError::new_err(e.to_string())),
+        "named" => Ok(Constraint::Named(grammar)),
+        _ => Err(PyValueError::new_err(
+            "Grammar type is specified but is not `regex`, `yacc`, `gbnf`, `lark`, `json_schema` or `named`",
+        )),
+    }
+}
+
 #[pyclass]
 /// An object wrapping the underlying Rust system to handle requests and process conversations.
 struct Runner {
@@ -94,7 +120,8 @@ impl Runner {
         token_source = "cache",
         chat_template = None,
         num_device_layers = None,
-        in_situ_quant = None
+        in_situ_quant = None,
+        grammars = None
     ))]
     fn new(
         which: Which,
@@ -105,6 +132,7 @@ impl Runner {
         chat_template: Option<String>,
         num_device_layers: Option<usize>,
         in_situ_quant: Option<String>,
+        grammars: Option<HashMap<String, (String, String)>>,
     ) -> PyResult<Self> {
         const REPEAT_LAST_N_DEFAULT: usize = 64;
         const GQA_DEFAULT: usize = 1;
@@ -394,6 +422,13 @@ impl Runner {
             )
             .map_err(|e| PyValueError::new_err(e.to_string()))?;
 
+        let named_grammars = grammars
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, (grammar_type, grammar))| {
+                Ok((name, parse_constraint(Some(&grammar), Some(&grammar_type))?))
+            })
+            .collect::<PyResult<HashMap<_, _>>>()?;
         let mistralrs = MistralRsBuilder::new(
             pipeline,
             SchedulerMethod::Fixed(
@@ -404,6 +439,7 @@ impl Runner {
         )
         .with_no_kv_cache(no_kv_cache)
         .with_prefix_cache_n(prefix_cache_n)
+        .with_named_grammars(named_grammars)
         .build();
 
         Ok(Self { runner: mistralrs })
@@ -414,34 +450,32 @@ impl Runner {
         &mut self,
         request: Py<ChatCompletionRequest>,
     ) -> PyResult<Either<ChatCompletionResponse, ChatCompletionStreamer>> {
-        let (tx, rx) = channel();
+        let (tx, mut rx) = unbounded_channel();
         Python::with_gil(|py| {
             let request = request.bind(py).borrow();
             let stop_toks = request
                 .stop_seqs
                 .as_ref()
                 .map(|x| StopTokens::Seqs(x.to_vec()));
-            let constraint = if request.grammar_type == Some("regex".to_string()) {
-                if request.grammar.is_none() {
-                    return Err(PyValueError::new_err(
-                        "Grammar type is specified but not grammar text",
-                    ));
-                }
-                Constraint::Regex(request.grammar.as_ref().unwrap().clone())
-            } else if request.grammar_type == Some("yacc".to_string()) {
-                if request.grammar.is_none() {
-                    return Err(PyValueError::new_err(
-                        "Grammar type is specified but not grammar text",
-                    ));
-                }
-                Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
-            } else if request.grammar_type.is_some() {
-                return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
-                ));
-            } else {
-                Constraint::None
-            };
+            let constraint =
+                parse_constraint(request.grammar.as_deref(), request.grammar_type.as_deref())?;
+            let tools = request
+                .tool_schemas
+                .as_ref()
+                .map(|schemas| {
+                    schemas
+                        .iter()
+                        .map(|schema| serde_json::from_str::<Tool>(schema))
+                        .collect::<serde_json::Result<Vec<_>>>()
+                })
+                .transpose()
+                .map_err(|e| PyValueError::new_err(e.to_string()))?;
+            let tool_choice = request.tool_choice.as_deref().map(|choice| match choice {
+                "none" => ToolChoice::None,
+                "auto" => ToolChoice::Auto,
+                "required" => ToolChoice::Required,
+                name => ToolChoice::Function(name.to_string()),
+            });
             let model_request = _Request {
                 id: {
                     let l = NEXT_REQUEST_ID.lock().unwrap();
@@ -459,6 +493,7 @@ impl Runner {
                                 Role::Assistant => "assistant",
                                 Role::User => "user",
                                 Role::System => "system",
+                                Role::Tool => "tool",
                             };
                             message_map.insert("role".to_string(), role.to_string());
                             message_map.insert("content".to_string(), message.content.clone());
@@ -479,19 +514,42 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
+                    min_p: request.min_p,
+                    typical_p: request.typical_p,
+                    tfs_z: request.tfs_z,
+                    mirostat: request.mirostat.and_then(|version| {
+                        Mirostat::from_version(version, request.mirostat_tau, request.mirostat_eta)
+                    }),
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: request.repetition_penalty,
+                    dry_params: request.dry_multiplier.map(|multiplier| {
+                        DrySamplingParams::new_with_defaults(
+                            multiplier,
+                            request.dry_base,
+                            request.dry_allowed_length,
+                            request.dry_sequence_breakers.clone(),
+                        )
+                    }),
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    seed: request.seed,
+                    raw_logprobs: request.raw_logprobs,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
                 is_streaming: request.stream,
                 constraint,
                 suffix: None,
+                tools,
+                tool_choice,
+                priority: 0,
+                tenant: None,
+                deadline: None,
+                max_queue_time: None,
             };
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -501,7 +559,7 @@ impl Runner {
             if request.stream {
                 Ok(Either::Right(ChatCompletionStreamer::from_rx(rx)))
             } else {
-                let response = rx.recv().unwrap();
+                let response = rx.blocking_recv().unwrap();
 
                 match response {
                     Response::ValidationError(e) | Response::InternalError(e) => {
@@ -512,6 +570,7 @@ impl Runner {
                     Response::Chunk(_) => unreachable!(),
                     Response::CompletionDone(_) => unreachable!(),
                     Response::CompletionModelError(_, _) => unreachable!(),
+                    Response::CompletionChunk(_) => unreachable!(),
                 }
             }
         })
@@ -521,35 +580,16 @@ impl Runner {
     fn send_completion_request(
         &mut self,
         request: Py<CompletionRequest>,
-    ) -> PyResult<CompletionResponse> {
-        let (tx, rx) = channel();
+    ) -> PyResult<Either<CompletionResponse, CompletionStreamer>> {
+        let (tx, mut rx) = unbounded_channel();
         Python::with_gil(|py| {
             let request = request.bind(py).borrow();
             let stop_toks = request
                 .stop_seqs
                 .as_ref()
                 .map(|x| StopTokens::Seqs(x.to_vec()));
-            let constraint = if request.grammar_type == Some("regex".to_string()) {
-                if request.grammar.is_none() {
-                    return Err(PyValueError::new_err(
-                        "Grammar type is specified but not grammar text",
-                    ));
-                }
-                Constraint::Regex(request.grammar.as_ref().unwrap().clone())
-            } else if request.grammar_type == Some("yacc".to_string()) {
-                if request.grammar.is_none() {
-                    return Err(PyValueError::new_err(
-                        "Grammar type is specified but not grammar text",
-                    ));
-                }
-                Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
-            } else if request.grammar_type.is_some() {
-                return Err(PyValueError::new_err(
-                    "Grammar type is specified but is not `regex` or `yacc`",
-                ));
-            } else {
-                Constraint::None
-            };
+            let constraint =
+                parse_constraint(request.grammar.as_deref(), request.grammar_type.as_deref())?;
             let model_request = _Request {
                 id: {
                     let l = NEXT_REQUEST_ID.lock().unwrap();
@@ -562,36 +602,64 @@ impl Runner {
                     text: request.prompt.clone(),
                     echo_prompt: request.echo_prompt,
                     best_of: request.best_of,
+                    token_healing: request.token_healing,
                 },
                 sampling_params: SamplingParams {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
+                    min_p: request.min_p,
+                    typical_p: request.typical_p,
+                    tfs_z: request.tfs_z,
+                    mirostat: request.mirostat.and_then(|version| {
+                        Mirostat::from_version(version, request.mirostat_tau, request.mirostat_eta)
+                    }),
                     top_n_logprobs: 1,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: request.repetition_penalty,
+                    dry_params: request.dry_multiplier.map(|multiplier| {
+                        DrySamplingParams::new_with_defaults(
+                            multiplier,
+                            request.dry_base,
+                            request.dry_allowed_length,
+                            request.dry_sequence_breakers.clone(),
+                        )
+                    }),
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    seed: request.seed,
+                    raw_logprobs: false,
                 },
                 response: tx,
                 return_logprobs: false,
-                is_streaming: false,
+                is_streaming: request.stream,
                 constraint,
                 suffix: request.suffix.clone(),
+                tools: None,
+                tool_choice: None,
+                priority: 0,
+                tenant: None,
+                deadline: None,
+                max_queue_time: None,
             };
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
             let sender = self.runner.get_sender();
             sender.send(model_request).unwrap();
-            let response = rx.recv().unwrap();
+
+            if request.stream {
+                return Ok(Either::Right(CompletionStreamer::from_rx(rx)));
+            }
+            let response = rx.blocking_recv().unwrap();
 
             match response {
                 Response::ValidationError(e) | Response::InternalError(e) => {
                     Err(PyValueError::new_err(e.to_string()))
                 }
-                Response::CompletionDone(response) => Ok(response),
+                Response::CompletionDone(response) => Ok(Either::Left(response)),
                 Response::CompletionModelError(msg, _) => {
                     Err(PyValueError::new_err(msg.to_string()))
                 }
@@ -628,9 +696,23 @@ struct CompletionRequest {
     temperature: Option<f64>,
     top_p: Option<f64>,
     suffix: Option<String>,
+    stream: bool,
     top_k: Option<usize>,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    tfs_z: Option<f64>,
+    mirostat: Option<usize>,
+    mirostat_tau: Option<f32>,
+    mirostat_eta: Option<f32>,
+    repetition_penalty: Option<f32>,
+    dry_multiplier: Option<f32>,
+    dry_base: Option<f32>,
+    dry_allowed_length: Option<usize>,
+    dry_sequence_breakers: Option<Vec<String>>,
     grammar: Option<String>,
     grammar_type: Option<String>,
+    seed: Option<u64>,
+    token_healing: bool,
 }
 
 #[pymethods]
@@ -650,9 +732,23 @@ impl CompletionRequest {
         temperature=None,
         top_p=None,
         suffix=None,
+        stream=false,
         top_k=None,
+        min_p=None,
+        typical_p=None,
+        tfs_z=None,
+        mirostat=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        repetition_penalty=None,
+        dry_multiplier=None,
+        dry_base=None,
+        dry_allowed_length=None,
+        dry_sequence_breakers=None,
         grammar = None,
-        grammar_type = None
+        grammar_type = None,
+        seed = None,
+        token_healing = false
     ))]
     fn new(
         prompt: String,
@@ -668,15 +764,30 @@ impl CompletionRequest {
         temperature: Option<f64>,
         top_p: Option<f64>,
         suffix: Option<String>,
+        stream: bool,
         top_k: Option<usize>,
+        min_p: Option<f64>,
+        typical_p: Option<f64>,
+        tfs_z: Option<f64>,
+        mirostat: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        repetition_penalty: Option<f32>,
+        dry_multiplier: Option<f32>,
+        dry_base: Option<f32>,
+        dry_allowed_length: Option<usize>,
+        dry_sequence_breakers: Option<Vec<String>>,
         grammar: Option<String>,
         grammar_type: Option<String>,
+        seed: Option<u64>,
+        token_healing: bool,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
             best_of,
             echo_prompt,
             suffix,
+            stream,
             _model: model,
             logit_bias,
             max_tokens,
@@ -687,8 +798,21 @@ impl CompletionRequest {
             temperature,
             top_p,
             top_k,
+            min_p,
+            typical_p,
+            tfs_z,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta,
+            repetition_penalty,
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
+            dry_sequence_breakers,
             grammar,
             grammar_type,
+            seed,
+            token_healing,
         })
     }
 }
@@ -710,9 +834,24 @@ struct ChatCompletionRequest {
     temperature: Option<f64>,
     top_p: Option<f64>,
     stream: bool,
+    raw_logprobs: bool,
     top_k: Option<usize>,
+    min_p: Option<f64>,
+    typical_p: Option<f64>,
+    tfs_z: Option<f64>,
+    mirostat: Option<usize>,
+    mirostat_tau: Option<f32>,
+    mirostat_eta: Option<f32>,
+    repetition_penalty: Option<f32>,
+    dry_multiplier: Option<f32>,
+    dry_base: Option<f32>,
+    dry_allowed_length: Option<usize>,
+    dry_sequence_breakers: Option<Vec<String>>,
     grammar: Option<String>,
     grammar_type: Option<String>,
+    seed: Option<u64>,
+    tool_schemas: Option<Vec<String>>,
+    tool_choice: Option<String>,
 }
 
 #[pymethods]
@@ -733,8 +872,23 @@ impl ChatCompletionRequest {
         top_p = None,
         top_k = None,
         stream=false,
+        raw_logprobs=false,
+        min_p = None,
+        typical_p = None,
+        tfs_z = None,
+        mirostat = None,
+        mirostat_tau = None,
+        mirostat_eta = None,
+        repetition_penalty = None,
+        dry_multiplier = None,
+        dry_base = None,
+        dry_allowed_length = None,
+        dry_sequence_breakers = None,
         grammar = None,
-        grammar_type = None
+        grammar_type = None,
+        seed = None,
+        tool_schemas = None,
+        tool_choice = None
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -751,8 +905,23 @@ impl ChatCompletionRequest {
         top_p: Option<f64>,
         top_k: Option<usize>,
         stream: Option<bool>,
+        raw_logprobs: bool,
+        min_p: Option<f64>,
+        typical_p: Option<f64>,
+        tfs_z: Option<f64>,
+        mirostat: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        repetition_penalty: Option<f32>,
+        dry_multiplier: Option<f32>,
+        dry_base: Option<f32>,
+        dry_allowed_length: Option<usize>,
+        dry_sequence_breakers: Option<Vec<String>>,
         grammar: Option<String>,
         grammar_type: Option<String>,
+        seed: Option<u64>,
+        tool_schemas: Option<Vec<String>>,
+        tool_choice: Option<String>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -783,8 +952,23 @@ impl ChatCompletionRequest {
             top_p,
             top_k,
             stream: stream.unwrap_or(false),
+            raw_logprobs,
+            min_p,
+            typical_p,
+            tfs_z,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta,
+            repetition_penalty,
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
+            dry_sequence_breakers,
             grammar,
             grammar_type,
+            seed,
+            tool_schemas,
+            tool_choice,
         })
     }
 }
@@ -799,6 +983,9 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Role>()?;
     m.add_class::<Architecture>()?;
 
+    m.add_class::<mistralrs_core::ToolType>()?;
+    m.add_class::<mistralrs_core::CalledFunction>()?;
+    m.add_class::<mistralrs_core::ToolCallResponse>()?;
     m.add_class::<mistralrs_core::ResponseMessage>()?;
     m.add_class::<mistralrs_core::Delta>()?;
     m.add_class::<mistralrs_core::ResponseLogprob>()?;
@@ -810,6 +997,8 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
     m.add_class::<mistralrs_core::CompletionResponse>()?;
+    m.add_class::<mistralrs_core::CompletionChunkChoice>()?;
+    m.add_class::<mistralrs_core::CompletionChunkResponse>()?;
     m.add_class::<mistralrs_core::TopLogprob>()?;
     Ok(())
 }
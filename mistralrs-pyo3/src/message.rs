@@ -6,6 +6,7 @@ pub enum Role {
     User,
     Assistant,
     System,
+    Tool,
 }
 
 #[pyclass]
@@ -1,16 +1,15 @@
-use std::sync::mpsc::Receiver;
-
-use mistralrs_core::{ChatCompletionChunkResponse, Response};
+use mistralrs_core::{ChatCompletionChunkResponse, CompletionChunkResponse, Response};
 use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyRef, PyRefMut, PyResult};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 #[pyclass]
 pub struct ChatCompletionStreamer {
-    rx: Receiver<Response>,
+    rx: UnboundedReceiver<Response>,
     is_done: bool,
 }
 
 impl ChatCompletionStreamer {
-    pub fn from_rx(rx: Receiver<Response>) -> Self {
+    pub fn from_rx(rx: UnboundedReceiver<Response>) -> Self {
         Self { rx, is_done: false }
     }
 }
@@ -24,8 +23,8 @@ impl ChatCompletionStreamer {
         if this.is_done {
             return None;
         }
-        match this.rx.recv() {
-            Ok(resp) => match resp {
+        match this.rx.blocking_recv() {
+            Some(resp) => match resp {
                 Response::ModelError(msg, _) => Some(Err(PyValueError::new_err(msg.to_string()))),
                 Response::ValidationError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
                 Response::InternalError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
@@ -38,8 +37,57 @@ impl ChatCompletionStreamer {
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
+            },
+            None => Some(Err(PyValueError::new_err(
+                "The engine dropped the request.",
+            ))),
+        }
+    }
+}
+
+#[pyclass]
+pub struct CompletionStreamer {
+    rx: UnboundedReceiver<Response>,
+    is_done: bool,
+}
+
+impl CompletionStreamer {
+    pub fn from_rx(rx: UnboundedReceiver<Response>) -> Self {
+        Self { rx, is_done: false }
+    }
+}
+
+#[pymethods]
+impl CompletionStreamer {
+    fn __iter__(this: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        this
+    }
+    fn __next__(mut this: PyRefMut<'_, Self>) -> Option<PyResult<CompletionChunkResponse>> {
+        if this.is_done {
+            return None;
+        }
+        match this.rx.blocking_recv() {
+            Some(resp) => match resp {
+                Response::CompletionModelError(msg, _) => {
+                    Some(Err(PyValueError::new_err(msg.to_string())))
+                }
+                Response::ValidationError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
+                Response::InternalError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
+                Response::CompletionChunk(response) => {
+                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                        this.is_done = true;
+                    }
+                    Some(Ok(response))
+                }
+                Response::CompletionDone(_) => unreachable!(),
+                Response::Done(_) => unreachable!(),
+                Response::Chunk(_) => unreachable!(),
+                Response::ModelError(_, _) => unreachable!(),
             },
-            Err(e) => Some(Err(PyValueError::new_err(e.to_string()))),
+            None => Some(Err(PyValueError::new_err(
+                "The engine dropped the request.",
+            ))),
         }
     }
 }
@@ -2,15 +2,14 @@ use std::{
     env,
     error::Error,
     pin::Pin,
-    sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc,
-    },
+    sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::openai::{ChatCompletionRequest, Grammar, StopTokens};
+use crate::openai::{
+    ChatCompletionRequest, Grammar, ResponseFormat, StopTokens, Tool, ToolChoice, ToolChoiceMode,
+};
 use anyhow::Result;
 use axum::{
     extract::{Json, State},
@@ -23,10 +22,13 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, MistralRs, Request, RequestMessage, Response,
-    SamplingParams, StopTokens as InternalStopTokens,
+    ChatCompletionResponse, Constraint, DrySamplingParams, Function, Mirostat, MistralRs,
+    QueueStatus, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens, Tool as InternalTool, ToolChoice as InternalToolChoice,
+    ToolType,
 };
 use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 #[derive(Debug)]
 struct ModelErrorMessage(String);
@@ -36,21 +38,54 @@ impl std::fmt::Display for ModelErrorMessage {
     }
 }
 impl std::error::Error for ModelErrorMessage {}
+
+/// Cancels a request when dropped, unless it was disarmed. Axum drops the handler future or
+/// response stream when the client disconnects, so this frees the request's sequences in the engine.
+pub struct CancelGuard {
+    state: Arc<MistralRs>,
+    request_id: usize,
+    armed: bool,
+}
+
+impl CancelGuard {
+    pub fn new(state: Arc<MistralRs>, request_id: usize) -> Self {
+        Self {
+            state,
+            request_id,
+            armed: true,
+        }
+    }
+
+    /// The request finished, so there is nothing to cancel.
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            self.state.cancel_request(self.request_id);
+        }
+    }
+}
+
 pub struct Streamer {
-    rx: Receiver<Response>,
+    rx: UnboundedReceiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    guard: CancelGuard,
 }
 
 impl futures::Stream for Streamer {
     type Item = Result<Event, axum::Error>;
 
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if self.is_done {
             return Poll::Ready(None);
         }
-        match self.rx.try_recv() {
-            Ok(resp) => match resp {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(resp)) => match resp {
                 Response::ModelError(msg, _) => {
                     MistralRs::maybe_log_error(
                         self.state.clone(),
@@ -68,6 +103,7 @@ impl futures::Stream for Streamer {
                 Response::Chunk(response) => {
                     if response.choices.iter().all(|x| x.finish_reason.is_some()) {
                         self.is_done = true;
+                        self.guard.disarm();
                     }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
                     Poll::Ready(Some(Event::default().json_data(response)))
@@ -75,8 +111,11 @@ impl futures::Stream for Streamer {
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
             },
-            Err(_) => Poll::Pending,
+            // The engine dropped the request, so no more responses will come.
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
@@ -85,8 +124,13 @@ pub enum ChatCompletionResponder {
     Sse(Sse<Streamer>),
     Json(ChatCompletionResponse),
     ModelError(String, ChatCompletionResponse),
+    /// The request passed its deadline, with the output generated so far.
+    Timeout(ChatCompletionResponse),
+    /// The request waited longer than its maximum queue time before it was scheduled.
+    QueueTimeout(ChatCompletionResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    QueueFull(QueueStatus),
 }
 
 trait ErrorToResponse: Serialize {
@@ -126,6 +170,34 @@ impl JsonModelError {
 
 impl ErrorToResponse for JsonModelError {}
 
+#[derive(Serialize)]
+pub struct JsonQueueFull {
+    message: String,
+    queue: QueueStatus,
+}
+
+impl JsonQueueFull {
+    pub fn new(queue: QueueStatus) -> Self {
+        Self {
+            message: "Too many requests are waiting, retry later.".to_string(),
+            queue,
+        }
+    }
+}
+
+/// A `429 Too Many Requests` response, asking the client to retry after a second.
+impl IntoResponse for JsonQueueFull {
+    fn into_response(self) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = http::StatusCode::TOO_MANY_REQUESTS;
+        r.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from_static("1"),
+        );
+        r
+    }
+}
+
 impl IntoResponse for ChatCompletionResponder {
     fn into_response(self) -> axum::response::Response {
         match self {
@@ -137,10 +209,21 @@ impl IntoResponse for ChatCompletionResponder {
             ChatCompletionResponder::ValidationError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
             }
+            ChatCompletionResponder::QueueFull(status) => {
+                JsonQueueFull::new(status).into_response()
+            }
             ChatCompletionResponder::ModelError(msg, response) => {
                 JsonModelError::new(msg, response)
                     .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
             }
+            ChatCompletionResponder::Timeout(response) => {
+                JsonModelError::new("The request timed out.".to_string(), response)
+                    .to_response(http::StatusCode::REQUEST_TIMEOUT)
+            }
+            ChatCompletionResponder::QueueTimeout(response) => {
+                JsonModelError::new("The request timed out in the queue.".to_string(), response)
+                    .to_response(http::StatusCode::SERVICE_UNAVAILABLE)
+            }
         }
     }
 }
@@ -148,7 +231,7 @@ impl IntoResponse for ChatCompletionResponder {
 fn parse_request(
     oairequest: ChatCompletionRequest,
     state: Arc<MistralRs>,
-    tx: Sender<Response>,
+    tx: UnboundedSender<Response>,
 ) -> Request {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
@@ -164,7 +247,17 @@ fn parse_request(
             for message in req_messages {
                 let mut message_map = IndexMap::new();
                 message_map.insert("role".to_string(), message.role);
-                message_map.insert("content".to_string(), message.content);
+                message_map.insert("content".to_string(), message.content.unwrap_or_default());
+                if let Some(tool_calls) = message.tool_calls {
+                    message_map.insert(
+                        "tool_calls".to_string(),
+                        serde_json::to_string(&tool_calls)
+                            .expect("Serialization of tool calls failed."),
+                    );
+                }
+                if let Some(tool_call_id) = message.tool_call_id {
+                    message_map.insert("tool_call_id".to_string(), tool_call_id);
+                }
                 messages.push(message_map);
             }
             RequestMessage::Chat(messages)
@@ -186,22 +279,79 @@ fn parse_request(
             temperature: oairequest.temperature,
             top_k: oairequest.top_k,
             top_p: oairequest.top_p,
+            min_p: oairequest.min_p,
+            typical_p: oairequest.typical_p,
+            tfs_z: oairequest.tfs_z,
+            mirostat: oairequest.mirostat.and_then(|version| {
+                Mirostat::from_version(version, oairequest.mirostat_tau, oairequest.mirostat_eta)
+            }),
             top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
             frequency_penalty: oairequest.frequency_penalty,
             presence_penalty: oairequest.presence_penalty,
+            repetition_penalty: oairequest.repetition_penalty,
+            dry_params: oairequest.dry_multiplier.map(|multiplier| {
+                DrySamplingParams::new_with_defaults(
+                    multiplier,
+                    oairequest.dry_base,
+                    oairequest.dry_allowed_length,
+                    oairequest.dry_sequence_breakers,
+                )
+            }),
             max_len: oairequest.max_tokens,
             stop_toks,
             logits_bias: oairequest.logit_bias,
             n_choices: oairequest.n_choices,
+            seed: oairequest.seed,
+            raw_logprobs: oairequest.raw_logprobs,
         },
         response: tx,
         return_logprobs: oairequest.logprobs,
         is_streaming: oairequest.stream.unwrap_or(false),
         suffix: None,
-        constraint: match oairequest.grammar {
-            Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-            Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-            None => Constraint::None,
+        // An explicit grammar takes precedence over the response format.
+        constraint: match (oairequest.grammar, oairequest.response_format) {
+            (Some(Grammar::Yacc(yacc)), _) => Constraint::Yacc(yacc),
+            (Some(Grammar::Gbnf(gbnf)), _) => Constraint::Gbnf(gbnf),
+            (Some(Grammar::Lark(lark)), _) => Constraint::Lark(lark),
+            (Some(Grammar::Regex(regex)), _) => Constraint::Regex(regex),
+            (Some(Grammar::JsonSchema(schema)), _) => Constraint::JsonSchema(schema),
+            (Some(Grammar::Named(name)), _) => Constraint::Named(name),
+            (None, Some(ResponseFormat::JsonObject)) => {
+                Constraint::JsonSchema(serde_json::json!({"type": "object"}))
+            }
+            (None, Some(ResponseFormat::JsonSchema { json_schema })) => {
+                Constraint::JsonSchema(json_schema.schema)
+            }
+            (None, Some(ResponseFormat::Text) | None) => Constraint::None,
+        },
+        tools: oairequest
+            .tools
+            .map(|tools| tools.into_iter().map(convert_tool).collect()),
+        tool_choice: oairequest.tool_choice.map(|choice| match choice {
+            ToolChoice::Mode(ToolChoiceMode::None) => InternalToolChoice::None,
+            ToolChoice::Mode(ToolChoiceMode::Auto) => InternalToolChoice::Auto,
+            ToolChoice::Mode(ToolChoiceMode::Required) => InternalToolChoice::Required,
+            ToolChoice::Function(named) => InternalToolChoice::Function(named.function.name),
+        }),
+        priority: oairequest.priority.unwrap_or(0),
+        tenant: oairequest.user,
+        deadline: oairequest
+            .timeout
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .map(|timeout| Instant::now() + timeout),
+        max_queue_time: oairequest
+            .max_queue_time
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok()),
+    }
+}
+
+fn convert_tool(tool: Tool) -> InternalTool {
+    InternalTool {
+        tp: ToolType::Function,
+        function: Function {
+            name: tool.function.name,
+            description: tool.function.description,
+            parameters: tool.function.parameters,
         },
     }
 }
@@ -217,17 +367,21 @@ pub async fn chatcompletions(
     State(state): State<Arc<MistralRs>>,
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
-    let (tx, rx) = channel();
+    let (tx, mut rx) = unbounded_channel();
     let request = parse_request(oairequest, state.clone(), tx);
     let is_streaming = request.is_streaming;
-    let sender = state.get_sender();
-    sender.send(request).unwrap();
+    let mut guard = CancelGuard::new(state.clone(), request.id);
+    if let Err(status) = state.try_send_request(request) {
+        guard.disarm();
+        return ChatCompletionResponder::QueueFull(status);
+    }
 
     if is_streaming {
         let streamer = Streamer {
             rx,
             is_done: false,
             state,
+            guard,
         };
 
         ChatCompletionResponder::Sse(
@@ -242,7 +396,14 @@ pub async fn chatcompletions(
             ),
         )
     } else {
-        let response = rx.recv().unwrap();
+        let response = rx.recv().await;
+        guard.disarm();
+        // The engine drops the sender without a response if it stopped, e.g. after a panic.
+        let Some(response) = response else {
+            let e: Box<dyn Error> = "The engine stopped without sending a response.".into();
+            MistralRs::maybe_log_error(state, &*e);
+            return ChatCompletionResponder::InternalError(e);
+        };
 
         match response {
             Response::InternalError(e) => {
@@ -257,11 +418,26 @@ pub async fn chatcompletions(
             Response::ValidationError(e) => ChatCompletionResponder::ValidationError(e),
             Response::Done(response) => {
                 MistralRs::maybe_log_response(state, &response);
-                ChatCompletionResponder::Json(response)
+                // A choice which passed the deadline makes the request time out even if others were never
+                // scheduled.
+                let has_reason = |reason: &str| {
+                    response
+                        .choices
+                        .iter()
+                        .any(|choice| choice.finish_reason == reason)
+                };
+                if has_reason("timeout") {
+                    ChatCompletionResponder::Timeout(response)
+                } else if has_reason("queue_timeout") {
+                    ChatCompletionResponder::QueueTimeout(response)
+                } else {
+                    ChatCompletionResponder::Json(response)
+                }
             }
             Response::Chunk(_) => unreachable!(),
             Response::CompletionDone(_) => unreachable!(),
             Response::CompletionModelError(_, _) => unreachable!(),
+            Response::CompletionChunk(_) => unreachable!(),
         }
     }
 }
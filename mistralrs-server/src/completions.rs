@@ -1,22 +1,30 @@
 use std::{
+    env,
     error::Error,
-    sync::{
-        mpsc::{channel, Sender},
-        Arc,
-    },
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::{
+    chat_completion::{CancelGuard, JsonQueueFull},
+    openai::{CompletionRequest, Grammar, StopTokens},
+};
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
-    response::IntoResponse,
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Sse,
+    },
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, MistralRs, Request, RequestMessage, Response, SamplingParams,
-    StopTokens as InternalStopTokens,
+    CompletionResponse, Constraint, DrySamplingParams, Mirostat, MistralRs, QueueStatus, Request,
+    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tracing::warn;
 
 #[derive(Debug)]
@@ -27,11 +35,67 @@ impl std::fmt::Display for ModelErrorMessage {
     }
 }
 impl std::error::Error for ModelErrorMessage {}
+pub struct Streamer {
+    rx: UnboundedReceiver<Response>,
+    is_done: bool,
+    state: Arc<MistralRs>,
+    guard: CancelGuard,
+}
+
+impl futures::Stream for Streamer {
+    type Item = Result<Event, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_done {
+            return Poll::Ready(None);
+        }
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(resp)) => match resp {
+                Response::CompletionModelError(msg, _) => {
+                    MistralRs::maybe_log_error(
+                        self.state.clone(),
+                        &ModelErrorMessage(msg.to_string()),
+                    );
+                    Poll::Ready(Some(Ok(Event::default().data(msg))))
+                }
+                Response::ValidationError(e) => {
+                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                }
+                Response::InternalError(e) => {
+                    MistralRs::maybe_log_error(self.state.clone(), &*e);
+                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                }
+                Response::CompletionChunk(response) => {
+                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                        self.is_done = true;
+                        self.guard.disarm();
+                    }
+                    MistralRs::maybe_log_response(self.state.clone(), &response);
+                    Poll::Ready(Some(Event::default().json_data(response)))
+                }
+                Response::CompletionDone(_) => unreachable!(),
+                Response::Done(_) => unreachable!(),
+                Response::Chunk(_) => unreachable!(),
+                Response::ModelError(_, _) => unreachable!(),
+            },
+            // The engine dropped the request, so no more responses will come.
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 pub enum CompletionResponder {
+    Sse(Sse<Streamer>),
     Json(CompletionResponse),
     ModelError(String, CompletionResponse),
+    /// The request passed its deadline, with the output generated so far.
+    Timeout(CompletionResponse),
+    /// The request waited longer than its maximum queue time before it was scheduled.
+    QueueTimeout(CompletionResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    QueueFull(QueueStatus),
 }
 
 trait ErrorToResponse: Serialize {
@@ -74,6 +138,7 @@ impl ErrorToResponse for JsonModelError {}
 impl IntoResponse for CompletionResponder {
     fn into_response(self) -> axum::response::Response {
         match self {
+            CompletionResponder::Sse(s) => s.into_response(),
             CompletionResponder::Json(s) => Json(s).into_response(),
             CompletionResponder::InternalError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
@@ -81,8 +146,17 @@ impl IntoResponse for CompletionResponder {
             CompletionResponder::ValidationError(e) => {
                 JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
             }
+            CompletionResponder::QueueFull(status) => JsonQueueFull::new(status).into_response(),
             CompletionResponder::ModelError(msg, response) => JsonModelError::new(msg, response)
                 .to_response(http::StatusCode::INTERNAL_SERVER_ERROR),
+            CompletionResponder::Timeout(response) => {
+                JsonModelError::new("The request timed out.".to_string(), response)
+                    .to_response(http::StatusCode::REQUEST_TIMEOUT)
+            }
+            CompletionResponder::QueueTimeout(response) => {
+                JsonModelError::new("The request timed out in the queue.".to_string(), response)
+                    .to_response(http::StatusCode::SERVICE_UNAVAILABLE)
+            }
         }
     }
 }
@@ -90,7 +164,7 @@ impl IntoResponse for CompletionResponder {
 fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
-    tx: Sender<Response>,
+    tx: UnboundedSender<Response>,
 ) -> Request {
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
@@ -101,12 +175,9 @@ fn parse_request(
         None => None,
     };
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
-
-    if oairequest._stream.is_some_and(|x| x) {
-        warn!("Completion requests do not support streaming.");
+    let is_streaming = oairequest.stream.unwrap_or(false);
+    if oairequest.logprobs.is_some() && !is_streaming {
+        warn!("Completion requests only support logprobs when streaming.");
     }
 
     Request {
@@ -115,28 +186,61 @@ fn parse_request(
             text: oairequest.prompt,
             echo_prompt: oairequest.echo_prompt,
             best_of: oairequest.best_of,
+            token_healing: oairequest.token_healing,
         },
         sampling_params: SamplingParams {
             temperature: oairequest.temperature,
             top_k: oairequest.top_k,
             top_p: oairequest.top_p,
-            top_n_logprobs: 1,
+            min_p: oairequest.min_p,
+            typical_p: oairequest.typical_p,
+            tfs_z: oairequest.tfs_z,
+            mirostat: oairequest.mirostat.and_then(|version| {
+                Mirostat::from_version(version, oairequest.mirostat_tau, oairequest.mirostat_eta)
+            }),
+            top_n_logprobs: oairequest.logprobs.unwrap_or(1),
             frequency_penalty: oairequest.frequency_penalty,
             presence_penalty: oairequest.presence_penalty,
+            repetition_penalty: oairequest.repetition_penalty,
+            dry_params: oairequest.dry_multiplier.map(|multiplier| {
+                DrySamplingParams::new_with_defaults(
+                    multiplier,
+                    oairequest.dry_base,
+                    oairequest.dry_allowed_length,
+                    oairequest.dry_sequence_breakers,
+                )
+            }),
             max_len: oairequest.max_tokens,
             stop_toks,
             logits_bias: oairequest.logit_bias,
             n_choices: oairequest.n_choices,
+            seed: oairequest.seed,
+            raw_logprobs: false,
         },
         response: tx,
-        return_logprobs: false,
-        is_streaming: false,
+        return_logprobs: is_streaming && oairequest.logprobs.is_some(),
+        is_streaming,
         suffix: oairequest.suffix,
         constraint: match oairequest.grammar {
             Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
+            Some(Grammar::Gbnf(gbnf)) => Constraint::Gbnf(gbnf),
+            Some(Grammar::Lark(lark)) => Constraint::Lark(lark),
             Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
+            Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
+            Some(Grammar::Named(name)) => Constraint::Named(name),
             None => Constraint::None,
         },
+        tools: None,
+        tool_choice: None,
+        priority: oairequest.priority.unwrap_or(0),
+        tenant: oairequest.user,
+        deadline: oairequest
+            .timeout
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .map(|timeout| Instant::now() + timeout),
+        max_queue_time: oairequest
+            .max_queue_time
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok()),
     }
 }
 
@@ -151,26 +255,44 @@ pub async fn completions(
     State(state): State<Arc<MistralRs>>,
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
-    let (tx, rx) = channel();
+    let (tx, mut rx) = unbounded_channel();
     let request = parse_request(oairequest, state.clone(), tx);
     let is_streaming = request.is_streaming;
-    let sender = state.get_sender();
-
-    if request.return_logprobs {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support logprobs.".into(),
-        );
+    let mut guard = CancelGuard::new(state.clone(), request.id);
+    if let Err(status) = state.try_send_request(request) {
+        guard.disarm();
+        return CompletionResponder::QueueFull(status);
     }
 
     if is_streaming {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support streaming.".into(),
+        let streamer = Streamer {
+            rx,
+            is_done: false,
+            state,
+            guard,
+        };
+
+        return CompletionResponder::Sse(
+            Sse::new(streamer).keep_alive(
+                KeepAlive::new()
+                    .interval(Duration::from_millis(
+                        env::var("KEEP_ALIVE_INTERVAL")
+                            .map(|val| val.parse::<u64>().unwrap_or(1000))
+                            .unwrap_or(1000),
+                    ))
+                    .text("keep-alive-text"),
+            ),
         );
     }
 
-    sender.send(request).unwrap();
-
-    let response = rx.recv().unwrap();
+    let response = rx.recv().await;
+    guard.disarm();
+    // The engine drops the sender without a response if it stopped, e.g. after a panic.
+    let Some(response) = response else {
+        let e: Box<dyn Error> = "The engine stopped without sending a response.".into();
+        MistralRs::maybe_log_error(state, &*e);
+        return CompletionResponder::InternalError(e);
+    };
 
     match response {
         Response::InternalError(e) => {
@@ -185,9 +307,24 @@ pub async fn completions(
         Response::ValidationError(e) => CompletionResponder::ValidationError(e),
         Response::CompletionDone(response) => {
             MistralRs::maybe_log_response(state, &response);
-            CompletionResponder::Json(response)
+            // A choice which passed the deadline makes the request time out even if others were never
+            // scheduled.
+            let has_reason = |reason: &str| {
+                response
+                    .choices
+                    .iter()
+                    .any(|choice| choice.finish_reason == reason)
+            };
+            if has_reason("timeout") {
+                CompletionResponder::Timeout(response)
+            } else if has_reason("queue_timeout") {
+                CompletionResponder::QueueTimeout(response)
+            } else {
+                CompletionResponder::Json(response)
+            }
         }
         Response::Chunk(_) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
         Response::Done(_) => unreachable!(),
         Response::ModelError(_, _) => unreachable!(),
     }
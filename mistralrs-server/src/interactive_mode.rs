@@ -2,11 +2,12 @@ use indexmap::IndexMap;
 use mistralrs_core::{Constraint, MistralRs, Request, RequestMessage, Response, SamplingParams};
 use std::{
     io::{self, Write},
-    sync::{mpsc::channel, Arc},
+    sync::Arc,
 };
+use tokio::sync::mpsc::unbounded_channel;
 use tracing::{error, info};
 
-pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
+pub async fn interactive_mode(mistralrs: Arc<MistralRs>) {
     let sender = mistralrs.get_sender();
     let mut messages = Vec::new();
 
@@ -14,13 +15,21 @@ pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
         temperature: Some(0.1),
         top_k: Some(32),
         top_p: Some(0.1),
+        min_p: None,
+        typical_p: None,
+        tfs_z: None,
+        mirostat: None,
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        dry_params: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
+        raw_logprobs: false,
     };
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
     'outer: loop {
@@ -35,7 +44,7 @@ pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
         user_message.insert("content".to_string(), prompt);
         messages.push(user_message);
 
-        let (tx, rx) = channel();
+        let (tx, mut rx) = unbounded_channel();
         let req = Request {
             id: mistralrs.next_request_id(),
             messages: RequestMessage::Chat(messages.clone()),
@@ -45,42 +54,46 @@ pub fn interactive_mode(mistralrs: Arc<MistralRs>) {
             is_streaming: true,
             constraint: Constraint::None,
             suffix: None,
+            tools: None,
+            tool_choice: None,
+            priority: 0,
+            tenant: None,
+            deadline: None,
+            max_queue_time: None,
         };
         sender.send(req).unwrap();
 
         let mut assistant_output = String::new();
-        loop {
-            let resp = rx.try_recv();
-            if let Ok(resp) = resp {
-                match resp {
-                    Response::Chunk(chunk) => {
-                        let choice = &chunk.choices[0];
-                        assistant_output.push_str(&choice.delta.content);
-                        print!("{}", choice.delta.content);
-                        io::stdout().flush().unwrap();
-                        if choice.finish_reason.is_some() {
-                            if matches!(choice.finish_reason.as_ref().unwrap().as_str(), "length") {
-                                print!("...");
-                            }
-                            break;
+        while let Some(resp) = rx.recv().await {
+            match resp {
+                Response::Chunk(chunk) => {
+                    let choice = &chunk.choices[0];
+                    assistant_output.push_str(&choice.delta.content);
+                    print!("{}", choice.delta.content);
+                    io::stdout().flush().unwrap();
+                    if choice.finish_reason.is_some() {
+                        if matches!(choice.finish_reason.as_ref().unwrap().as_str(), "length") {
+                            print!("...");
                         }
+                        break;
                     }
-                    Response::InternalError(e) => {
-                        error!("Got an internal error: {e:?}");
-                        break 'outer;
-                    }
-                    Response::ModelError(e, resp) => {
-                        error!("Got a model error: {e:?}, response: {resp:?}");
-                        break 'outer;
-                    }
-                    Response::ValidationError(e) => {
-                        error!("Got a validation error: {e:?}");
-                        break 'outer;
-                    }
-                    Response::Done(_) => unreachable!(),
-                    Response::CompletionDone(_) => unreachable!(),
-                    Response::CompletionModelError(_, _) => unreachable!(),
                 }
+                Response::InternalError(e) => {
+                    error!("Got an internal error: {e:?}");
+                    break 'outer;
+                }
+                Response::ModelError(e, resp) => {
+                    error!("Got a model error: {e:?}, response: {resp:?}");
+                    break 'outer;
+                }
+                Response::ValidationError(e) => {
+                    error!("Got a validation error: {e:?}");
+                    break 'outer;
+                }
+                Response::Done(_) => unreachable!(),
+                Response::CompletionDone(_) => unreachable!(),
+                Response::CompletionModelError(_, _) => unreachable!(),
+                Response::CompletionChunk(_) => unreachable!(),
             }
         }
         let mut assistant_message = IndexMap::new();
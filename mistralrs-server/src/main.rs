@@ -8,10 +8,14 @@ use axum::{
 use candle_core::{quantized::GgmlDType, Device};
 use clap::Parser;
 use mistralrs_core::{
-    get_tgt_non_granular_index, DeviceMapMetadata, Loader, LoaderBuilder, MistralRs,
-    MistralRsBuilder, ModelKind, ModelSelected, SchedulerMethod, TokenSource,
+    get_tgt_non_granular_index, Constraint, DeviceMapMetadata, GGUFLoaderBuilder,
+    GGUFSpecificConfig, Loader, LoaderBuilder, MistralRs, MistralRsBuilder, ModelKind,
+    ModelSelected, PagedAttentionConfig, PrefixCacheDiskConfig, PromptLookupConfig, QueueStatus,
+    SchedulerMethod, SpeculativeConfig, TokenSource,
+};
+use openai::{
+    ChatCompletionRequest, Message, ModelObjects, ResponseFormat, StopTokens, Tool, ToolChoice,
 };
-use openai::{ChatCompletionRequest, Message, ModelObjects, StopTokens};
 use std::sync::Arc;
 mod chat_completion;
 mod completions;
@@ -49,6 +53,34 @@ fn parse_isq(s: &str) -> Result<GgmlDType, String> {
     }
 }
 
+/// Parse `<name>=<path>`, where the type of the grammar is given by the extension of the file.
+fn parse_named_grammar(s: &str) -> Result<(String, Constraint), String> {
+    let Some((name, path)) = s.split_once('=') else {
+        return Err(format!("Expected `<name>=<path>`, got `{s}`"));
+    };
+    let grammar = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read grammar `{path}`: {e}"))?;
+    let constraint = match std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some("regex") => Constraint::Regex(grammar.trim_end_matches('\n').to_string()),
+        Some("y" | "yacc") => Constraint::Yacc(grammar),
+        Some("gbnf") => Constraint::Gbnf(grammar),
+        Some("lark") => Constraint::Lark(grammar),
+        Some("json") => Constraint::JsonSchema(
+            serde_json::from_str(&grammar)
+                .map_err(|e| format!("Invalid JSON schema `{path}`: {e}"))?,
+        ),
+        _ => {
+            return Err(format!(
+                "Unknown grammar type of `{path}`, expected a .regex, .y, .yacc, .gbnf, .lark or .json file"
+            ))
+        }
+    };
+    Ok((name.to_string(), constraint))
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -82,6 +114,11 @@ struct Args {
     #[arg(long, default_value_t = false)]
     no_kv_cache: bool,
 
+    /// Schedule sequences by their estimated KV cache size instead of `max_seqs`, keeping it under this many MB.
+    /// Running sequences will be preempted and recomputed later if they outgrow the budget.
+    #[arg(long)]
+    kv_cache_budget_mb: Option<usize>,
+
     /// JINJA chat template with `messages`, `add_generation_prompt`, `bos_token`, `eos_token`, and `unk_token` as inputs.
     /// Used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
     #[arg(short, long)]
@@ -101,6 +138,22 @@ struct Args {
     #[arg(long, default_value_t = 16)]
     prefix_cache_n: usize,
 
+    /// Maximum size of the prefix caches on the device, in MB. The least recently used ones are evicted to the CPU first.
+    #[arg(long)]
+    prefix_cache_device_mb: Option<usize>,
+
+    /// Maximum size of the prefix caches on the CPU, in MB. The least recently used ones are dropped first.
+    #[arg(long, default_value_t = 4096)]
+    prefix_cache_host_mb: usize,
+
+    /// Directory to write prefix caches to when they are evicted from the device, so that they can be reused after a restart.
+    #[arg(long)]
+    prefix_cache_dir: Option<String>,
+
+    /// Maximum size of the prefix caches stored in `prefix_cache_dir`, in MB. The least recently used ones are deleted first.
+    #[arg(long, default_value_t = 10240)]
+    prefix_cache_disk_mb: usize,
+
     /// Number of device layers to load and run on the device. All others will be on the CPU.
     #[arg(short, long)]
     num_device_layers: Option<usize>,
@@ -108,6 +161,64 @@ struct Args {
     /// In-situ quantization to apply. You may specify one of the GGML data type (except F32 or F16): formatted like this: `Q4_0` or `Q4K`.
     #[arg(long = "isq", value_parser = parse_isq)]
     in_situ_quant: Option<GgmlDType>,
+
+    /// Number of KV cache blocks to allocate per layer for paged attention. Paged attention is enabled if this is set.
+    /// This disables the prefix cache, and is ignored for X-LoRA models.
+    #[arg(long)]
+    paged_attn_num_blocks: Option<usize>,
+
+    /// Number of tokens in each paged attention KV cache block.
+    #[arg(long, default_value_t = 16)]
+    paged_attn_block_size: usize,
+
+    /// Register a grammar which requests can use by name, formatted like `<name>=<path>`. It is compiled at startup.
+    /// The type of the grammar is given by the file extension: `.regex`, `.y` or `.yacc`, `.gbnf`, `.lark` or `.json` for a JSON schema.
+    #[arg(long = "grammar", value_parser = parse_named_grammar)]
+    named_grammars: Vec<(String, Constraint)>,
+
+    /// Number of compiled grammars to cache. The least recently used ones are dropped first.
+    #[arg(long, default_value_t = 32)]
+    grammar_cache_n: usize,
+
+    /// Model ID of the GGUF draft model for speculative decoding, which proposes tokens for the model to verify.
+    /// It must use the same tokenizer as the model. Speculative decoding is enabled if this is set.
+    #[arg(long, requires_all = ["draft_quantized_filename", "draft_tok_model_id"])]
+    draft_quantized_model_id: Option<String>,
+
+    /// Filename of the GGUF draft model within `draft_quantized_model_id`.
+    #[arg(long)]
+    draft_quantized_filename: Option<String>,
+
+    /// Model ID to load the tokenizer of the draft model from.
+    #[arg(long)]
+    draft_tok_model_id: Option<String>,
+
+    /// Number of tokens which the draft model or prompt lookup proposes at each step.
+    #[arg(long, default_value_t = 4)]
+    speculative_gamma: usize,
+
+    /// Use prompt lookup decoding, which proposes the tokens that followed the last generated tokens in the prompt,
+    /// matching up to this many tokens. This is ignored if a draft model is used.
+    #[arg(long)]
+    prompt_lookup_max_ngram: Option<usize>,
+
+    /// Run prompts in chunks of at most this many tokens, one chunk per step, so that other requests keep
+    /// generating while a long prompt is processed. This is not supported with paged attention.
+    #[arg(long)]
+    prefill_chunk_size: Option<usize>,
+
+    /// Schedule the waiting requests of the same priority from each tenant (the `user` of the request) in turn,
+    /// so that a tenant with many requests does not hold back the others.
+    #[arg(long)]
+    fair_share: bool,
+
+    /// Respond with `429 Too Many Requests` while this many requests are waiting to be scheduled.
+    #[arg(long)]
+    max_waiting_requests: Option<usize>,
+
+    /// Respond with `429 Too Many Requests` while the requests waiting to be scheduled have this many tokens to run.
+    #[arg(long)]
+    max_waiting_tokens: Option<usize>,
 }
 
 #[utoipa::path(
@@ -138,12 +249,22 @@ async fn health() -> &'static str {
     "OK"
 }
 
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/queue",
+    responses((status = 200, description = "Requests waiting to be scheduled"))
+)]
+async fn queue(State(state): State<Arc<MistralRs>>) -> Json<QueueStatus> {
+    Json(state.queue_status())
+}
+
 fn get_router(state: Arc<MistralRs>) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, health, queue, chatcompletions),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, StopTokens, Message, Tool, ToolChoice, ResponseFormat)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -171,6 +292,7 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .route("/v1/completions", post(completions))
         .route("/v1/models", get(models))
         .route("/health", get(health))
+        .route("/queue", get(queue))
         .route("/", get(health))
         .with_state(state)
 }
@@ -188,6 +310,7 @@ async fn main() -> Result<()> {
 
     if tgt_non_granular_index.is_some() {
         args.max_seqs = 1;
+        args.kv_cache_budget_mb = None;
     }
 
     let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
@@ -229,7 +352,7 @@ async fn main() -> Result<()> {
     info!("Model kind is: {}", loader.get_kind().as_ref());
     let pipeline = loader.load_model(
         None,
-        args.token_source,
+        args.token_source.clone(),
         None,
         &device,
         false,
@@ -240,18 +363,86 @@ async fn main() -> Result<()> {
     )?;
     info!("Model loaded.");
 
-    let mistralrs = MistralRsBuilder::new(
-        pipeline,
-        SchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
-    )
-    .with_opt_log(args.log)
-    .with_truncate_sequence(args.truncate_sequence)
-    .with_no_kv_cache(args.no_kv_cache)
-    .with_prefix_cache_n(args.prefix_cache_n)
-    .build();
+    let draft = match (
+        args.draft_quantized_model_id,
+        args.draft_quantized_filename,
+        args.draft_tok_model_id,
+    ) {
+        (Some(quantized_model_id), Some(quantized_filename), tok_model_id) => {
+            let draft_loader = GGUFLoaderBuilder::new(
+                GGUFSpecificConfig { repeat_last_n: 64 },
+                None,
+                None,
+                tok_model_id,
+                quantized_model_id,
+                quantized_filename,
+            )
+            .build();
+            info!("Loading draft model `{}`...", draft_loader.get_id());
+            let draft = draft_loader.load_model(
+                None,
+                args.token_source,
+                None,
+                &device,
+                false,
+                DeviceMapMetadata::dummy(),
+                None,
+            )?;
+            info!("Draft model loaded.");
+            Some(draft)
+        }
+        _ => None,
+    };
+
+    let method = match args.kv_cache_budget_mb {
+        Some(budget_mb) => {
+            SchedulerMethod::KvCacheBudget((budget_mb * 1024 * 1024).try_into().unwrap())
+        }
+        None => SchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+    };
+    let mut builder = MistralRsBuilder::new(pipeline, method)
+        .with_opt_log(args.log)
+        .with_truncate_sequence(args.truncate_sequence)
+        .with_no_kv_cache(args.no_kv_cache)
+        .with_prefix_cache_n(args.prefix_cache_n)
+        .with_prefix_cache_host_bytes(args.prefix_cache_host_mb * 1024 * 1024)
+        .with_grammar_cache_n(args.grammar_cache_n)
+        .with_named_grammars(args.named_grammars.into_iter().collect())
+        .with_fair_share(args.fair_share);
+    if let Some(max_waiting_requests) = args.max_waiting_requests {
+        builder = builder.with_max_waiting_requests(max_waiting_requests);
+    }
+    if let Some(max_waiting_tokens) = args.max_waiting_tokens {
+        builder = builder.with_max_waiting_tokens(max_waiting_tokens);
+    }
+    if let Some(device_mb) = args.prefix_cache_device_mb {
+        builder = builder.with_prefix_cache_device_bytes(device_mb * 1024 * 1024);
+    }
+    if let Some(num_blocks) = args.paged_attn_num_blocks {
+        builder = builder.with_paged_attn(PagedAttentionConfig::new(
+            args.paged_attn_block_size,
+            num_blocks,
+        ));
+    }
+    if let Some(draft) = draft {
+        builder = builder.with_speculative(draft, SpeculativeConfig::new(args.speculative_gamma));
+    } else if let Some(max_ngram) = args.prompt_lookup_max_ngram {
+        builder =
+            builder.with_prompt_lookup(PromptLookupConfig::new(args.speculative_gamma, max_ngram));
+    }
+    if let Some(chunk_size) = args.prefill_chunk_size {
+        builder = builder.with_prefill_chunk_size(chunk_size);
+    }
+    if let Some(dir) = args.prefix_cache_dir {
+        builder = builder.with_prefix_cache_disk(PrefixCacheDiskConfig::new(
+            dir,
+            args.prefix_cache_disk_mb * 1024 * 1024,
+        ));
+    }
+    let mistralrs = builder.build();
 
     if args.interactive_mode {
-        interactive_mode(mistralrs);
+        interactive_mode(mistralrs).await;
         return Ok(());
     }
 
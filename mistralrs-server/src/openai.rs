@@ -5,9 +5,77 @@ use utoipa::ToSchema;
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct Message {
-    pub content: String,
+    /// May be null for an assistant message with tool calls.
+    pub content: Option<String>,
     pub role: String,
     pub name: Option<String>,
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The ID of the tool call which a `tool` message responds to.
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, ToSchema)]
+pub enum ToolType {
+    #[serde(rename = "function")]
+    Function,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct CalledFunction {
+    pub name: String,
+    /// The arguments as a JSON string.
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: CalledFunction,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+    /// The JSON schema of the arguments.
+    #[schema(value_type = Option<Object>)]
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: Function,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    None,
+    Auto,
+    Required,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct NamedToolChoice {
+    #[serde(rename = "type")]
+    pub tp: ToolType,
+    pub function: FunctionName,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function(NamedToolChoice),
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -32,11 +100,44 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    #[serde(rename = "gbnf")]
+    Gbnf(String),
+    #[serde(rename = "lark")]
+    Lark(String),
+    #[serde(rename = "json_schema")]
+    JsonSchema(#[schema(value_type = Object)] serde_json::Value),
+    /// A grammar registered with `--grammar` when the server was started.
+    #[serde(rename = "named")]
+    Named(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct JsonSchemaResponseFormat {
+    pub name: String,
+    pub description: Option<String>,
+    #[schema(value_type = Object)]
+    pub schema: serde_json::Value,
+    /// The output always validates against the schema, so this is ignored.
+    pub strict: Option<bool>,
+}
+
+/// The format of the output. `json_object` and `json_schema` constrain the output to JSON.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponseFormat {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "json_object")]
+    JsonObject,
+    #[serde(rename = "json_schema")]
+    JsonSchema {
+        json_schema: JsonSchemaResponseFormat,
+    },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ChatCompletionRequest {
-    #[schema(example = json!(vec![Message{content:"Why did the crab cross the road?".to_string(), role:"user".to_string(), name: None}]))]
+    #[schema(example = json!(vec![Message{content:Some("Why did the crab cross the road?".to_string()), role:"user".to_string(), name: None, tool_calls: None, tool_call_id: None}]))]
     #[serde(with = "either::serde_untagged")]
     pub messages: Either<Vec<Message>, String>,
     #[schema(example = "mistral")]
@@ -67,13 +168,57 @@ pub struct ChatCompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    #[schema(example = json!(Option::None::<Vec<Tool>>))]
+    pub tools: Option<Vec<Tool>>,
+    #[schema(example = json!(Option::None::<ToolChoice>))]
+    pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
 
     // mistral.rs additional
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub raw_logprobs: bool,
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub mirostat: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_multiplier: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_base: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dry_allowed_length: Option<usize>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub dry_sequence_breakers: Option<Vec<String>>,
 
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+    /// Seconds after which the request is finished with the `timeout` finish reason.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub timeout: Option<f64>,
+    /// Seconds which the request may wait to be scheduled before it is finished with the `queue_timeout`
+    /// finish reason.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub max_queue_time: Option<f64>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -120,21 +265,57 @@ pub struct CompletionRequest {
     #[serde(rename = "stop")]
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
-    #[serde(rename = "stream")]
-    pub _stream: Option<bool>,
+    #[schema(example = false)]
+    pub stream: Option<bool>,
     #[schema(example = 0.7)]
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
-    #[serde(rename = "user")]
-    pub _user: Option<String>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub min_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub mirostat: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_multiplier: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub dry_base: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub dry_allowed_length: Option<usize>,
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub dry_sequence_breakers: Option<Vec<String>>,
 
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub token_healing: bool,
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+    /// Seconds after which the request is finished with the `timeout` finish reason.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub timeout: Option<f64>,
+    /// Seconds which the request may wait to be scheduled before it is finished with the `queue_timeout`
+    /// finish reason.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub max_queue_time: Option<f64>,
 }
@@ -1,4 +1,5 @@
-use std::sync::{mpsc::channel, Arc};
+use std::sync::Arc;
+use tokio::sync::mpsc::unbounded_channel;
 
 use candle_core::Device;
 use mistralrs::{
@@ -35,12 +36,13 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
 fn main() -> anyhow::Result<()> {
     let mistralrs = setup()?;
 
-    let (tx, rx) = channel();
+    let (tx, mut rx) = unbounded_channel();
     let request = Request {
         messages: RequestMessage::Completion {
             text: "Hello! My name is ".to_string(),
             echo_prompt: false,
             best_of: 1,
+            token_healing: false,
         },
         sampling_params: SamplingParams::default(),
         response: tx,
@@ -49,10 +51,16 @@ fn main() -> anyhow::Result<()> {
         id: 0,
         constraint: Constraint::None,
         suffix: None,
+        tools: None,
+        tool_choice: None,
+        priority: 0,
+        tenant: None,
+        deadline: None,
+        max_queue_time: None,
     };
     mistralrs.get_sender().send(request)?;
 
-    let response = rx.recv().unwrap();
+    let response = rx.blocking_recv().unwrap();
     match response {
         Response::CompletionDone(c) => println!("Text: {}", c.choices[0].text),
         _ => unreachable!(),
@@ -1,6 +1,7 @@
 pub use mistralrs_core::{
     ChatCompletionResponse, CompletionResponse, Constraint, DeviceMapMetadata, GGMLLoaderBuilder,
     GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, MistralRs, MistralRsBuilder,
-    NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Request,
-    RequestMessage, Response, SamplingParams, SchedulerMethod, StopTokens, TokenSource, Usage,
+    NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig,
+    PagedAttentionConfig, PrefixCacheDiskConfig, Request, RequestMessage, Response, SamplingParams,
+    SchedulerMethod, StopTokens, TokenSource, Usage,
 };